@@ -0,0 +1,87 @@
+//! リンクするcannylsのバージョンを求め、環境変数`KANILS_CANNYLS_VERSION`としてコンパイル時に渡す。
+//!
+//! ベンチマークの結果ファイルに記録し、cannylsのバージョンごとの結果を比較できるようにするために使う。
+//!
+//! バージョンは`cargo metadata --locked --offline`が返す依存関係の解決結果から求める。
+//! ロックファイルがない場合（`cargo install`を`--locked`なしで実行した場合など）や、
+//! 調べたワークスペースが実際にビルドしているものと異なる場合（他のクレートの依存としてビルドした場合など）は、
+//! 警告を出して`unknown`を渡す。
+use serde_json::Value;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    let version = cannyls_version().unwrap_or_else(|e| {
+        println!(
+            "cargo:warning=cannot determine the cannyls version ({}); benchmark results record it as `unknown`",
+            e
+        );
+        "unknown".to_owned()
+    });
+    println!("cargo:rustc-env=KANILS_CANNYLS_VERSION={}", version);
+}
+
+fn cannyls_version() -> Result<String, String> {
+    let manifest_path = PathBuf::from(env_var("CARGO_MANIFEST_DIR")?).join("Cargo.toml");
+    let output = Command::new(env_var("CARGO")?)
+        .args(["metadata", "--format-version", "1", "--locked", "--offline"])
+        .arg("--manifest-path")
+        .arg(&manifest_path)
+        .output()
+        .map_err(|e| format!("cannot run `cargo metadata`: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!(
+            "`cargo metadata` failed: {}",
+            stderr.lines().next().unwrap_or("").trim()
+        ));
+    }
+    let metadata: Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| format!("invalid output of `cargo metadata`: {}", e))?;
+
+    // ロックファイルが変わったら求め直す
+    if let Some(root) = metadata["workspace_root"].as_str() {
+        println!(
+            "cargo:rerun-if-changed={}",
+            Path::new(root).join("Cargo.lock").display()
+        );
+    }
+
+    // 出力先がワークスペースのtargetディレクトリ内でなければ、別のワークスペースの一部としてビルドされている
+    // （`CARGO_TARGET_DIR`で両者が同じディレクトリを使っている場合は見分けられない）
+    let target_dir = metadata["target_directory"].as_str().unwrap_or("");
+    if !Path::new(&env_var("OUT_DIR")?).starts_with(target_dir) {
+        return Err(format!(
+            "the lock file of {} is not the one used in this build",
+            manifest_path.display()
+        ));
+    }
+
+    let packages = metadata["packages"].as_array().ok_or("no packages")?;
+    let kanils = packages
+        .iter()
+        .find(|package| package["manifest_path"].as_str() == manifest_path.to_str())
+        .ok_or("kanils is not found")?;
+    let nodes = metadata["resolve"]["nodes"]
+        .as_array()
+        .ok_or("no resolve")?;
+    let cannyls_id = nodes
+        .iter()
+        .find(|node| node["id"] == kanils["id"])
+        .and_then(|node| node["deps"].as_array())
+        .and_then(|deps| deps.iter().find(|dep| dep["name"] == "cannyls"))
+        .map(|dep| &dep["pkg"])
+        .ok_or("cannyls is not resolved")?;
+    packages
+        .iter()
+        .find(|package| package["id"] == *cannyls_id)
+        .and_then(|package| package["version"].as_str())
+        .map(str::to_owned)
+        .ok_or_else(|| "cannyls is not found".to_owned())
+}
+
+fn env_var(name: &str) -> Result<String, String> {
+    env::var(name).map_err(|_| format!("`{}` is not set", name))
+}
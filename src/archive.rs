@@ -0,0 +1,338 @@
+//! 複数のlumpを一つのファイルにまとめるアーカイブ形式。
+//!
+//! `GetRange --export`で書き出したlumpや、`Delete --backup`などで削除前に保存したlumpを、
+//! 後から`Import`でストレージに書き戻せるようにするために用いる。
+//! 形式は次の通り（整数は全てビッグエンディアン）:
+//!
+//! ```text
+//! アーカイブ := マジックナンバー(8バイト: "KNLSLUMP") バージョン(1バイト: 1) エントリ*
+//! エントリ   := 種類(1バイト: 0=block, 1=embedded) lump id(16バイト) 値の長さ(4バイト) 値
+//! ```
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use cannyls::lump::{LumpData, LumpId};
+use cannyls::nvm::NonVolatileMemory;
+use cannyls::ErrorKind;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use trackable::error::ErrorKindExt;
+
+use crate::handle::{LumpKind, StorageHandle};
+
+/// アーカイブの先頭に置くマジックナンバー。
+pub const MAGIC: &[u8; 8] = b"KNLSLUMP";
+
+/// アーカイブ形式のバージョン。
+pub const VERSION: u8 = 1;
+
+/// アーカイブ中の一つのlump。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveEntry {
+    pub lump_id: LumpId,
+    pub kind: LumpKind,
+    pub data: Vec<u8>,
+}
+
+/// アーカイブを書き出す。
+#[derive(Debug)]
+pub struct ArchiveWriter<W: Write> {
+    inner: W,
+    entries: usize,
+    bytes: u64,
+}
+impl<W: Write> ArchiveWriter<W> {
+    /// ヘッダ（マジックナンバーとバージョン）を書き込む。
+    pub fn new(mut inner: W) -> Result<Self, cannyls::Error> {
+        track_io!(inner.write_all(MAGIC))?;
+        track_io!(inner.write_u8(VERSION))?;
+        Ok(ArchiveWriter {
+            inner,
+            entries: 0,
+            bytes: 0,
+        })
+    }
+
+    /// lumpを一つ追記する。
+    pub fn append(
+        &mut self,
+        lump_id: LumpId,
+        kind: LumpKind,
+        data: &[u8],
+    ) -> Result<(), cannyls::Error> {
+        let tag = match kind {
+            LumpKind::Block => 0,
+            LumpKind::Embedded => 1,
+        };
+        track_io!(self.inner.write_u8(tag))?;
+        track_io!(self.inner.write_u128::<BigEndian>(lump_id.as_u128()))?;
+        track_io!(self.inner.write_u32::<BigEndian>(data.len() as u32))?;
+        track_io!(self.inner.write_all(data))?;
+        self.entries += 1;
+        self.bytes += data.len() as u64;
+        Ok(())
+    }
+
+    /// これまでに追記したlumpの数。
+    pub fn entries(&self) -> usize {
+        self.entries
+    }
+
+    /// これまでに追記したlumpの値の合計バイト数。
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// 書き込みをフラッシュし、内部のライタを返す。
+    pub fn finish(mut self) -> Result<W, cannyls::Error> {
+        track_io!(self.inner.flush())?;
+        Ok(self.inner)
+    }
+}
+
+/// アーカイブを読み込む。
+#[derive(Debug)]
+pub struct ArchiveReader<R: Read> {
+    inner: R,
+}
+impl<R: Read> ArchiveReader<R> {
+    /// ヘッダを読み込み、アーカイブ形式であることを検証する。
+    pub fn new(mut inner: R) -> Result<Self, cannyls::Error> {
+        let mut magic = [0; 8];
+        track!(read_exact(&mut inner, &mut magic))?;
+        track_assert!(
+            &magic == MAGIC,
+            ErrorKind::InvalidInput,
+            "not a KaNiLS lump archive"
+        );
+        let mut version = [0; 1];
+        track!(read_exact(&mut inner, &mut version))?;
+        track_assert_eq!(
+            version[0],
+            VERSION,
+            ErrorKind::InvalidInput,
+            "unsupported archive version"
+        );
+        Ok(ArchiveReader { inner })
+    }
+
+    /// 次のエントリを読み込む（末尾に達した場合は`None`）。
+    pub fn next_entry(&mut self) -> Result<Option<ArchiveEntry>, cannyls::Error> {
+        let mut tag = [0; 1];
+        if track_io!(self.inner.read(&mut tag))? == 0 {
+            return Ok(None);
+        }
+        let kind = match tag[0] {
+            0 => LumpKind::Block,
+            1 => LumpKind::Embedded,
+            tag => track_panic!(ErrorKind::InvalidInput, "unknown lump kind tag: {}", tag),
+        };
+        let mut header = [0; 20];
+        track!(read_exact(&mut self.inner, &mut header))?;
+        let mut header = &header[..];
+        let lump_id = LumpId::new(track_io!(header.read_u128::<BigEndian>())?);
+        let len = track_io!(header.read_u32::<BigEndian>())? as usize;
+        track_assert!(
+            len <= LumpData::MAX_SIZE,
+            ErrorKind::InvalidInput,
+            "the archive is corrupted (lump {} has {} bytes > {} bytes)",
+            lump_id.as_u128(),
+            len,
+            LumpData::MAX_SIZE
+        );
+        let mut data = vec![0; len];
+        track!(read_exact(&mut self.inner, &mut data))?;
+        Ok(Some(ArchiveEntry {
+            lump_id,
+            kind,
+            data,
+        }))
+    }
+}
+
+// 途中で終わっているアーカイブを、I/Oエラーではなく不正な入力として扱う
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<(), cannyls::Error> {
+    reader.read_exact(buf).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            ErrorKind::InvalidInput
+                .cause("the archive is truncated")
+                .into()
+        } else {
+            cannyls::Error::from(e)
+        }
+    })
+}
+
+/// `lump_ids`のlumpを、格納場所とともに`writer`に書き出す（存在しないlumpは無視する）。
+pub fn export<N: NonVolatileMemory, W: Write>(
+    handle: &mut StorageHandle<N>,
+    lump_ids: &[LumpId],
+    writer: &mut ArchiveWriter<W>,
+) -> Result<(), cannyls::Error> {
+    for lump_id in lump_ids {
+        if let Some((data, kind)) = track!(handle.get_with_kind(lump_id.as_u128()))? {
+            // 格納場所が分からないlumpは、どんな値でも格納できるブロックとして書き出す
+            let kind = kind.unwrap_or(LumpKind::Block);
+            track!(writer.append(*lump_id, kind, data.as_bytes()))?;
+        }
+    }
+    Ok(())
+}
+
+/// `lump_ids`のlumpを新たに作成する`path`のアーカイブに書き出し、lumpの数と値の合計バイト数を返す。
+///
+/// 既存のファイルは上書きしない。書き出しに失敗した場合は作りかけのファイルを削除する。
+pub fn export_to_file<N: NonVolatileMemory, P: AsRef<Path>>(
+    handle: &mut StorageHandle<N>,
+    lump_ids: &[LumpId],
+    path: P,
+) -> Result<(usize, u64), cannyls::Error> {
+    let path = path.as_ref();
+    let file = track_io!(OpenOptions::new().write(true).create_new(true).open(path))?;
+    let result = ArchiveWriter::new(BufWriter::new(file)).and_then(|mut writer| {
+        track!(export(handle, lump_ids, &mut writer))?;
+        let summary = (writer.entries(), writer.bytes());
+        track!(writer.finish())?;
+        Ok(summary)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(path);
+    }
+    result
+}
+
+/// `Import`の結果。
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ImportSummary {
+    /// 書き込んだlumpの数
+    pub lumps: usize,
+
+    /// 書き込んだlumpの値の合計バイト数
+    pub bytes: u64,
+
+    /// 既存のlumpを上書きした数
+    pub overwritten: usize,
+}
+
+/// アーカイブ中のlumpを、アーカイブに記録された格納場所（embedded/block）のまま`handle`に書き込む。
+///
+/// 同じidのlumpが既に存在する場合は上書きする。
+pub fn import<N: NonVolatileMemory, R: Read>(
+    handle: &mut StorageHandle<N>,
+    reader: &mut ArchiveReader<R>,
+) -> Result<ImportSummary, cannyls::Error> {
+    let mut summary = ImportSummary::default();
+    while let Some(entry) = track!(reader.next_entry())? {
+        let key = entry.lump_id.as_u128();
+        let created = match entry.kind {
+            LumpKind::Embedded => track!(handle.embed_bytes(key, &entry.data))?,
+            LumpKind::Block => track!(handle.put_bytes(key, &entry.data))?,
+        };
+        summary.lumps += 1;
+        summary.bytes += entry.data.len() as u64;
+        if !created {
+            summary.overwritten += 1;
+        }
+    }
+    Ok(summary)
+}
+
+/// `path`のアーカイブ中のlumpを`handle`に書き込む。
+pub fn import_from_file<N: NonVolatileMemory, P: AsRef<Path>>(
+    handle: &mut StorageHandle<N>,
+    path: P,
+) -> Result<ImportSummary, cannyls::Error> {
+    let file = track_io!(File::open(path))?;
+    let mut reader = track!(ArchiveReader::new(BufReader::new(file)))?;
+    track!(import(handle, &mut reader))
+}
+
+#[cfg(test)]
+mod tests {
+    use cannyls::nvm::MemoryNvm;
+    use cannyls::storage::Storage;
+    use trackable::result::TestResult;
+
+    use super::*;
+
+    #[test]
+    fn export_and_read_work() -> TestResult {
+        let nvm = MemoryNvm::new(vec![0; 1024 * 1024]);
+        let mut handle = StorageHandle::new(track!(Storage::create(nvm))?);
+        track!(handle.put_str(1, "one"))?;
+        track!(handle.embed_bytes(2, &[0, 255]))?;
+        track!(handle.put_str(3, "three"))?;
+
+        let mut writer = track!(ArchiveWriter::new(Vec::new()))?;
+        let lump_ids = handle.keys_in_range(1, 3);
+        track!(export(&mut handle, &lump_ids, &mut writer))?;
+        assert_eq!((writer.entries(), writer.bytes()), (2, 5));
+        let archive = track!(writer.finish())?;
+
+        let mut reader = track!(ArchiveReader::new(&archive[..]))?;
+        let entry = track!(reader.next_entry())?.unwrap();
+        assert_eq!(
+            entry,
+            ArchiveEntry {
+                lump_id: LumpId::new(1),
+                kind: LumpKind::Block,
+                data: b"one".to_vec(),
+            }
+        );
+        let entry = track!(reader.next_entry())?.unwrap();
+        assert_eq!((entry.kind, entry.data), (LumpKind::Embedded, vec![0, 255]));
+        assert!(track!(reader.next_entry())?.is_none());
+
+        // 途中で終わっているアーカイブや、アーカイブでないファイルはエラーになる
+        let mut reader = track!(ArchiveReader::new(&archive[..archive.len() - 1]))?;
+        assert!(track!(reader.next_entry())?.is_some());
+        assert!(reader.next_entry().is_err());
+        assert!(ArchiveReader::new(&b"KNLSLUMQ\x01"[..]).is_err());
+
+        // 値の長さが壊れている場合は、確保する前にエラーになる
+        let mut corrupted = archive[..MAGIC.len() + 1 + 17].to_vec();
+        corrupted.extend_from_slice(&[0xff; 4]);
+        let mut reader = track!(ArchiveReader::new(&corrupted[..]))?;
+        assert_eq!(
+            *reader.next_entry().unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn import_restores_deleted_lumps() -> TestResult {
+        let nvm = MemoryNvm::new(vec![0; 1024 * 1024]);
+        let mut handle = StorageHandle::new(track!(Storage::create(nvm))?);
+        track!(handle.put_str(1, "one"))?;
+        track!(handle.embed_str(2, "two"))?;
+
+        let mut writer = track!(ArchiveWriter::new(Vec::new()))?;
+        let lump_ids = handle.keys_in_range(0, 10);
+        track!(export(&mut handle, &lump_ids, &mut writer))?;
+        let archive = track!(writer.finish())?;
+        track!(handle.delete_range_keys(0, 2))?;
+
+        let mut reader = track!(ArchiveReader::new(&archive[..]))?;
+        let summary = track!(import(&mut handle, &mut reader))?;
+        assert_eq!(
+            summary,
+            ImportSummary {
+                lumps: 2,
+                bytes: 6,
+                overwritten: 1,
+            }
+        );
+        assert_eq!(handle.lump_kinds().len(), 2);
+        let (data, kind) = track!(handle.get_with_kind(1))?.unwrap();
+        assert_eq!(
+            (data.as_bytes(), kind),
+            (&b"one"[..], Some(LumpKind::Block))
+        );
+        let (data, kind) = track!(handle.get_with_kind(2))?.unwrap();
+        assert_eq!(
+            (data.as_bytes(), kind),
+            (&b"two"[..], Some(LumpKind::Embedded))
+        );
+        Ok(())
+    }
+}
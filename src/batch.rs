@@ -0,0 +1,178 @@
+//! コマンドを記述したスクリプトを、1回のストレージセッションの中で順に実行するバッチモード。
+//!
+//! スクリプトの各行は対話モード（`Open`）と同じコマンド言語で記述する。
+//! 空行と`#`から始まる行は無視され、`quit`を実行するとそれ以降の行は実行されない。
+use cannyls::nvm::NonVolatileMemory;
+use std::io::BufRead;
+
+use crate::repl::{describe_error, Control, Session};
+
+/// バッチ実行の結果の集計。
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BatchSummary {
+    /// 実行に成功したコマンド数
+    pub succeeded: usize,
+
+    /// 実行に失敗したコマンド数
+    pub failed: usize,
+
+    /// `stop_on_error`によって実行されなかったコマンド数
+    pub skipped: usize,
+
+    /// スクリプトの終了時にcommitされずに破棄された変更の数
+    pub uncommitted: usize,
+}
+impl BatchSummary {
+    /// 全てのコマンドが成功したかどうか
+    pub fn is_success(&self) -> bool {
+        self.failed == 0 && self.skipped == 0 && self.uncommitted == 0
+    }
+}
+
+fn is_command_line(line: &str) -> bool {
+    let line = line.trim();
+    !line.is_empty() && !line.starts_with('#')
+}
+
+/// `script`の各行を`session`で実行し、行ごとの結果と最後に集計を出力する。
+///
+/// `stop_on_error`が`true`の場合には、最初に失敗したコマンド以降は実行しない。
+/// 最後にジャーナルを同期するので、このセッションでの操作は全て永続化される。
+/// ただし、スクリプトの終了時にcommitされていないトランザクションの変更は破棄される。
+pub fn run_batch<N: NonVolatileMemory, R: BufRead>(
+    session: &mut Session<N>,
+    script: R,
+    stop_on_error: bool,
+) -> Result<BatchSummary, cannyls::Error> {
+    let mut summary = BatchSummary::default();
+    let mut stopped = false;
+    for (i, line) in script.lines().enumerate() {
+        let line = track!(line.map_err(cannyls::Error::from))?;
+        if !is_command_line(&line) {
+            continue;
+        }
+        if stopped {
+            summary.skipped += 1;
+            continue;
+        }
+
+        let line_number = i + 1;
+        println!("[line {}] {}", line_number, line.trim());
+        match session.handle_input(&line) {
+            Ok(Control::Continue) => {
+                summary.succeeded += 1;
+            }
+            Ok(Control::Quit) => {
+                summary.succeeded += 1;
+                break;
+            }
+            Err(e) => {
+                println!("[line {}] error: {}", line_number, describe_error(&e));
+                summary.failed += 1;
+                stopped = stop_on_error;
+            }
+        }
+    }
+    if let Some(n) = session.pending_changes() {
+        println!(
+            "discarded {} uncommitted change(s) at the end of the script",
+            n
+        );
+        summary.uncommitted = n;
+    }
+    track!(session.handle_mut().journal_sync())?;
+
+    println!(
+        "batch finished: {} succeeded, {} failed, {} skipped, {} uncommitted",
+        summary.succeeded, summary.failed, summary.skipped, summary.uncommitted
+    );
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use cannyls::nvm::FileNvm;
+    use cannyls::storage::Storage;
+    use tempfile::Builder;
+    use trackable::result::TestResult;
+
+    use super::*;
+    use crate::handle::StorageHandle;
+
+    #[test]
+    fn run_batch_works() -> TestResult {
+        let dir = track_io!(Builder::new().prefix("cannyls_test").tempdir())?;
+        let path = dir.path().join("test.lusf");
+
+        let nvm = track_try_unwrap!(FileNvm::create(path, 4_000_000));
+        let storage = track_try_unwrap!(Storage::create(nvm));
+        let mut session = Session::new(StorageHandle::new(storage));
+
+        let script = "# comment\nput 1 foo\n\nput 0x2 bar\nget zzz\ndelete 1\n";
+        let summary = track!(run_batch(&mut session, script.as_bytes(), false))?;
+        assert_eq!(
+            summary,
+            BatchSummary {
+                succeeded: 3,
+                failed: 1,
+                skipped: 0,
+                uncommitted: 0
+            }
+        );
+        assert!(session.handle_mut().get_as_string(1)?.is_none());
+        assert_eq!(
+            session.handle_mut().get_as_string(2)?.unwrap().unwrap(),
+            "bar".to_owned()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn run_batch_stops_on_error() -> TestResult {
+        let dir = track_io!(Builder::new().prefix("cannyls_test").tempdir())?;
+        let path = dir.path().join("test.lusf");
+
+        let nvm = track_try_unwrap!(FileNvm::create(path, 4_000_000));
+        let storage = track_try_unwrap!(Storage::create(nvm));
+        let mut session = Session::new(StorageHandle::new(storage));
+
+        let script = "put 1 foo\nunknown\nput 2 bar\nput 3 baz\n";
+        let summary = track!(run_batch(&mut session, script.as_bytes(), true))?;
+        assert_eq!(
+            summary,
+            BatchSummary {
+                succeeded: 1,
+                failed: 1,
+                skipped: 2,
+                uncommitted: 0
+            }
+        );
+        assert!(!summary.is_success());
+        assert!(session.handle_mut().get_as_string(2)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn transactions_work() -> TestResult {
+        let dir = track_io!(Builder::new().prefix("cannyls_test").tempdir())?;
+        let path = dir.path().join("test.lusf");
+
+        let nvm = track_try_unwrap!(FileNvm::create(path, 4_000_000));
+        let storage = track_try_unwrap!(Storage::create(nvm));
+        let mut session = Session::new(StorageHandle::new(storage));
+
+        let script = "put 1 foo\nbegin\nput 2 bar\ndelete 1\nabort\n\
+                      begin\nput 3 baz\ncommit\nbegin\nput 4 qux\n";
+        let summary = track!(run_batch(&mut session, script.as_bytes(), false))?;
+        assert_eq!(summary.succeeded, 10);
+        assert_eq!(summary.uncommitted, 1);
+        assert!(!summary.is_success());
+
+        let handle = session.handle_mut();
+        assert_eq!(handle.get_as_string(1)?.unwrap().unwrap(), "foo".to_owned());
+        assert!(handle.get_as_string(2)?.is_none());
+        assert_eq!(handle.get_as_string(3)?.unwrap().unwrap(), "baz".to_owned());
+        assert!(handle.get_as_string(4)?.is_none());
+        Ok(())
+    }
+}
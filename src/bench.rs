@@ -1,123 +1,377 @@
+use cannyls::device::{Device, DeviceHandle};
 use cannyls::lump::LumpId;
-use cannyls::nvm::FileNvm;
+use cannyls::nvm::{FileNvm, MemoryNvm, NonVolatileMemory};
 use cannyls::storage::{Storage, StorageBuilder};
-use std::path::PathBuf;
-use std::time::SystemTime;
+use cannyls::ErrorKind;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use trackable::error::ErrorKindExt;
 
 use indicatif::{ProgressBar, ProgressStyle};
 
-struct Timer {
-    start: SystemTime,
-    message: String,
+use crate::device::{block_on, print_device_metrics, spawn_device, RequestOptions};
+use crate::fault::{FaultInjector, FaultNvm, FaultPlan};
+use crate::lock::{LockMode, StorageLock};
+use crate::readonly::ReadOnlyNvm;
+use crate::report::{BenchResult, PhaseRecorder, PhaseResult};
+use crate::size::format_bytes_with_size;
+use crate::workload::{Op, OpKind, Payload, SizeDistribution, WorkloadGenerator, WorkloadSpec};
+
+/// ベンチマークに用いるストレージの置き場所
+#[derive(Debug, Clone)]
+pub enum BenchTarget {
+    /// 指定したパスに新たにlusfファイルを作成する
+    File(PathBuf),
+
+    /// メモリ上にストレージを作成する（`MemoryNvm`を用いる）
+    Memory,
+
+    /// 既存のlusfファイルを読み込み専用で開く（ファイルには一切書き込まない）
+    ///
+    /// `lock`が`true`の場合は、ベンチマークの間ファイルの共有ロックを保持する。
+    Existing { path: PathBuf, lock: bool },
+
+    /// 既存のlusfファイル`original`を`copy`に複製し、複製に対してベンチマークを行う
+    ///
+    /// `lock`が`true`の場合は、複製の間`original`の共有ロックを保持する。
+    Copy {
+        original: PathBuf,
+        copy: PathBuf,
+        lock: bool,
+    },
+}
+
+/// ベンチマークの前にストレージを埋めておく量
+#[derive(Debug, Clone)]
+pub struct Fill {
+    /// データ領域の使用率の目標（0以上1未満）
+    pub ratio: f64,
+
+    /// 埋めるために書き込むlumpのサイズ
+    pub size: SizeDistribution,
 }
-impl Timer {
-    pub fn new(message: &str) -> Self {
-        println!("[{}] start", message);
-        Timer {
-            start: SystemTime::now(),
-            message: message.to_owned(),
+
+/// ベンチマークに用いるストレージの指定
+#[derive(Debug, Clone)]
+pub struct BenchStorage {
+    pub target: BenchTarget,
+
+    /// ベンチマークの前にデータ領域を埋めておく量
+    pub fill: Option<Fill>,
+
+    /// ベンチマークの後に、作成したファイル（`File`または`Copy`の複製）を削除する
+    pub remove_after: bool,
+}
+impl BenchStorage {
+    /// 作成するファイルまたはメモリ上に、ストレージを新たに作成する指定を返す。
+    pub fn new(target: BenchTarget) -> Self {
+        BenchStorage {
+            target,
+            fill: None,
+            remove_after: false,
         }
     }
 
-    fn secs_to_readable(_secs: u64) -> String {
-        let mut secs = _secs;
-        let mut result: String = String::from("");
-        if secs > 60 * 60 {
-            let hour = secs / (60 * 60);
-            secs %= 60 * 60;
-            result.push_str(&format!("{}h ", hour));
-        }
-        if secs > 60 {
-            let minutes = secs / 60;
-            secs %= 60;
-            result.push_str(&format!("{}m ", minutes));
+    // ベンチマークの後始末として、必要であれば作成したファイルを削除する
+    fn finish(&self) {
+        if !self.remove_after {
+            return;
         }
-        result.push_str(&format!("{}s", secs));
-        result
+        let path = match self.target {
+            BenchTarget::File(ref path) => path,
+            BenchTarget::Copy { ref copy, .. } => copy,
+            BenchTarget::Memory | BenchTarget::Existing { .. } => return,
+        };
+        track_try_unwrap!(track_io!(fs::remove_file(path)));
+        println!("removed {:?}", path);
     }
 }
 
-impl Drop for Timer {
-    fn drop(&mut self) {
-        let elapsed = self.start.elapsed().expect("should be succeeded");
-        println!(
-            "[{}] finish @ {} {}ms",
-            self.message,
-            Timer::secs_to_readable(elapsed.as_secs()),
-            elapsed.subsec_millis()
-        );
-    }
-}
+/// 埋めるためのlumpに用いるlump idの開始値（ベンチマークが用いるキーと衝突しないように、上位の範囲を用いる）
+pub const FILL_KEY_BASE: u128 = 1 << 127;
 
-fn create_storage_for_benchmark(
-    path: PathBuf,
+fn create_storage_for_benchmark<N: NonVolatileMemory>(
     count: u64,
     size: u64,
-) -> Result<(Storage<FileNvm>, u64), cannyls::Error> {
+    fill: Option<&Fill>,
+    create_nvm: impl FnOnce(u64) -> Result<N, cannyls::Error>,
+) -> Result<(Storage<N>, u64), cannyls::Error> {
     let total = count * size;
-    let capacity = total * 2;
+    let mut capacity = total * 2;
+    if let Some(fill) = fill {
+        // 埋めた後にも、ベンチマークで書き込む分（と25%の余裕）が残るようにする
+        let required = (total as f64 * 1.25 / (1.0 - fill.ratio)) as u64;
+        capacity = capacity.max(required);
+    }
     let mut journal_ratio = 0.01f64;
-    if ((capacity as f64 * journal_ratio) as u64) < 256 * count as u64 {
+    if ((capacity as f64 * journal_ratio) as u64) < 256 * count {
         // 256 is sufficient large byte for one journal record
-        journal_ratio = (256 * count as u64) as f64 / capacity as f64;
+        journal_ratio = (256 * count) as f64 / capacity as f64;
     }
-    let nvm = track_try_unwrap!(FileNvm::create(path, capacity as u64));
+    let nvm = track!(create_nvm(capacity))?;
     track!(StorageBuilder::new()
         .journal_region_ratio(journal_ratio)
         .create(nvm))
-    .map(|s| (s, total as u64))
+    .map(|s| (s, total))
 }
 
-pub fn seq_write(path: PathBuf, count: u64, size: u64) {
-    println!("count = {:?}, size = {:?}", count, size);
+// `original`を`copy`に複製し、複製を開く（`copy`が既に存在する場合はエラー）
+fn copy_storage(
+    original: &Path,
+    copy: &Path,
+    lock: bool,
+) -> Result<Storage<FileNvm>, cannyls::Error> {
+    {
+        let _lock = if lock {
+            Some(track!(StorageLock::acquire(original, LockMode::Shared))?)
+        } else {
+            None
+        };
+        let mut src = track_io!(File::open(original))?;
+        let mut dst = track_io!(OpenOptions::new().write(true).create_new(true).open(copy))?;
+        let bytes = track_io!(io::copy(&mut src, &mut dst))?;
+        track_io!(dst.sync_all())?;
+        println!(
+            "copied {:?} to {:?} ({})",
+            original,
+            copy,
+            format_bytes_with_size(bytes)
+        );
+    }
+    let nvm = track!(FileNvm::open(copy))?;
+    track!(StorageBuilder::new().open(nvm))
+}
+
+// データ領域の使用率が`fill.ratio`に達するまで、`FILL_KEY_BASE`以降のlump idでlumpをputする
+fn fill_storage<N: NonVolatileMemory>(
+    storage: &mut Storage<N>,
+    fill: &Fill,
+) -> Result<(), cannyls::Error> {
+    let capacity = storage.metrics().data_region().capacity_bytes();
+    let target = (capacity as f64 * fill.ratio) as u64;
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut next = FILL_KEY_BASE;
+    let mut lumps = 0;
+    while storage.metrics().data_region().usage_bytes() < target {
+        let lump_id = LumpId::new(next);
+        next += 1;
+        if storage.head(&lump_id).is_some() {
+            continue;
+        }
+        let size = fill.size.sample(&mut rng);
+        let data = track!(storage.allocate_lump_data_with_bytes(&vec![0; size as usize]))?;
+        match storage.put(&lump_id, &data) {
+            Ok(_) => lumps += 1,
+            Err(ref e) if *e.kind() == ErrorKind::StorageFull => break,
+            Err(e) => return Err(track!(e)),
+        }
+    }
+    track!(storage.journal_sync())?;
+    let usage = storage.metrics().data_region().usage_bytes();
+    println!(
+        "filled the storage with {} lumps: data region usage = {} / {} ({:.1}%)",
+        lumps,
+        format_bytes_with_size(usage),
+        format_bytes_with_size(capacity),
+        usage as f64 / capacity as f64 * 100.0
+    );
+    Ok(())
+}
+
+// `storage`に応じたストレージを用意し、`$f(storage, total, count, size, ...)`を呼び出す
+//
+// ストレージの置き場所ごとにストレージの型が異なるので、マクロで全てを展開する。
+macro_rules! with_bench_storage {
+    ($storage:expr, $count:expr, $size:expr, $f:ident $(, $arg:expr)*) => {{
+        let bench_storage: BenchStorage = $storage;
+        let fill = bench_storage.fill.as_ref();
+        let result = match bench_storage.target {
+            BenchTarget::File(ref path) => {
+                let (mut storage, total) = track_try_unwrap!(create_storage_for_benchmark(
+                    $count,
+                    $size,
+                    fill,
+                    |capacity| FileNvm::create(path, capacity)
+                ));
+                if let Some(fill) = fill {
+                    track_try_unwrap!(fill_storage(&mut storage, fill));
+                }
+                $f(storage, total, $count, $size $(, $arg)*)
+            }
+            BenchTarget::Memory => {
+                let (mut storage, total) = track_try_unwrap!(create_storage_for_benchmark(
+                    $count,
+                    $size,
+                    fill,
+                    |capacity| Ok(MemoryNvm::new(vec![0; capacity as usize]))
+                ));
+                if let Some(fill) = fill {
+                    track_try_unwrap!(fill_storage(&mut storage, fill));
+                }
+                $f(storage, total, $count, $size $(, $arg)*)
+            }
+            BenchTarget::Existing { ref path, lock } => {
+                let _lock = if lock {
+                    Some(track_try_unwrap!(StorageLock::acquire(path, LockMode::Shared)))
+                } else {
+                    None
+                };
+                let nvm = track_try_unwrap!(ReadOnlyNvm::open(path));
+                let storage = track_try_unwrap!(StorageBuilder::new().open(nvm));
+                $f(storage, $count * $size, $count, $size $(, $arg)*)
+            }
+            BenchTarget::Copy {
+                ref original,
+                ref copy,
+                lock,
+            } => {
+                let mut storage = track_try_unwrap!(copy_storage(original, copy, lock));
+                if let Some(fill) = fill {
+                    track_try_unwrap!(fill_storage(&mut storage, fill));
+                }
+                $f(storage, $count * $size, $count, $size $(, $arg)*)
+            }
+        };
+        bench_storage.finish();
+        result
+    }};
+}
+
+pub fn seq_write(
+    storage: BenchStorage,
+    count: u64,
+    size: u64,
+    device: Option<DeviceBenchOptions>,
+) -> BenchResult {
+    with_bench_storage!(storage, count, size, seq_write_on, device)
+}
+
+fn print_total(total: u64, now: SystemTime) {
+    if let Ok(elapsed) = now.elapsed() {
+        println!(
+            "total = {}, elapsed = {:?}",
+            format_bytes_with_size(total),
+            elapsed
+        );
+    }
+}
+
+fn seq_write_on<N: NonVolatileMemory + Send + 'static>(
+    mut storage: Storage<N>,
+    total: u64,
+    count: u64,
+    size: u64,
+    device: Option<DeviceBenchOptions>,
+) -> BenchResult {
+    println!(
+        "count = {:?}, size = {}",
+        count,
+        format_bytes_with_size(size)
+    );
+    let mut result = BenchResult::new("seq_write");
+    result.retain_metrics(&storage);
+    if let Some(mut options) = device {
+        // 直接操作する場合と同様に、putの度にジャーナルを同期する
+        options.request.journal_sync = true;
+        result.phases = run_on_device(storage, options, |bench| {
+            let now = SystemTime::now();
+            let phases = bench.run("Putting Data", count, |handle, i, samples| {
+                samples.put(handle, i, size);
+            });
+            print_total(total, now);
+            phases
+        });
+        return result;
+    }
 
-    let (mut storage, total) =
-        create_storage_for_benchmark(path, count as u64, size as u64).unwrap();
     let tmp_vec: Vec<u8> = vec![0; size as usize];
 
     let now = SystemTime::now();
 
+    let mut phase = PhaseRecorder::start("Putting Data");
     for i in 0..count {
         let lump_id = LumpId::new(u128::from(i));
         let lump_data = track_try_unwrap!(storage.allocate_lump_data_with_bytes(tmp_vec.as_ref()));
-        storage.put(&lump_id, &lump_data).unwrap();
-        storage.journal_sync().unwrap();
+        phase
+            .time("put", size, || storage.put(&lump_id, &lump_data))
+            .unwrap();
+        phase
+            .time("journal_sync", 0, || storage.journal_sync())
+            .unwrap();
     }
+    result.phases.push(phase.finish());
 
-    if let Ok(elapsed) = now.elapsed() {
-        println!("total = {}Byte, elapsed = {:?}", total, elapsed);
-    }
+    print_total(total, now);
+    result
 }
 
-pub fn random_get(path: PathBuf, count: u64, size: u64) {
+pub fn random_get(
+    storage: BenchStorage,
+    count: u64,
+    size: u64,
+    device: Option<DeviceBenchOptions>,
+) -> BenchResult {
+    with_bench_storage!(storage, count, size, random_get_on, device)
+}
+
+fn random_get_on<N: NonVolatileMemory + Send + 'static>(
+    mut storage: Storage<N>,
+    _total: u64,
+    count: u64,
+    size: u64,
+    device: Option<DeviceBenchOptions>,
+) -> BenchResult {
     use rand::seq::SliceRandom;
     use rand::thread_rng;
 
-    dbg!(path.clone());
-    dbg!(count);
-    dbg!(size);
+    let mut result = BenchResult::new("random_get");
+    result.retain_metrics(&storage);
+    if let Some(options) = device {
+        result.phases = run_on_device(storage, options, |bench| {
+            let mut phases = bench.run("Putting Data", count, |handle, i, samples| {
+                samples.put(handle, i, size);
+            });
+            let mut access_pattern: Vec<u64> = (0..count).collect();
+            access_pattern.shuffle(&mut thread_rng());
+            phases.extend(bench.run("Getting Data", count, |handle, i, samples| {
+                samples.get(handle, access_pattern[i as usize], size);
+            }));
+            phases
+        });
+        return result;
+    }
 
     let pb = ProgressBar::new(count);
     pb.set_style(ProgressStyle::default_bar()
         .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta}, {msg})")
         .progress_chars("#>-"));
 
-    let (mut storage, _total) =
-        create_storage_for_benchmark(path, count as u64, size as u64).unwrap();
     let tmp_vec: Vec<u8> = vec![0; size as usize];
 
     {
-        let _put_timer = Timer::new("Putting Data");
+        let mut phase = PhaseRecorder::start("Putting Data");
         for i in 0..count {
             let lump_id = LumpId::new(u128::from(i));
             let lump_data =
                 track_try_unwrap!(storage.allocate_lump_data_with_bytes(tmp_vec.as_ref()));
-            storage.put(&lump_id, &lump_data).unwrap();
+            phase
+                .time("put", size, || storage.put(&lump_id, &lump_data))
+                .unwrap();
             pb.set_position(i);
         }
 
-        storage.journal_sync().unwrap();
+        phase
+            .time("journal_sync", 0, || storage.journal_sync())
+            .unwrap();
         pb.finish_with_message("done");
+        result.phases.push(phase.finish());
     }
 
     let mut access_pattern: Vec<u64> = (0..count).collect();
@@ -130,47 +384,908 @@ pub fn random_get(path: PathBuf, count: u64, size: u64) {
         .progress_chars("#>-"));
 
     {
-        let _get_timer = Timer::new("Getting Data");
+        let mut phase = PhaseRecorder::start("Getting Data");
         for i in access_pattern {
             let lump_id = LumpId::new(u128::from(i));
-            storage.get(&lump_id).unwrap();
+            phase.time("get", size, || storage.get(&lump_id)).unwrap();
             pb2.inc(1);
         }
 
-        storage.journal_sync().unwrap();
+        phase
+            .time("journal_sync", 0, || storage.journal_sync())
+            .unwrap();
         pb2.finish_with_message("done");
+        result.phases.push(phase.finish());
     }
+    result
+}
+
+pub fn marching(
+    storage: BenchStorage,
+    count: u64,
+    size: u64,
+    device: Option<DeviceBenchOptions>,
+) -> BenchResult {
+    with_bench_storage!(storage, count, size, marching_on, device)
 }
 
-pub fn marching(path: PathBuf, count: u64, size: u64) {
-    let (mut storage, total) =
-        create_storage_for_benchmark(path, count as u64, size as u64).unwrap();
+fn marching_on<N: NonVolatileMemory + Send + 'static>(
+    mut storage: Storage<N>,
+    total: u64,
+    count: u64,
+    size: u64,
+    device: Option<DeviceBenchOptions>,
+) -> BenchResult {
     let tmp_vec: Vec<u8> = vec![0; size as usize];
 
     let now = SystemTime::now();
 
     // access pattern: marching
     let marching_len = 100;
+    let mut result = BenchResult::new("marching");
+    result.retain_metrics(&storage);
+    if let Some(options) = device {
+        // `marching_len`件ずつputし、直後にそれらをgetする（各スレッドはこの単位で処理を受け持つ）
+        result.phases = run_on_device(storage, options, |bench| {
+            let chunks = count.div_ceil(marching_len);
+            let phases = bench.run("Marching", chunks, |handle, chunk, samples| {
+                let keys = chunk * marching_len..count.min((chunk + 1) * marching_len);
+                for i in keys.clone() {
+                    samples.put(handle, i, size);
+                }
+                for i in keys {
+                    samples.get(handle, i, size);
+                }
+            });
+            print_total(total, now);
+            phases
+        });
+        return result;
+    }
+    let mut phase = PhaseRecorder::start("Marching");
     let mut c = 0;
-    let mut keystore = Vec::with_capacity(marching_len);
+    let mut keystore = Vec::with_capacity(marching_len as usize);
     for i in 0..count {
         let lump_id = LumpId::new(u128::from(i));
         let lump_data = track_try_unwrap!(storage.allocate_lump_data_with_bytes(tmp_vec.as_ref()));
-        storage.put(&lump_id, &lump_data).unwrap();
+        phase
+            .time("put", size, || storage.put(&lump_id, &lump_data))
+            .unwrap();
         if c < marching_len - 1 {
             keystore.push(lump_id);
             c += 1;
         } else {
             // c == marching_len - 1
             for k in &keystore {
-                let _ = storage.get(k);
+                let _ = phase.time("get", size, || storage.get(k));
             }
             keystore.clear();
             c = 0;
         }
     }
+    result.phases.push(phase.finish());
 
-    if let Ok(elapsed) = now.elapsed() {
-        println!("total = {}Byte, elapsed = {:?}", total, elapsed);
+    print_total(total, now);
+    result
+}
+
+/// `spec`に従ったワークロードを実行するベンチマーク
+///
+/// ストレージは`key_space`件の最大サイズの値を保持できる大きさで作成する。
+/// 複数のストレージ（`File`か`Memory`のいずれか）を指定した場合は、frugalosと同様に
+/// ストレージごとに専用のスレッド（`Device`経由の場合は専用のデバイス）で並行に操作する。
+/// 各ストレージは独立したキー空間を持ち、`ops`はストレージごとの操作数となる。
+/// `per_thread`が`true`の場合は、全体の結果に加えてスレッドごとの結果も出力する。
+///
+/// `faults`を指定した場合は、新たに作成する各ファイルのストレージ（`File`のみ）に計画に従って障害を注入する。
+/// この場合は、失敗した操作をパニックせずに`<op> (error)`として記録する。
+pub fn workload(
+    storages: Vec<BenchStorage>,
+    spec: WorkloadSpec,
+    device: Option<DeviceBenchOptions>,
+    per_thread: bool,
+    faults: Option<&FaultPlan>,
+) -> BenchResult {
+    let count = spec.key_space;
+    let size = spec.value_size.max().max(1);
+    if let Some(plan) = faults {
+        return workload_with_faults(storages, spec, per_thread, plan);
+    }
+    let mut storages = storages;
+    if storages.len() == 1 {
+        let storage = storages.remove(0);
+        return with_bench_storage!(storage, count, size, workload_on, spec, device, per_thread);
+    }
+
+    let result = match storages[0].target {
+        BenchTarget::File(_) => {
+            let opened = track_try_unwrap!(create_storages(
+                &storages,
+                count,
+                size,
+                |target, capacity| {
+                    match *target {
+                        BenchTarget::File(ref path) => FileNvm::create(path, capacity),
+                        _ => unreachable!("all storages must be files"),
+                    }
+                }
+            ));
+            workload_on_storages(opened, spec, device, per_thread, false)
+        }
+        BenchTarget::Memory => {
+            let opened =
+                track_try_unwrap!(create_storages(&storages, count, size, |_, capacity| {
+                    Ok(MemoryNvm::new(vec![0; capacity as usize]))
+                }));
+            workload_on_storages(opened, spec, device, per_thread, false)
+        }
+        _ => panic!("multiple storages must be new files or in-memory storages"),
+    };
+    for storage in &storages {
+        storage.finish();
+    }
+    result
+}
+
+// 新たに作成する各ファイルのストレージに障害を注入しながら、ワークロードを実行する
+//
+// 注入は、ストレージの作成と`--fill`による書き込みを終えてから有効にする。
+// 各ストレージには異なる（が再現可能な）障害のシードを用いる。
+fn workload_with_faults(
+    storages: Vec<BenchStorage>,
+    spec: WorkloadSpec,
+    per_thread: bool,
+    plan: &FaultPlan,
+) -> BenchResult {
+    let seed = plan.seed.unwrap_or_else(rand::random);
+    println!(
+        "fault plan: {} (seed = {}; pass `seed={}` in the plan to reproduce)",
+        plan, seed, seed
+    );
+    let injectors = (0..storages.len())
+        .map(|i| FaultInjector::with_seed(plan, seed.wrapping_add(i as u64)))
+        .collect::<Vec<_>>();
+    let mut next_injector = injectors.iter().cloned();
+    let count = spec.key_space;
+    let size = spec.value_size.max().max(1);
+    let opened = track_try_unwrap!(create_storages(
+        &storages,
+        count,
+        size,
+        |target, capacity| {
+            let nvm = match *target {
+                BenchTarget::File(ref path) => track!(FileNvm::create(path, capacity))?,
+                _ => unreachable!("fault injection is only supported for new files"),
+            };
+            Ok(FaultNvm::new(
+                nvm,
+                next_injector.next().expect("never fails"),
+            ))
+        }
+    ));
+    for injector in &injectors {
+        injector.activate();
+    }
+    let result = workload_on_storages(opened, spec, None, per_thread, true);
+    for (i, injector) in injectors.iter().enumerate() {
+        if injectors.len() > 1 {
+            println!("[storage {}] {}", i, injector.summary());
+        } else {
+            println!("{}", injector.summary());
+        }
+    }
+    for storage in &storages {
+        storage.finish();
+    }
+    result
+}
+
+// 新たに作成する（`File`または`Memory`の）ストレージを全て作成し、必要であれば埋める
+fn create_storages<N: NonVolatileMemory>(
+    storages: &[BenchStorage],
+    count: u64,
+    size: u64,
+    mut create_nvm: impl FnMut(&BenchTarget, u64) -> Result<N, cannyls::Error>,
+) -> Result<Vec<Storage<N>>, cannyls::Error> {
+    let mut opened = Vec::with_capacity(storages.len());
+    for s in storages {
+        let fill = s.fill.as_ref();
+        let (mut storage, _) = track!(create_storage_for_benchmark(
+            count,
+            size,
+            fill,
+            |capacity| create_nvm(&s.target, capacity)
+        ))?;
+        if let Some(fill) = fill {
+            track!(fill_storage(&mut storage, fill))?;
+        }
+        opened.push(storage);
+    }
+    Ok(opened)
+}
+
+fn workload_on<N: NonVolatileMemory + Send + 'static>(
+    storage: Storage<N>,
+    _total: u64,
+    _count: u64,
+    _size: u64,
+    spec: WorkloadSpec,
+    device: Option<DeviceBenchOptions>,
+    per_thread: bool,
+) -> BenchResult {
+    workload_on_storages(vec![storage], spec, device, per_thread, false)
+}
+
+// 一つのストレージに対してワークロードを実行するための状態
+struct WorkloadWorker {
+    // `existing_keys=yes`の場合は、`key_space`をストレージ中のlumpの数に置き換えたもの
+    spec: WorkloadSpec,
+    keys: WorkloadKeys,
+    generator: Mutex<WorkloadGenerator>,
+}
+impl WorkloadWorker {
+    fn new<N: NonVolatileMemory>(storage: &Storage<N>, spec: &WorkloadSpec, seed: u64) -> Self {
+        let mut spec = spec.clone();
+        let keys = if spec.existing_keys {
+            let ids = storage.list();
+            if ids.is_empty() {
+                track_try_unwrap!(Err(cannyls::Error::from(
+                    ErrorKind::InvalidInput
+                        .cause("`existing_keys=yes` requires a non-empty storage")
+                )));
+            }
+            spec.key_space = ids.len() as u64;
+            WorkloadKeys(Some(ids))
+        } else {
+            WorkloadKeys(None)
+        };
+        let generator = Mutex::new(WorkloadGenerator::new(&spec, seed));
+        WorkloadWorker {
+            spec,
+            keys,
+            generator,
+        }
+    }
+
+    fn next_op(&self) -> Op {
+        self.generator.lock().expect("never fails").next_op()
+    }
+
+    fn preload_ops(&self) -> Vec<Op> {
+        self.generator.lock().expect("never fails").preload_ops()
+    }
+}
+
+// `record_errors`が`true`の場合は、失敗した操作をパニックせずに`<op> (error)`として記録する
+fn workload_on_storages<N: NonVolatileMemory + Send + 'static>(
+    mut storages: Vec<Storage<N>>,
+    spec: WorkloadSpec,
+    device: Option<DeviceBenchOptions>,
+    per_thread: bool,
+    record_errors: bool,
+) -> BenchResult {
+    let seed = spec.seed.unwrap_or_else(rand::random);
+    // 各ストレージには異なる（が再現可能な）シードを用いる
+    let workers = storages
+        .iter()
+        .enumerate()
+        .map(|(i, storage)| WorkloadWorker::new(storage, &spec, seed.wrapping_add(i as u64)))
+        .collect::<Vec<_>>();
+    println!("workload: {}", workers[0].spec);
+    println!(
+        "seed = {} (pass `seed={}` to reproduce this run)",
+        seed, seed
+    );
+    if storages.len() > 1 {
+        println!(
+            "storages = {} (each storage has its own key space and runs the workload with seed + i)",
+            storages.len()
+        );
+    }
+    let payload = spec.payload;
+
+    let mut result = BenchResult::new("workload");
+    for storage in &storages {
+        result.retain_metrics(storage);
+    }
+    if let Some(options) = device {
+        result.phases = run_on_devices(storages, options, |bench| {
+            let mut phases = Vec::new();
+            if spec.preload {
+                let ops = workers.iter().map(|w| w.preload_ops()).collect::<Vec<_>>();
+                let continues = |d: usize, i| i < ops[d].len() as u64;
+                phases.extend(
+                    bench.run_while("Preloading", continues, |handle, i, samples| {
+                        let d = samples.device;
+                        samples.workload_op(handle, &workers[d].keys, &ops[d][i as usize], payload);
+                    }),
+                );
+            }
+            let started = Instant::now();
+            let continues = |d: usize, i| workers[d].spec.continues(i, started.elapsed());
+            phases.extend(
+                bench.run_while("Running Workload", continues, |handle, _, samples| {
+                    let worker = &workers[samples.device];
+                    let op = worker.next_op();
+                    samples.workload_op(handle, &worker.keys, &op, payload);
+                }),
+            );
+            phases
+        });
+        return result;
+    }
+
+    if spec.preload {
+        let phases = run_on_storages(
+            &mut storages,
+            "Preloading",
+            per_thread,
+            |i, storage, phase| {
+                let mut buf = Vec::new();
+                for op in workers[i].preload_ops() {
+                    let keys = &workers[i].keys;
+                    run_workload_op(storage, keys, &op, payload, &mut buf, phase, record_errors);
+                }
+                let started = Instant::now();
+                let result = storage.journal_sync().map(|()| 0);
+                record_op(phase, "journal_sync", started, result, record_errors);
+            },
+        );
+        result.phases.extend(phases);
+    }
+
+    let started = Instant::now();
+    let phases = run_on_storages(
+        &mut storages,
+        "Running Workload",
+        per_thread,
+        |i, storage, phase| {
+            let worker = &workers[i];
+            let mut buf = Vec::new();
+            let mut n = 0;
+            while worker.spec.continues(n, started.elapsed()) {
+                let op = worker.next_op();
+                run_workload_op(
+                    storage,
+                    &worker.keys,
+                    &op,
+                    payload,
+                    &mut buf,
+                    phase,
+                    record_errors,
+                );
+                n += 1;
+            }
+        },
+    );
+    result.phases.extend(phases);
+    result
+}
+
+// 各ストレージを専用のスレッドで操作し（`f(i, storage, phase)`）、全体の結果を集計する
+//
+// `per_thread`が`true`の場合は、全体の結果に続けてスレッドごとの結果も返す。
+fn run_on_storages<N, F>(
+    storages: &mut [Storage<N>],
+    message: &str,
+    per_thread: bool,
+    f: F,
+) -> Vec<PhaseResult>
+where
+    N: NonVolatileMemory + Send,
+    F: Fn(usize, &mut Storage<N>, &mut PhaseRecorder) + Sync,
+{
+    let mut phase = PhaseRecorder::start(message);
+    let recorders = thread::scope(|scope| {
+        let workers = storages
+            .iter_mut()
+            .enumerate()
+            .map(|(i, storage)| {
+                let f = &f;
+                scope.spawn(move || {
+                    let mut recorder = PhaseRecorder::start(&thread_phase_name(message, i));
+                    f(i, storage, &mut recorder);
+                    recorder
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("worker thread panicked"))
+            .collect::<Vec<_>>()
+    });
+    for recorder in &recorders {
+        phase.merge(recorder);
+    }
+    let mut phases = vec![phase.finish()];
+    if per_thread {
+        phases.extend(recorders.into_iter().map(PhaseRecorder::finish));
+    }
+    phases
+}
+
+fn thread_phase_name(message: &str, thread: usize) -> String {
+    format!("{} (thread {})", message, thread)
+}
+
+// ワークロードのキーとlump idとの対応
+//
+// `existing_keys=yes`の場合は、キー`i`は開始時点で存在した`i`番目（昇順）のlump idを表す。
+struct WorkloadKeys(Option<Vec<LumpId>>);
+impl WorkloadKeys {
+    fn lump_id(&self, key: u64) -> LumpId {
+        match self.0 {
+            None => LumpId::new(u128::from(key)),
+            Some(ref ids) => match ids.get(key as usize) {
+                Some(id) => *id,
+                // 区間の終端として、最大のlump idの次を指す
+                None => LumpId::new(ids[ids.len() - 1].as_u128() + 1),
+            },
+        }
+    }
+}
+
+// ワークロードの操作を一つ実行し、`op.sync`の場合はその後にジャーナルを同期する
+fn run_workload_op<N: NonVolatileMemory>(
+    storage: &mut Storage<N>,
+    keys: &WorkloadKeys,
+    op: &Op,
+    payload: Payload,
+    buf: &mut Vec<u8>,
+    phase: &mut PhaseRecorder,
+    record_errors: bool,
+) {
+    let lump_id = keys.lump_id(op.key);
+    let (name, started, result) = match op.kind {
+        OpKind::Put => {
+            let data =
+                track_try_unwrap!(storage.allocate_lump_data_with_bytes(op.payload(payload, buf)));
+            let started = Instant::now();
+            (
+                "put",
+                started,
+                storage.put(&lump_id, &data).map(|_| op.size),
+            )
+        }
+        OpKind::Get => {
+            let started = Instant::now();
+            let result = storage
+                .get(&lump_id)
+                .map(|data| data.map_or(0, |d| d.as_bytes().len() as u64));
+            ("get", started, result)
+        }
+        OpKind::Delete => {
+            let started = Instant::now();
+            ("delete", started, storage.delete(&lump_id).map(|_| 0))
+        }
+        OpKind::DeleteRange => {
+            let range = lump_id..keys.lump_id(op.end);
+            let started = Instant::now();
+            (
+                "delete_range",
+                started,
+                storage.delete_range(range).map(|_| 0),
+            )
+        }
+    };
+    record_op(phase, name, started, result, record_errors);
+    if op.sync {
+        let started = Instant::now();
+        let result = storage.journal_sync().map(|()| 0);
+        record_op(phase, "journal_sync", started, result, record_errors);
+    }
+}
+
+// `started`に開始した操作`op`の結果（成功した場合は読み書きしたバイト数）を記録する
+//
+// 失敗した場合は、`record_errors`が`true`ならば`<op> (error)`として記録し、そうでなければパニックする。
+fn record_op(
+    phase: &mut PhaseRecorder,
+    op: &str,
+    started: Instant,
+    result: Result<u64, cannyls::Error>,
+    record_errors: bool,
+) {
+    let latency = started.elapsed();
+    match result {
+        Ok(bytes) => phase.record(op, bytes, latency),
+        Err(_) if record_errors => phase.record(&format!("{} (error)", op), 0, latency),
+        Err(e) => {
+            track_try_unwrap!(Err(e));
+        }
+    }
+}
+
+/// ベンチマークを`Device`経由で行う場合の設定
+#[derive(Debug, Clone, Default)]
+pub struct DeviceBenchOptions {
+    /// 各リクエストに付与するオプション
+    pub request: RequestOptions,
+
+    /// デバイスごとの、同時にリクエストを発行するスレッドの数
+    pub concurrency: usize,
+
+    /// 全体の結果に加えて、スレッドごとの結果も出力する
+    pub per_thread: bool,
+}
+
+// `storage`を扱うデバイスを起動して`f`を実行し、最後にデバイスのメトリクスを出力して停止する
+fn run_on_device<N, F>(storage: Storage<N>, options: DeviceBenchOptions, f: F) -> Vec<PhaseResult>
+where
+    N: NonVolatileMemory + Send + 'static,
+    F: FnOnce(&DeviceBench) -> Vec<PhaseResult>,
+{
+    run_on_devices(vec![storage], options, f)
+}
+
+// `run_on_device`と同様だが、ストレージごとにデバイスを起動する
+fn run_on_devices<N, F>(
+    storages: Vec<Storage<N>>,
+    options: DeviceBenchOptions,
+    f: F,
+) -> Vec<PhaseResult>
+where
+    N: NonVolatileMemory + Send + 'static,
+    F: FnOnce(&DeviceBench) -> Vec<PhaseResult>,
+{
+    let devices = storages
+        .into_iter()
+        .map(|storage| track_try_unwrap!(spawn_device(move || Ok(storage))))
+        .collect::<Vec<_>>();
+    if devices.len() == 1 {
+        println!(
+            "run through the device: concurrency = {}, deadline = {:?}, prioritized = {}",
+            options.concurrency, options.request.deadline, options.request.prioritized
+        );
+    } else {
+        println!(
+            "run through {} devices: concurrency = {} per device, deadline = {:?}, prioritized = {}",
+            devices.len(),
+            options.concurrency,
+            options.request.deadline,
+            options.request.prioritized
+        );
+    }
+    let bench = DeviceBench { devices, options };
+    let phases = f(&bench);
+    for (i, device) in bench.devices.iter().enumerate() {
+        if bench.devices.len() > 1 {
+            println!("[device {}]", i);
+        }
+        print_device_metrics(device.handle().metrics());
+    }
+    bench.stop();
+    phases
+}
+
+struct DeviceBench {
+    devices: Vec<Device>,
+    options: DeviceBenchOptions,
+}
+impl DeviceBench {
+    // `0..jobs`の各ジョブを`concurrency`個のスレッドで分担して`job`を実行し、レイテンシを集計する
+    //
+    // デバイスが複数ある場合は、デバイスごとに`0..jobs`のジョブを実行する。
+    fn run<F>(&self, message: &str, jobs: u64, job: F) -> Vec<PhaseResult>
+    where
+        F: Fn(&DeviceHandle, u64, &mut RequestSamples) + Sync,
+    {
+        self.run_while(message, |_, i| i < jobs, job)
+    }
+
+    // `run`と同様だが、デバイス`d`の`i`番目のジョブは`continues(d, i)`が真の場合にのみ実行する
+    //
+    // `continues`が一度偽を返した後は、そのデバイスのそれ以降のジョブは実行しない。
+    // 結果は全体の集計結果と、`per_thread`の場合はそれに続くスレッドごとの結果からなる。
+    fn run_while<C, F>(&self, message: &str, continues: C, job: F) -> Vec<PhaseResult>
+    where
+        C: Fn(usize, u64) -> bool + Sync,
+        F: Fn(&DeviceHandle, u64, &mut RequestSamples) + Sync,
+    {
+        let mut phase = PhaseRecorder::start(message);
+        let concurrency = self.options.concurrency.max(1);
+        let threads = self.devices.len() * concurrency;
+        let mut recorders = (0..threads)
+            .map(|t| PhaseRecorder::start(&thread_phase_name(message, t)))
+            .collect::<Vec<_>>();
+        let next = self
+            .devices
+            .iter()
+            .map(|_| AtomicU64::new(0))
+            .collect::<Vec<_>>();
+        let samples = thread::scope(|scope| {
+            let workers = (0..threads)
+                .map(|thread| {
+                    let device = thread / concurrency;
+                    let handle = self.devices[device].handle();
+                    let (next, continues, job, options) =
+                        (&next[device], &continues, &job, &self.options.request);
+                    scope.spawn(move || {
+                        let mut samples = RequestSamples::new(options, device, thread);
+                        loop {
+                            let i = next.fetch_add(1, Ordering::SeqCst);
+                            if !continues(device, i) {
+                                break;
+                            }
+                            job(&handle, i, &mut samples);
+                        }
+                        samples.samples
+                    })
+                })
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().expect("worker thread panicked"))
+                .collect::<Vec<_>>()
+        });
+        // キュー待ちと実行時間の推定はデバイスごとに行う
+        for device_samples in samples.chunks(concurrency) {
+            let device_samples = device_samples.iter().flatten().cloned().collect();
+            for (thread, op, bytes, queueing, execution) in split_latencies(device_samples) {
+                phase.record_split(op, bytes, queueing, execution);
+                recorders[thread].record_split(op, bytes, queueing, execution);
+            }
+        }
+        let mut phases = vec![phase.finish()];
+        if self.options.per_thread {
+            phases.extend(recorders.into_iter().map(PhaseRecorder::finish));
+        }
+        phases
+    }
+
+    fn stop(self) {
+        for device in self.devices {
+            device.stop(cannyls::deadline::Deadline::Infinity);
+            track_try_unwrap!(block_on(device));
+        }
+    }
+}
+
+// リクエスト1件の発行時刻と完了時刻
+#[derive(Clone)]
+struct RequestSample {
+    // リクエストを発行したスレッド
+    thread: usize,
+    op: &'static str,
+    bytes: u64,
+    submitted: Instant,
+    completed: Instant,
+}
+
+// 各スレッドが発行したリクエストの計測結果
+struct RequestSamples<'a> {
+    options: &'a RequestOptions,
+
+    // リクエストの発行先のデバイス
+    device: usize,
+    thread: usize,
+    samples: Vec<RequestSample>,
+    buf: Vec<u8>,
+}
+impl<'a> RequestSamples<'a> {
+    fn new(options: &'a RequestOptions, device: usize, thread: usize) -> Self {
+        RequestSamples {
+            options,
+            device,
+            thread,
+            samples: Vec::new(),
+            buf: Vec::new(),
+        }
+    }
+
+    fn put(&mut self, handle: &DeviceHandle, key: u64, size: u64) {
+        let data = track_try_unwrap!(handle.allocate_lump_data_with_bytes(&vec![0; size as usize]));
+        let future = self
+            .options
+            .request(handle)
+            .put(LumpId::new(u128::from(key)), data);
+        self.record("put", move || block_on(future), |_| size);
+    }
+
+    fn get(&mut self, handle: &DeviceHandle, key: u64, size: u64) {
+        let future = self
+            .options
+            .request(handle)
+            .get(LumpId::new(u128::from(key)));
+        self.record("get", move || block_on(future), |_| size);
+    }
+
+    // ワークロードの操作を一つ実行する（`op.sync`の場合は、そのリクエストでジャーナルを同期させる）
+    fn workload_op(
+        &mut self,
+        handle: &DeviceHandle,
+        keys: &WorkloadKeys,
+        op: &Op,
+        payload: Payload,
+    ) {
+        let mut request = self.options.request(handle);
+        if op.sync {
+            request.journal_sync();
+        }
+        let lump_id = keys.lump_id(op.key);
+        match op.kind {
+            OpKind::Put => {
+                let data = track_try_unwrap!(
+                    handle.allocate_lump_data_with_bytes(op.payload(payload, &mut self.buf))
+                );
+                let future = request.put(lump_id, data);
+                let size = op.size;
+                self.record("put", move || block_on(future), |_| size);
+            }
+            OpKind::Get => {
+                let future = request.get(lump_id);
+                self.record(
+                    "get",
+                    move || block_on(future),
+                    |data| data.as_ref().map_or(0, |d| d.as_bytes().len() as u64),
+                );
+            }
+            OpKind::Delete => {
+                let future = request.delete(lump_id);
+                self.record("delete", move || block_on(future), |_| 0);
+            }
+            OpKind::DeleteRange => {
+                let range = lump_id..keys.lump_id(op.end);
+                let future = request.delete_range(range);
+                self.record("delete_range", move || block_on(future), |_| 0);
+            }
+        }
+    }
+
+    fn record<T>(
+        &mut self,
+        op: &'static str,
+        f: impl FnOnce() -> Result<T, cannyls::Error>,
+        bytes: impl FnOnce(&T) -> u64,
+    ) {
+        let submitted = Instant::now();
+        let value = track_try_unwrap!(f());
+        let completed = Instant::now();
+        self.samples.push(RequestSample {
+            thread: self.thread,
+            op,
+            bytes: bytes(&value),
+            submitted,
+            completed,
+        });
+    }
+}
+
+// デバイスはリクエストを一つずつ順に処理するので、完了時刻順に並べると
+// 各リクエストの実行開始時刻は「発行時刻と、直前に完了したリクエストの完了時刻の遅い方」と推定できる。
+// これを用いて、発行から完了までの時間をキュー待ちの時間と実行時間とに分ける。
+// （デバイスが補助タスクを実行していた時間は実行時間に含まれる）
+fn split_latencies(
+    mut samples: Vec<RequestSample>,
+) -> Vec<(usize, &'static str, u64, Duration, Duration)> {
+    samples.sort_by_key(|s| s.completed);
+    let mut previous: Option<Instant> = None;
+    samples
+        .into_iter()
+        .map(|s| {
+            let started = previous.map_or(s.submitted, |p| p.max(s.submitted));
+            previous = Some(s.completed);
+            (
+                s.thread,
+                s.op,
+                s.bytes,
+                started - s.submitted,
+                s.completed - started,
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_storage_works() {
+        let count = 10;
+        let size = 4096;
+        let fill = Fill {
+            ratio: 0.8,
+            size: SizeDistribution::Uniform {
+                min: 1024,
+                max: 8192,
+            },
+        };
+        let (mut storage, _) = create_storage_for_benchmark(count, size, Some(&fill), |capacity| {
+            Ok(MemoryNvm::new(vec![0; capacity as usize]))
+        })
+        .unwrap();
+        fill_storage(&mut storage, &fill).unwrap();
+
+        let region = storage.metrics().data_region();
+        let usage = region.usage_bytes() as f64 / region.capacity_bytes() as f64;
+        assert!((0.8..0.9).contains(&usage), "usage = {}", usage);
+        assert!(storage
+            .list()
+            .iter()
+            .all(|id| id.as_u128() >= FILL_KEY_BASE));
+
+        // 埋めた後にも、ベンチマークで書き込む分の空きが残っている
+        let data = storage
+            .allocate_lump_data_with_bytes(&vec![0; size as usize])
+            .unwrap();
+        for i in 0..count {
+            storage.put(&LumpId::new(u128::from(i)), &data).unwrap();
+        }
+    }
+
+    #[test]
+    fn workload_on_storages_works() {
+        let spec: WorkloadSpec = "put=1,ops=50,size=1KiB,key_space=100,seed=3"
+            .parse()
+            .unwrap();
+        let storages = (0..2)
+            .map(|_| {
+                create_storage_for_benchmark(100, 1024, None, |capacity| {
+                    Ok(MemoryNvm::new(vec![0; capacity as usize]))
+                })
+                .unwrap()
+                .0
+            })
+            .collect();
+        let result = workload_on_storages(storages, spec, None, true, false);
+
+        // 全体の結果に続いて、スレッドごとの結果が並ぶ
+        let names = result
+            .phases
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "Running Workload",
+                "Running Workload (thread 0)",
+                "Running Workload (thread 1)"
+            ]
+        );
+        let puts = |i: usize| result.phases[i].ops[0].latency.count();
+        assert_eq!(puts(0), 100);
+        assert_eq!(puts(1), 50);
+        assert_eq!(puts(2), 50);
+    }
+
+    #[test]
+    fn split_latencies_works() {
+        let t = Instant::now();
+        let ms = Duration::from_millis;
+        let samples = vec![
+            // 2番目のリクエストは、1番目の完了を待ってから実行される
+            RequestSample {
+                thread: 0,
+                op: "get",
+                bytes: 1,
+                submitted: t + ms(1),
+                completed: t + ms(5),
+            },
+            RequestSample {
+                thread: 0,
+                op: "put",
+                bytes: 1,
+                submitted: t,
+                completed: t + ms(3),
+            },
+            // 直前の完了より後に発行されたリクエストは待たされない
+            RequestSample {
+                thread: 0,
+                op: "put",
+                bytes: 1,
+                submitted: t + ms(10),
+                completed: t + ms(12),
+            },
+        ];
+        assert_eq!(
+            split_latencies(samples),
+            vec![
+                (0, "put", 1, ms(0), ms(3)),
+                (0, "get", 1, ms(2), ms(2)),
+                (0, "put", 1, ms(0), ms(2)),
+            ]
+        );
     }
 }
@@ -0,0 +1,396 @@
+//! 保存したベンチマーク結果（`--results`で書き出したJSON）の比較。
+//!
+//! フェーズ名と操作名の組ごとに、スループットとレイテンシのパーセンタイルを並べて変化率を出力し、
+//! 指定した指標が閾値を超えて悪化したもの（リグレッション）を検出する。
+use cannyls::ErrorKind;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use trackable::error::ErrorKindExt;
+
+use crate::duration::format_duration;
+use crate::report::{BenchResultRecord, OpRecord};
+
+/// 比較する指標（名前, 大きい方が良いかどうか）
+///
+/// 名前は結果ファイルのJSONのキー（レイテンシは`latency_ns`のメンバ）と同じ。
+const METRICS: [(&str, bool); 8] = [
+    ("ops_per_sec", true),
+    ("mb_per_sec", true),
+    ("avg", false),
+    ("p50", false),
+    ("p90", false),
+    ("p99", false),
+    ("p99.9", false),
+    ("max", false),
+];
+
+/// 比較する指標の一つ。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metric {
+    name: &'static str,
+    higher_is_better: bool,
+}
+impl Metric {
+    /// 指標の名前（例: `ops_per_sec`, `p99`）
+    pub fn name(self) -> &'static str {
+        self.name
+    }
+
+    /// 値が大きい方が良い指標（スループット）かどうか
+    pub fn higher_is_better(self) -> bool {
+        self.higher_is_better
+    }
+
+    fn all() -> impl Iterator<Item = Metric> {
+        METRICS.iter().map(|&(name, higher_is_better)| Metric {
+            name,
+            higher_is_better,
+        })
+    }
+
+    // 結果ファイルの一つの操作の結果から、この指標の値を取り出す
+    fn value_of(self, stats: &OpRecord) -> f64 {
+        let latency = &stats.latency_ns;
+        let nanos = match self.name {
+            "ops_per_sec" => return stats.ops_per_sec,
+            "mb_per_sec" => return stats.mb_per_sec,
+            "avg" => latency.avg,
+            "p50" => latency.p50,
+            "p90" => latency.p90,
+            "p99" => latency.p99,
+            "p99.9" => latency.p99_9,
+            _ => latency.max,
+        };
+        nanos as f64
+    }
+
+    fn format(self, value: f64) -> String {
+        if self.higher_is_better {
+            format!("{:.1}", value)
+        } else {
+            format_duration(Duration::from_nanos(value as u64))
+        }
+    }
+}
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Metric::all().find(|m| m.name == s).ok_or_else(|| {
+            let names = METRICS.iter().map(|m| m.0).collect::<Vec<_>>();
+            format!(
+                "unknown metric `{}` (expected one of {})",
+                s,
+                names.join(", ")
+            )
+        })
+    }
+}
+
+/// 結果ファイル中の、一つのフェーズの一つの操作の結果。
+#[derive(Debug, Clone, PartialEq)]
+pub struct SavedOp {
+    pub phase: String,
+    pub op: String,
+    pub count: u64,
+
+    /// 指標ごとの値（スループットは1秒あたりの量、レイテンシはナノ秒）
+    pub values: Vec<(Metric, f64)>,
+}
+impl SavedOp {
+    fn value(&self, metric: Metric) -> Option<f64> {
+        self.values
+            .iter()
+            .find(|(m, _)| *m == metric)
+            .map(|&(_, v)| v)
+    }
+}
+
+/// `BenchResult::to_json`で書き出した結果を読み込んだもの。
+#[derive(Debug, Clone, PartialEq)]
+pub struct SavedResult {
+    pub benchmark: String,
+    pub kanils_version: Option<String>,
+    pub cannyls_version: Option<String>,
+    pub ops: Vec<SavedOp>,
+}
+impl SavedResult {
+    /// JSONファイルから結果を読み込む。
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, cannyls::Error> {
+        let path = path.as_ref();
+        let content = track_io!(fs::read_to_string(path))?;
+        track!(Self::parse(&content), "path={:?}", path)
+    }
+
+    /// JSONで表現された結果を読み込む。
+    pub fn parse(json: &str) -> Result<Self, cannyls::Error> {
+        let record: BenchResultRecord = track!(serde_json::from_str(json).map_err(|e| {
+            cannyls::Error::from(
+                ErrorKind::InvalidInput.cause(format!("malformed benchmark results: {}", e)),
+            )
+        }))?;
+        Ok(Self::from_record(record))
+    }
+
+    /// 結果ファイルの形式の結果から、比較する値を取り出す。
+    pub fn from_record(record: BenchResultRecord) -> Self {
+        let ops = record
+            .phases
+            .iter()
+            .flat_map(|phase| {
+                phase.ops.iter().map(move |stats| SavedOp {
+                    phase: phase.name.clone(),
+                    op: stats.op.clone(),
+                    count: stats.count,
+                    values: Metric::all()
+                        .map(|metric| (metric, metric.value_of(stats)))
+                        .collect(),
+                })
+            })
+            .collect();
+        SavedResult {
+            benchmark: record.benchmark,
+            kanils_version: record.kanils_version,
+            cannyls_version: record.cannyls_version,
+            ops,
+        }
+    }
+
+    fn find(&self, phase: &str, op: &str) -> Option<&SavedOp> {
+        self.ops.iter().find(|o| o.phase == phase && o.op == op)
+    }
+}
+
+/// 一つの指標の比較結果。
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricDiff {
+    pub phase: String,
+    pub op: String,
+    pub metric: Metric,
+    pub baseline: f64,
+    pub current: f64,
+
+    /// リグレッションの判定対象の指標かどうか
+    pub checked: bool,
+}
+impl MetricDiff {
+    /// ベースラインからの変化率（%）。ベースラインが0の場合は`None`
+    pub fn delta_percent(&self) -> Option<f64> {
+        if self.baseline == 0.0 {
+            None
+        } else {
+            Some((self.current - self.baseline) / self.baseline * 100.0)
+        }
+    }
+
+    /// 悪化の割合（%）が`threshold`を超えているかどうか
+    pub fn is_regression(&self, threshold: f64) -> bool {
+        if !self.checked {
+            return false;
+        }
+        self.delta_percent().is_some_and(|delta| {
+            let worsened = if self.metric.higher_is_better {
+                -delta
+            } else {
+                delta
+            };
+            worsened > threshold
+        })
+    }
+}
+
+/// 二つの結果の比較。
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    pub diffs: Vec<MetricDiff>,
+
+    /// 片方の結果にしか存在しない(フェーズ名, 操作名)
+    pub only_in_baseline: Vec<(String, String)>,
+    pub only_in_current: Vec<(String, String)>,
+
+    /// リグレッションと見なす悪化の割合（%）
+    pub threshold: f64,
+}
+impl Comparison {
+    /// `baseline`と`current`を比較する（`checks`がリグレッションの判定対象の指標）。
+    pub fn new(
+        baseline: &SavedResult,
+        current: &SavedResult,
+        checks: &[Metric],
+        threshold: f64,
+    ) -> Self {
+        let mut diffs = Vec::new();
+        let mut only_in_baseline = Vec::new();
+        for b in &baseline.ops {
+            let c = match current.find(&b.phase, &b.op) {
+                Some(c) => c,
+                None => {
+                    only_in_baseline.push((b.phase.clone(), b.op.clone()));
+                    continue;
+                }
+            };
+            for metric in Metric::all() {
+                if let (Some(baseline), Some(current)) = (b.value(metric), c.value(metric)) {
+                    // バイト数を伴わない操作（`journal_sync`など）のMB/sは比較しない
+                    if baseline == 0.0 && current == 0.0 && metric.name == "mb_per_sec" {
+                        continue;
+                    }
+                    diffs.push(MetricDiff {
+                        phase: b.phase.clone(),
+                        op: b.op.clone(),
+                        metric,
+                        baseline,
+                        current,
+                        checked: checks.contains(&metric),
+                    });
+                }
+            }
+        }
+        let only_in_current = current
+            .ops
+            .iter()
+            .filter(|c| baseline.find(&c.phase, &c.op).is_none())
+            .map(|c| (c.phase.clone(), c.op.clone()))
+            .collect();
+        Comparison {
+            diffs,
+            only_in_baseline,
+            only_in_current,
+            threshold,
+        }
+    }
+
+    /// 閾値を超えて悪化した指標
+    pub fn regressions(&self) -> Vec<&MetricDiff> {
+        self.diffs
+            .iter()
+            .filter(|d| d.is_regression(self.threshold))
+            .collect()
+    }
+
+    /// 比較結果を表形式で標準出力に出力する。
+    pub fn print(&self) {
+        let mut current_op = None;
+        for diff in &self.diffs {
+            if current_op != Some((&diff.phase, &diff.op)) {
+                current_op = Some((&diff.phase, &diff.op));
+                println!("[{}] {}", diff.phase, diff.op);
+                println!(
+                    "  {:<12} {:>12} {:>12} {:>10}",
+                    "metric", "baseline", "current", "delta"
+                );
+            }
+            let delta = diff
+                .delta_percent()
+                .map_or_else(|| "-".to_owned(), |d| format!("{:+.2}%", d));
+            let mark = if diff.is_regression(self.threshold) {
+                "  REGRESSION"
+            } else if diff.checked {
+                "  *"
+            } else {
+                ""
+            };
+            println!(
+                "  {:<12} {:>12} {:>12} {:>10}{}",
+                diff.metric.name,
+                diff.metric.format(diff.baseline),
+                diff.metric.format(diff.current),
+                delta,
+                mark
+            );
+        }
+        for (phase, op) in &self.only_in_baseline {
+            println!("[{}] {}: only in the baseline", phase, op);
+        }
+        for (phase, op) in &self.only_in_current {
+            println!("[{}] {}: only in the current results", phase, op);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::{BenchResult, PhaseRecorder};
+
+    fn saved_result(latency_micros: u64, elapsed_secs: u64) -> SavedResult {
+        let mut recorder = PhaseRecorder::start("Putting Data");
+        for _ in 0..10 {
+            recorder.record("put", 1000, Duration::from_micros(latency_micros));
+        }
+        recorder.record("journal_sync", 0, Duration::from_millis(1));
+        let mut phase = recorder.finish();
+        phase.elapsed = Duration::from_secs(elapsed_secs);
+        let mut result = BenchResult::new("WBench");
+        result.phases.push(phase);
+
+        // 書き出したJSONを読み込めることも確認する
+        SavedResult::parse(&result.to_json()).unwrap()
+    }
+
+    #[test]
+    fn load_works() {
+        let result = saved_result(100, 2);
+        assert_eq!(result.benchmark, "WBench");
+        assert_eq!(result.ops.len(), 2);
+        let put = &result.ops[0];
+        assert_eq!(
+            (put.phase.as_str(), put.op.as_str()),
+            ("Putting Data", "put")
+        );
+        assert_eq!(put.count, 10);
+        assert_eq!(put.value("ops_per_sec".parse().unwrap()), Some(5.0));
+        assert_eq!(put.value("p50".parse().unwrap()), Some(100_000.0));
+        assert!("p42".parse::<Metric>().is_err());
+        assert_eq!(
+            result.cannyls_version.as_deref(),
+            Some(crate::report::CANNYLS_VERSION)
+        );
+        assert!(SavedResult::parse(r#"{"benchmark":"WBench"}"#).is_err());
+    }
+
+    #[test]
+    fn regressions_are_detected() {
+        let checks = ["ops_per_sec".parse().unwrap(), "p99".parse().unwrap()];
+        let baseline = saved_result(100, 2);
+
+        // スループットが半分、レイテンシが2倍になった
+        let slower = saved_result(200, 4);
+        let comparison = Comparison::new(&baseline, &slower, &checks, 10.0);
+        let regressions = comparison
+            .regressions()
+            .iter()
+            .map(|d| (d.op.as_str(), d.metric.name()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            regressions,
+            [
+                ("put", "ops_per_sec"),
+                ("put", "p99"),
+                ("journal_sync", "ops_per_sec")
+            ]
+        );
+        let put_p99 = comparison
+            .diffs
+            .iter()
+            .find(|d| d.op == "put" && d.metric.name() == "p99")
+            .unwrap();
+        assert_eq!(put_p99.delta_percent(), Some(100.0));
+
+        // 改善はリグレッションではない
+        let comparison = Comparison::new(&slower, &baseline, &checks, 10.0);
+        assert!(comparison.regressions().is_empty());
+
+        // 閾値を超えなければリグレッションではない
+        let comparison = Comparison::new(&baseline, &slower, &checks, 200.0);
+        assert!(comparison.regressions().is_empty());
+    }
+}
@@ -0,0 +1,60 @@
+//! 破壊的なコマンドを実行する前の確認。
+//!
+//! 確認は標準入力と標準エラー出力が端末の場合にだけ求める。
+//! スクリプトなどから実行された場合（パイプやリダイレクト）は、従来通り確認せずに実行する。
+use std::io::{self, BufRead, IsTerminal, Write};
+
+/// 標準入力と標準エラー出力が共に端末かどうかを返す。
+pub fn is_interactive() -> bool {
+    io::stdin().is_terminal() && io::stderr().is_terminal()
+}
+
+/// `output`に`prompt`を出力して`input`から一行読み込み、`y`または`yes`が入力された場合に真を返す。
+pub fn ask<R: BufRead, W: Write>(input: &mut R, output: &mut W, prompt: &str) -> io::Result<bool> {
+    write!(output, "{} [y/N] ", prompt)?;
+    output.flush()?;
+    let mut line = String::new();
+    input.read_line(&mut line)?;
+    Ok(matches!(
+        line.trim().to_ascii_lowercase().as_str(),
+        "y" | "yes"
+    ))
+}
+
+/// 影響を受けるものの一覧を、先頭の`limit`件だけ列挙した文字列にする。
+pub fn summarize(items: &[String], limit: usize) -> String {
+    if items.len() <= limit {
+        items.join(", ")
+    } else {
+        format!(
+            "{}, ... ({} more)",
+            items[..limit].join(", "),
+            items.len() - limit
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ask_works() -> io::Result<()> {
+        let mut output = Vec::new();
+        assert!(ask(&mut &b"y\n"[..], &mut output, "delete?")?);
+        assert_eq!(output, b"delete? [y/N] ");
+        assert!(ask(&mut &b" YES \n"[..], &mut Vec::new(), "delete?")?);
+        assert!(!ask(&mut &b"\n"[..], &mut Vec::new(), "delete?")?);
+        assert!(!ask(&mut &b"no\n"[..], &mut Vec::new(), "delete?")?);
+        assert!(!ask(&mut &b""[..], &mut Vec::new(), "delete?")?);
+        Ok(())
+    }
+
+    #[test]
+    fn summarize_works() {
+        let items = (1..=4).map(|i| i.to_string()).collect::<Vec<_>>();
+        assert_eq!(summarize(&items, 4), "1, 2, 3, 4");
+        assert_eq!(summarize(&items, 2), "1, 2, ... (2 more)");
+        assert_eq!(summarize(&[], 2), "");
+    }
+}
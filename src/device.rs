@@ -0,0 +1,434 @@
+//! cannylsの`Device`（リクエストキューを持つ専用スレッド）経由でストレージを操作する。
+//!
+//! frugalosは`Storage`を直接呼び出すのではなく、`Device`/`DeviceHandle`を介してデッドライン付きの
+//! リクエストを発行する。本番と同じ経路で操作したり計測したりするために用いる。
+use cannyls::deadline::Deadline;
+use cannyls::device::{Device, DeviceBuilder, DeviceHandle, DeviceRequest};
+use cannyls::lump::{LumpData, LumpId};
+use cannyls::metrics::{DeviceCommandCounter, DeviceMetrics};
+use cannyls::nvm::NonVolatileMemory;
+use cannyls::storage::Storage;
+use cannyls::ErrorKind;
+use fibers::sync::oneshot::MonitorError;
+use fibers::{Executor, InPlaceExecutor, Spawn};
+use futures::Future;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::Path;
+use trackable::error::ErrorKindExt;
+
+use crate::duration::{parse_duration, ParseDurationError};
+use crate::handle::{LumpKind, StorageHandle};
+use crate::lock::StorageLock;
+use crate::lumpid::IdFormat;
+use crate::output;
+
+/// `--deadline`の値を解析する。
+///
+/// `immediate`, `infinity`, または`100ms`のような時間（`Deadline::Within`）を受け付ける。
+pub fn parse_deadline(s: &str) -> Result<Deadline, ParseDurationError> {
+    match s.trim().to_lowercase().as_str() {
+        "immediate" => Ok(Deadline::Immediate),
+        "infinity" => Ok(Deadline::Infinity),
+        _ => parse_duration(s).map(Deadline::Within),
+    }
+}
+
+/// デバイスに発行する各リクエストに付与するオプション。
+#[derive(Debug, Clone, Default)]
+pub struct RequestOptions {
+    /// リクエストのデッドライン
+    pub deadline: Deadline,
+
+    /// 過負荷時にもリクエストを破棄させない（`DeviceRequest::prioritized`）
+    pub prioritized: bool,
+
+    /// 発行時にキュー長がこの値を超えていれば、`DeviceBusy`エラーにする
+    pub max_queue_len: Option<usize>,
+
+    /// リクエストの処理後にジャーナルを同期させる
+    pub journal_sync: bool,
+}
+impl RequestOptions {
+    /// オプションを反映したリクエストのビルダを返す。
+    pub fn request<'a>(&self, handle: &'a DeviceHandle) -> DeviceRequest<'a> {
+        let mut request = handle.request();
+        request.wait_for_running().deadline(self.deadline);
+        if self.prioritized {
+            request.prioritized();
+        }
+        if let Some(max) = self.max_queue_len {
+            request.max_queue_len(max);
+        }
+        if self.journal_sync {
+            request.journal_sync();
+        }
+        request
+    }
+}
+
+thread_local! {
+    static EXECUTOR: RefCell<Option<InPlaceExecutor>> = const { RefCell::new(None) };
+}
+
+/// `future`が完了するまで、呼び出し元のスレッドをブロックして待つ。
+///
+/// デバイスの応答に用いられるfibersのチャネルはfibersの実行器上でしか起床しないので、
+/// `Future::wait`ではなく、スレッドごとの`InPlaceExecutor`上でファイバーとして実行する。
+pub fn block_on<F>(future: F) -> Result<F::Item, cannyls::Error>
+where
+    F: Future<Error = cannyls::Error> + Send + 'static,
+    F::Item: Send + 'static,
+{
+    EXECUTOR.with(|executor| {
+        let mut executor = executor.borrow_mut();
+        if executor.is_none() {
+            *executor = Some(track_io!(InPlaceExecutor::new())?);
+        }
+        let executor = executor.as_mut().expect("never fails");
+        let monitor = executor.spawn_monitor(future);
+        match track_io!(executor.run_fiber(monitor))? {
+            Ok(item) => Ok(item),
+            Err(MonitorError::Failed(e)) => Err(track!(e)),
+            Err(MonitorError::Aborted) => Err(track!(cannyls::Error::from(
+                ErrorKind::Other.cause("the request was aborted")
+            ))),
+        }
+    })
+}
+
+/// ストレージを扱うデバイスを起動し、起動が完了するまで待つ。
+pub fn spawn_device<N, F>(init_storage: F) -> Result<Device, cannyls::Error>
+where
+    N: NonVolatileMemory + Send + 'static,
+    F: FnOnce() -> Result<Storage<N>, cannyls::Error> + Send + 'static,
+{
+    let device = DeviceBuilder::new().spawn(init_storage);
+    track!(block_on(device.wait_for_running()))
+}
+
+/// `Device`経由でストレージを操作するためのハンドル。
+///
+/// 各操作はリクエストを発行し、その完了を待ってから返る。
+/// 出力の形式は`StorageHandle`の対応するメソッドと同じ。
+/// ハンドルが破棄されるとデバイスを停止し、その終了（ジャーナルの書き出しを含む）を待つ。
+pub struct DeviceClient {
+    device: Option<Device>,
+    handle: DeviceHandle,
+    options: RequestOptions,
+    id_format: IdFormat,
+    read_only: bool,
+
+    // 開く際にジャーナルから求めたlumpの格納場所（`open_with_lump_kinds`の場合のみ）
+    lump_kinds: Option<BTreeMap<LumpId, LumpKind>>,
+
+    // デバイスの停止後に解放されるように、`device`よりも後に置く
+    lock: Option<StorageLock>,
+}
+impl DeviceClient {
+    /// 起動済みのデバイスを包む。
+    pub fn new(device: Device, options: RequestOptions) -> Self {
+        let handle = device.handle();
+        DeviceClient {
+            device: Some(device),
+            handle,
+            options,
+            id_format: IdFormat::default(),
+            read_only: false,
+            lump_kinds: None,
+            lock: None,
+        }
+    }
+
+    /// lusfファイルを開くデバイスを起動する。
+    ///
+    /// `read_only`が`true`の場合は`ReadOnlyNvm`を用いてファイルを一切書き換えず、変更系の操作はエラーになる。
+    /// `lock`の扱いは`StorageHandle::open`および`StorageHandle::open_read_only`と同じ。
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        lock: bool,
+        read_only: bool,
+        options: RequestOptions,
+    ) -> Result<Self, cannyls::Error> {
+        track!(Self::open_with(path, lock, read_only, options, false))
+    }
+
+    /// `open`と同様にデバイスを起動するが、起動前にジャーナルを再生してlumpの格納場所を求めておき、
+    /// `get`などの出力に含める（`Device`経由では格納場所を知る手段がないため）。
+    pub fn open_with_lump_kinds<P: AsRef<Path>>(
+        path: P,
+        lock: bool,
+        read_only: bool,
+        options: RequestOptions,
+    ) -> Result<Self, cannyls::Error> {
+        track!(Self::open_with(path, lock, read_only, options, true))
+    }
+
+    fn open_with<P: AsRef<Path>>(
+        path: P,
+        lock: bool,
+        read_only: bool,
+        options: RequestOptions,
+        lump_kinds: bool,
+    ) -> Result<Self, cannyls::Error> {
+        if read_only {
+            let handle = track!(StorageHandle::open_read_only(path, lock))?;
+            track!(Self::spawn(handle, options, lump_kinds))
+        } else {
+            let handle = track!(StorageHandle::open(path, lock))?;
+            track!(Self::spawn(handle, options, lump_kinds))
+        }
+    }
+
+    /// 既に開いたストレージのハンドルを引き継ぐデバイスを起動する。
+    ///
+    /// ハンドルが保持していたロックは、デバイスが停止するまで保持する。
+    /// lump idの形式と読み込み専用かどうかもハンドルから引き継ぐ。
+    pub fn from_handle<N>(
+        handle: StorageHandle<N>,
+        options: RequestOptions,
+    ) -> Result<Self, cannyls::Error>
+    where
+        N: NonVolatileMemory + Send + 'static,
+    {
+        track!(Self::spawn(handle, options, false))
+    }
+
+    // `lump_kinds`が`true`の場合は、ハンドルが求めていたlumpの格納場所も引き継ぐ
+    fn spawn<N>(
+        handle: StorageHandle<N>,
+        options: RequestOptions,
+        lump_kinds: bool,
+    ) -> Result<Self, cannyls::Error>
+    where
+        N: NonVolatileMemory + Send + 'static,
+    {
+        let id_format = handle.id_format();
+        let read_only = handle.is_read_only();
+        let (storage, kinds, lock) = handle.into_parts();
+        let device = track!(spawn_device(move || Ok(storage)))?;
+        let mut client = DeviceClient::new(device, options);
+        client.id_format = id_format;
+        client.read_only = read_only;
+        client.lump_kinds = if lump_kinds { Some(kinds) } else { None };
+        client.lock = lock;
+        Ok(client)
+    }
+
+    /// 出力に用いるlump idの形式を設定する
+    pub fn set_id_format(&mut self, id_format: IdFormat) {
+        self.id_format = id_format;
+    }
+
+    /// デバイスのメトリクスを返す
+    pub fn metrics(&self) -> &DeviceMetrics {
+        self.handle.metrics()
+    }
+
+    fn check_writable(&self) -> Result<(), cannyls::Error> {
+        if self.read_only {
+            track_panic!(
+                ErrorKind::InvalidInput,
+                "the storage is opened in read-only mode"
+            );
+        }
+        Ok(())
+    }
+
+    fn request(&self) -> DeviceRequest<'_> {
+        self.options.request(&self.handle)
+    }
+
+    pub fn put_bytes(&mut self, key: u128, value: &[u8]) -> Result<bool, cannyls::Error> {
+        track!(self.check_writable())?;
+        let lump_data = track!(self.handle.allocate_lump_data_with_bytes(value))?;
+        let created = track!(block_on(self.request().put(LumpId::new(key), lump_data)))?;
+        if let Some(ref mut kinds) = self.lump_kinds {
+            kinds.insert(LumpId::new(key), LumpKind::Block);
+        }
+        Ok(created)
+    }
+    pub fn put(&mut self, key: u128, value: &str) -> Result<(), cannyls::Error> {
+        let created = track!(self.put_bytes(key, value.as_bytes()))?;
+        output::print_stored(
+            self.id_format,
+            "put",
+            key,
+            format_args!("value={}", value),
+            created,
+        );
+        Ok(())
+    }
+
+    pub fn get_as_bytes(&mut self, key: u128) -> Result<Option<Vec<u8>>, cannyls::Error> {
+        let data = track!(block_on(self.request().get(LumpId::new(key))))?;
+        Ok(data.map(|data: LumpData| data.as_bytes().to_vec()))
+    }
+    pub fn get(&mut self, key: u128) -> Result<(), cannyls::Error> {
+        track!(self.print_lump(key, false))
+    }
+    /// keyに対応するlump dataを16進数表記で出力する
+    pub fn print_as_bytes(&mut self, key: u128) -> Result<(), cannyls::Error> {
+        track!(self.print_lump(key, true))
+    }
+    // 格納場所は`open_with_lump_kinds`で開いた場合にだけ出力する
+    fn print_lump(&mut self, key: u128, hex: bool) -> Result<(), cannyls::Error> {
+        let bytes = track!(self.get_as_bytes(key))?;
+        let kind = self
+            .lump_kinds
+            .as_ref()
+            .and_then(|kinds| kinds.get(&LumpId::new(key)).cloned());
+        let lump = bytes.as_ref().map(|bytes| (&bytes[..], kind));
+        output::print_lump(self.id_format, key, lump, hex);
+        Ok(())
+    }
+
+    pub fn delete_key(&mut self, key: u128) -> Result<bool, cannyls::Error> {
+        track!(self.check_writable())?;
+        let deleted = track!(block_on(self.request().delete(LumpId::new(key))))?;
+        if let Some(ref mut kinds) = self.lump_kinds {
+            kinds.remove(&LumpId::new(key));
+        }
+        Ok(deleted)
+    }
+    pub fn delete(&mut self, key: u128) -> Result<(), cannyls::Error> {
+        let deleted = track!(self.delete_key(key))?;
+        output::print_deleted(deleted);
+        Ok(())
+    }
+
+    pub fn delete_range(&mut self, start: u128, end: u128) -> Result<(), cannyls::Error> {
+        track!(self.check_writable())?;
+        let range = Range {
+            start: LumpId::new(start),
+            end: LumpId::new(end),
+        };
+        let deleted = track!(block_on(self.request().delete_range(range)))?;
+        if let Some(ref mut kinds) = self.lump_kinds {
+            for lump_id in &deleted {
+                kinds.remove(lump_id);
+            }
+        }
+        output::print_deleted_range(self.id_format, &deleted);
+        Ok(())
+    }
+
+    pub fn list(&mut self) -> Result<Vec<LumpId>, cannyls::Error> {
+        track!(block_on(self.request().list()))
+    }
+    pub fn print_list_of_lumpids(&mut self) -> Result<(), cannyls::Error> {
+        let ids = track!(self.list())?;
+        output::print_lump_ids(self.id_format, &ids, self.lump_kinds.as_ref());
+        Ok(())
+    }
+
+    /// デバイスを停止し、その終了を待つ。
+    pub fn stop(mut self) -> Result<(), cannyls::Error> {
+        track!(self.stop_device())
+    }
+
+    fn stop_device(&mut self) -> Result<(), cannyls::Error> {
+        if let Some(device) = self.device.take() {
+            device.stop(Deadline::Infinity);
+            track!(block_on(device))?;
+        }
+        Ok(())
+    }
+}
+impl Drop for DeviceClient {
+    fn drop(&mut self) {
+        let _ = self.stop_device();
+    }
+}
+
+fn total_commands(counter: &DeviceCommandCounter) -> u64 {
+    counter.put()
+        + counter.get()
+        + counter.head()
+        + counter.delete()
+        + counter.delete_range()
+        + counter.list()
+        + counter.list_range()
+        + counter.usage_range()
+        + counter.stop()
+}
+
+/// デバイスのメトリクスを出力する。
+pub fn print_device_metrics(metrics: &DeviceMetrics) {
+    println!("device status = {:?}", metrics.status());
+    println!("device queue length = {}", metrics.queue_len());
+    println!("device side jobs = {}", metrics.side_jobs());
+    println!("device commands (enqueued / dequeued / failed / busy):");
+    let counters = [
+        metrics.enqueued_commands(),
+        metrics.dequeued_commands(),
+        metrics.failed_commands(),
+        metrics.busy_commands(),
+    ];
+    type Count = fn(&DeviceCommandCounter) -> u64;
+    let rows: [(&str, Count); 10] = [
+        ("put", DeviceCommandCounter::put),
+        ("get", DeviceCommandCounter::get),
+        ("head", DeviceCommandCounter::head),
+        ("delete", DeviceCommandCounter::delete),
+        ("delete_range", DeviceCommandCounter::delete_range),
+        ("list", DeviceCommandCounter::list),
+        ("list_range", DeviceCommandCounter::list_range),
+        ("usage_range", DeviceCommandCounter::usage_range),
+        ("stop", DeviceCommandCounter::stop),
+        ("total", total_commands),
+    ];
+    for (name, count) in rows.iter() {
+        let values = counters
+            .iter()
+            .map(|counter| count(counter).to_string())
+            .collect::<Vec<_>>();
+        println!("  {:<12} = {}", name, values.join(" / "));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cannyls::nvm::MemoryNvm;
+    use std::time::Duration;
+    use trackable::result::TestResult;
+
+    use super::*;
+
+    #[test]
+    fn parse_deadline_works() {
+        assert_eq!(parse_deadline("immediate"), Ok(Deadline::Immediate));
+        assert_eq!(parse_deadline("Infinity"), Ok(Deadline::Infinity));
+        assert_eq!(
+            parse_deadline("100ms"),
+            Ok(Deadline::Within(Duration::from_millis(100)))
+        );
+        assert!(parse_deadline("soon").is_err());
+    }
+
+    #[test]
+    fn device_client_works() -> TestResult {
+        let device = track!(spawn_device(|| {
+            let nvm = MemoryNvm::new(vec![0; 1024 * 1024]);
+            track!(Storage::create(nvm))
+        }))?;
+        let options = RequestOptions {
+            deadline: Deadline::Immediate,
+            prioritized: true,
+            max_queue_len: Some(16),
+            journal_sync: true,
+        };
+        let mut client = DeviceClient::new(device, options);
+        assert!(track!(client.put_bytes(0, b"foo"))?);
+        assert!(!track!(client.put_bytes(0, b"bar"))?);
+        assert!(track!(client.put_bytes(1, b"baz"))?);
+        assert_eq!(track!(client.get_as_bytes(0))?, Some(b"bar".to_vec()));
+        assert_eq!(track!(client.list())?.len(), 2);
+        assert!(track!(client.delete_key(1))?);
+        assert_eq!(track!(client.get_as_bytes(1))?, None);
+        assert_eq!(client.metrics().enqueued_commands().put(), 3);
+        track!(client.stop())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+//! 時間指定（`--deadline`など）の解析と、人間に読みやすい時間表記を扱う。
+//!
+//! 単位は`ns`, `us`（または`µs`）, `ms`, `s`, `m`, `h`のいずれかで、省略はできない。
+//! 数値部には`_`による区切りと小数（例: `1.5s`）を使える。
+use std::fmt;
+use std::time::Duration;
+
+/// 時間文字列の解析に失敗したことを表すエラー。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseDurationError {
+    input: String,
+    reason: String,
+}
+impl ParseDurationError {
+    fn new(input: &str, reason: &str) -> Self {
+        ParseDurationError {
+            input: input.to_owned(),
+            reason: reason.to_owned(),
+        }
+    }
+}
+impl fmt::Display for ParseDurationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid duration `{}`: {}", self.input, self.reason)
+    }
+}
+impl std::error::Error for ParseDurationError {}
+
+fn unit_nanos(unit: &str) -> Option<u128> {
+    let nanos = match unit {
+        "ns" => 1,
+        "us" | "µs" => 1_000,
+        "ms" => 1_000_000,
+        "s" => 1_000_000_000,
+        "m" => 60 * 1_000_000_000,
+        "h" => 60 * 60 * 1_000_000_000,
+        _ => return None,
+    };
+    Some(nanos)
+}
+
+/// `input`を時間に変換する。
+///
+/// 例: `500us`, `100ms`, `1.5s`, `10m`, `1h`
+pub fn parse_duration(input: &str) -> Result<Duration, ParseDurationError> {
+    let trimmed = input.trim();
+    let split = trimmed
+        .find(|c: char| !(c.is_ascii_digit() || c == '_' || c == '.'))
+        .unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(split);
+    let nanos_per_unit = unit_nanos(unit.trim_start()).ok_or_else(|| {
+        ParseDurationError::new(
+            input,
+            "unknown or missing unit (expected one of ns, us, ms, s, m, h)",
+        )
+    })?;
+
+    let number: String = number.chars().filter(|&c| c != '_').collect();
+    let mut parts = number.splitn(2, '.');
+    let integer = parts.next().unwrap_or("");
+    let fraction = parts.next().unwrap_or("");
+    if integer.is_empty() && fraction.is_empty() {
+        return Err(ParseDurationError::new(input, "missing number"));
+    }
+    if fraction.contains('.') {
+        return Err(ParseDurationError::new(input, "too many decimal points"));
+    }
+
+    let overflow = || ParseDurationError::new(input, "too large");
+    let integer: u128 = if integer.is_empty() {
+        0
+    } else {
+        integer.parse().map_err(|_| overflow())?
+    };
+    let mut nanos = integer.checked_mul(nanos_per_unit).ok_or_else(overflow)?;
+    if !fraction.is_empty() {
+        // 1ns未満の端数は切り捨てる
+        let digits = fraction.len() as u32;
+        let denominator = 10u128
+            .checked_pow(digits)
+            .ok_or_else(|| ParseDurationError::new(input, "too many fractional digits"))?;
+        let numerator: u128 = fraction.parse().map_err(|_| overflow())?;
+        let extra = numerator.checked_mul(nanos_per_unit).ok_or_else(overflow)? / denominator;
+        nanos = nanos.checked_add(extra).ok_or_else(overflow)?;
+    }
+    if nanos > u128::from(u64::MAX) {
+        return Err(overflow());
+    }
+    Ok(Duration::from_nanos(nanos as u64))
+}
+
+/// 時間を読みやすい表記（例: `1.25ms`）に変換する。
+pub fn format_duration(duration: Duration) -> String {
+    let nanos = duration.as_nanos();
+    if nanos < 1_000 {
+        format!("{}ns", nanos)
+    } else if nanos < 1_000_000 {
+        format!("{:.2}us", nanos as f64 / 1e3)
+    } else if nanos < 1_000_000_000 {
+        format!("{:.2}ms", nanos as f64 / 1e6)
+    } else {
+        format!("{:.2}s", nanos as f64 / 1e9)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration_works() {
+        assert_eq!(parse_duration("100ns"), Ok(Duration::from_nanos(100)));
+        assert_eq!(parse_duration("500us"), Ok(Duration::from_micros(500)));
+        assert_eq!(parse_duration("500µs"), Ok(Duration::from_micros(500)));
+        assert_eq!(parse_duration("10ms"), Ok(Duration::from_millis(10)));
+        assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1_000 ms"), Ok(Duration::from_secs(1)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("10").is_err());
+        assert!(parse_duration("ms").is_err());
+        assert!(parse_duration("1.2.3s").is_err());
+        assert!(parse_duration("-1s").is_err());
+    }
+
+    #[test]
+    fn format_duration_works() {
+        assert_eq!(format_duration(Duration::from_nanos(512)), "512ns");
+        assert_eq!(format_duration(Duration::from_micros(1500)), "1.50ms");
+        assert_eq!(format_duration(Duration::from_secs(2)), "2.00s");
+    }
+}
@@ -0,0 +1,683 @@
+//! I/Oの障害を注入するためのNVM。
+//!
+//! `FaultNvm`は他のNVM（通常は`FileNvm`）を包み、障害計画（`FaultPlan`）に従って
+//! 読み書きのエラー、途中までしか書き込まない書き込み（short write）、ビット反転、同期のエラー、
+//! レイテンシの急増を注入する。
+//! 障害の発生はシードを持つ乱数で決まるので、同じ操作列に対しては同じ障害が再現される。
+//!
+//! 障害計画は`key=value`の組をカンマ（または空白・改行）で区切って記述する。
+//! `#`から行末まではコメントとして無視する。例:
+//!
+//! ```text
+//! read_error=0.001, write_error=0.001, latency=0.01, latency_spike=200ms, seed=42
+//! at=120:short_write   # 120回目のI/Oでshort writeを起こす
+//! ```
+//!
+//! 使えるキーは次の通り:
+//!
+//! - `read_error`, `write_error`, `short_write`, `bit_flip`, `write_bit_flip`, `sync_error`, `latency`:
+//!   各I/Oでその障害を起こす確率（0以上1以下。デフォルトは0）
+//!   - `bit_flip`は読み込んだデータの1ビットを反転させる（ファイルの内容は変わらない）
+//!   - `write_bit_flip`は書き込むデータの1ビットを反転させる（反転した内容がファイルに残る）
+//!   - `short_write`はブロック単位で途中までだけ書き込み、書き込んだバイト数を返す
+//! - `latency_spike`: `latency`で挿入する遅延（デフォルトは`100ms`）
+//! - `at`: `N:障害`の形式で、N回目（1始まり）以降で最初に該当するI/Oに障害を起こす（複数指定できる）
+//! - `after`: 最初のN回のI/Oには確率的な障害を起こさない（デフォルトは0）
+//! - `max_faults`: 注入する障害の総数の上限（デフォルトは無制限）
+//! - `seed`: 乱数のシード（省略時はランダムに決めて表示する）
+//! - `log`: `yes`（デフォルト）ならば、注入した障害を標準エラー出力に出力する
+//!
+//! I/Oの回数は、ストレージを開き終えて注入が有効になった（`FaultInjector::activate`）後から数える。
+use cannyls::block::BlockSize;
+use cannyls::nvm::NonVolatileMemory;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::duration::{format_duration, parse_duration};
+use crate::spec::{
+    parse_bool, parse_pairs, parse_ratio, read_spec_file, PairError, ParseSpecError,
+};
+
+/// 注入する障害の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    ReadError,
+    WriteError,
+    ShortWrite,
+    BitFlip,
+    WriteBitFlip,
+    SyncError,
+    Latency,
+}
+impl FaultKind {
+    const ALL: [FaultKind; 7] = [
+        FaultKind::ReadError,
+        FaultKind::WriteError,
+        FaultKind::ShortWrite,
+        FaultKind::BitFlip,
+        FaultKind::WriteBitFlip,
+        FaultKind::SyncError,
+        FaultKind::Latency,
+    ];
+
+    /// 障害の名前（障害計画の記述と出力で用いる）
+    pub fn name(self) -> &'static str {
+        match self {
+            FaultKind::ReadError => "read_error",
+            FaultKind::WriteError => "write_error",
+            FaultKind::ShortWrite => "short_write",
+            FaultKind::BitFlip => "bit_flip",
+            FaultKind::WriteBitFlip => "write_bit_flip",
+            FaultKind::SyncError => "sync_error",
+            FaultKind::Latency => "latency",
+        }
+    }
+
+    fn applies_to(self, io: IoKind) -> bool {
+        match self {
+            FaultKind::ReadError | FaultKind::BitFlip => io == IoKind::Read,
+            FaultKind::WriteError | FaultKind::ShortWrite | FaultKind::WriteBitFlip => {
+                io == IoKind::Write
+            }
+            FaultKind::SyncError => io == IoKind::Sync,
+            FaultKind::Latency => true,
+        }
+    }
+}
+impl fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+impl FromStr for FaultKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FaultKind::ALL
+            .iter()
+            .find(|kind| kind.name() == s)
+            .cloned()
+            .ok_or_else(|| format!("unknown fault `{}`", s))
+    }
+}
+
+/// 障害計画
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultPlan {
+    /// 障害の種類ごとの、各I/Oでその障害を起こす確率（正の値のもののみ）
+    pub rates: Vec<(FaultKind, f64)>,
+
+    /// `latency`で挿入する遅延
+    pub latency_spike: Duration,
+
+    /// (何回目のI/O以降か, 障害)の一覧（I/Oの回数の昇順）
+    pub schedule: Vec<(u64, FaultKind)>,
+
+    /// 確率的な障害を起こさない、最初のI/Oの回数
+    pub after: u64,
+
+    /// 注入する障害の総数の上限
+    pub max_faults: Option<u64>,
+
+    /// 乱数のシード
+    pub seed: Option<u64>,
+
+    /// 注入した障害を標準エラー出力に出力するかどうか
+    pub log: bool,
+}
+impl FaultPlan {
+    /// ファイルから障害計画を読み込む。
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ParseSpecError> {
+        read_spec_file("fault plan", path)?.parse()
+    }
+
+    fn rate(&self, kind: FaultKind) -> f64 {
+        self.rates
+            .iter()
+            .find(|&&(k, _)| k == kind)
+            .map_or(0.0, |&(_, rate)| rate)
+    }
+}
+impl Default for FaultPlan {
+    fn default() -> Self {
+        FaultPlan {
+            rates: Vec::new(),
+            latency_spike: Duration::from_millis(100),
+            schedule: Vec::new(),
+            after: 0,
+            max_faults: None,
+            seed: None,
+            log: true,
+        }
+    }
+}
+impl fmt::Display for FaultPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut items = self
+            .rates
+            .iter()
+            .map(|&(kind, rate)| format!("{}={}", kind, rate))
+            .collect::<Vec<_>>();
+        if self.rate(FaultKind::Latency) > 0.0
+            || self.schedule.iter().any(|&(_, k)| k == FaultKind::Latency)
+        {
+            items.push(format!(
+                "latency_spike={}",
+                format_duration(self.latency_spike)
+            ));
+        }
+        items.extend(
+            self.schedule
+                .iter()
+                .map(|&(n, kind)| format!("at={}:{}", n, kind)),
+        );
+        if self.after > 0 {
+            items.push(format!("after={}", self.after));
+        }
+        if let Some(max) = self.max_faults {
+            items.push(format!("max_faults={}", max));
+        }
+        if items.is_empty() {
+            write!(f, "no faults")
+        } else {
+            write!(f, "{}", items.join(","))
+        }
+    }
+}
+impl FromStr for FaultPlan {
+    type Err = ParseSpecError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let mut plan = FaultPlan::default();
+        parse_pairs("fault plan", input, |key, value| {
+            if let Ok(kind) = key.parse::<FaultKind>() {
+                let rate = parse_ratio(value).map_err(PairError::invalid)?;
+                plan.rates.retain(|&(k, _)| k != kind);
+                plan.rates.push((kind, rate));
+                return Ok(());
+            }
+            match key {
+                "latency_spike" => {
+                    plan.latency_spike = parse_duration(value).map_err(PairError::invalid)?
+                }
+                "at" => {
+                    let (n, kind) = value.split_once(':').ok_or_else(|| {
+                        PairError::invalid("expected `N:fault` (e.g. `120:write_error`)")
+                    })?;
+                    let n = match n.parse() {
+                        Ok(0) => return Err(PairError::invalid("the I/O number starts from 1")),
+                        Ok(n) => n,
+                        Err(e) => return Err(PairError::invalid(e)),
+                    };
+                    let kind = kind.parse().map_err(PairError::invalid)?;
+                    plan.schedule.push((n, kind));
+                }
+                "after" => plan.after = value.parse().map_err(PairError::invalid)?,
+                "max_faults" => plan.max_faults = Some(value.parse().map_err(PairError::invalid)?),
+                "seed" => plan.seed = Some(value.parse().map_err(PairError::invalid)?),
+                "log" => plan.log = parse_bool(value).map_err(PairError::invalid)?,
+                _ => return Err(PairError::UnknownKey),
+            }
+            Ok(())
+        })?;
+        plan.rates.retain(|&(_, rate)| rate > 0.0);
+        plan.schedule.sort_by_key(|&(n, _)| n);
+        Ok(plan)
+    }
+}
+
+/// 注入した障害の集計
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FaultSummary {
+    /// 注入が有効になってからのI/Oの回数
+    pub ios: u64,
+
+    /// (障害, 注入した回数)の一覧（最初に注入した順）
+    pub injected: Vec<(FaultKind, u64)>,
+}
+impl FaultSummary {
+    /// 注入した障害の総数
+    pub fn total(&self) -> u64 {
+        self.injected.iter().map(|&(_, n)| n).sum()
+    }
+}
+impl fmt::Display for FaultSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "injected {} faults in {} I/O operations",
+            self.total(),
+            self.ios
+        )?;
+        if !self.injected.is_empty() {
+            let counts = self
+                .injected
+                .iter()
+                .map(|&(kind, n)| format!("{} = {}", kind, n))
+                .collect::<Vec<_>>();
+            write!(f, " ({})", counts.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IoKind {
+    Read,
+    Write,
+    Sync,
+}
+impl IoKind {
+    fn name(self) -> &'static str {
+        match self {
+            IoKind::Read => "read",
+            IoKind::Write => "write",
+            IoKind::Sync => "sync",
+        }
+    }
+}
+
+// 一回のI/Oに注入する障害
+#[derive(Debug, Default)]
+struct Injection {
+    latency: Option<Duration>,
+    fault: Option<FaultKind>,
+    // ビット反転させる位置や、short writeで書き込むブロック数を決めるための乱数
+    choice: u64,
+}
+
+#[derive(Debug)]
+struct InjectorState {
+    plan: FaultPlan,
+    seed: u64,
+    rng: StdRng,
+    active: bool,
+    summary: FaultSummary,
+    // まだ注入していない`plan.schedule`の要素
+    schedule: Vec<(u64, FaultKind)>,
+}
+impl InjectorState {
+    fn record(&mut self, kind: FaultKind) {
+        match self
+            .summary
+            .injected
+            .iter_mut()
+            .find(|&&mut (k, _)| k == kind)
+        {
+            Some(entry) => entry.1 += 1,
+            None => self.summary.injected.push((kind, 1)),
+        }
+    }
+
+    fn decide(&mut self, io: IoKind, offset: u64, len: usize) -> Injection {
+        let mut injection = Injection::default();
+        if !self.active {
+            return injection;
+        }
+        self.summary.ios += 1;
+        let n = self.summary.ios;
+        let mut faults = Vec::new();
+
+        // 予定された障害を優先する
+        let due = self
+            .schedule
+            .iter()
+            .filter(|&&(at, kind)| at <= n && kind.applies_to(io))
+            .map(|&(_, kind)| kind)
+            .collect::<Vec<_>>();
+        for kind in due {
+            if let Some(i) = self.schedule.iter().position(|&(_, k)| k == kind) {
+                self.schedule.remove(i);
+            }
+            faults.push(kind);
+        }
+        if n > self.plan.after {
+            for &(kind, rate) in &self.plan.rates {
+                if kind.applies_to(io) && self.rng.gen::<f64>() < rate {
+                    faults.push(kind);
+                }
+            }
+        }
+        injection.choice = self.rng.gen();
+
+        for kind in faults {
+            if self
+                .plan
+                .max_faults
+                .is_some_and(|max| self.summary.total() >= max)
+            {
+                break;
+            }
+            let injected = if kind == FaultKind::Latency {
+                injection.latency.replace(self.plan.latency_spike).is_none()
+            } else {
+                injection.fault.get_or_insert(kind);
+                injection.fault == Some(kind)
+            };
+            if !injected {
+                continue;
+            }
+            self.record(kind);
+            if self.plan.log {
+                eprintln!(
+                    "[fault] I/O #{}: {} at offset {} ({} bytes): {}",
+                    n,
+                    io.name(),
+                    offset,
+                    len,
+                    kind
+                );
+            }
+        }
+        injection
+    }
+}
+
+/// 障害計画に従って障害を注入するかどうかを決める。
+///
+/// 複製したもの同士（`FaultNvm::split`で分割されたNVMを含む）は、乱数とI/Oの回数を共有する。
+/// 作成直後は注入は無効で、`activate`を呼ぶまでは全てのI/Oをそのまま通す。
+#[derive(Debug, Clone)]
+pub struct FaultInjector(Arc<Mutex<InjectorState>>);
+impl FaultInjector {
+    /// 障害計画のシード（省略時はランダムなシード）を用いて作成する。
+    pub fn new(plan: &FaultPlan) -> Self {
+        Self::with_seed(plan, plan.seed.unwrap_or_else(rand::random))
+    }
+
+    /// 指定したシードを用いて作成する。
+    pub fn with_seed(plan: &FaultPlan, seed: u64) -> Self {
+        FaultInjector(Arc::new(Mutex::new(InjectorState {
+            plan: plan.clone(),
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            active: false,
+            summary: FaultSummary::default(),
+            schedule: plan.schedule.clone(),
+        })))
+    }
+
+    /// 用いているシード
+    pub fn seed(&self) -> u64 {
+        self.state().seed
+    }
+
+    /// 障害の注入を有効にする。
+    pub fn activate(&self) {
+        self.state().active = true;
+    }
+
+    /// これまでに注入した障害の集計を返す。
+    pub fn summary(&self) -> FaultSummary {
+        self.state().summary.clone()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, InjectorState> {
+        self.0.lock().expect("never fails")
+    }
+
+    fn decide(&self, io: IoKind, offset: u64, len: usize) -> Injection {
+        let injection = self.state().decide(io, offset, len);
+        if let Some(latency) = injection.latency {
+            thread::sleep(latency);
+        }
+        injection
+    }
+}
+
+fn injected_error(io: IoKind, offset: u64) -> io::Error {
+    io::Error::other(format!("injected {} error at offset {}", io.name(), offset))
+}
+
+/// 他のNVMを包み、`FaultInjector`の決定に従って障害を注入する`NonVolatileMemory`の実装。
+#[derive(Debug)]
+pub struct FaultNvm<N> {
+    inner: N,
+    view_start: u64,
+    injector: FaultInjector,
+}
+impl<N: NonVolatileMemory> FaultNvm<N> {
+    pub fn new(inner: N, injector: FaultInjector) -> Self {
+        FaultNvm {
+            inner,
+            view_start: 0,
+            injector,
+        }
+    }
+
+    // 包んでいるNVM全体での現在位置
+    fn offset(&self) -> u64 {
+        self.view_start + self.inner.position()
+    }
+}
+impl<N: NonVolatileMemory> NonVolatileMemory for FaultNvm<N> {
+    fn sync(&mut self) -> Result<(), cannyls::Error> {
+        let offset = self.offset();
+        let injection = self.injector.decide(IoKind::Sync, offset, 0);
+        if injection.fault == Some(FaultKind::SyncError) {
+            return Err(track!(cannyls::Error::from(injected_error(
+                IoKind::Sync,
+                offset
+            ))));
+        }
+        track!(self.inner.sync())
+    }
+    fn position(&self) -> u64 {
+        self.inner.position()
+    }
+    fn capacity(&self) -> u64 {
+        self.inner.capacity()
+    }
+    fn block_size(&self) -> BlockSize {
+        self.inner.block_size()
+    }
+    fn split(self, position: u64) -> Result<(Self, Self), cannyls::Error> {
+        let (left, right) = track!(self.inner.split(position))?;
+        let left = FaultNvm {
+            inner: left,
+            view_start: self.view_start,
+            injector: self.injector.clone(),
+        };
+        let right = FaultNvm {
+            inner: right,
+            view_start: self.view_start + position,
+            injector: self.injector,
+        };
+        Ok((left, right))
+    }
+}
+impl<N: NonVolatileMemory> Seek for FaultNvm<N> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+impl<N: NonVolatileMemory> Read for FaultNvm<N> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let offset = self.offset();
+        let injection = self.injector.decide(IoKind::Read, offset, buf.len());
+        if injection.fault == Some(FaultKind::ReadError) {
+            return Err(injected_error(IoKind::Read, offset));
+        }
+        let read = self.inner.read(buf)?;
+        if injection.fault == Some(FaultKind::BitFlip) && read > 0 {
+            let bit = (injection.choice % (read as u64 * 8)) as usize;
+            buf[bit / 8] ^= 1 << (bit % 8);
+        }
+        Ok(read)
+    }
+}
+impl<N: NonVolatileMemory> Write for FaultNvm<N> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let offset = self.offset();
+        let injection = self.injector.decide(IoKind::Write, offset, buf.len());
+        match injection.fault {
+            Some(FaultKind::WriteError) => Err(injected_error(IoKind::Write, offset)),
+            Some(FaultKind::ShortWrite) => {
+                // ブロック単位で、全体よりも短い（空の場合もある）先頭部分だけを書き込む
+                let block = usize::from(self.block_size().as_u16());
+                let blocks = buf.len() / block;
+                if blocks == 0 {
+                    return Ok(0);
+                }
+                let len = (injection.choice % blocks as u64) as usize * block;
+                if len == 0 {
+                    return Ok(0);
+                }
+                self.inner.write(&buf[..len])
+            }
+            Some(FaultKind::WriteBitFlip) if !buf.is_empty() => {
+                let mut flipped = buf.to_vec();
+                let bit = (injection.choice % (buf.len() as u64 * 8)) as usize;
+                flipped[bit / 8] ^= 1 << (bit % 8);
+                self.inner.write(&flipped)
+            }
+            _ => self.inner.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cannyls::lump::LumpId;
+    use cannyls::nvm::MemoryNvm;
+    use cannyls::storage::{Storage, StorageBuilder};
+    use trackable::result::TestResult;
+
+    use super::*;
+
+    fn plan(s: &str) -> FaultPlan {
+        let mut plan: FaultPlan = s.parse().unwrap();
+        plan.log = false;
+        plan
+    }
+
+    fn open_storage(plan: &FaultPlan) -> (Storage<FaultNvm<MemoryNvm>>, FaultInjector) {
+        let injector = FaultInjector::new(plan);
+        let nvm = FaultNvm::new(MemoryNvm::new(vec![0; 4 * 1024 * 1024]), injector.clone());
+        let storage = track_try_unwrap!(StorageBuilder::new().create(nvm));
+        (storage, injector)
+    }
+
+    fn put(storage: &mut Storage<FaultNvm<MemoryNvm>>, key: u128, value: &[u8]) -> TestResult {
+        let data = track!(storage.allocate_lump_data_with_bytes(value))?;
+        track!(storage.put(&LumpId::new(key), &data))?;
+        track!(storage.journal_sync())?;
+        Ok(())
+    }
+
+    #[test]
+    fn parse_works() {
+        let plan: FaultPlan = "read_error=0.1, latency=0.5,latency_spike=10ms # comment\n\
+                               at=20:short_write at=3:sync_error seed=7 log=no"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            plan.rates,
+            vec![(FaultKind::ReadError, 0.1), (FaultKind::Latency, 0.5)]
+        );
+        assert_eq!(plan.latency_spike, Duration::from_millis(10));
+        assert_eq!(
+            plan.schedule,
+            vec![(3, FaultKind::SyncError), (20, FaultKind::ShortWrite)]
+        );
+        assert_eq!(plan.seed, Some(7));
+        assert!(!plan.log);
+        assert_eq!(
+            plan.to_string(),
+            "read_error=0.1,latency=0.5,latency_spike=10.00ms,at=3:sync_error,at=20:short_write"
+        );
+
+        assert!("read_error=2".parse::<FaultPlan>().is_err());
+        assert!("at=0:read_error".parse::<FaultPlan>().is_err());
+        assert!("at=1:explode".parse::<FaultPlan>().is_err());
+        assert!("foo=1".parse::<FaultPlan>().is_err());
+        assert_eq!("".parse::<FaultPlan>().unwrap().to_string(), "no faults");
+    }
+
+    #[test]
+    fn same_seed_injects_same_faults() {
+        let plan = plan("read_error=0.3,write_error=0.3,seed=42");
+        let run = || {
+            let injector = FaultInjector::new(&plan);
+            injector.activate();
+            (0..100)
+                .map(|i| {
+                    let io = if i % 2 == 0 {
+                        IoKind::Read
+                    } else {
+                        IoKind::Write
+                    };
+                    injector.decide(io, 0, 512).fault
+                })
+                .collect::<Vec<_>>()
+        };
+        let faults = run();
+        assert_eq!(faults, run());
+        assert!(faults.contains(&Some(FaultKind::ReadError)));
+        assert!(faults.contains(&Some(FaultKind::WriteError)));
+        assert!(faults.contains(&None));
+    }
+
+    #[test]
+    fn injector_is_inactive_until_activated() {
+        let injector = FaultInjector::new(&plan("read_error=1"));
+        assert!(injector.decide(IoKind::Read, 0, 512).fault.is_none());
+        injector.activate();
+        assert_eq!(
+            injector.decide(IoKind::Read, 0, 512).fault,
+            Some(FaultKind::ReadError)
+        );
+        assert_eq!(injector.summary().ios, 1);
+    }
+
+    #[test]
+    fn scheduled_faults_and_limits_work() {
+        let injector = FaultInjector::new(&plan("at=2:write_error,at=2:sync_error,max_faults=1"));
+        injector.activate();
+        assert!(injector.decide(IoKind::Write, 0, 512).fault.is_none());
+        // 2回目のI/Oは読み込みなので、書き込みのエラーは次の書き込みまで延期される
+        assert!(injector.decide(IoKind::Read, 0, 512).fault.is_none());
+        assert_eq!(
+            injector.decide(IoKind::Write, 0, 512).fault,
+            Some(FaultKind::WriteError)
+        );
+        // 上限に達したので、同期のエラーは注入されない
+        assert!(injector.decide(IoKind::Sync, 0, 0).fault.is_none());
+        assert_eq!(
+            injector.summary().to_string(),
+            "injected 1 faults in 4 I/O operations (write_error = 1)"
+        );
+    }
+
+    #[test]
+    fn faults_reach_storage_operations() -> TestResult {
+        // 書き込みのエラーは、put（とジャーナルの同期）の失敗として現れる
+        let (mut storage, injector) = open_storage(&plan("write_error=1"));
+        injector.activate();
+        assert!(put(&mut storage, 1, b"foo").is_err());
+        assert!(injector.summary().total() > 0);
+
+        // 読み込んだデータのビット反転は、getの結果に現れる（ストレージの内容は変わらない）
+        let (mut storage, injector) = open_storage(&plan("bit_flip=1,max_faults=1,seed=1"));
+        put(&mut storage, 1, &[0; 510])?;
+        injector.activate();
+        let lump_id = LumpId::new(1);
+        let flipped = storage
+            .get(&lump_id)
+            .map(|data| data.is_none_or(|d| d.as_bytes() != &[0; 510][..]));
+        assert!(flipped.unwrap_or(true));
+        let data = track!(storage.get(&lump_id))?;
+        assert_eq!(data.map(|d| d.as_bytes().to_vec()), Some(vec![0; 510]));
+        Ok(())
+    }
+}
@@ -1,112 +1,408 @@
 extern crate cannyls;
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use cannyls::block::BlockSize;
 use cannyls::lump::{LumpData, LumpId};
-use cannyls::nvm::FileNvm;
-use cannyls::storage::{JournalSnapshot, Storage, StorageBuilder};
-use std::fs::{File, OpenOptions};
-use std::io::Seek;
+use cannyls::metrics::StorageMetrics;
+use cannyls::nvm::{FileNvm, FileNvmBuilder, NonVolatileMemory};
+use cannyls::storage::{
+    JournalEntry, JournalRecord, JournalSnapshot, Storage, StorageBuilder, StorageHeader,
+};
+use cannyls::ErrorKind;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
 use std::path::Path;
-use std::str;
+use std::str::{self, FromStr};
 
-macro_rules! track_io {
-    ($expr:expr) => {
-        $expr.map_err(|e: ::std::io::Error| track!(cannyls::Error::from(e)))
-    };
-}
+use crate::fault::{FaultInjector, FaultNvm};
+use crate::header::LusfHeader;
+use crate::journal::{Entry, JournalView};
+use crate::lock::{LockMode, StorageLock};
+use crate::lumpid::IdFormat;
+use crate::output;
+use crate::readonly::ReadOnlyNvm;
+use crate::size::format_bytes_with_size;
 
 fn lumpdata_to_string(data: &LumpData) -> Option<String> {
     String::from_utf8(data.as_bytes().to_vec()).ok()
 }
 
-struct Version {
-    pub major_version: u16,
-    pub minor_version: u16,
+// 各lumpの格納場所を、ジャーナルを再生して求める
+//
+// 開いた直後のストレージのジャーナルには、存在する全てのlumpのレコードがある
+// （GCの途中でレコードがメモリ上のキューに移されていると、スナップショットには現れないので、開いた直後に限る）。
+// 再生に失敗した場合は、格納場所が分からないものとして空にする。
+fn replay_lump_kinds<N: NonVolatileMemory>(storage: &mut Storage<N>) -> BTreeMap<LumpId, LumpKind> {
+    let mut kinds = BTreeMap::new();
+    let snapshot = match storage.journal_snapshot() {
+        Ok(snapshot) => snapshot,
+        Err(_) => return kinds,
+    };
+    for entry in snapshot.entries {
+        match entry.record {
+            JournalRecord::Put(lump_id, _) => {
+                kinds.insert(lump_id, LumpKind::Block);
+            }
+            JournalRecord::Embed(lump_id, _) => {
+                kinds.insert(lump_id, LumpKind::Embedded);
+            }
+            JournalRecord::Delete(lump_id) => {
+                kinds.remove(&lump_id);
+            }
+            JournalRecord::DeleteRange(range) => {
+                let deleted = kinds.range(range).map(|(id, _)| *id).collect::<Vec<_>>();
+                for lump_id in deleted {
+                    kinds.remove(&lump_id);
+                }
+            }
+            JournalRecord::EndOfRecords | JournalRecord::GoToFront => {}
+        }
+    }
+    kinds
 }
 
-// Version構造体をlusfファイルから計算する。
-// ヘッダ領域の構造を要参照:
-//   https://github.com/frugalos/cannyls/wiki/Storage-Format#2-%E3%83%98%E3%83%83%E3%83%80%E9%A0%98%E5%9F%9F
-fn get_version_from_lusf_file<P: AsRef<Path>>(path: P) -> Result<Version, std::io::Error> {
-    let mut file = track_io!(File::open(path))?;
-
-    // 次の定数6は、Magic Number 4バイトとHeader Size 2バイトを足したもの。
-    // ここから2バイトずつMajor VersionとMinor Versionが続く。
-    track_io!(file.seek(std::io::SeekFrom::Start(6)))?;
-    let major_version = track_io!(file.read_u16::<BigEndian>())?;
-    let minor_version = track_io!(file.read_u16::<BigEndian>())?;
-
-    Ok(Version {
-        major_version,
-        minor_version,
-    })
+/// `value`を埋め込みlumpとして格納できるかどうかを検証する。
+pub fn check_embeddable(value: &[u8]) -> Result<(), cannyls::Error> {
+    track_assert!(
+        value.len() <= LumpData::MAX_EMBEDDED_SIZE,
+        ErrorKind::InvalidInput,
+        "the value is too large to embed ({} bytes > {} bytes)",
+        value.len(),
+        LumpData::MAX_EMBEDDED_SIZE
+    );
+    Ok(())
 }
 
-// lusfファイルにVersion構造体を書き戻す。
-// 書き込み先のlusfファイルの状態などは検証しない。
-fn put_version_to_lusf_file<P: AsRef<Path>>(
-    path: P,
-    version: Version,
-) -> Result<(), std::io::Error> {
-    let mut file = track_io!(OpenOptions::new().write(true).open(path)).unwrap();
+/// データ領域の容量が`capacity`バイトとなるストレージの全体サイズと、ジャーナル領域の割合を求める。
+///
+/// ジャーナル領域には、データ領域の1ブロックあたり20バイト（最低でも2ブロック分）を割り当てる。
+pub fn storage_layout(capacity: u64) -> (u64, f64) {
+    let block_size = BlockSize::min();
+    let block_size_u64 = u64::from(block_size.as_u16());
 
-    // 次の定数6は、Magic Number 4バイトとHeader Size 2バイトを足したもの。
-    // ここから2バイトずつMajor VersionとMinor Versionが続く。
-    track_io!(file.seek(std::io::SeekFrom::Start(6)))?;
-    track_io!(file.write_u16::<BigEndian>(version.major_version))?;
-    track_io!(file.write_u16::<BigEndian>(version.minor_version))?;
+    let data_region_size = block_size.ceil_align(capacity);
 
-    Ok(())
-}
+    let journal_header_size = block_size_u64;
+    let journal_record_size =
+        std::cmp::max(block_size_u64 * 2, 20 * (data_region_size / block_size_u64));
+    let journal_region_size = journal_header_size + journal_record_size;
 
-pub struct StorageHandle {
-    storage: Storage<FileNvm>,
+    let header_size = block_size_u64;
+
+    let total_size = data_region_size + journal_region_size + header_size;
+    let journal_ratio = 0.01f64.max(journal_region_size as f64 / total_size as f64);
+    (total_size, journal_ratio)
 }
 
-impl StorageHandle {
-    pub fn new(storage: Storage<FileNvm>) -> Self {
-        StorageHandle { storage }
+/// lumpの格納場所。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LumpKind {
+    /// ジャーナル領域に埋め込まれている（`LumpData::MAX_EMBEDDED_SIZE`バイト以下）
+    Embedded,
+
+    /// データ領域のブロックに格納されている
+    Block,
+}
+impl LumpKind {
+    /// コマンドライン引数として受け付ける値の一覧。
+    pub const VARIANTS: [&'static str; 2] = ["embedded", "block"];
+}
+impl fmt::Display for LumpKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LumpKind::Embedded => write!(f, "embedded"),
+            LumpKind::Block => write!(f, "block"),
+        }
     }
+}
+impl FromStr for LumpKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "embedded" => Ok(LumpKind::Embedded),
+            "block" => Ok(LumpKind::Block),
+            _ => Err(format!(
+                "invalid lump kind `{}` (expected one of embedded, block)",
+                s
+            )),
+        }
+    }
+}
+
+/// 区間に含まれるlumpの数とバイト数。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RangeCount {
+    pub lumps: usize,
+    pub bytes: u64,
 
+    /// `bytes`が値の正確なバイト数の合計かどうか（偽の場合は近似値）
+    pub exact: bool,
+}
+
+pub struct StorageHandle<N: NonVolatileMemory = FileNvm> {
+    storage: Storage<N>,
+    id_format: IdFormat,
+    read_only: bool,
+
+    // 各lumpの格納場所（開いた時点のジャーナルから求め、以降はput・embed・deleteの度に更新する）
+    lump_kinds: BTreeMap<LumpId, LumpKind>,
+
+    // ストレージを閉じた（`storage`を破棄した）後に解放されるように、`storage`よりも後に置く
+    lock: Option<StorageLock>,
+}
+
+impl StorageHandle<FileNvm> {
     pub fn create<T: AsRef<Path>>(path: T) -> Self {
-        let nvm = track_try_unwrap!(FileNvm::open(path));
-        let storage = track_try_unwrap!(StorageBuilder::new().open(nvm));
-        StorageHandle { storage }
+        track_try_unwrap!(StorageHandle::open(path, true))
     }
 
-    pub fn put_str(&mut self, key: u128, value: &str) -> Result<bool, cannyls::Error> {
-        let lump_id = LumpId::new(key);
-        let lump_data =
-            track_try_unwrap!(self.storage.allocate_lump_data_with_bytes(value.as_bytes()));
-        self.storage.put(&lump_id, &lump_data)
+    /// lusfファイルを読み書き可能なモードで開く
+    ///
+    /// `lock`が`true`の場合は、開く前にファイルの排他ロックを取得し、ハンドルが破棄されるまで保持する。
+    /// 他のプロセスがロックを保持している場合はエラーになる。
+    pub fn open<T: AsRef<Path>>(path: T, lock: bool) -> Result<Self, cannyls::Error> {
+        let lock = if lock {
+            Some(track!(StorageLock::acquire(&path, LockMode::Exclusive))?)
+        } else {
+            None
+        };
+        // ロックは既に取得済み（または不要）なので、`FileNvm`自身には排他ロックを取らせない
+        let nvm = track!(FileNvmBuilder::new().exclusive_lock(false).open(&path))?;
+        let storage = track!(StorageBuilder::new().open(nvm))?;
+        let mut handle = StorageHandle::new(storage);
+        handle.lock = lock;
+        Ok(handle)
+    }
+
+    /// lusfファイルの`(major version, minor version)`を返す
+    pub fn lusf_version<T: AsRef<Path>>(path: T) -> Result<(u16, u16), cannyls::Error> {
+        let header = track!(LusfHeader::read_from_file(path))?;
+        Ok((header.major_version, header.minor_version))
     }
-    pub fn put(&mut self, key: u128, value: &str) {
-        let result = track_try_unwrap!(self.put_str(key, value));
 
-        if result {
-            println!("put key={}, value={}", key, value);
+    pub fn change_major_version_to<T: AsRef<Path> + Clone>(path: T, new_major_version: u16) {
+        let mut header = track_try_unwrap!(LusfHeader::read_from_file(path.clone()));
+        let old_major_version = header.major_version;
+        header.major_version = new_major_version;
+
+        track_try_unwrap!(header.write_to_file(path));
+
+        println!("change from {} to {}", old_major_version, new_major_version);
+    }
+
+    pub fn change_minor_version_to<T: AsRef<Path> + Clone>(path: T, new_minor_version: u16) {
+        let mut header = track_try_unwrap!(LusfHeader::read_from_file(path.clone()));
+        let old_minor_version = header.minor_version;
+        header.minor_version = new_minor_version;
+
+        track_try_unwrap!(header.write_to_file(path));
+
+        println!("change from {} to {}", old_minor_version, new_minor_version);
+    }
+}
+
+impl StorageHandle<ReadOnlyNvm> {
+    /// lusfファイルを読み込み専用モードで開く
+    ///
+    /// ファイルには一切書き込まず、変更系の操作（put, embed, delete, delete_range, journal_gc）は
+    /// エラーになる。
+    /// `lock`が`true`の場合は、開く前にファイルの共有ロックを取得し、ハンドルが破棄されるまで保持する。
+    pub fn open_read_only<T: AsRef<Path>>(path: T, lock: bool) -> Result<Self, cannyls::Error> {
+        let lock = if lock {
+            Some(track!(StorageLock::acquire(&path, LockMode::Shared))?)
+        } else {
+            None
+        };
+        let nvm = track!(ReadOnlyNvm::open(path))?;
+        let storage = track!(StorageBuilder::new().open(nvm))?;
+        let mut handle = StorageHandle::new(storage);
+        handle.read_only = true;
+        handle.lock = lock;
+        Ok(handle)
+    }
+}
+
+impl StorageHandle<FaultNvm<FileNvm>> {
+    /// `StorageHandle::open`と同様にlusfファイルを開くが、`injector`に従って障害を注入するNVMを介して操作する。
+    ///
+    /// 障害の注入は、ストレージを開き終えた後に有効になる。
+    pub fn open_with_faults<T: AsRef<Path>>(
+        path: T,
+        lock: bool,
+        injector: FaultInjector,
+    ) -> Result<Self, cannyls::Error> {
+        let lock = if lock {
+            Some(track!(StorageLock::acquire(&path, LockMode::Exclusive))?)
         } else {
-            println!("[overwrite] put key={}, value={}", key, value);
+            None
+        };
+        let nvm = track!(FileNvmBuilder::new().exclusive_lock(false).open(&path))?;
+        let storage = track!(StorageBuilder::new().open(FaultNvm::new(nvm, injector.clone())))?;
+        injector.activate();
+        let mut handle = StorageHandle::new(storage);
+        handle.lock = lock;
+        Ok(handle)
+    }
+}
+
+impl StorageHandle<FaultNvm<ReadOnlyNvm>> {
+    /// `StorageHandle::open_read_only`と同様にlusfファイルを開くが、`injector`に従って障害を注入するNVMを介して操作する。
+    ///
+    /// 書き込みはメモリ上にのみ保持されるので、`write_bit_flip`などの障害もファイルには影響しない。
+    pub fn open_read_only_with_faults<T: AsRef<Path>>(
+        path: T,
+        lock: bool,
+        injector: FaultInjector,
+    ) -> Result<Self, cannyls::Error> {
+        let lock = if lock {
+            Some(track!(StorageLock::acquire(&path, LockMode::Shared))?)
+        } else {
+            None
+        };
+        let nvm = track!(ReadOnlyNvm::open(path))?;
+        let storage = track!(StorageBuilder::new().open(FaultNvm::new(nvm, injector.clone())))?;
+        injector.activate();
+        let mut handle = StorageHandle::new(storage);
+        handle.read_only = true;
+        handle.lock = lock;
+        Ok(handle)
+    }
+}
+
+impl<N: NonVolatileMemory> StorageHandle<N> {
+    /// 開いたばかりのストレージを包む
+    ///
+    /// この時点のジャーナルを再生して、各lumpの格納場所を求めておく（`lump_kinds`を参照）。
+    pub fn new(mut storage: Storage<N>) -> Self {
+        let lump_kinds = replay_lump_kinds(&mut storage);
+        StorageHandle {
+            storage,
+            id_format: IdFormat::default(),
+            read_only: false,
+            lump_kinds,
+            lock: None,
         }
     }
+
+    /// ハンドルを分解し、ストレージと各lumpの格納場所と（取得していれば）ロックを返す
+    ///
+    /// ロックはストレージを閉じるまで保持し続けること。
+    pub fn into_parts(self) -> (Storage<N>, BTreeMap<LumpId, LumpKind>, Option<StorageLock>) {
+        (self.storage, self.lump_kinds, self.lock)
+    }
+
+    /// 読み込み専用モードで開かれているかどうか
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// 読み込み専用モードで開かれている場合にはエラーを返す
+    pub fn check_writable(&self) -> Result<(), cannyls::Error> {
+        if self.read_only {
+            track_panic!(
+                ErrorKind::InvalidInput,
+                "the storage is opened in read-only mode"
+            );
+        }
+        Ok(())
+    }
+
+    /// 出力に用いるlump idの形式を設定する
+    pub fn set_id_format(&mut self, id_format: IdFormat) {
+        self.id_format = id_format;
+    }
+
+    pub fn id_format(&self) -> IdFormat {
+        self.id_format
+    }
+
+    fn format_id(&self, lump_id: &LumpId) -> String {
+        self.id_format.format(lump_id.as_u128())
+    }
+    fn format_range(&self, start: u128, end: u128) -> String {
+        format!(
+            "[{}, {})",
+            self.id_format.format(start),
+            self.id_format.format(end)
+        )
+    }
+
+    /// ジャーナルエントリを`[位置] レコード`の形式の文字列にする
+    pub fn format_journal_entry(&self, entry: &JournalEntry) -> String {
+        Entry::from(entry).format(self.id_format)
+    }
+
+    /// ジャーナル領域の内容を`JournalView`として返す
+    pub fn journal_view(&mut self) -> Result<JournalView, cannyls::Error> {
+        let snapshot = track!(self.journal_info())?;
+        Ok(JournalView::from_snapshot(self.header(), &snapshot))
+    }
+
+    pub fn put_str(&mut self, key: u128, value: &str) -> Result<bool, cannyls::Error> {
+        track!(self.check_writable())?;
+        let lump_id = LumpId::new(key);
+        let lump_data = track!(self.storage.allocate_lump_data_with_bytes(value.as_bytes()))?;
+        let created = track!(self.storage.put(&lump_id, &lump_data))?;
+        self.lump_kinds.insert(lump_id, LumpKind::Block);
+        Ok(created)
+    }
+    pub fn put(&mut self, key: u128, value: &str) -> Result<(), cannyls::Error> {
+        let created = track!(self.put_str(key, value))?;
+        output::print_stored(
+            self.id_format,
+            "put",
+            key,
+            format_args!("value={}", value),
+            created,
+        );
+        Ok(())
+    }
     pub fn put_bytes(&mut self, key: u128, value: &[u8]) -> Result<bool, cannyls::Error> {
+        track!(self.check_writable())?;
         let lump_id = LumpId::new(key);
-        let lump_data = track!(self.storage.allocate_lump_data_with_bytes(value)).unwrap();
-        self.storage.put(&lump_id, &lump_data)
+        let lump_data = track!(self.storage.allocate_lump_data_with_bytes(value))?;
+        let created = track!(self.storage.put(&lump_id, &lump_data))?;
+        self.lump_kinds.insert(lump_id, LumpKind::Block);
+        Ok(created)
     }
 
     pub fn embed_str(&mut self, key: u128, value: &str) -> Result<bool, cannyls::Error> {
+        track!(self.embed_bytes(key, value.as_bytes()))
+    }
+    pub fn embed_bytes(&mut self, key: u128, value: &[u8]) -> Result<bool, cannyls::Error> {
+        track!(self.check_writable())?;
+        track!(check_embeddable(value))?;
         let lump_id = LumpId::new(key);
-        let lump_data = track!(LumpData::new_embedded(value.as_bytes().to_vec())).unwrap();
-        self.storage.put(&lump_id, &lump_data)
+        let lump_data = track!(LumpData::new_embedded(value.to_vec()))?;
+        let created = track!(self.storage.put(&lump_id, &lump_data))?;
+        self.lump_kinds.insert(lump_id, LumpKind::Embedded);
+        Ok(created)
     }
-    pub fn embed(&mut self, key: u128, value: &str) {
-        let result = track_try_unwrap!(self.embed_str(key, value));
-
-        if result {
-            println!("embed key={}, value={}", key, value);
-        } else {
-            println!("[overwrite] put key={}, value={}", key, value);
+    pub fn embed(&mut self, key: u128, value: &str) -> Result<(), cannyls::Error> {
+        let created = track!(self.embed_str(key, value))?;
+        output::print_stored(
+            self.id_format,
+            "embed",
+            key,
+            format_args!("value={}", value),
+            created,
+        );
+        Ok(())
+    }
+    /// 任意のバイト列を埋め込みlumpとして格納する（UTF-8の文字列であれば`embed`と同じ出力をする）
+    pub fn embed_binary(&mut self, key: u128, value: &[u8]) -> Result<(), cannyls::Error> {
+        if let Ok(value) = str::from_utf8(value) {
+            return track!(self.embed(key, value));
         }
+        let created = track!(self.embed_bytes(key, value))?;
+        output::print_stored(
+            self.id_format,
+            "embed",
+            key,
+            format_args!("{}-bytes data", value.len()),
+            created,
+        );
+        Ok(())
     }
 
     #[allow(clippy::option_option)]
@@ -122,64 +418,176 @@ impl StorageHandle {
             .get(&lump_id)
             .map(|s| s.map(|s| s.as_bytes().to_vec()))
     }
-    pub fn get(&mut self, key: u128) {
-        let result = track!(self.get_as_string(key)).unwrap();
-        match result {
-            Some(Some(string)) => {
-                // the putted data is a string
-                println!("get(as string) => {:?}", string);
-            }
-            Some(None) => {
-                // the putted data is not a string
-                let bytes = track!(self.get_as_bytes(key)).unwrap().unwrap();
-                println!("get({}-bytes data) =>\n{:?}", bytes.len(), bytes);
-            }
-            None => {
-                // there is no data having the `key`
-                println!("no entry for the key {:?}", key);
-            }
-        }
+    /// keyに対応するlump dataを、格納場所とともに取得する
+    ///
+    /// 格納場所が分からない場合（`lump_kinds`を参照）は`None`とする。
+    pub fn get_with_kind(
+        &mut self,
+        key: u128,
+    ) -> Result<Option<(LumpData, Option<LumpKind>)>, cannyls::Error> {
+        let lump_id = LumpId::new(key);
+        let data = track!(self.storage.get(&lump_id))?;
+        Ok(data.map(|data| (data, self.lump_kind(&lump_id))))
+    }
+    pub fn get(&mut self, key: u128) -> Result<(), cannyls::Error> {
+        track!(self.print_lump(key, false))
     }
     /// keyに対応するlump dataを16進数表記で出力する
-    pub fn print_as_bytes(&mut self, key: u128) {
-        let result = track!(self.get_as_bytes(key)).unwrap();
+    pub fn print_as_bytes(&mut self, key: u128) -> Result<(), cannyls::Error> {
+        track!(self.print_lump(key, true))
+    }
+    fn print_lump(&mut self, key: u128, hex: bool) -> Result<(), cannyls::Error> {
+        let result = track!(self.get_with_kind(key))?;
+        let lump = result.as_ref().map(|(data, kind)| (data.as_bytes(), *kind));
+        output::print_lump(self.id_format, key, lump, hex);
+        Ok(())
+    }
+
+    /// keyに対応するlumpを`to`の格納場所に移し、移す前の格納場所を返す（lumpが存在しなければ`None`）
+    ///
+    /// 既に`to`に格納されている場合は何もしない。
+    /// 移す前の格納場所が分からない場合は、常に`to`に格納し直して`Some(None)`を返す。
+    #[allow(clippy::option_option)]
+    pub fn convert_key(
+        &mut self,
+        key: u128,
+        to: LumpKind,
+    ) -> Result<Option<Option<LumpKind>>, cannyls::Error> {
+        track!(self.check_writable())?;
+        let (data, kind) = match track!(self.get_with_kind(key))? {
+            None => return Ok(None),
+            Some(found) => found,
+        };
+        if kind != Some(to) {
+            match to {
+                LumpKind::Embedded => track!(self.embed_bytes(key, data.as_bytes()))?,
+                LumpKind::Block => track!(self.put_bytes(key, data.as_bytes()))?,
+            };
+        }
+        Ok(Some(kind))
+    }
+    pub fn convert(&mut self, key: u128, to: LumpKind) -> Result<(), cannyls::Error> {
+        let result = track!(self.convert_key(key, to))?;
+        let key = self.id_format.format(key);
         match result {
-            Some(bytes) => {
-                println!(
-                    "get({}-bytes data) [hex format] =>\n{:02x?}",
-                    bytes.len(),
-                    bytes
-                );
-            }
-            None => {
-                println!("no entry for the key {:?}", key);
-            }
+            None => println!("no entry for the key {}", key),
+            Some(Some(kind)) if kind == to => println!("key={} is already {}", key, to),
+            Some(kind) => println!(
+                "converted key={} from {} to {}",
+                key,
+                output::format_kind(kind),
+                to
+            ),
         }
+        Ok(())
     }
 
     pub fn delete_key(&mut self, key: u128) -> Result<bool, cannyls::Error> {
+        track!(self.check_writable())?;
         let lump_id = LumpId::new(key);
-        self.storage.delete(&lump_id)
+        let deleted = track!(self.storage.delete(&lump_id))?;
+        self.lump_kinds.remove(&lump_id);
+        Ok(deleted)
     }
-    pub fn delete(&mut self, key: u128) {
-        let result = track_try_unwrap!(self.delete_key(key));
-        println!("delete result => {:?}", result);
+    pub fn delete(&mut self, key: u128) -> Result<(), cannyls::Error> {
+        let deleted = track!(self.delete_key(key))?;
+        output::print_deleted(deleted);
+        Ok(())
     }
 
-    pub fn delete_range(&mut self, start: u128, end: u128) {
-        use std::ops::Range;
+    /// 区間[start, end)に含まれるkeyを全て削除し、削除したkeyを返す
+    pub fn delete_range_keys(
+        &mut self,
+        start: u128,
+        end: u128,
+    ) -> Result<Vec<LumpId>, cannyls::Error> {
+        track!(self.check_writable())?;
         let start = LumpId::new(start);
         let end = LumpId::new(end);
-        let result = track_try_unwrap!(self.storage.delete_range(Range { start, end }));
-        println!("delete_range result => {:?}", result);
+        let deleted = track!(self.storage.delete_range(Range { start, end }))?;
+        for lump_id in &deleted {
+            self.lump_kinds.remove(lump_id);
+        }
+        Ok(deleted)
+    }
+    pub fn delete_range(&mut self, start: u128, end: u128) -> Result<(), cannyls::Error> {
+        let deleted = track!(self.delete_range_keys(start, end))?;
+        output::print_deleted_range(self.id_format, &deleted);
+        Ok(())
+    }
+    /// `delete_range`で削除されるlumpを、削除せずに出力する
+    pub fn print_delete_range_plan(&mut self, start: u128, end: u128) {
+        let ids = self.keys_in_range(start, end);
+        println!(
+            "delete_range {} (dry run) would delete {} lumps => [{}]",
+            self.format_range(start, end),
+            ids.len(),
+            output::format_ids(self.id_format, &ids)
+        );
+    }
+
+    /// 区間`[start, end)`に含まれるlumpの数とバイト数を数える
+    ///
+    /// `exact`が偽の場合は、ストレージのインデックスだけから求めた近似値（データ領域のlumpはブロック単位）を返す。
+    /// 真の場合は各lumpを読み込んで、値の正確なバイト数の合計を返す。
+    pub fn count_range(
+        &mut self,
+        start: u128,
+        end: u128,
+        exact: bool,
+    ) -> Result<RangeCount, cannyls::Error> {
+        let ids = self.keys_in_range(start, end);
+        let bytes = if exact {
+            let mut bytes = 0;
+            for lump_id in &ids {
+                if let Some(data) = track!(self.storage.get(lump_id))? {
+                    bytes += data.as_bytes().len() as u64;
+                }
+            }
+            bytes
+        } else {
+            let range = Range {
+                start: LumpId::new(start),
+                end: LumpId::new(end),
+            };
+            self.storage
+                .usage_range(range)
+                .bytecount()
+                .unwrap_or_default()
+        };
+        Ok(RangeCount {
+            lumps: ids.len(),
+            bytes,
+            exact,
+        })
+    }
+    pub fn print_range_count(
+        &mut self,
+        start: u128,
+        end: u128,
+        exact: bool,
+    ) -> Result<(), cannyls::Error> {
+        let count = track!(self.count_range(start, end, exact))?;
+        println!(
+            "count_range {} => lumps = {}, bytes = {}{}",
+            self.format_range(start, end),
+            count.lumps,
+            format_bytes_with_size(count.bytes),
+            if count.exact {
+                ""
+            } else {
+                " (approximate: data region lumps are counted in blocks)"
+            }
+        );
+        Ok(())
     }
 
     pub fn journal_info(&mut self) -> Result<JournalSnapshot, cannyls::Error> {
         self.storage.journal_snapshot()
     }
 
-    pub fn print_journal_info(&mut self) {
-        let snapshot = track_try_unwrap!(self.journal_info());
+    pub fn print_journal_info(&mut self) -> Result<(), cannyls::Error> {
+        let snapshot = track!(self.journal_info())?;
 
         println!(
             "journal [unreleased head] position = {}",
@@ -193,60 +601,114 @@ impl StorageHandle {
         } else {
             println!("<journal entries>");
             for e in snapshot.entries {
-                println!("{:?}", e);
+                println!("{}", self.format_journal_entry(&e));
             }
             println!("</journal entries>");
         }
+        Ok(())
     }
 
-    pub fn journal_gc(&mut self) {
-        println!("run journal full GC ...");
-        track_try_unwrap!(self.storage.journal_sync());
-        let result = self.storage.journal_gc();
-        if let Err(error) = result {
-            panic!("journal_gc failed with the error {:?}", error);
-        } else {
-            println!("journal full GC succeeded!");
+    /// ジャーナルをディスクに同期する（読み込み専用モードでは何もしない）
+    pub fn journal_sync(&mut self) -> Result<(), cannyls::Error> {
+        if self.read_only {
+            return Ok(());
         }
+        track!(self.storage.journal_sync())
+    }
+
+    pub fn journal_gc(&mut self) -> Result<(), cannyls::Error> {
+        track!(self.check_writable())?;
+        println!("run journal full GC ...");
+        track!(self.storage.journal_sync())?;
+        track!(self.storage.journal_gc())?;
+        println!("journal full GC succeeded!");
+        Ok(())
     }
 
-    pub fn all_keys(&mut self) -> Vec<LumpId> {
+    pub fn all_keys(&self) -> Vec<LumpId> {
         self.storage.list()
     }
 
-    pub fn print_list_of_lumpids(&mut self) {
+    /// keyが存在するかどうか
+    pub fn contains_key(&self, key: u128) -> bool {
+        self.storage.head(&LumpId::new(key)).is_some()
+    }
+
+    /// 区間[start, end)に含まれるkeyを昇順に返す
+    pub fn keys_in_range(&mut self, start: u128, end: u128) -> Vec<LumpId> {
+        let start = LumpId::new(start);
+        let end = LumpId::new(end);
+        self.storage.list_range(Range { start, end })
+    }
+
+    pub fn header(&self) -> &StorageHeader {
+        self.storage.header()
+    }
+
+    pub fn metrics(&self) -> &StorageMetrics {
+        self.storage.metrics()
+    }
+
+    /// 存在する各lumpの格納場所
+    ///
+    /// 開いた時点のジャーナルを再生して求め、以降はこのハンドルを通した変更の度に更新する。
+    /// ジャーナルの再生に失敗した場合や、`new`に渡す前にストレージを変更していた場合は、一部のlumpが含まれないことがある。
+    pub fn lump_kinds(&self) -> &BTreeMap<LumpId, LumpKind> {
+        &self.lump_kinds
+    }
+
+    /// 格納場所ごとのlumpの数（ジャーナルに埋め込まれたもの、データ領域に格納されたもの）
+    pub fn lump_kind_counts(&self) -> (usize, usize) {
+        let embedded = self
+            .lump_kinds
+            .values()
+            .filter(|&&kind| kind == LumpKind::Embedded)
+            .count();
+        (embedded, self.lump_kinds.len() - embedded)
+    }
+
+    /// lumpの格納場所（分からない場合は`None`）
+    pub fn lump_kind(&self, lump_id: &LumpId) -> Option<LumpKind> {
+        self.lump_kinds.get(lump_id).cloned()
+    }
+
+    pub fn print_list_of_lumpids(&mut self) -> Result<(), cannyls::Error> {
         let ids = self.storage.list();
-        if ids.is_empty() {
-            println!("there are no lumps");
-        } else {
-            println!("<lumpid list>");
-            for lumpid in ids {
-                println!("{:?}", lumpid);
-            }
-            println!("</lumpid list>");
-        }
+        output::print_lump_ids(self.id_format, &ids, Some(&self.lump_kinds));
+        Ok(())
     }
 
-    pub fn print_all_key_value_pairs(&mut self) {
+    pub fn print_all_key_value_pairs(&mut self) -> Result<(), cannyls::Error> {
         let ids = self.storage.list();
+        track!(self.print_lumps(&ids))
+    }
+    /// 区間`[start, end)`に含まれるlumpを`print_all_key_value_pairs`と同じ形式で出力する
+    pub fn print_range(&mut self, start: u128, end: u128) -> Result<(), cannyls::Error> {
+        let ids = self.keys_in_range(start, end);
+        track!(self.print_lumps(&ids))
+    }
+    fn print_lumps(&mut self, ids: &[LumpId]) -> Result<(), cannyls::Error> {
         if ids.is_empty() {
             println!("there are no lumps");
         } else {
-            let result = ids
-                .iter()
-                .map(|key| {
-                    (
-                        key,
-                        lumpdata_to_string(&self.storage.get(key).unwrap().unwrap()),
-                    )
-                })
-                .collect::<Vec<_>>();
+            let mut result = Vec::with_capacity(ids.len());
+            for key in ids {
+                if let Some((data, kind)) = track!(self.get_with_kind(key.as_u128()))? {
+                    result.push((key, kind, lumpdata_to_string(&data)));
+                }
+            }
             println!("<lump list>");
-            for lump in result {
-                println!("{:?}", lump);
+            for (lumpid, kind, value) in result {
+                println!(
+                    "({}, {}, {:?})",
+                    self.format_id(lumpid),
+                    output::format_kind(kind),
+                    value
+                );
             }
             println!("</lump list>");
         }
+        Ok(())
     }
 
     pub fn print_header_info(&mut self) {
@@ -257,40 +719,39 @@ impl StorageHandle {
         let block_size_u64 = u64::from(header.block_size.as_u16());
         println!("  block size = {}", block_size_u64);
         println!("  uuid = {}", header.instance_uuid);
-        println!("  journal region size = {}", header.journal_region_size);
+        println!(
+            "  journal region size = {}",
+            format_bytes_with_size(header.journal_region_size)
+        );
         println!("    journal header size = {}", block_size_u64);
         println!(
             "    journal record size = {}",
-            header.journal_region_size - block_size_u64
+            format_bytes_with_size(header.journal_region_size - block_size_u64)
+        );
+        println!(
+            "  data region size = {}",
+            format_bytes_with_size(header.data_region_size)
         );
-        println!("  data region size = {}", header.data_region_size);
         println!("  storage header size => {}", header.region_size());
-        println!("  storage total size = {}", header.storage_size());
-    }
-
-    pub fn change_major_version_to<T: AsRef<Path> + Clone>(path: T, new_major_version: u16) {
-        let mut version = track_io!(get_version_from_lusf_file(path.clone())).unwrap();
-        let old_major_version = version.major_version;
-        version.major_version = new_major_version;
-
-        track_io!(put_version_to_lusf_file(path, version)).unwrap();
-
-        println!("change from {} to {}", old_major_version, new_major_version);
-    }
-
-    pub fn change_minor_version_to<T: AsRef<Path> + Clone>(path: T, new_minor_version: u16) {
-        let mut version = track_io!(get_version_from_lusf_file(path.clone())).unwrap();
-        let old_minor_version = version.minor_version;
-        version.minor_version = new_minor_version;
-
-        track_io!(put_version_to_lusf_file(path, version)).unwrap();
-
-        println!("change from {} to {}", old_minor_version, new_minor_version);
+        println!(
+            "  storage total size = {}",
+            format_bytes_with_size(header.storage_size())
+        );
+        let (embedded, block) = self.lump_kind_counts();
+        println!("lumps =>");
+        println!("  lumps = {}", self.storage.metrics().lumps());
+        println!("    embedded = {}", embedded);
+        println!("    block = {}", block);
+        println!(
+            "  max embedded size = {}",
+            format_bytes_with_size(LumpData::MAX_EMBEDDED_SIZE as u64)
+        );
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use cannyls::nvm::MemoryNvm;
     use tempfile::Builder;
     use trackable::result::TestResult;
 
@@ -323,12 +784,27 @@ mod tests {
         let mut handle = StorageHandle::new(storage);
 
         assert!(handle.put_str(0, "hoge").is_ok());
-        assert_eq!(handle.delete_key(0)?, true);
+        assert!(handle.delete_key(0)?);
         assert!(handle.get_as_string(0)?.is_none());
 
         Ok(())
     }
 
+    #[test]
+    fn works_on_memory_nvm() -> TestResult {
+        let nvm = MemoryNvm::new(vec![0; 1024 * 1024]);
+        let storage = track!(Storage::create(nvm))?;
+        let mut handle = StorageHandle::new(storage);
+
+        assert!(handle.put_str(0, "hoge")?);
+        assert!(handle.embed_str(1, "bar")?);
+        assert_eq!(handle.all_keys().len(), 2);
+        assert!(handle.delete_key(0)?);
+        assert_eq!(handle.get_as_string(1)?, Some(Some("bar".to_owned())));
+
+        Ok(())
+    }
+
     #[test]
     fn puts_and_gets_bytes() -> TestResult {
         let dir = track_io!(Builder::new().prefix("cannyls_test").tempdir())?;
@@ -343,4 +819,145 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn lump_kinds_work() -> TestResult {
+        let nvm = MemoryNvm::new(vec![0; 1024 * 1024]);
+        let mut handle = StorageHandle::new(track!(Storage::create(nvm))?);
+        track!(handle.put_str(1, "block"))?;
+        track!(handle.embed_str(2, "embedded"))?;
+        track!(handle.embed_str(3, "deleted"))?;
+        track!(handle.delete_key(3))?;
+
+        let (data, kind) = track!(handle.get_with_kind(1))?.unwrap();
+        assert_eq!(
+            (data.as_bytes(), kind),
+            (&b"block"[..], Some(LumpKind::Block))
+        );
+        let (data, kind) = track!(handle.get_with_kind(2))?.unwrap();
+        assert_eq!(
+            (data.as_bytes(), kind),
+            (&b"embedded"[..], Some(LumpKind::Embedded))
+        );
+        assert!(track!(handle.get_with_kind(3))?.is_none());
+
+        let kinds = handle.lump_kinds();
+        assert_eq!(kinds.len(), 2);
+        assert_eq!(kinds[&LumpId::new(1)], LumpKind::Block);
+        assert_eq!(kinds[&LumpId::new(2)], LumpKind::Embedded);
+
+        assert_eq!("Block".parse::<LumpKind>(), Ok(LumpKind::Block));
+        assert!("journal".parse::<LumpKind>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn lump_kinds_survive_journal_gc() -> TestResult {
+        let dir = track_io!(Builder::new().prefix("cannyls_test").tempdir())?;
+        let path = dir.path().join("test.lusf");
+        let nvm = track!(FileNvm::create(&path, 1024 * 1024))?;
+        let storage = track!(StorageBuilder::new().journal_region_ratio(0.2).create(nvm))?;
+        let mut handle = StorageHandle::new(storage);
+
+        // 上書きを繰り返してジャーナルのGCを起こす（GC中のレコードはスナップショットに現れない）
+        let expected = |key: u128| {
+            if key.is_multiple_of(2) {
+                LumpKind::Block
+            } else {
+                LumpKind::Embedded
+            }
+        };
+        let mut incomplete_snapshots = 0;
+        for round in 0..300 {
+            for key in 0..20 {
+                let value = format!("{}-{}", key, round);
+                match expected(key) {
+                    LumpKind::Block => track!(handle.put_str(key, &value))?,
+                    LumpKind::Embedded => track!(handle.embed_str(key, &value))?,
+                };
+            }
+
+            // スナップショットから一部のlumpが欠けていても、全てのlumpの格納場所が分かる
+            if replay_lump_kinds(&mut handle.storage).len() < 20 {
+                incomplete_snapshots += 1;
+                for key in 0..20 {
+                    let (data, kind) = track!(handle.get_with_kind(key))?.unwrap();
+                    assert_eq!(data.as_bytes(), format!("{}-{}", key, round).as_bytes());
+                    assert_eq!(kind, Some(expected(key)));
+                }
+            }
+        }
+        track!(handle.journal_sync())?;
+        assert!(handle.metrics().journal_region().gc_enqueued_records() > 0);
+        assert!(incomplete_snapshots > 0);
+
+        for key in 0..20 {
+            let (data, kind) = track!(handle.get_with_kind(key))?.unwrap();
+            assert_eq!(data.as_bytes(), format!("{}-299", key).as_bytes());
+            assert_eq!(kind, Some(expected(key)));
+        }
+
+        // 開き直した場合もジャーナルから全てのlumpの格納場所が求まる
+        drop(handle);
+        let mut handle = track!(StorageHandle::open(&path, false))?;
+        assert_eq!(handle.lump_kinds().len(), 20);
+        assert_eq!(handle.lump_kind_counts(), (10, 10));
+        for key in 0..20 {
+            let (_, kind) = track!(handle.get_with_kind(key))?.unwrap();
+            assert_eq!(kind, Some(expected(key)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn convert_works() -> TestResult {
+        let nvm = MemoryNvm::new(vec![0; 1024 * 1024]);
+        let mut handle = StorageHandle::new(track!(Storage::create(nvm))?);
+        track!(handle.put_bytes(1, &[0, 1, 2]))?;
+
+        assert_eq!(
+            track!(handle.convert_key(1, LumpKind::Embedded))?,
+            Some(Some(LumpKind::Block))
+        );
+        assert_eq!(
+            track!(handle.convert_key(1, LumpKind::Embedded))?,
+            Some(Some(LumpKind::Embedded))
+        );
+        let (data, kind) = track!(handle.get_with_kind(1))?.unwrap();
+        assert_eq!(
+            (data.as_bytes(), kind),
+            (&[0, 1, 2][..], Some(LumpKind::Embedded))
+        );
+
+        assert_eq!(
+            track!(handle.convert_key(1, LumpKind::Block))?,
+            Some(Some(LumpKind::Embedded))
+        );
+        assert_eq!(handle.lump_kind(&LumpId::new(1)), Some(LumpKind::Block));
+        assert_eq!(track!(handle.convert_key(2, LumpKind::Block))?, None);
+
+        // 埋め込めない大きさのlumpは移せない
+        track!(handle.put_bytes(3, &vec![0; LumpData::MAX_EMBEDDED_SIZE + 1]))?;
+        let e = handle.convert_key(3, LumpKind::Embedded).unwrap_err();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+        assert_eq!(handle.lump_kind(&LumpId::new(3)), Some(LumpKind::Block));
+        Ok(())
+    }
+
+    #[test]
+    fn count_range_works() -> TestResult {
+        let nvm = MemoryNvm::new(vec![0; 1024 * 1024]);
+        let mut handle = StorageHandle::new(track!(Storage::create(nvm))?);
+        track!(handle.put_str(1, "foo"))?;
+        track!(handle.embed_str(2, "ba"))?;
+        track!(handle.put_str(10, "out of range"))?;
+
+        let block_size = u64::from(handle.header().block_size.as_u16());
+        let count = track!(handle.count_range(0, 10, false))?;
+        assert_eq!((count.lumps, count.bytes), (2, block_size + 2));
+        let count = track!(handle.count_range(0, 10, true))?;
+        assert_eq!((count.lumps, count.bytes), (2, 5));
+        assert_eq!(track!(handle.count_range(3, 10, true))?.lumps, 0);
+        Ok(())
+    }
 }
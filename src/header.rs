@@ -0,0 +1,226 @@
+//! lusfファイルのヘッダ領域の読み書き。
+//!
+//! cannylsの`StorageHeader`はサポートしないバージョンのヘッダを読み込めず、また書き込みもストレージの作成時にしか行わない。
+//! ここではバージョンによらずヘッダをそのまま読み込み、個々のフィールドを書き換えて書き戻せるようにする。
+//! ヘッダの形式（整数は全てビッグエンディアン）:
+//!
+//! ```text
+//! オフセット  サイズ  フィールド
+//!          0       4  マジックナンバー（"lusf"）
+//!          4       2  ヘッダサイズ（以降のフィールドのバイト数。38）
+//!          6       2  major version
+//!          8       2  minor version
+//!         10       2  ブロックサイズ
+//!         12      16  インスタンスのUUID
+//!         28       8  ジャーナル領域のサイズ
+//!         36       8  データ領域のサイズ
+//! ```
+//!
+//! 詳細は https://github.com/frugalos/cannyls/wiki/Storage-Format を参照。
+use byteorder::{BigEndian, ByteOrder};
+use cannyls::storage::MAGIC_NUMBER;
+use cannyls::ErrorKind;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+use uuid::Uuid;
+
+/// マジックナンバーとヘッダサイズを除いた、ヘッダのフィールドのバイト数。
+pub const HEADER_SIZE: u16 = 2 + 2 + 2 + 16 + 8 + 8;
+
+/// マジックナンバーとヘッダサイズを含めた、ヘッダのバイト数。
+pub const FULL_HEADER_SIZE: usize = 4 + 2 + HEADER_SIZE as usize;
+
+/// ヘッダの各フィールドの名前、オフセット、バイト数。
+pub const FIELDS: [(&str, usize, usize); 8] = [
+    ("magic number", 0, 4),
+    ("header size", 4, 2),
+    ("major version", 6, 2),
+    ("minor version", 8, 2),
+    ("block size", 10, 2),
+    ("uuid", 12, 16),
+    ("journal region size", 28, 8),
+    ("data region size", 36, 8),
+];
+
+/// lusfファイルのヘッダ。
+///
+/// 値の妥当性（サポートするバージョンか、ブロックサイズが512の倍数かなど）は検証しない。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LusfHeader {
+    pub header_size: u16,
+    pub major_version: u16,
+    pub minor_version: u16,
+    pub block_size: u16,
+    pub instance_uuid: Uuid,
+    pub journal_region_size: u64,
+    pub data_region_size: u64,
+}
+impl LusfHeader {
+    /// `bytes`（ファイルの先頭の`FULL_HEADER_SIZE`バイト以上）からヘッダを読み込む。
+    pub fn decode(bytes: &[u8]) -> Result<Self, cannyls::Error> {
+        track_assert!(
+            bytes.len() >= FULL_HEADER_SIZE,
+            ErrorKind::InvalidInput,
+            "the header is too short ({} bytes < {} bytes)",
+            bytes.len(),
+            FULL_HEADER_SIZE
+        );
+        track_assert!(
+            bytes[..4] == MAGIC_NUMBER,
+            ErrorKind::InvalidInput,
+            "not a lusf file (magic number = {:02x?})",
+            &bytes[..4]
+        );
+        let header_size = BigEndian::read_u16(&bytes[4..]);
+        track_assert_eq!(
+            header_size,
+            HEADER_SIZE,
+            ErrorKind::InvalidInput,
+            "unsupported header size"
+        );
+        let mut uuid = [0; 16];
+        uuid.copy_from_slice(&bytes[12..28]);
+        Ok(LusfHeader {
+            header_size,
+            major_version: BigEndian::read_u16(&bytes[6..]),
+            minor_version: BigEndian::read_u16(&bytes[8..]),
+            block_size: BigEndian::read_u16(&bytes[10..]),
+            instance_uuid: Uuid::from_bytes(uuid),
+            journal_region_size: BigEndian::read_u64(&bytes[28..]),
+            data_region_size: BigEndian::read_u64(&bytes[36..]),
+        })
+    }
+
+    /// ヘッダを`FULL_HEADER_SIZE`バイトのバイト列にする。
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![0; FULL_HEADER_SIZE];
+        bytes[..4].copy_from_slice(&MAGIC_NUMBER);
+        BigEndian::write_u16(&mut bytes[4..], self.header_size);
+        BigEndian::write_u16(&mut bytes[6..], self.major_version);
+        BigEndian::write_u16(&mut bytes[8..], self.minor_version);
+        BigEndian::write_u16(&mut bytes[10..], self.block_size);
+        bytes[12..28].copy_from_slice(self.instance_uuid.as_bytes());
+        BigEndian::write_u64(&mut bytes[28..], self.journal_region_size);
+        BigEndian::write_u64(&mut bytes[36..], self.data_region_size);
+        bytes
+    }
+
+    /// `path`のlusfファイルからヘッダを読み込む。
+    pub fn read_from_file<P: AsRef<Path>>(path: P) -> Result<Self, cannyls::Error> {
+        let bytes = track!(read_raw_header(path))?;
+        track!(Self::decode(&bytes))
+    }
+
+    /// `path`のlusfファイルの先頭の`FULL_HEADER_SIZE`バイトをこのヘッダで書き換える。
+    ///
+    /// ファイルの他の部分（ヘッダ領域のパディングを含む）には書き込まない。
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), cannyls::Error> {
+        let mut file = track_io!(OpenOptions::new().write(true).open(path))?;
+        track_io!(file.write_all(&self.encode()))?;
+        track_io!(file.sync_all())?;
+        Ok(())
+    }
+}
+
+/// UUIDを解析する（`random`の場合はランダムに生成する）。
+pub fn parse_uuid(s: &str) -> Result<Uuid, String> {
+    if s.eq_ignore_ascii_case("random") {
+        return Ok(Uuid::new_v4());
+    }
+    Uuid::parse_str(s).map_err(|_| {
+        format!(
+            "invalid uuid `{}` (expected a UUID such as 936da01f-9abd-4d9d-80c7-02af85c822a8, or `random`)",
+            s
+        )
+    })
+}
+
+/// `path`のファイルの先頭の`FULL_HEADER_SIZE`バイトを読み込む。
+pub fn read_raw_header<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, cannyls::Error> {
+    let mut file = track_io!(File::open(path))?;
+    let mut bytes = vec![0; FULL_HEADER_SIZE];
+    track_io!(file.read_exact(&mut bytes))?;
+    Ok(bytes)
+}
+
+/// ヘッダのバイト列を、フィールドごとに`オフセット: 16進数  名前 = 値`の形式の行にする。
+///
+/// マジックナンバーやヘッダサイズが不正な場合は、その旨を表す行を末尾に加える。
+pub fn format_raw(bytes: &[u8]) -> Vec<String> {
+    let decoded = LusfHeader::decode(bytes).ok();
+    let mut lines = FIELDS
+        .iter()
+        .map(|&(name, offset, len)| {
+            let field = &bytes[offset..offset + len];
+            let hex = field
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let value = match (name, &decoded) {
+                ("magic number", _) => format!("{:?}", String::from_utf8_lossy(field)),
+                ("uuid", Some(header)) => header.instance_uuid.to_string(),
+                (_, _) if len == 2 => BigEndian::read_u16(field).to_string(),
+                (_, _) if len == 8 => BigEndian::read_u64(field).to_string(),
+                _ => "?".to_owned(),
+            };
+            format!("{:04x}: {:<47}  {} = {}", offset, hex, name, value)
+        })
+        .collect::<Vec<_>>();
+    if decoded.is_none() {
+        lines.push("(not a valid lusf header)".to_owned());
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use cannyls::nvm::MemoryNvm;
+    use cannyls::storage::Storage;
+    use trackable::result::TestResult;
+
+    use super::*;
+
+    #[test]
+    fn codec_works() -> TestResult {
+        let storage = track!(Storage::create(MemoryNvm::new(vec![0; 1024 * 1024])))?;
+        let expected = storage.header().clone();
+        let mut bytes = Vec::new();
+        track!(expected.write_to(&mut bytes))?;
+
+        let header = track!(LusfHeader::decode(&bytes))?;
+        assert_eq!(header.header_size, HEADER_SIZE);
+        assert_eq!(header.major_version, expected.major_version);
+        assert_eq!(header.minor_version, expected.minor_version);
+        assert_eq!(header.block_size, expected.block_size.as_u16());
+        assert_eq!(header.instance_uuid, expected.instance_uuid);
+        assert_eq!(header.journal_region_size, expected.journal_region_size);
+        assert_eq!(header.data_region_size, expected.data_region_size);
+        assert_eq!(header.encode(), bytes);
+
+        let lines = format_raw(&bytes);
+        assert_eq!(lines.len(), FIELDS.len());
+        assert_eq!(
+            lines[0],
+            format!("0000: {:<47}  magic number = \"lusf\"", "6c 75 73 66")
+        );
+        assert!(lines[5].ends_with(&format!("uuid = {}", expected.instance_uuid)));
+
+        assert_eq!(
+            parse_uuid("936DA01F-9ABD-4D9D-80C7-02AF85C822A8").map(|uuid| uuid.to_string()),
+            Ok("936da01f-9abd-4d9d-80c7-02af85c822a8".to_owned())
+        );
+        assert_ne!(parse_uuid("random"), parse_uuid("random"));
+        assert!(parse_uuid("936da01f").is_err());
+
+        bytes[0] = b'x';
+        assert_eq!(
+            format_raw(&bytes).last().unwrap(),
+            "(not a valid lusf header)"
+        );
+        assert!(LusfHeader::decode(&bytes).is_err());
+        assert!(LusfHeader::decode(&bytes[..FULL_HEADER_SIZE - 1]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+//! ベンチマークのレイテンシを記録するためのヒストグラム。
+//!
+//! 値（ナノ秒）を、2のべき乗ごとの区間をさらに128等分したバケットに数える。
+//! 全ての値を保持せずに済み、パーセンタイルの相対誤差は1%未満に収まる。
+use std::time::Duration;
+
+// 2のべき乗ごとの区間を`2^SUB_BUCKET_BITS`個のバケットに分ける
+const SUB_BUCKET_BITS: u32 = 7;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+const BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let shift = 63 - value.leading_zeros() - SUB_BUCKET_BITS;
+    let sub_bucket = (value >> shift) as usize - SUB_BUCKETS;
+    (shift as usize + 1) * SUB_BUCKETS + sub_bucket
+}
+
+// バケットに含まれる値の最大値
+fn bucket_upper_bound(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+    let shift = (index / SUB_BUCKETS - 1) as u32;
+    let sub_bucket = (index % SUB_BUCKETS + SUB_BUCKETS) as u128;
+    (((sub_bucket + 1) << shift) - 1).min(u128::from(u64::MAX)) as u64
+}
+
+/// レイテンシのヒストグラム。
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    count: u64,
+    sum: u128,
+    min: u64,
+    max: u64,
+}
+impl Histogram {
+    /// 空のヒストグラムを作成する。
+    pub fn new() -> Self {
+        Histogram {
+            counts: vec![0; BUCKETS],
+            count: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+
+    /// 値を一つ記録する。
+    pub fn record(&mut self, latency: Duration) {
+        let nanos = latency.as_nanos().min(u128::from(u64::MAX)) as u64;
+        self.counts[bucket_index(nanos)] += 1;
+        self.count += 1;
+        self.sum += u128::from(nanos);
+        self.min = self.min.min(nanos);
+        self.max = self.max.max(nanos);
+    }
+
+    /// `other`に記録された値を全てこのヒストグラムに加える。
+    pub fn merge(&mut self, other: &Histogram) {
+        for (c, o) in self.counts.iter_mut().zip(&other.counts) {
+            *c += o;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    /// 記録された値の数
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// 最小値（値が一つも無い場合は0）
+    pub fn min(&self) -> Duration {
+        if self.count == 0 {
+            Duration::default()
+        } else {
+            Duration::from_nanos(self.min)
+        }
+    }
+
+    /// 最大値
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max)
+    }
+
+    /// 平均値
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::default();
+        }
+        Duration::from_nanos((self.sum / u128::from(self.count)) as u64)
+    }
+
+    /// `percentile`パーセンタイル（例: `99.9`）の値
+    ///
+    /// 値は該当するバケットの上限で近似するが、記録された最大値は超えない。
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::default();
+        }
+        let rank = ((percentile / 100.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (index, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let value = bucket_upper_bound(index).clamp(self.min, self.max);
+                return Duration::from_nanos(value);
+            }
+        }
+        self.max()
+    }
+}
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_cover_all_values() {
+        for &value in &[0, 1, 127, 128, 129, 255, 256, 1_000_000, u64::MAX] {
+            let index = bucket_index(value);
+            assert!(index < BUCKETS);
+            assert!(value <= bucket_upper_bound(index), "value={}", value);
+            if index > 0 {
+                assert!(bucket_upper_bound(index - 1) < value, "value={}", value);
+            }
+        }
+    }
+
+    #[test]
+    fn percentiles_work() {
+        let mut histogram = Histogram::new();
+        for micros in 1..=1000 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        assert_eq!(histogram.count(), 1000);
+        assert_eq!(histogram.min(), Duration::from_micros(1));
+        assert_eq!(histogram.max(), Duration::from_micros(1000));
+        assert_eq!(histogram.mean(), Duration::from_nanos(500_500));
+
+        for &(percentile, expected) in &[(50.0, 500), (90.0, 900), (99.0, 990), (99.9, 999)] {
+            let actual = histogram.percentile(percentile).as_nanos() as f64;
+            let expected = expected as f64 * 1000.0;
+            assert!(
+                (actual - expected).abs() / expected < 0.01,
+                "p{}: {} vs {}",
+                percentile,
+                actual,
+                expected
+            );
+        }
+        assert_eq!(histogram.percentile(100.0), Duration::from_micros(1000));
+
+        let mut merged = Histogram::new();
+        merged.merge(&histogram);
+        merged.merge(&histogram);
+        assert_eq!(merged.count(), 2000);
+        assert_eq!(merged.percentile(50.0), histogram.percentile(50.0));
+    }
+}
@@ -0,0 +1,339 @@
+//! lusfファイルのジャーナル領域を、ストレージを開かずに直接読み込む。
+//!
+//! `StorageBuilder::open`はジャーナルを再生してインデックスを構築するので、大きなストレージでは時間がかかる。
+//! watchモードのように、ジャーナルの内容だけを繰り返し読みたい場合に用いる。
+//! 読み込む区間は、cannylsがストレージを開く時に再生するのと同じ
+//! （ジャーナルのヘッダが指すheadから`EndOfRecords`まで）。
+//!
+//! ジャーナル領域の形式:
+//!
+//! ```text
+//! オフセット（ジャーナル領域の先頭から）  内容
+//!                                      0  ジャーナルのヘッダ（1ブロック。先頭の8バイトがリングバッファのhead）
+//!                             block_size  リングバッファ（レコードの列）
+//! ```
+//!
+//! 各レコードは`チェックサム（4バイト）, タグ（1バイト）, 本体`の形式で、
+//! チェックサムはタグと本体のadler32である（cannylsの`storage::journal::record`を参照）。
+use adler32::RollingAdler32;
+use byteorder::{BigEndian, ByteOrder};
+use cannyls::lump::LumpId;
+use cannyls::storage::{JournalEntry, JournalRecord, JournalSnapshot, StorageHeader};
+use cannyls::ErrorKind;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+
+use crate::header::{LusfHeader, FULL_HEADER_SIZE};
+use crate::lumpid::IdFormat;
+
+const TAG_END_OF_RECORDS: u8 = 0;
+const TAG_GO_TO_FRONT: u8 = 1;
+const TAG_PUT: u8 = 3;
+const TAG_EMBED: u8 = 4;
+const TAG_DELETE: u8 = 5;
+const TAG_DELETE_RANGE: u8 = 6;
+
+// チェックサムとタグのサイズ
+const RECORD_HEADER_SIZE: u64 = 4 + 1;
+
+/// ジャーナルのレコード（`EndOfRecords`と`GoToFront`を除く）。
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Record {
+    /// データ領域の`start`ブロック目から`len`ブロックにlumpを書き込んだ
+    Put {
+        lump_id: LumpId,
+        start: u64,
+        len: u16,
+    },
+
+    /// `len`バイトのlumpをレコードに埋め込んだ
+    Embed {
+        lump_id: LumpId,
+        len: usize,
+    },
+
+    Delete(LumpId),
+    DeleteRange(Range<LumpId>),
+}
+
+/// ジャーナルのエントリ（レコードとそのリングバッファ内の位置）。
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Entry {
+    pub position: u64,
+    pub record: Record,
+}
+impl Entry {
+    /// `[位置] レコード`の形式の文字列にする（`Journal`コマンドの出力と同じ形式）。
+    pub fn format(&self, id_format: IdFormat) -> String {
+        let id = |lump_id: &LumpId| id_format.format(lump_id.as_u128());
+        let record = match self.record {
+            Record::Put {
+                ref lump_id,
+                start,
+                len,
+            } => format!(
+                "Put(key={}, portion={{ start: {}, len: {} }})",
+                id(lump_id),
+                start,
+                len
+            ),
+            Record::Embed { ref lump_id, len } => {
+                format!("Embed(key={}, {}-bytes data)", id(lump_id), len)
+            }
+            Record::Delete(ref lump_id) => format!("Delete(key={})", id(lump_id)),
+            Record::DeleteRange(ref range) => format!(
+                "DeleteRange(start={}, end={})",
+                id(&range.start),
+                id(&range.end)
+            ),
+        };
+        format!("[{}] {}", self.position, record)
+    }
+}
+impl<'a> From<&'a JournalEntry> for Entry {
+    fn from(entry: &'a JournalEntry) -> Self {
+        let record = match entry.record {
+            JournalRecord::Put(lump_id, ref portion) => Record::Put {
+                lump_id,
+                start: portion.start.as_u64(),
+                len: portion.len,
+            },
+            JournalRecord::Embed(lump_id, ref data) => Record::Embed {
+                lump_id,
+                len: data.len(),
+            },
+            JournalRecord::Delete(lump_id) => Record::Delete(lump_id),
+            JournalRecord::DeleteRange(ref range) => Record::DeleteRange(range.clone()),
+            JournalRecord::EndOfRecords | JournalRecord::GoToFront => {
+                unreachable!("snapshots never contain markers")
+            }
+        };
+        Entry {
+            position: entry.start.as_u64(),
+            record,
+        }
+    }
+}
+
+/// ジャーナル領域の内容。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalView {
+    /// ストレージのインスタンスのUUID
+    pub uuid: String,
+
+    pub unreleased_head: u64,
+    pub head: u64,
+    pub tail: u64,
+    pub entries: Vec<Entry>,
+}
+impl JournalView {
+    /// 開いたストレージのジャーナルのスナップショットから作る。
+    pub fn from_snapshot(header: &StorageHeader, snapshot: &JournalSnapshot) -> Self {
+        JournalView {
+            uuid: header.instance_uuid.to_string(),
+            unreleased_head: snapshot.unreleased_head,
+            head: snapshot.head,
+            tail: snapshot.tail,
+            entries: snapshot.entries.iter().map(Entry::from).collect(),
+        }
+    }
+}
+
+/// `path`のlusfファイルのジャーナル領域を読み込む。
+///
+/// ファイルは読み込み専用で開き、ロックは取得しない。
+/// 他のプロセスが書き込み中のレコードを読んだ場合などは、`ErrorKind::StorageCorrupted`のエラーになる。
+pub fn read_journal<P: AsRef<Path>>(path: P) -> Result<JournalView, cannyls::Error> {
+    let file = track_io!(File::open(path))?;
+    track!(read_journal_from(BufReader::new(file)))
+}
+
+/// `reader`（lusfファイルの内容）からジャーナル領域を読み込む。
+pub fn read_journal_from<R: Read + Seek>(mut reader: R) -> Result<JournalView, cannyls::Error> {
+    let mut bytes = vec![0; FULL_HEADER_SIZE];
+    track_io!(reader.read_exact(&mut bytes))?;
+    let header = track!(LusfHeader::decode(&bytes))?;
+    let block_size = u64::from(header.block_size);
+    track_assert!(
+        block_size > 0 && header.journal_region_size > block_size,
+        ErrorKind::InvalidInput,
+        "invalid layout (block size = {}, journal region size = {})",
+        block_size,
+        header.journal_region_size
+    );
+
+    // ヘッダ領域はヘッダをブロックサイズに切り上げた大きさ
+    let journal_start = (FULL_HEADER_SIZE as u64).div_ceil(block_size) * block_size;
+    let ring_start = journal_start + block_size;
+    let capacity = header.journal_region_size - block_size;
+    track_io!(reader.seek(SeekFrom::Start(journal_start)))?;
+    let mut head = [0; 8];
+    track_io!(reader.read_exact(&mut head))?;
+    let head = BigEndian::read_u64(&head);
+    track_assert!(
+        head < capacity,
+        ErrorKind::StorageCorrupted,
+        "the journal head {} is out of the ring buffer (capacity = {})",
+        head,
+        capacity
+    );
+
+    let mut entries = Vec::new();
+    let mut position = head;
+    let mut second_lap = false;
+    track_io!(reader.seek(SeekFrom::Start(ring_start + position)))?;
+    loop {
+        let (tag, body) = track!(read_record(&mut reader, position, capacity))?;
+        let record = match tag {
+            TAG_END_OF_RECORDS => break,
+            TAG_GO_TO_FRONT => {
+                track_assert!(
+                    !second_lap,
+                    ErrorKind::StorageCorrupted,
+                    "the journal wraps around twice"
+                );
+                second_lap = true;
+                position = 0;
+                track_io!(reader.seek(SeekFrom::Start(ring_start)))?;
+                continue;
+            }
+            TAG_PUT => Record::Put {
+                lump_id: LumpId::new(BigEndian::read_u128(&body)),
+                len: BigEndian::read_u16(&body[16..]),
+                start: BigEndian::read_uint(&body[18..], 5),
+            },
+            TAG_EMBED => Record::Embed {
+                lump_id: LumpId::new(BigEndian::read_u128(&body)),
+                len: body.len() - 18,
+            },
+            TAG_DELETE => Record::Delete(LumpId::new(BigEndian::read_u128(&body))),
+            TAG_DELETE_RANGE => Record::DeleteRange(Range {
+                start: LumpId::new(BigEndian::read_u128(&body)),
+                end: LumpId::new(BigEndian::read_u128(&body[16..])),
+            }),
+            _ => unreachable!(),
+        };
+        entries.push(Entry { position, record });
+        position += RECORD_HEADER_SIZE + body.len() as u64;
+    }
+    Ok(JournalView {
+        uuid: header.instance_uuid.to_string(),
+        unreleased_head: head,
+        head,
+        tail: position,
+        entries,
+    })
+}
+
+// リングバッファの`position`にあるレコードを読み込み、タグと本体を返す
+fn read_record<R: Read>(
+    reader: &mut R,
+    position: u64,
+    capacity: u64,
+) -> Result<(u8, Vec<u8>), cannyls::Error> {
+    let mut record_header = [0; RECORD_HEADER_SIZE as usize];
+    track!(read_within(reader, &mut record_header, position, capacity))?;
+    let checksum = BigEndian::read_u32(&record_header);
+    let tag = record_header[4];
+    let body_position = position + RECORD_HEADER_SIZE;
+    let mut body = match tag {
+        TAG_END_OF_RECORDS | TAG_GO_TO_FRONT => Vec::new(),
+        TAG_PUT => vec![0; 16 + 2 + 5],
+        TAG_EMBED => {
+            let mut prefix = [0; 16 + 2];
+            track!(read_within(reader, &mut prefix, body_position, capacity))?;
+            let len = usize::from(BigEndian::read_u16(&prefix[16..]));
+            let mut body = prefix.to_vec();
+            body.resize(prefix.len() + len, 0);
+            track!(read_within(
+                reader,
+                &mut body[prefix.len()..],
+                body_position + prefix.len() as u64,
+                capacity
+            ))?;
+            body
+        }
+        TAG_DELETE => vec![0; 16],
+        TAG_DELETE_RANGE => vec![0; 32],
+        _ => track_panic!(
+            ErrorKind::StorageCorrupted,
+            "unknown journal record tag {} at {}",
+            tag,
+            position
+        ),
+    };
+    if tag != TAG_EMBED {
+        track!(read_within(reader, &mut body, body_position, capacity))?;
+    }
+
+    let mut adler32 = RollingAdler32::new();
+    adler32.update(tag);
+    adler32.update_buffer(&body);
+    track_assert_eq!(
+        adler32.hash(),
+        checksum,
+        ErrorKind::StorageCorrupted,
+        "checksum mismatch at {}",
+        position
+    );
+    Ok((tag, body))
+}
+
+// リングバッファの`position`から`buf`を読み込む（リングバッファの終端を越える場合はエラー）
+fn read_within<R: Read>(
+    reader: &mut R,
+    buf: &mut [u8],
+    position: u64,
+    capacity: u64,
+) -> Result<(), cannyls::Error> {
+    track_assert!(
+        position + buf.len() as u64 <= capacity,
+        ErrorKind::StorageCorrupted,
+        "the record at {} exceeds the ring buffer (capacity = {})",
+        position,
+        capacity
+    );
+    track_io!(reader.read_exact(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use cannyls::nvm::FileNvm;
+    use cannyls::storage::Storage;
+    use tempfile::Builder;
+    use trackable::result::TestResult;
+
+    use super::*;
+    use crate::handle::StorageHandle;
+
+    #[test]
+    fn read_journal_works() -> TestResult {
+        let dir = track_io!(Builder::new().prefix("kanils_test").tempdir())?;
+        let path = dir.path().join("test.lusf");
+        let nvm = track!(FileNvm::create(&path, 1024 * 1024))?;
+        let mut handle = StorageHandle::new(track!(Storage::create(nvm))?);
+        track!(handle.put_str(1, "foo"))?;
+        track!(handle.embed_bytes(2, b"bar"))?;
+        track!(handle.delete_key(1))?;
+        track!(handle.delete_range_keys(0, 10))?;
+        track!(handle.journal_sync())?;
+
+        let view = track!(read_journal(&path))?;
+        let snapshot = track!(handle.journal_info())?;
+        assert_eq!(view, JournalView::from_snapshot(handle.header(), &snapshot));
+        assert_eq!(view.entries.len(), 4);
+        assert_eq!(
+            view.entries[1].format(IdFormat::default()),
+            "[28] Embed(key=2, 3-bytes data)"
+        );
+
+        // 書き込み途中のレコードはチェックサムの不一致として検出する
+        let mut bytes = track_io!(std::fs::read(&path))?;
+        bytes[2 * 512 + 28 + 23] ^= 0xFF;
+        let e = read_journal_from(std::io::Cursor::new(bytes)).unwrap_err();
+        assert_eq!(*e.kind(), ErrorKind::StorageCorrupted);
+        Ok(())
+    }
+}
@@ -1,11 +1,51 @@
+extern crate adler32;
 extern crate byteorder;
 #[macro_use]
 extern crate trackable;
 extern crate cannyls;
+extern crate fibers;
+extern crate futures;
 extern crate indicatif;
+extern crate libc;
+extern crate prometrics;
 extern crate rand;
+extern crate rustyline;
+extern crate serde;
+extern crate serde_json;
 #[cfg(test)]
 extern crate tempfile;
+extern crate uuid;
 
+macro_rules! track_io {
+    ($expr:expr) => {
+        $expr.map_err(|e: ::std::io::Error| track!(cannyls::Error::from(e)))
+    };
+}
+
+pub mod archive;
+pub mod batch;
 pub mod bench;
+pub mod compare;
+pub mod confirm;
+pub mod device;
+pub mod duration;
+pub mod fault;
 pub mod handle;
+pub mod header;
+pub mod histogram;
+pub mod journal;
+pub mod lock;
+pub mod lumpid;
+pub mod memory;
+pub mod metrics;
+pub mod output;
+pub mod readonly;
+pub mod repl;
+pub mod report;
+pub mod scrub;
+pub mod server;
+pub mod size;
+pub mod spec;
+pub mod torture;
+pub mod watch;
+pub mod workload;
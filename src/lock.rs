@@ -0,0 +1,129 @@
+//! lusfファイルに対する`flock`による勧告ロックを扱う。
+//!
+//! 複数のプロセス（KaNiLS同士や、KaNiLSと稼働中のfrugalos）が同じlusfファイルを
+//! 同時に書き換えて壊してしまうことを防ぐために用いる。
+//! cannylsの`FileNvm`が取得する排他ロックも`flock`なので、frugalosとの間でも競合を検出できる。
+use cannyls::ErrorKind;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use trackable::error::ErrorKindExt;
+
+/// ロックの種類。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// 共有ロック（読み込み専用のセッション用）
+    ///
+    /// 他の共有ロックとは両立するが、排他ロックとは両立しない。
+    Shared,
+
+    /// 排他ロック（ファイルを書き換え得るセッション用）
+    Exclusive,
+}
+
+/// lusfファイルに対して取得した勧告ロック。
+///
+/// このインスタンスが破棄されるとロックは解放される。
+#[derive(Debug)]
+pub struct StorageLock {
+    // ロックはこのファイルが閉じられた時に解放される
+    _file: File,
+    mode: LockMode,
+}
+impl StorageLock {
+    /// `path`のファイルに対してロックを取得する。
+    ///
+    /// 他のプロセスが両立しないロックを保持している場合には、待たずにエラーを返す。
+    /// エラーメッセージには、（分かる場合には）ロックを保持しているプロセスのpidが含まれる。
+    pub fn acquire<P: AsRef<Path>>(path: P, mode: LockMode) -> Result<Self, cannyls::Error> {
+        let path = path.as_ref();
+        let file = track_io!(File::open(path))?;
+        let operation = match mode {
+            LockMode::Shared => libc::LOCK_SH,
+            LockMode::Exclusive => libc::LOCK_EX,
+        };
+        if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::WouldBlock {
+                return Err(track!(cannyls::Error::from(e)));
+            }
+            let holder = match lock_holder_pid(&file) {
+                Some(pid) => format!("pid {}", pid),
+                None => "another process".to_owned(),
+            };
+            let message = format!(
+                "storage {:?} is in use by {} (use `--no-lock` to skip locking)",
+                path, holder
+            );
+            return Err(track!(cannyls::Error::from(
+                ErrorKind::Other.cause(message)
+            )));
+        }
+        Ok(StorageLock { _file: file, mode })
+    }
+
+    /// ロックの種類を返す。
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+}
+
+// `/proc/locks`から、`file`に対する`flock`ロックを保持しているプロセスのpidを探す
+//
+// `/proc/locks`の各行は`1: FLOCK  ADVISORY  WRITE 1234 08:01:5678 0 EOF`の形式で、
+// 5番目のフィールドがpid、6番目のフィールドが`major:minor:inode`（major, minorは16進数）である。
+// ロック待ちの行（`1: -> FLOCK ...`）は対象外。
+fn lock_holder_pid(file: &File) -> Option<u32> {
+    let metadata = file.metadata().ok()?;
+    let dev = metadata.dev();
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    let target = (major, minor, metadata.ino());
+
+    let locks = fs::read_to_string("/proc/locks").ok()?;
+    locks.lines().find_map(|line| {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        if fields.len() < 6 || fields[1] != "FLOCK" {
+            return None;
+        }
+        let mut id = fields[5].split(':');
+        let major = u64::from_str_radix(id.next()?, 16).ok()?;
+        let minor = u64::from_str_radix(id.next()?, 16).ok()?;
+        let inode = id.next()?.parse::<u64>().ok()?;
+        if (major, minor, inode) == target {
+            fields[4].parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::Builder;
+    use trackable::result::TestResult;
+
+    use super::*;
+
+    #[test]
+    fn locks_conflict() -> TestResult {
+        let dir = track_io!(Builder::new().prefix("cannyls_test").tempdir())?;
+        let path = dir.path().join("test.lusf");
+        track_io!(fs::write(&path, b"dummy"))?;
+
+        let shared0 = track!(StorageLock::acquire(&path, LockMode::Shared))?;
+        let shared1 = track!(StorageLock::acquire(&path, LockMode::Shared))?;
+        assert_eq!(shared1.mode(), LockMode::Shared);
+        let e = StorageLock::acquire(&path, LockMode::Exclusive).unwrap_err();
+        assert!(e.to_string().contains("is in use by"));
+        drop(shared0);
+        drop(shared1);
+
+        let _exclusive = track!(StorageLock::acquire(&path, LockMode::Exclusive))?;
+        assert!(StorageLock::acquire(&path, LockMode::Shared).is_err());
+        assert!(StorageLock::acquire(&path, LockMode::Exclusive).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+//! lump idの解析と出力形式を扱う。
+//!
+//! 受け付ける表記は次の通り:
+//!
+//! - 10進数: `42`, `1_000_000`
+//! - 16進数: `0x2a`, `0X2A`, `0xdead_beef`
+//! - frugalos形式: `namespace:node:version`
+//!   - 上位8bitがnamespace、続く56bitがnode（ローカルノードID）、下位64bitがversion
+//!   - 各フィールドには10進数・16進数のどちらも使える（例: `1:0x2a:100`）
+use std::fmt;
+use std::str::FromStr;
+
+/// lump idの解析に失敗したことを表すエラー。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseLumpIdError {
+    input: String,
+    reason: String,
+}
+impl ParseLumpIdError {
+    fn new(input: &str, reason: &str) -> Self {
+        ParseLumpIdError {
+            input: input.to_owned(),
+            reason: reason.to_owned(),
+        }
+    }
+}
+impl fmt::Display for ParseLumpIdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid lump id `{}`: {}", self.input, self.reason)
+    }
+}
+impl std::error::Error for ParseLumpIdError {}
+
+/// frugalos形式のlump idを構成する各フィールドのビット幅（上位から順に）。
+const STRUCTURED_FIELD_BITS: [u32; 3] = [8, 56, 64];
+
+fn parse_number(input: &str, field: &str) -> Result<u128, ParseLumpIdError> {
+    let field = field.trim();
+    let (digits, radix) = if let Some(hex) = field
+        .strip_prefix("0x")
+        .or_else(|| field.strip_prefix("0X"))
+    {
+        (hex, 16)
+    } else {
+        (field, 10)
+    };
+    if digits.starts_with('_') || digits.ends_with('_') {
+        return Err(ParseLumpIdError::new(input, "misplaced `_` separator"));
+    }
+    let digits: String = digits.chars().filter(|&c| c != '_').collect();
+    if digits.is_empty() {
+        return Err(ParseLumpIdError::new(input, "missing digits"));
+    }
+    if let Some(c) = digits.chars().find(|c| !c.is_digit(radix)) {
+        let reason = if radix == 16 {
+            format!("`{}` is not a hexadecimal digit", c)
+        } else {
+            format!("`{}` is not a decimal digit (use `0x` for hexadecimal)", c)
+        };
+        return Err(ParseLumpIdError::new(input, &reason));
+    }
+    u128::from_str_radix(&digits, radix)
+        .map_err(|_| ParseLumpIdError::new(input, "does not fit in 128 bits"))
+}
+
+/// `input`をlump id（128bit整数）に変換する。
+pub fn parse_lump_id(input: &str) -> Result<u128, ParseLumpIdError> {
+    let input_trimmed = input.trim();
+    if !input_trimmed.contains(':') {
+        return parse_number(input, input_trimmed);
+    }
+
+    let fields = input_trimmed.split(':').collect::<Vec<_>>();
+    if fields.len() != STRUCTURED_FIELD_BITS.len() {
+        return Err(ParseLumpIdError::new(
+            input,
+            "structured ids must have the form `namespace:node:version`",
+        ));
+    }
+    let mut id = 0u128;
+    for (field, &bits) in fields.iter().zip(STRUCTURED_FIELD_BITS.iter()) {
+        let value = parse_number(input, field)?;
+        if value >> bits != 0 {
+            let reason = format!("field `{}` does not fit in {} bits", field.trim(), bits);
+            return Err(ParseLumpIdError::new(input, &reason));
+        }
+        id = (id << bits) | value;
+    }
+    Ok(id)
+}
+
+/// lump idの出力形式。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IdFormat {
+    /// 10進数（例: `42`）
+    #[default]
+    Dec,
+
+    /// 32桁の16進数（例: `0x0000000000000000000000000000002a`）
+    Hex,
+
+    /// 10進数と16進数の併記（例: `42 (0x0000000000000000000000000000002a)`）
+    Both,
+}
+impl IdFormat {
+    /// コマンドライン引数として受け付ける値の一覧。
+    pub const VARIANTS: [&'static str; 3] = ["dec", "hex", "both"];
+
+    /// `id`をこの形式で文字列にする。
+    pub fn format(self, id: u128) -> String {
+        match self {
+            IdFormat::Dec => id.to_string(),
+            IdFormat::Hex => format!("0x{:032x}", id),
+            IdFormat::Both => format!("{} (0x{:032x})", id, id),
+        }
+    }
+}
+impl FromStr for IdFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "dec" => Ok(IdFormat::Dec),
+            "hex" => Ok(IdFormat::Hex),
+            "both" => Ok(IdFormat::Both),
+            _ => Err(format!(
+                "invalid id format `{}` (expected one of dec, hex, both)",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_lump_id_works() {
+        assert_eq!(parse_lump_id("42"), Ok(42));
+        assert_eq!(parse_lump_id("1_000"), Ok(1000));
+        assert_eq!(parse_lump_id("0x2a"), Ok(42));
+        assert_eq!(parse_lump_id("0X2A"), Ok(42));
+        assert_eq!(parse_lump_id("0xdead_BEEF"), Ok(0xdead_beef));
+        assert_eq!(parse_lump_id(&u128::MAX.to_string()), Ok(u128::MAX));
+        assert_eq!(
+            parse_lump_id("1:0x2a:100"),
+            Ok((1 << 120) | (42 << 64) | 100)
+        );
+        assert_eq!(parse_lump_id("0:0:7"), Ok(7));
+    }
+
+    #[test]
+    fn parse_lump_id_rejects_invalid_inputs() {
+        assert!(parse_lump_id("").is_err());
+        assert!(parse_lump_id("0x").is_err());
+        assert!(parse_lump_id("2a").is_err());
+        assert!(parse_lump_id("-1").is_err());
+        assert!(parse_lump_id("_1").is_err());
+        assert!(parse_lump_id("340282366920938463463374607431768211456").is_err());
+        assert!(parse_lump_id("1:2").is_err());
+        assert!(parse_lump_id("256:0:0").is_err());
+    }
+
+    #[test]
+    fn id_format_works() {
+        assert_eq!(IdFormat::Dec.format(42), "42");
+        assert_eq!(
+            IdFormat::Hex.format(42),
+            "0x0000000000000000000000000000002a"
+        );
+        assert_eq!(
+            IdFormat::Both.format(42),
+            "42 (0x0000000000000000000000000000002a)"
+        );
+        assert_eq!("HEX".parse(), Ok(IdFormat::Hex));
+        assert!("oct".parse::<IdFormat>().is_err());
+    }
+}
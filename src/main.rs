@@ -3,269 +3,1461 @@
 #[allow(unused_imports)]
 #[macro_use]
 extern crate structopt;
-#[macro_use]
 extern crate clap;
 #[macro_use]
 extern crate trackable;
 
 extern crate cannyls;
 extern crate kanils;
-extern crate regex;
-extern crate rustyline;
 
-use kanils::bench;
-use kanils::handle::StorageHandle;
+use kanils::archive::{export_to_file, import_from_file};
+use kanils::batch::run_batch;
+use kanils::bench::{self, BenchStorage, BenchTarget, DeviceBenchOptions, Fill};
+use kanils::compare::{Comparison, Metric, SavedResult};
+use kanils::confirm;
+use kanils::device::{parse_deadline, print_device_metrics, DeviceClient, RequestOptions};
+use kanils::duration::parse_duration;
+use kanils::fault::{FaultInjector, FaultNvm, FaultPlan};
+use kanils::handle::{storage_layout, LumpKind, StorageHandle};
+use kanils::header::{self, parse_uuid, read_raw_header, LusfHeader};
+use kanils::lock::{LockMode, StorageLock};
+use kanils::lumpid::{parse_lump_id, IdFormat};
+use kanils::memory::{create_in_memory, load_into_memory};
+use kanils::metrics;
+use kanils::readonly::ReadOnlyNvm;
+use kanils::repl::{run_repl, Session};
+use kanils::report::{BenchResult, ResultsFormat};
+use kanils::scrub::scrub;
+use kanils::server;
+use kanils::size::{format_bytes_with_size, parse_size};
+use kanils::torture::{self, CrashMode, TortureOptions};
+use kanils::watch::{run_watch, WatchOptions};
+use kanils::workload::{parse_size_distribution, parse_workload, SizeDistribution, WorkloadSpec};
 
-use cannyls::block::BlockSize;
-use cannyls::nvm::FileNvm;
+use cannyls::deadline::Deadline;
+use cannyls::lump::{LumpData, LumpId};
+use cannyls::nvm::{FileNvm, FileNvmBuilder};
 use cannyls::storage::StorageBuilder;
 
-use regex::Regex;
-
-use rustyline::error::ReadlineError;
-use rustyline::Editor;
+use clap::{AppSettings, Shell};
 
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::net::{SocketAddr, TcpListener};
 use std::path::PathBuf;
-use std::str;
+use std::time::Duration;
 
 use structopt::StructOpt;
+use uuid::Uuid;
+
+/// 対象とするlusfストレージの指定
+#[derive(StructOpt, Debug)]
+struct StorageOpt {
+    /// lusfファイルのパス
+    #[structopt(long = "storage", parse(from_os_str))]
+    storage_path: PathBuf,
+
+    /// lusfファイルの勧告ロック（`flock`）を取得しない
+    ///
+    /// 通常は、変更を伴うコマンドでは排他ロックを、`--read-only`の場合は共有ロックを取得し、
+    /// 他のプロセスが両立しないロックを保持していればエラーになる。
+    #[structopt(long = "no-lock")]
+    no_lock: bool,
+}
+
+/// lump idを出力するコマンドに共通のオプション
+#[derive(StructOpt, Debug)]
+struct OutputOpt {
+    /// 出力するlump idの形式
+    #[structopt(
+        long = "id-format",
+        default_value = "dec",
+        raw(possible_values = "&IdFormat::VARIANTS", case_insensitive = "true")
+    )]
+    id_format: IdFormat,
+}
+
+/// 参照系のコマンドに共通のオプション
+#[derive(StructOpt, Debug)]
+struct AccessOpt {
+    /// lusfファイルを読み込み専用で開く（ファイルには一切書き込まず、変更系のコマンドは拒否する）
+    #[structopt(long = "read-only")]
+    read_only: bool,
+}
+
+/// 破壊的なコマンドに共通のオプション
+#[derive(StructOpt, Debug)]
+struct ConfirmOpt {
+    /// 確認を求めずに実行する（標準入力が端末でない場合は指定しなくても確認しない）
+    #[structopt(long = "yes", short = "y")]
+    yes: bool,
+}
+
+impl ConfirmOpt {
+    // 確認を求めるかどうか
+    fn prompts(&self) -> bool {
+        !self.yes && confirm::is_interactive()
+    }
 
-arg_enum! {
-    #[derive(Debug)]
-    enum Command {
-        // capacityバイトの容量を持つlusfストレージを新たに生成する
-        // kanils Create --storage=storage_path --capacity=num
-        // (storage_pathが既に存在する場合には何もしない)
-        Create,
-
-        // lusfストレージ中のデータをダンプする
-        // kanils Dump --storage=storage_path
-        Dump,
-
-        // lusfストレージ中に存在するlumpid一覧を出力する
-        // kanils List --storage=storage_path
-        List,
-
-        // lusfストレージに、keyをkey, valueをstringとしてkey-value組を追加する
-        // 既にkeyが存在する場合は上書きする挙動に注意
-        // kanils Put --storage=storage_path --key=lumpid --data=string
-        Put,
-
-        // lusfストレージに、keyをkey, valueをstringとしてkey-value組を「埋め込み」で追加する
-        Embed,
-
-        // lusfストレージの指定したkeyを持つ値を「文字列として」取得する
-        // 存在しないkeyが指定された場合はその旨が出力される
-        // kanils Get --storage=storage_path --key=lumpid
-        Get,
-
-        // lusfストレージの指定したkeyを持つ値を「バイト列として」取得する
-        // 存在しないkeyが指定された場合はその旨が出力される
-        // kanils Get --storage=storage_path --key=lumpid
-        GetBytes,
-
-        // lusfストレージの指定したkeyを削除する
-        // 存在しないkeyが指定された場合はその旨が出力される
-        // kanils Delete --storage=storage_path --key=lumpid
-        Delete,
-
-        // 渡された2つのkey start, endによる区間[start, end)に含まれる
-        // keyを（すなわち start <= key < endとなるkey）を全て削除する。
-        // 結果は削除に成功したkeyの配列となる。
-        // kanils RangeDelete --storage=storage_path --start=lumpid --end=lumpid
-        RangeDelete,
-
-        // lusfストレージ中のヘッダ情報を出力する
-        // ヘッダ情報についての詳細は https://github.com/frugalos/cannyls/wiki/Storage-Format を参照
-        // kanils Header --storage=storage_path
-        Header,
-
-        // lusfストレージ中のジャーナル領域の内容を出力する
-        // kanils Journal --storage=storage_path
-        Journal,
-
-        // lusfストレージ中のジャーナル領域に対してfull GCを行う
-        // kanils JournalGC --storage=storage_path
-        JournalGC,
-
-        // 存在するlusfストレージを開き
-        // 対話的に Dump, List, Put, Get, Delete, Header の操作を試すことができる
-        // kanils Open --storage=storage_path
-        Open,
-
-        // 新たにlusfストレージを作成し、1件sizeバイト長データを、count個書き込む
-        // 書き込みのみを行う簡易ベンチマークツール
-        // kanils WBench --stoage=storage_path --count=number --size=number
-        WBench,
-
-        // 新たにlusfストレージを作成し、1件sizeバイト長データを、cout個書き込みつつ
-        // 読み込みも行うような、書き込み読み込み混合の簡易ベンチマークツール
-        // kanils WRBench --storage=storage_path --count=number --size=number
-        WRBench,
-
-        RandomGetBench,
-
-        // 与えられた16bit数 versionを用いて、lusfファイルのmajor versionを強制的に書き換える。
-        // 出力は `書き換え前のversion => 書き換え後のversion` となる。
-        // kanils ChangeMajorVersionTo --storage=storage_path --version=u16
-        ChangeMajorVersionTo,
-
-        // 与えられた16bit数 versionを用いて、lusfファイルのminor versionを強制的に書き換える。
-        // 出力は `書き換え前のversion => 書き換え後のversion` となる。
-        // kanils ChangeMinorVersionTo --storage=storage_path --version=u16
-        ChangeMinorVersionTo,
+    // 必要であれば確認を求め、拒否された場合はコマンドを中止する
+    fn confirm(&self, prompt: &str) {
+        if !self.prompts() {
+            return;
+        }
+        let stdin = io::stdin();
+        let accepted =
+            track_try_unwrap!(confirm::ask(&mut stdin.lock(), &mut io::stderr(), prompt)
+                .map_err(cannyls::Error::from));
+        if !accepted {
+            eprintln!("aborted");
+            std::process::exit(1);
+        }
     }
 }
 
+/// lumpを削除するコマンドに共通のオプション
 #[derive(StructOpt, Debug)]
-#[structopt(name = "KaNiLS")]
-struct Opt {
-    #[structopt(long = "storage", parse(from_os_str))]
-    storage_path: PathBuf,
+struct BackupOpt {
+    /// 削除する前に、削除されるlumpをこのアーカイブファイルに保存する（`Import`で書き戻せる。既存のファイルは上書きしない）
+    #[structopt(long = "backup", parse(from_os_str))]
+    backup: Option<PathBuf>,
+}
+
+// `--fill`の値を解析する
+fn parse_fill_ratio(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(ratio) if (0.0..1.0).contains(&ratio) => Ok(ratio),
+        Ok(_) => Err(format!("fill ratio `{}` must be in the range [0, 1)", s)),
+        Err(e) => Err(format!("invalid fill ratio `{}`: {}", s, e)),
+    }
+}
 
-    #[structopt(long = "capacity")]
-    capacity: Option<u64>,
+// `BenchCompare`の`--threshold`（パーセント。末尾の`%`は省略可）を解析する
+fn parse_threshold(s: &str) -> Result<f64, String> {
+    match s.trim_end_matches('%').parse::<f64>() {
+        Ok(threshold) if threshold >= 0.0 => Ok(threshold),
+        Ok(_) => Err(format!("threshold `{}` must not be negative", s)),
+        Err(e) => Err(format!("invalid threshold `{}`: {}", s, e)),
+    }
+}
 
-    #[structopt(long = "key")]
-    lumpid: Option<String>,
+// `--results`のパスを検証する（ベンチマークを実行してから形式の誤りに気付くことがないように）
+fn parse_results_path(s: &OsStr) -> Result<PathBuf, OsString> {
+    let path = PathBuf::from(s);
+    if ResultsFormat::from_path(&path).is_some() {
+        Ok(path)
+    } else {
+        Err(OsString::from(format!(
+            "unknown results file format {:?} (the extension must be `.csv` or `.json`)",
+            path
+        )))
+    }
+}
+
+// `--fault-plan`のファイルを読み込む（`Command`が大きくなり過ぎないように、`Box`に入れて返す）
+fn parse_fault_plan(s: &OsStr) -> Result<Box<FaultPlan>, OsString> {
+    FaultPlan::load(s)
+        .map(Box::new)
+        .map_err(|e| OsString::from(e.to_string()))
+}
 
-    #[structopt(long = "start")]
-    lumpid_start: Option<String>,
+/// cannylsの`Device`経由で操作するためのオプション
+#[derive(StructOpt, Debug)]
+struct DeviceOpt {
+    /// ストレージを直接操作する代わりに、frugalosと同様にcannylsの`Device`（リクエストキュー）経由で操作する
+    #[structopt(long = "device")]
+    device: bool,
 
-    #[structopt(long = "end")]
-    lumpid_end: Option<String>,
+    /// 各リクエストのデッドライン（`immediate`, `infinity`, または`100ms`のような時間。デフォルトは`infinity`）
+    #[structopt(
+        long = "deadline",
+        parse(try_from_str = "parse_deadline"),
+        requires = "device"
+    )]
+    deadline: Option<Deadline>,
 
-    #[structopt(long = "value")]
-    data: Option<String>,
+    /// リクエストを優先的に処理させる（過負荷時にも破棄されない）
+    #[structopt(long = "prioritized", requires = "device")]
+    prioritized: bool,
 
+    /// リクエストの発行時にデバイスのキュー長がこの値を超えていれば、リクエストを拒否させる
+    #[structopt(long = "max-queue-len", requires = "device")]
+    max_queue_len: Option<usize>,
+
+    /// 各リクエストの処理後にジャーナルを同期させる
+    #[structopt(long = "journal-sync", requires = "device")]
+    journal_sync: bool,
+
+    /// 終了時にデバイスのメトリクスを出力する
+    #[structopt(long = "device-metrics", requires = "device")]
+    device_metrics: bool,
+}
+
+impl DeviceOpt {
+    fn request_options(&self) -> RequestOptions {
+        RequestOptions {
+            deadline: self.deadline.unwrap_or_default(),
+            prioritized: self.prioritized,
+            max_queue_len: self.max_queue_len,
+            journal_sync: self.journal_sync,
+        }
+    }
+}
+
+/// 簡易ベンチマークコマンドのオプション
+#[derive(StructOpt, Debug)]
+struct BenchOpt {
+    /// 書き込むlumpの件数
     #[structopt(long = "count")]
-    count: Option<u64>,
+    count: u64,
+
+    /// 1件あたりのデータサイズ（例: `4096`, `3MiB`）
+    #[structopt(long = "size", parse(try_from_str = "parse_size"))]
+    size: u64,
+
+    #[structopt(flatten)]
+    run: BenchRunOpt,
+}
+
+/// ベンチマークコマンドに共通の、実行方法と結果の出力に関するオプション
+#[derive(StructOpt, Debug)]
+struct BenchRunOpt {
+    /// 新たに作成するlusfファイルのパス（既に存在する場合はエラー）
+    ///
+    /// `Bench`コマンドでは複数回指定でき、その場合は各ストレージを並行に操作する。
+    #[structopt(
+        long = "storage",
+        parse(from_os_str),
+        number_of_values = 1,
+        raw(required_unless_one = r#"&["memory", "existing"]"#)
+    )]
+    storage_paths: Vec<PathBuf>,
+
+    /// ファイルの代わりにメモリ上のストレージを用いる
+    #[structopt(long = "memory", conflicts_with = "storage_paths")]
+    memory: bool,
+
+    /// 既存のlusfファイルに対してベンチマークを行う
+    ///
+    /// `--copy-to`を指定しなければ読み込み専用で開き、ファイルには一切書き込まない
+    /// （この場合は、読み込みのみのワークロードしか実行できない）。
+    #[structopt(
+        long = "existing",
+        parse(from_os_str),
+        raw(conflicts_with_all = r#"&["storage_paths", "memory"]"#)
+    )]
+    existing: Option<PathBuf>,
+
+    /// `--existing`のファイルをこのパスに複製し、複製に対してベンチマークを行う（既に存在する場合はエラー）
+    #[structopt(long = "copy-to", parse(from_os_str), requires = "existing")]
+    copy_to: Option<PathBuf>,
+
+    /// `--existing`のファイルの勧告ロック（`flock`）を取得しない
+    #[structopt(long = "no-lock", requires = "existing")]
+    no_lock: bool,
+
+    /// ベンチマークの前に、データ領域の使用率がこの割合（0以上1未満。例: `0.9`）に達するまでlumpを書き込んでおく
+    #[structopt(long = "fill", parse(try_from_str = "parse_fill_ratio"))]
+    fill: Option<f64>,
+
+    /// `--fill`で書き込むlumpのサイズ（例: `64KiB`, `4KiB..1MiB`, `4KiB@3|1MiB@1`。デフォルトは`64KiB`）
+    #[structopt(
+        long = "fill-size",
+        parse(try_from_str = "parse_size_distribution"),
+        requires = "fill"
+    )]
+    fill_size: Option<SizeDistribution>,
+
+    /// ベンチマークの後に、作成したlusfファイル（`--storage`または`--copy-to`）を削除する
+    #[structopt(long = "remove-after", conflicts_with = "memory")]
+    remove_after: bool,
+
+    #[structopt(flatten)]
+    device: DeviceOpt,
+
+    /// `--device`の場合に、デバイスごとに同時にリクエストを発行するスレッドの数（デフォルトは1）
+    #[structopt(long = "concurrency", requires = "device")]
+    concurrency: Option<usize>,
+
+    /// 全体の結果に加えて、スレッドごとの結果も出力する
+    #[structopt(long = "per-thread")]
+    per_thread: bool,
+
+    /// 結果（操作ごとのレイテンシのパーセンタイルとスループット）を書き出すファイル（拡張子は`.csv`か`.json`）
+    #[structopt(long = "results", parse(try_from_os_str = "parse_results_path"))]
+    results: Option<PathBuf>,
+
+    /// ベンチマークの後に、ストレージのメトリクスをPrometheusのテキスト形式で書き出すファイル
+    ///
+    /// 複数のストレージを用いた場合は、同じ名前とラベルのメトリクスを合算する。
+    #[structopt(long = "prometheus", parse(from_os_str))]
+    prometheus: Option<PathBuf>,
+}
+
+impl BenchRunOpt {
+    // ベンチマークに用いるストレージの指定を返す（`writes`はベンチマークがストレージを変更するかどうか）
+    fn storage(&self, writes: bool) -> BenchStorage {
+        if self.storage_paths.len() > 1 {
+            clap::Error::with_description(
+                "multiple `--storage` options are only supported by the `Bench` command",
+                clap::ErrorKind::ArgumentConflict,
+            )
+            .exit();
+        }
+        let target = match (&self.existing, &self.copy_to, self.storage_paths.first()) {
+            (Some(original), Some(copy), _) => BenchTarget::Copy {
+                original: original.clone(),
+                copy: copy.clone(),
+                lock: !self.no_lock,
+            },
+            (Some(path), None, _) => {
+                let conflict = if writes {
+                    Some("this benchmark writes to the storage")
+                } else if self.fill.is_some() {
+                    Some("`--fill` writes to the storage")
+                } else if self.remove_after {
+                    Some("`--remove-after` would remove the original file")
+                } else {
+                    None
+                };
+                if let Some(reason) = conflict {
+                    let message = format!(
+                        "{}, but `--existing` without `--copy-to` opens the file read-only \
+                         (use `--copy-to` to run on a copy)",
+                        reason
+                    );
+                    clap::Error::with_description(&message, clap::ErrorKind::ArgumentConflict)
+                        .exit();
+                }
+                BenchTarget::Existing {
+                    path: path.clone(),
+                    lock: !self.no_lock,
+                }
+            }
+            (None, _, Some(path)) if !self.memory => BenchTarget::File(path.clone()),
+            _ => BenchTarget::Memory,
+        };
+        self.with_fill(BenchStorage::new(target))
+    }
+
+    fn with_fill(&self, mut storage: BenchStorage) -> BenchStorage {
+        storage.fill = self.fill.map(|ratio| Fill {
+            ratio,
+            size: self
+                .fill_size
+                .clone()
+                .unwrap_or(SizeDistribution::Fixed(64 * 1024)),
+        });
+        storage.remove_after = self.remove_after;
+        storage
+    }
 
-    #[structopt(long = "size")]
-    size: Option<String>,
+    // `storage`と同様だが、複数の`--storage`（または`memory_storages`個のメモリ上のストレージ）を扱う
+    fn storages(&self, writes: bool, memory_storages: usize) -> Vec<BenchStorage> {
+        if self.storage_paths.len() > 1 {
+            self.storage_paths
+                .iter()
+                .map(|path| self.with_fill(BenchStorage::new(BenchTarget::File(path.clone()))))
+                .collect()
+        } else if self.memory {
+            (0..memory_storages.max(1))
+                .map(|_| self.with_fill(BenchStorage::new(BenchTarget::Memory)))
+                .collect()
+        } else {
+            vec![self.storage(writes)]
+        }
+    }
+
+    fn write_results(&self, result: &BenchResult) {
+        if let Some(ref path) = self.results {
+            track_try_unwrap!(result.write_to_file(path));
+            println!("wrote the results to {:?}", path);
+        }
+        // `result`がストレージのメトリクスを保持しているので、ストレージを閉じた後でも収集できる
+        if let Some(ref path) = self.prometheus {
+            track_try_unwrap!(metrics::write_prometheus_file(path));
+            println!("wrote the metrics to {:?}", path);
+        }
+    }
+
+    fn device_options(&self) -> Option<DeviceBenchOptions> {
+        if self.device.device {
+            Some(DeviceBenchOptions {
+                request: self.device.request_options(),
+                concurrency: self.concurrency.unwrap_or(1),
+                per_thread: self.per_thread,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// lusfファイルのバージョンを書き換えるコマンドのオプション
+#[derive(StructOpt, Debug)]
+struct VersionOpt {
+    #[structopt(flatten)]
+    storage: StorageOpt,
+    #[structopt(flatten)]
+    confirm: ConfirmOpt,
 
+    /// 書き換え後のバージョン番号（16bit）
     #[structopt(long = "version")]
-    version: Option<u16>,
-
-    #[structopt(raw(
-        possible_values = "&Command::variants()",
-        requires_ifs = r#"&[
-("Create", "capacity"),
-("Put", "lumpid"),("Put", "data"),
-("Embed", "lumpid"), ("Embed", "data"),
-("Get", "lumpid"),("GetBytes", "lumpid"),
-("Delete", "lumpid"),
-("RangeDelete", "lumpid_start"), ("RangeDelete", "lumpid_end"),
-("WBench", "count"),("WBench", "size"),
-("WRBench", "count"),("WRBench", "size"),
-("ChangeMajorVersionTo", "version"),
-("ChangeMinorVersionTo", "version")
-]"#
-    ))]
+    version: u16,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// capacityバイトのデータ領域を持つlusfストレージを新たに生成する
+    #[structopt(
+        name = "Create",
+        raw(alias = r#""create""#),
+        after_help = "EXAMPLES:\n    kanils Create --storage demo.lusf --capacity 64MiB"
+    )]
+    Create {
+        #[structopt(flatten)]
+        storage: StorageOpt,
+
+        /// データ領域の容量（例: `2048`, `64MiB`, `1.5GB`）
+        #[structopt(long = "capacity", parse(try_from_str = "parse_size"))]
+        capacity: u64,
+    },
+
+    /// lusfストレージ中の全lumpを（文字列として）出力する
+    #[structopt(name = "Dump", raw(alias = r#""dump""#))]
+    Dump {
+        #[structopt(flatten)]
+        storage: StorageOpt,
+        #[structopt(flatten)]
+        output: OutputOpt,
+        #[structopt(flatten)]
+        access: AccessOpt,
+    },
+
+    /// lusfストレージ中に存在するlump idの一覧を出力する
+    #[structopt(name = "List", raw(alias = r#""list""#))]
+    List {
+        #[structopt(flatten)]
+        storage: StorageOpt,
+        #[structopt(flatten)]
+        device: DeviceOpt,
+        #[structopt(flatten)]
+        output: OutputOpt,
+        #[structopt(flatten)]
+        access: AccessOpt,
+    },
+
+    /// key-value組を追加する（既にkeyが存在する場合は上書きする）
+    #[structopt(
+        name = "Put",
+        raw(alias = r#""put""#),
+        after_help = "EXAMPLES:\n    kanils Put --storage demo.lusf --key 42 --value test_string\n    \
+                      kanils Put --storage demo.lusf --key 0x2a --value test_string"
+    )]
+    Put {
+        #[structopt(flatten)]
+        storage: StorageOpt,
+        #[structopt(flatten)]
+        device: DeviceOpt,
+        #[structopt(flatten)]
+        output: OutputOpt,
+
+        /// lump id（10進数、`0x`付き16進数、または`namespace:node:version`）
+        #[structopt(long = "key", parse(try_from_str = "parse_lump_id"))]
+        key: u128,
+
+        /// 書き込む値（文字列）
+        #[structopt(long = "value")]
+        value: String,
+    },
+
+    /// key-value組を「埋め込み」lumpとして追加する（値は最大65535バイト）
+    #[structopt(
+        name = "Embed",
+        raw(alias = r#""embed""#),
+        after_help = "EXAMPLES:\n    kanils Embed --storage demo.lusf --key 7 --value small_string\n    \
+                      kanils Embed --storage demo.lusf --key 8 --value-file icon.png"
+    )]
+    Embed {
+        #[structopt(flatten)]
+        storage: StorageOpt,
+        #[structopt(flatten)]
+        output: OutputOpt,
+
+        /// lump id（10進数、`0x`付き16進数、または`namespace:node:version`）
+        #[structopt(long = "key", parse(try_from_str = "parse_lump_id"))]
+        key: u128,
+
+        /// 書き込む値（文字列）
+        #[structopt(long = "value", required_unless = "value_file")]
+        value: Option<String>,
+
+        /// 書き込む値を読み込むファイル（任意のバイト列、`-`の場合は標準入力）
+        #[structopt(long = "value-file", parse(from_os_str), conflicts_with = "value")]
+        value_file: Option<PathBuf>,
+    },
+
+    /// lumpを埋め込みlumpとデータ領域のlumpの間で移す
+    #[structopt(
+        name = "Convert",
+        raw(alias = r#""convert""#),
+        after_help = "EXAMPLES:\n    kanils Convert --storage demo.lusf --key 42 --to embedded\n    \
+                      kanils Convert --storage demo.lusf --key 42 --to block"
+    )]
+    Convert {
+        #[structopt(flatten)]
+        storage: StorageOpt,
+        #[structopt(flatten)]
+        output: OutputOpt,
+
+        /// lump id（10進数、`0x`付き16進数、または`namespace:node:version`）
+        #[structopt(long = "key", parse(try_from_str = "parse_lump_id"))]
+        key: u128,
+
+        /// 移し先（embedded, block）
+        #[structopt(
+            long = "to",
+            raw(possible_values = "&LumpKind::VARIANTS", case_insensitive = "true")
+        )]
+        to: LumpKind,
+    },
+
+    /// 指定したkeyを持つ値を「文字列として」取得する
+    #[structopt(
+        name = "Get",
+        raw(alias = r#""get""#),
+        after_help = "EXAMPLES:\n    kanils Get --storage demo.lusf --key 42"
+    )]
+    Get {
+        #[structopt(flatten)]
+        storage: StorageOpt,
+        #[structopt(flatten)]
+        device: DeviceOpt,
+        #[structopt(flatten)]
+        output: OutputOpt,
+        #[structopt(flatten)]
+        access: AccessOpt,
+
+        /// lump id（10進数、`0x`付き16進数、または`namespace:node:version`）
+        #[structopt(long = "key", parse(try_from_str = "parse_lump_id"))]
+        key: u128,
+    },
+
+    /// 指定したkeyを持つ値を「バイト列として」取得する
+    #[structopt(name = "GetBytes", raw(alias = r#""get-bytes""#))]
+    GetBytes {
+        #[structopt(flatten)]
+        storage: StorageOpt,
+        #[structopt(flatten)]
+        device: DeviceOpt,
+        #[structopt(flatten)]
+        output: OutputOpt,
+        #[structopt(flatten)]
+        access: AccessOpt,
+
+        /// lump id（10進数、`0x`付き16進数、または`namespace:node:version`）
+        #[structopt(long = "key", parse(try_from_str = "parse_lump_id"))]
+        key: u128,
+    },
+
+    /// 指定したkeyを削除する
+    ///
+    /// 端末から実行した場合は、削除する前に確認を求める（`--yes`で省略できる）。
+    #[structopt(
+        name = "Delete",
+        raw(alias = r#""delete""#),
+        after_help = "EXAMPLES:\n    kanils Delete --storage demo.lusf --key 42\n    \
+                      kanils Delete --storage demo.lusf --key 42 --yes --backup deleted.knls"
+    )]
+    Delete {
+        #[structopt(flatten)]
+        storage: StorageOpt,
+        #[structopt(flatten)]
+        device: DeviceOpt,
+        #[structopt(flatten)]
+        confirm: ConfirmOpt,
+        #[structopt(flatten)]
+        backup: BackupOpt,
+
+        /// lump id（10進数、`0x`付き16進数、または`namespace:node:version`）
+        #[structopt(long = "key", parse(try_from_str = "parse_lump_id"))]
+        key: u128,
+    },
+
+    /// 区間[start, end)に含まれるkeyを全て削除し、削除に成功したkeyを出力する
+    ///
+    /// 端末から実行した場合は、削除する前に削除されるkeyを示して確認を求める（`--yes`で省略できる）。
+    #[structopt(
+        name = "RangeDelete",
+        raw(alias = r#""range-delete""#),
+        after_help = "EXAMPLES:\n    kanils RangeDelete --storage demo.lusf --start 0 --end 100 --dry-run\n    \
+                      kanils RangeDelete --storage demo.lusf --start 0 --end 100\n    \
+                      kanils RangeDelete --storage demo.lusf --start 0 --end 100 --yes --backup deleted.knls"
+    )]
+    RangeDelete {
+        #[structopt(flatten)]
+        storage: StorageOpt,
+        #[structopt(flatten)]
+        device: DeviceOpt,
+        #[structopt(flatten)]
+        output: OutputOpt,
+        #[structopt(flatten)]
+        confirm: ConfirmOpt,
+        #[structopt(flatten)]
+        backup: BackupOpt,
+
+        /// 区間の開始lump id（この値を含む）
+        #[structopt(long = "start", parse(try_from_str = "parse_lump_id"))]
+        start: u128,
+
+        /// 区間の終了lump id（この値を含まない）
+        #[structopt(long = "end", parse(try_from_str = "parse_lump_id"))]
+        end: u128,
+
+        /// 削除せずに、削除されるlump idの一覧を出力する（ストレージは読み込み専用で開く）
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+    },
+
+    /// 区間[start, end)に含まれるlumpを出力する、またはアーカイブファイルに書き出す
+    #[structopt(
+        name = "GetRange",
+        raw(alias = r#""get-range""#),
+        after_help = "EXAMPLES:\n    kanils GetRange --storage demo.lusf --start 0 --end 100\n    \
+                      kanils GetRange --storage demo.lusf --start 0 --end 100 --export lumps.knls"
+    )]
+    GetRange {
+        #[structopt(flatten)]
+        storage: StorageOpt,
+        #[structopt(flatten)]
+        output: OutputOpt,
+        #[structopt(flatten)]
+        access: AccessOpt,
+
+        /// 区間の開始lump id（この値を含む）
+        #[structopt(long = "start", parse(try_from_str = "parse_lump_id"))]
+        start: u128,
+
+        /// 区間の終了lump id（この値を含まない）
+        #[structopt(long = "end", parse(try_from_str = "parse_lump_id"))]
+        end: u128,
+
+        /// 出力する代わりに、lumpの値と格納場所をこのアーカイブファイルに書き出す（既存のファイルは上書きしない）
+        #[structopt(long = "export", parse(from_os_str))]
+        export: Option<PathBuf>,
+    },
+
+    /// 区間[start, end)に含まれるlumpの数とバイト数を出力する
+    #[structopt(
+        name = "CountRange",
+        raw(alias = r#""count-range""#),
+        after_help = "EXAMPLES:\n    kanils CountRange --storage demo.lusf --start 0 --end 100\n    \
+                      kanils CountRange --storage demo.lusf --start 0 --end 100 --exact"
+    )]
+    CountRange {
+        #[structopt(flatten)]
+        storage: StorageOpt,
+        #[structopt(flatten)]
+        output: OutputOpt,
+        #[structopt(flatten)]
+        access: AccessOpt,
+
+        /// 区間の開始lump id（この値を含む）
+        #[structopt(long = "start", parse(try_from_str = "parse_lump_id"))]
+        start: u128,
+
+        /// 区間の終了lump id（この値を含まない）
+        #[structopt(long = "end", parse(try_from_str = "parse_lump_id"))]
+        end: u128,
+
+        /// 各lumpを読み込んで正確なバイト数を求める（指定しない場合はブロック単位の近似値）
+        #[structopt(long = "exact")]
+        exact: bool,
+    },
+
+    /// lusfストレージのヘッダ情報を出力する
+    ///
+    /// ヘッダ情報についての詳細は https://github.com/frugalos/cannyls/wiki/Storage-Format を参照
+    #[structopt(name = "Header", raw(alias = r#""header""#))]
+    Header {
+        #[structopt(flatten)]
+        storage: StorageOpt,
+        #[structopt(flatten)]
+        access: AccessOpt,
+
+        /// ヘッダのバイト列をフィールドごとに16進数で出力する（ストレージとしては開かないので、cannylsが開けないファイルにも使える）
+        #[structopt(long = "raw")]
+        raw: bool,
+    },
+
+    /// lusfファイルのヘッダのフィールドを書き換える
+    ///
+    /// ディスクを複製した場合などに、インスタンスのUUIDを再生成するために使う。
+    /// 端末から実行した場合は、書き換える前に確認を求める（`--yes`で省略できる）。
+    #[structopt(
+        name = "HeaderSet",
+        raw(alias = r#""header-set""#),
+        after_help = "EXAMPLES:\n    kanils HeaderSet --storage cloned.lusf --uuid random\n    \
+                      kanils HeaderSet --storage cloned.lusf --uuid 936da01f-9abd-4d9d-80c7-02af85c822a8"
+    )]
+    HeaderSet {
+        #[structopt(flatten)]
+        storage: StorageOpt,
+        #[structopt(flatten)]
+        confirm: ConfirmOpt,
+
+        /// 新しいインスタンスのUUID（`random`の場合はランダムに生成する）
+        #[structopt(long = "uuid", parse(try_from_str = "parse_uuid"))]
+        uuid: Uuid,
+    },
+
+    /// lumpの値を取り除いた、構造が同一のlusfファイルを作成する（不具合の報告用）
+    ///
+    /// ヘッダ、ジャーナルのレコードの配置、lumpのid・サイズ・埋め込みかどうか・データ領域内の配置は元のファイルと一致し、
+    /// lumpの値はlump idから決まるパターンで置き換えられる。元のファイルには一切書き込まない。
+    #[structopt(
+        name = "Scrub",
+        raw(alias = r#""scrub""#),
+        after_help = "EXAMPLES:\n    kanils Scrub --storage production.lusf --into shareable.lusf"
+    )]
+    Scrub {
+        #[structopt(flatten)]
+        storage: StorageOpt,
+
+        /// 新たに作成するlusfファイルのパス（既に存在する場合はエラー）
+        #[structopt(long = "into", parse(from_os_str))]
+        into: PathBuf,
+    },
+
+    /// lusfストレージを開き、cannylsが記録するメトリクス（カウンタとゲージ）を出力する
+    ///
+    /// カウンタは、ストレージを開いてから（ジャーナルの再生を含む）の値になる。
+    #[structopt(
+        name = "Metrics",
+        raw(alias = r#""metrics""#),
+        after_help = "EXAMPLES:\n    kanils Metrics --storage demo.lusf\n    \
+                      kanils Metrics --storage demo.lusf --read-only --prometheus > demo.prom"
+    )]
+    Metrics {
+        #[structopt(flatten)]
+        storage: StorageOpt,
+        #[structopt(flatten)]
+        access: AccessOpt,
+
+        /// Prometheusのテキスト形式（exposition format）で出力する
+        #[structopt(long = "prometheus")]
+        prometheus: bool,
+    },
+
+    /// lusfストレージのジャーナル領域の内容を出力する
+    #[structopt(name = "Journal", raw(alias = r#""journal""#))]
+    Journal {
+        #[structopt(flatten)]
+        storage: StorageOpt,
+        #[structopt(flatten)]
+        output: OutputOpt,
+        #[structopt(flatten)]
+        access: AccessOpt,
+    },
+
+    /// lusfストレージのジャーナル領域を定期的に読み直し、追記されたレコードやhead・tailの移動を出力し続ける
+    ///
+    /// 毎回ストレージを開かずにジャーナル領域だけを読み込み、ファイルには一切書き込まない。
+    /// デフォルトではロックを取得しないので、他のプロセスが使用中のファイルも監視できる。
+    /// 読み込みに失敗した場合（書き込み中のレコードを読んだ場合など）は、次の回に再試行する。
+    #[structopt(
+        name = "Watch",
+        raw(alias = r#""watch""#),
+        after_help = "EXAMPLES:\n    kanils Watch --storage demo.lusf\n    \
+                      kanils Watch --storage live.lusf --interval 500ms --lines 0"
+    )]
+    Watch {
+        /// lusfファイルのパス
+        #[structopt(long = "storage", parse(from_os_str))]
+        storage_path: PathBuf,
+
+        /// 読み込む間だけ共有ロックを取得する（他のプロセスが排他ロックを保持している間は、読み込みを再試行し続ける）
+        #[structopt(long = "lock")]
+        lock: bool,
+
+        /// ロックを取得しない（デフォルトの動作。以前のバージョンとの互換性のために受け付ける）
+        #[structopt(long = "no-lock", conflicts_with = "lock", raw(hidden = "true"))]
+        _no_lock: bool,
+
+        #[structopt(flatten)]
+        output: OutputOpt,
+
+        /// ジャーナルを読み直す間隔
+        #[structopt(
+            long = "interval",
+            default_value = "1s",
+            parse(try_from_str = "parse_duration")
+        )]
+        interval: Duration,
+
+        /// 開始時に出力する既存のエントリの件数（末尾から数える）
+        #[structopt(long = "lines", default_value = "10")]
+        lines: usize,
+
+        /// 指定した回数だけ読み直して終了する（デフォルトは中断されるまで続ける）
+        #[structopt(long = "polls")]
+        polls: Option<u64>,
+    },
+
+    /// lusfストレージのジャーナル領域に対してfull GCを行う
+    ///
+    /// 端末から実行した場合は、実行する前に確認を求める（`--yes`で省略できる）。
+    #[structopt(name = "JournalGC", raw(alias = r#""journal-gc""#))]
+    JournalGC {
+        #[structopt(flatten)]
+        storage: StorageOpt,
+        #[structopt(flatten)]
+        confirm: ConfirmOpt,
+    },
+
+    /// アーカイブファイル（`GetRange --export`や`--backup`で作成したもの）のlumpをストレージに書き戻す
+    ///
+    /// lumpは保存時と同じ格納場所（embedded/block）に書き込む。同じkeyのlumpが既に存在する場合は上書きする。
+    #[structopt(
+        name = "Import",
+        raw(alias = r#""import""#),
+        after_help = "EXAMPLES:\n    kanils Import --storage demo.lusf --from deleted.knls"
+    )]
+    Import {
+        #[structopt(flatten)]
+        storage: StorageOpt,
+        #[structopt(flatten)]
+        output: OutputOpt,
+
+        /// 読み込むアーカイブファイル
+        #[structopt(long = "from", parse(from_os_str))]
+        from: PathBuf,
+    },
+
+    /// 存在するlusfストレージを開き、対話的に操作する
+    ///
+    /// 使えるコマンドは対話モードで`help`と入力すると確認できる。
+    /// Tabキーでコマンド名やlump idを補完できる。
+    /// `--memory`を指定すると、ディスクに触れずにメモリ上のストレージを操作できる。
+    #[structopt(
+        name = "Open",
+        raw(alias = r#""open""#),
+        after_help = "EXAMPLES:\n    kanils Open --storage demo.lusf\n    \
+                      kanils Open --memory --capacity 64MiB\n    \
+                      kanils Open --memory --storage demo.lusf --save-to experiment.lusf"
+    )]
+    Open {
+        /// lusfファイルのパス（`--memory`の場合は、メモリ上に読み込むファイル）
+        #[structopt(long = "storage", parse(from_os_str), required_unless = "capacity")]
+        storage_path: Option<PathBuf>,
+
+        /// lusfファイルの勧告ロック（`flock`）を取得しない
+        #[structopt(long = "no-lock")]
+        no_lock: bool,
+
+        #[structopt(flatten)]
+        output: OutputOpt,
+        #[structopt(flatten)]
+        access: AccessOpt,
+
+        /// ストレージをメモリ上に置く（`--storage`のファイルを読み込むか、`--capacity`で新たに作成する）
+        ///
+        /// 変更はメモリ上にのみ反映され、`--save-to`を指定しない限りファイルには書き込まれない。
+        #[structopt(long = "memory", conflicts_with = "read_only")]
+        memory: bool,
+
+        /// `--memory`で新たに作成するストレージのデータ領域の容量（例: `64MiB`）
+        #[structopt(
+            long = "capacity",
+            parse(try_from_str = "parse_size"),
+            requires = "memory",
+            conflicts_with = "storage_path"
+        )]
+        capacity: Option<u64>,
+
+        /// `--memory`の場合に、終了時にメモリ上のストレージを書き出すlusfファイル
+        #[structopt(long = "save-to", parse(from_os_str), requires = "memory")]
+        save_to: Option<PathBuf>,
+
+        /// 障害計画を記述したファイル（ファイルのストレージに、計画に従ってI/Oの障害を注入する）
+        #[structopt(
+            long = "fault-plan",
+            parse(try_from_os_str = "parse_fault_plan"),
+            conflicts_with = "memory"
+        )]
+        fault_plan: Option<Box<FaultPlan>>,
+
+        /// 入力履歴を保存するファイル（デフォルトは`~/.kanils_history`）
+        #[structopt(long = "history", parse(from_os_str))]
+        history: Option<PathBuf>,
+
+        /// 入力履歴をファイルに保存しない
+        #[structopt(long = "no-history", conflicts_with = "history")]
+        no_history: bool,
+    },
+
+    /// スクリプトに記述したコマンドを、ストレージを一度だけ開いて順に実行する
+    ///
+    /// スクリプトの各行には対話モード（`Open`）と同じコマンドを書く。
+    /// 空行と`#`から始まる行は無視される。失敗したコマンドがあった場合は終了コード1で終了する。
+    #[structopt(
+        name = "Batch",
+        raw(alias = r#""batch""#),
+        after_help = "EXAMPLES:\n    kanils Batch --storage demo.lusf --script ops.txt\n    \
+                      cat ops.txt | kanils Batch --storage demo.lusf --script - --stop-on-error"
+    )]
+    Batch {
+        #[structopt(flatten)]
+        storage: StorageOpt,
+        #[structopt(flatten)]
+        output: OutputOpt,
+        #[structopt(flatten)]
+        access: AccessOpt,
+
+        /// コマンドを記述したファイルのパス（`-`の場合は標準入力から読み込む）
+        #[structopt(long = "script", parse(from_os_str))]
+        script: PathBuf,
+
+        /// コマンドが失敗した時点で、以降のコマンドを実行せずに終了する
+        #[structopt(long = "stop-on-error")]
+        stop_on_error: bool,
+
+        /// 障害計画を記述したファイル（計画に従ってI/Oの障害を注入する）
+        #[structopt(long = "fault-plan", parse(try_from_os_str = "parse_fault_plan"))]
+        fault_plan: Option<Box<FaultPlan>>,
+    },
+
+    /// lusfストレージを開き、HTTPでlumpの取得・追加・削除などを受け付けるサーバを起動する
+    ///
+    /// 全てのリクエストは一つのストレージに対して直列に処理される。
+    /// `POST /shutdown`でジャーナルを同期して終了する（それ以外の方法で終了すると、
+    /// 同期されていない操作が失われうる）。エンドポイントの一覧はREADMEを参照。
+    #[structopt(
+        name = "Serve",
+        raw(alias = r#""serve""#),
+        after_help = "EXAMPLES:\n    kanils Serve --storage demo.lusf --listen 127.0.0.1:8080\n    \
+                      curl -X PUT --data-binary @value.bin http://127.0.0.1:8080/lumps/42\n    \
+                      curl http://127.0.0.1:8080/stats\n    \
+                      curl -X POST http://127.0.0.1:8080/shutdown"
+    )]
+    Serve {
+        #[structopt(flatten)]
+        storage: StorageOpt,
+        #[structopt(flatten)]
+        output: OutputOpt,
+        #[structopt(flatten)]
+        access: AccessOpt,
+
+        /// 待ち受けるアドレス（ポート番号を0にすると空いているポートを用いる）
+        #[structopt(long = "listen", default_value = "127.0.0.1:8080")]
+        listen: SocketAddr,
+    },
+
+    /// lusfストレージに対して、記述したワークロード（操作の比率、キーとサイズの分布など）を実行する
+    ///
+    /// 対象のストレージは`--storage`（新たに作成する）、`--memory`、`--existing`（既存のファイル。
+    /// `--copy-to`を指定しなければ読み込み専用で開く）のいずれかで指定する。
+    /// ワークロードは`key=value`の組をカンマで区切って記述する（`@path`の場合はファイルから読み込む）。
+    /// 使えるキー: put, get, delete, delete_range（各操作の比率）, keys, key_space, existing_keys, size,
+    /// range_len, sync, ops, duration, seed, payload, preload。
+    /// `existing_keys=yes`の場合は、開始時点でストレージに存在するlump idを対象に操作する。詳細はREADMEを参照。
+    #[structopt(
+        name = "Bench",
+        raw(alias = r#""bench""#),
+        after_help = "EXAMPLES:\n    \
+                      kanils Bench --memory --workload put=70,get=30,keys=zipfian,size=1KiB..64KiB\n    \
+                      kanils Bench --storage bench.lusf --workload put=1,sync=100,duration=30s,seed=42\n    \
+                      kanils Bench --storage bench.lusf --workload @workload.txt --results r.json\n    \
+                      kanils Bench --storage a.lusf --storage b.lusf --workload put=1 --per-thread\n    \
+                      kanils Bench --existing data.lusf --workload get=1,keys=zipfian,existing_keys=yes\n    \
+                      kanils Bench --memory --storages 4 --device --concurrency 8 --workload get=1,preload=yes"
+    )]
+    Bench {
+        /// 実行するワークロード（例: `put=70,get=30,keys=zipfian,size=4KiB,ops=100000`）
+        #[structopt(long = "workload", parse(try_from_str = "parse_workload"))]
+        workload: WorkloadSpec,
+
+        /// `--memory`の場合に作成するストレージの数（各ストレージを並行に操作する）
+        #[structopt(long = "storages", requires = "memory")]
+        storages: Option<usize>,
+
+        /// 障害計画を記述したファイル（新たに作成する各ストレージに、計画に従ってI/Oの障害を注入する）
+        ///
+        /// 失敗した操作は`<op> (error)`として記録する。
+        #[structopt(
+            long = "fault-plan",
+            parse(try_from_os_str = "parse_fault_plan"),
+            raw(conflicts_with_all = r#"&["memory", "existing", "device"]"#)
+        )]
+        fault_plan: Option<Box<FaultPlan>>,
+
+        #[structopt(flatten)]
+        run: BenchRunOpt,
+    },
+
+    /// ランダムなワークロードの実行中のクラッシュを模擬し、開き直したストレージの整合性を検証する
+    ///
+    /// 同期（`journal_sync`）済みの操作が全て残っていることと、書き込まれたことのない値が現れないことを検証し、
+    /// 不整合を検出した場合はクラッシュ後のファイルを`<storage>.failed`として残して終了コード1で終了する。
+    #[structopt(
+        name = "Torture",
+        raw(alias = r#""torture""#),
+        after_help = "EXAMPLES:\n    \
+                      kanils Torture --storage torture.lusf\n    \
+                      kanils Torture --storage torture.lusf --rounds 20 --crash-mode kill --kill-delay 50ms\n    \
+                      kanils Torture --storage torture.lusf --workload put=1,delete=1,size=4KiB,sync=3,seed=42"
+    )]
+    Torture {
+        /// 作業用に新たに作成するlusfファイルのパス（既に存在する場合はエラー）
+        #[structopt(long = "storage", parse(from_os_str))]
+        storage_path: PathBuf,
+
+        /// データ領域の容量（デフォルトはワークロードの全てのキー（高々`--rounds`と`--ops-per-round`の積の数）に最大サイズの値を書き込める大きさ）
+        #[structopt(long = "capacity", parse(try_from_str = "parse_size"))]
+        capacity: Option<u64>,
+
+        /// 実行するワークロード（`ops`と`duration`は用いない）
+        #[structopt(
+            long = "workload",
+            default_value = "put=60,get=20,delete=15,delete_range=1,key_space=1000,size=1..16KiB,sync=10",
+            parse(try_from_str = "parse_workload")
+        )]
+        workload: WorkloadSpec,
+
+        /// クラッシュさせる回数
+        #[structopt(long = "rounds", default_value = "100")]
+        rounds: u64,
+
+        /// 一回のクラッシュまでに実行する操作の最大数（`kill`以外）
+        #[structopt(long = "ops-per-round", default_value = "200")]
+        ops_per_round: u64,
+
+        /// クラッシュの模擬方法（ラウンドごとに無作為に選ぶ）
+        #[structopt(
+            long = "crash-mode",
+            default_value = "discard,prefix,truncate",
+            use_delimiter = true,
+            raw(possible_values = "&CrashMode::VARIANTS", case_insensitive = "true")
+        )]
+        crash_modes: Vec<CrashMode>,
+
+        /// `kill`の場合に、子プロセスが操作を開始してから強制終了させるまでの最大の時間
+        #[structopt(
+            long = "kill-delay",
+            default_value = "100ms",
+            parse(try_from_str = "parse_duration")
+        )]
+        kill_delay: Duration,
+
+        /// 成功した場合にも作業用のlusfファイルを削除しない
+        #[structopt(long = "keep-files")]
+        keep_files: bool,
+
+        /// `kill`の子プロセスとして起動された場合の、操作を生成するためのシード（内部用）
+        #[structopt(long = "child-seed", raw(hidden = "true"))]
+        child_seed: Option<u64>,
+    },
+
+    /// `--results`で保存した二つのベンチマーク結果（JSON）を比較する
+    ///
+    /// フェーズと操作ごとにスループットとレイテンシのパーセンタイルを並べて変化率を出力し、
+    /// `--check`の指標のいずれかが`--threshold`を超えて悪化していれば終了コード1で終了する。
+    #[structopt(
+        name = "BenchCompare",
+        raw(alias = r#""bench-compare""#),
+        after_help = "EXAMPLES:\n    \
+                      kanils BenchCompare baseline.json current.json\n    \
+                      kanils BenchCompare baseline.json current.json --threshold 5% --check ops_per_sec,p99.9"
+    )]
+    BenchCompare {
+        /// 基準とする結果のJSONファイル
+        #[structopt(parse(from_os_str))]
+        baseline: PathBuf,
+
+        /// 比較する結果のJSONファイル
+        #[structopt(parse(from_os_str))]
+        current: PathBuf,
+
+        /// リグレッションと見なす悪化の割合（パーセント）
+        #[structopt(
+            long = "threshold",
+            default_value = "10",
+            parse(try_from_str = "parse_threshold")
+        )]
+        threshold: f64,
+
+        /// リグレッションの判定に用いる指標（`ops_per_sec`, `mb_per_sec`, `avg`, `p50`, `p90`, `p99`, `p99.9`, `max`）
+        #[structopt(
+            long = "check",
+            default_value = "ops_per_sec,p50,p99",
+            use_delimiter = true
+        )]
+        checks: Vec<Metric>,
+    },
+
+    /// 新たにlusfストレージを作成し、sizeバイトのデータをcount件書き込む（書き込みのみの簡易ベンチマーク）
+    #[structopt(
+        name = "WBench",
+        raw(alias = r#""wbench""#),
+        after_help = "EXAMPLES:\n    kanils WBench --storage bench.lusf --count 1000 --size 4KiB"
+    )]
+    WBench {
+        #[structopt(flatten)]
+        bench: BenchOpt,
+    },
+
+    /// 新たにlusfストレージを作成し、sizeバイトのデータをcount件書き込みつつ読み込みも行う簡易ベンチマーク
+    #[structopt(name = "WRBench", raw(alias = r#""wrbench""#))]
+    WRBench {
+        #[structopt(flatten)]
+        bench: BenchOpt,
+    },
+
+    /// 新たにlusfストレージを作成し、sizeバイトのデータをcount件書き込んだ後にランダムに読み込む簡易ベンチマーク
+    #[structopt(
+        name = "RandomGetBench",
+        raw(alias = r#""random-get-bench""#),
+        after_help = "EXAMPLES:\n    kanils RandomGetBench --storage test.lusf --count 1000 --size 3MiB"
+    )]
+    RandomGetBench {
+        #[structopt(flatten)]
+        bench: BenchOpt,
+    },
+
+    /// lusfファイルのmajor versionを強制的に書き換える
+    ///
+    /// 出力は `書き換え前のversion => 書き換え後のversion` となる。
+    #[structopt(
+        name = "ChangeMajorVersionTo",
+        raw(alias = r#""change-major-version-to""#)
+    )]
+    ChangeMajorVersionTo {
+        #[structopt(flatten)]
+        version: VersionOpt,
+    },
+
+    /// lusfファイルのminor versionを強制的に書き換える
+    ///
+    /// 出力は `書き換え前のversion => 書き換え後のversion` となる。
+    #[structopt(
+        name = "ChangeMinorVersionTo",
+        raw(alias = r#""change-minor-version-to""#)
+    )]
+    ChangeMinorVersionTo {
+        #[structopt(flatten)]
+        version: VersionOpt,
+    },
+
+    /// シェル補完スクリプトを標準出力に出力する
+    #[structopt(
+        name = "Completions",
+        raw(alias = r#""completions""#),
+        after_help = "EXAMPLES:\n    kanils Completions bash > /etc/bash_completion.d/kanils\n    \
+                      kanils Completions zsh > ~/.zfunc/_kanils\n    \
+                      kanils Completions fish > ~/.config/fish/completions/kanils.fish"
+    )]
+    Completions {
+        /// 対象のシェル
+        #[structopt(raw(possible_values = r#"&["bash", "zsh", "fish"]"#))]
+        shell: Shell,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "KaNiLS",
+    raw(global_settings = "&[AppSettings::VersionlessSubcommands]")
+)]
+struct Opt {
+    #[structopt(subcommand)]
     command: Command,
 }
 
-/// 0x... --try to convert as hexadecimal number--> u128
-/// otherwise --try to convert as decimal number--> u128
-fn string_to_u128(lumpid_str: &str) -> u128 {
-    if let Some(hex) = lumpid_str.strip_prefix("0x") {
-        u128::from_str_radix(hex, 16).unwrap()
+// `--no-lock`が指定されていなければ、lusfファイルの排他ロックを取得する
+fn lock_storage(storage: &StorageOpt) -> Option<StorageLock> {
+    if storage.no_lock {
+        None
     } else {
-        u128::from_str_radix(&lumpid_str, 10).unwrap()
+        Some(track_try_unwrap!(StorageLock::acquire(
+            &storage.storage_path,
+            LockMode::Exclusive
+        )))
     }
 }
 
-fn is_valid_characters(data: &str) -> bool {
-    std::str::from_utf8(data.as_bytes()).is_ok()
+// lumpを削除する前に、削除されるlump（`lump_ids`）を示して確認を求め、`--backup`の指定があればアーカイブに保存する
+//
+// `handle`は削除にも用いる、排他的に開いたハンドルであること。
+// 一覧の取得から削除までの間に他のプロセスが書き込み、バックアップされないlumpが削除されることを防ぐ。
+fn prepare_deletion(
+    handle: &mut StorageHandle,
+    storage: &StorageOpt,
+    confirm: &ConfirmOpt,
+    backup: &BackupOpt,
+    lump_ids: &[LumpId],
+) {
+    if lump_ids.is_empty() {
+        return;
+    }
+    if confirm.prompts() {
+        let id_format = handle.id_format();
+        let formatted = lump_ids
+            .iter()
+            .map(|lump_id| id_format.format(lump_id.as_u128()))
+            .collect::<Vec<_>>();
+        confirm.confirm(&format!(
+            "delete {} lumps ({}) from {:?}?",
+            lump_ids.len(),
+            confirm::summarize(&formatted, 10),
+            storage.storage_path
+        ));
+    }
+    if let Some(ref path) = backup.backup {
+        let (lumps, bytes) = track_try_unwrap!(export_to_file(handle, lump_ids, path));
+        println!(
+            "backed up {} lumps ({} bytes) to {:?}",
+            lumps,
+            format_bytes_with_size(bytes),
+            path
+        );
+    }
 }
 
-fn size_to_bytes(input: &str) -> Option<u64> {
-    let kb_regex = Regex::new(r"^([0-9]+)KB$").unwrap();
-    let mb_regex = Regex::new(r"^([0-9]+)MB$").unwrap();
-    let gb_regex = Regex::new(r"^([0-9]+)GB$").unwrap();
-
-    if let Some(captured) = kb_regex.captures(&input) {
-        let kb: u64 = captured.get(1).unwrap().as_str().parse().unwrap();
-        Some(kb * 1024)
-    } else if let Some(captured) = mb_regex.captures(&input) {
-        let mb: u64 = captured.get(1).unwrap().as_str().parse().unwrap();
-        Some(mb * 1024 * 1024)
-    } else if let Some(captured) = gb_regex.captures(&input) {
-        let gb: u64 = captured.get(1).unwrap().as_str().parse().unwrap();
-        Some(gb * 1024 * 1024 * 1024)
-    } else {
-        let byte: Option<u64> = input.parse().ok();
-        byte
+// バージョンを書き換える前に、現在のバージョン（`select`で選んだもの）を示して確認を求める
+fn confirm_version_change<F>(version: &VersionOpt, name: &str, select: F)
+where
+    F: FnOnce((u16, u16)) -> u16,
+{
+    if !version.confirm.prompts() {
+        return;
     }
+    let current = track_try_unwrap!(StorageHandle::lusf_version(&version.storage.storage_path));
+    version.confirm.confirm(&format!(
+        "change the {} version of {:?} from {} to {} (cannyls may refuse to open it afterwards)?",
+        name,
+        version.storage.storage_path,
+        select(current),
+        version.version
+    ));
+}
+
+fn open_handle(storage: &StorageOpt, output: &OutputOpt) -> StorageHandle {
+    let mut handle =
+        track_try_unwrap!(StorageHandle::open(&storage.storage_path, !storage.no_lock));
+    handle.set_id_format(output.id_format);
+    handle
 }
 
-fn handle_input(handle: &mut StorageHandle, input: &str) {
-    let put_regex = Regex::new(r"^put\s+([0-9]+|0x[0-9a-f]+)\s+([^\x00]+)$").unwrap();
-    let get_regex = Regex::new(r"^get\s+([0-9]+|0x[0-9a-f]+)$").unwrap();
-    let get_as_bytes_regex = Regex::new(r"^get_bytes\s+([0-9]+|0x[0-9a-f]+)$").unwrap();
-    let delete_regex = Regex::new(r"^delete\s*([0-9]+|0x[0-9a-f]+)$").unwrap();
+fn open_read_only_handle(storage: &StorageOpt, output: &OutputOpt) -> StorageHandle<ReadOnlyNvm> {
+    let mut handle = track_try_unwrap!(StorageHandle::open_read_only(
+        &storage.storage_path,
+        !storage.no_lock
+    ));
+    handle.set_id_format(output.id_format);
+    handle
+}
 
-    if let Some(captured) = put_regex.captures(&input) {
-        println!("captured = {:?}", captured);
+fn open_faulty_handle(
+    storage: &StorageOpt,
+    output: &OutputOpt,
+    injector: &FaultInjector,
+) -> StorageHandle<FaultNvm<FileNvm>> {
+    let mut handle = track_try_unwrap!(StorageHandle::open_with_faults(
+        &storage.storage_path,
+        !storage.no_lock,
+        injector.clone()
+    ));
+    handle.set_id_format(output.id_format);
+    handle
+}
 
-        let key: u128 = string_to_u128(captured.get(1).unwrap().as_str());
-        let value: &str = captured.get(2).unwrap().as_str();
+fn open_faulty_read_only_handle(
+    storage: &StorageOpt,
+    output: &OutputOpt,
+    injector: &FaultInjector,
+) -> StorageHandle<FaultNvm<ReadOnlyNvm>> {
+    let mut handle = track_try_unwrap!(StorageHandle::open_read_only_with_faults(
+        &storage.storage_path,
+        !storage.no_lock,
+        injector.clone()
+    ));
+    handle.set_id_format(output.id_format);
+    handle
+}
 
-        println!("key = {:?}", key);
-        println!("value = {:?}", value);
+// 障害計画に従って障害を注入する`FaultInjector`を作成し、計画とシードを出力する
+fn fault_injector(plan: &FaultPlan) -> FaultInjector {
+    let injector = FaultInjector::new(plan);
+    println!(
+        "fault plan: {} (seed = {}; pass `seed={}` in the plan to reproduce)",
+        plan,
+        injector.seed(),
+        injector.seed()
+    );
+    injector
+}
 
-        if is_valid_characters(value) {
-            handle.put(key, value);
-        } else {
-            println!("your input value {} is invalid wrt UTF-8", input);
-        }
-    } else if let Some(captured) = get_regex.captures(&input) {
-        let key: u128 = string_to_u128(captured.get(1).unwrap().as_str());
-        handle.get(key);
-    } else if let Some(captured) = get_as_bytes_regex.captures(&input) {
-        let key: u128 = string_to_u128(captured.get(1).unwrap().as_str());
-        handle.print_as_bytes(key);
-    } else if let Some(captured) = delete_regex.captures(&input) {
-        let key: u128 = string_to_u128(captured.get(1).unwrap().as_str());
-        handle.delete(key);
-    } else if input == "list" {
-        handle.print_list_of_lumpids();
-    } else if input == "dump" {
-        handle.print_all_key_value_pairs();
-    } else if input == "header" {
-        handle.print_header_info();
-    } else if input == "journal" {
-        handle.print_journal_info();
-    } else if input == "journal_gc" {
-        handle.journal_gc();
+fn open_device_client(
+    storage: &StorageOpt,
+    output: &OutputOpt,
+    read_only: bool,
+    device: &DeviceOpt,
+    lump_kinds: bool,
+) -> DeviceClient {
+    let open = if lump_kinds {
+        DeviceClient::open_with_lump_kinds
     } else {
-        println!("`{}` is an invalid command", input);
+        DeviceClient::open
+    };
+    let mut client = track_try_unwrap!(open(
+        &storage.storage_path,
+        !storage.no_lock,
+        read_only,
+        device.request_options()
+    ));
+    client.set_id_format(output.id_format);
+    client
+}
+
+// 必要であればデバイスのメトリクスを出力し、デバイスを停止する
+fn finish_device_client(client: DeviceClient, device: &DeviceOpt) {
+    if device.device_metrics {
+        print_device_metrics(client.metrics());
     }
+    track_try_unwrap!(client.stop());
 }
 
-fn main() {
-    let opt = Opt::from_args();
+// `--device`の指定に応じて、`Device`経由（`$client`）か直接（`$direct`）かのいずれかで操作する
+//
+// 先頭に`lump_kinds`を指定すると、`$client`はlumpの格納場所も出力する（`DeviceClient::open_with_lump_kinds`）。
+macro_rules! with_device_or {
+    (lump_kinds, $storage:expr, $output:expr, $read_only:expr, $device:expr, |$client:ident| $body:expr, $direct:expr) => {
+        with_device_or!(@open true, $storage, $output, $read_only, $device, |$client| $body, $direct)
+    };
+    ($storage:expr, $output:expr, $read_only:expr, $device:expr, |$client:ident| $body:expr, $direct:expr) => {
+        with_device_or!(@open false, $storage, $output, $read_only, $device, |$client| $body, $direct)
+    };
+    (@open $lump_kinds:expr, $storage:expr, $output:expr, $read_only:expr, $device:expr, |$client:ident| $body:expr, $direct:expr) => {
+        if $device.device {
+            let mut $client =
+                open_device_client(&$storage, &$output, $read_only, &$device, $lump_kinds);
+            track_try_unwrap!($body);
+            finish_device_client($client, &$device);
+        } else {
+            $direct
+        }
+    };
+}
 
-    match opt.command {
-        Command::Create => {
-            let mut data_region_size = opt.capacity.unwrap();
-            println!("passed data region size = {}", data_region_size);
-            let block_size = BlockSize::min();
-            let block_size_u64 = u64::from(block_size.as_u16());
+// `with_device_or!`と同様だが、既に開いたハンドル（`$handle`）のストレージを用いる
+//
+// `--device`の場合は、ハンドルのストレージとロックをデバイスに引き継ぐ。
+macro_rules! with_opened_device_or {
+    ($handle:ident, $device:expr, |$client:ident| $body:expr, $direct:expr) => {
+        if $device.device {
+            let mut $client = track_try_unwrap!(DeviceClient::from_handle(
+                $handle,
+                $device.request_options()
+            ));
+            track_try_unwrap!($body);
+            finish_device_client($client, &$device);
+        } else {
+            $direct
+        }
+    };
+}
 
-            data_region_size = block_size.ceil_align(data_region_size);
+// `--read-only`の指定に応じてストレージを開き、`$body`を実行する
+//
+// 読み込み専用の場合とそうでない場合とでハンドルの型が異なるので、マクロで両方を展開する。
+// 障害計画（`Option<FaultPlan>`）を渡した場合は、計画に従って障害を注入するNVMを介して開き、
+// 終了時に注入した障害の集計を出力する。
+macro_rules! with_handle {
+    ($storage:expr, $output:expr, $access:expr, $faults:expr, |$handle:ident| $body:expr) => {
+        match $faults {
+            Some(ref plan) => {
+                let injector = fault_injector(plan);
+                let result = if $access.read_only {
+                    #[allow(unused_mut)]
+                    let mut $handle = open_faulty_read_only_handle(&$storage, &$output, &injector);
+                    $body
+                } else {
+                    #[allow(unused_mut)]
+                    let mut $handle = open_faulty_handle(&$storage, &$output, &injector);
+                    $body
+                };
+                println!("{}", injector.summary());
+                result
+            }
+            None => with_handle!($storage, $output, $access, |$handle| $body),
+        }
+    };
+    ($storage:expr, $output:expr, $access:expr, |$handle:ident| $body:expr) => {
+        if $access.read_only {
+            #[allow(unused_mut)]
+            let mut $handle = open_read_only_handle(&$storage, &$output);
+            $body
+        } else {
+            #[allow(unused_mut)]
+            let mut $handle = open_handle(&$storage, &$output);
+            $body
+        }
+    };
+}
 
-            let journal_header_size = block_size_u64;
-            let journal_record_size =
-                std::cmp::max(block_size_u64 * 2, 20 * (data_region_size / block_size_u64));
-            let journal_region_size = journal_header_size + journal_record_size;
+// サブコマンドより前に置かれたストレージの指定（`--storage`, `--read-only`, `--no-lock`）を、サブコマンドの直後に移す
+//
+// 以前のkanilsはこれらのオプションをサブコマンドより前で受け付けていた（例: `kanils --storage x.lusf Header`）ので、
+// その順序で書かれたスクリプトもそのまま動くようにする。
+fn reorder_storage_options<I>(args: I) -> Vec<OsString>
+where
+    I: IntoIterator<Item = OsString>,
+{
+    let mut args = args.into_iter();
+    let mut result: Vec<OsString> = args.next().into_iter().collect();
+    let mut moved = Vec::new();
+    while let Some(arg) = args.next() {
+        let is_storage_option = arg.to_str().is_some_and(|arg| {
+            ["--storage", "--read-only", "--no-lock"].contains(&arg)
+                || arg.starts_with("--storage=")
+        });
+        if !is_storage_option {
+            let is_command = !arg.to_string_lossy().starts_with('-');
+            if is_command {
+                result.push(arg);
+                result.append(&mut moved);
+            } else {
+                result.append(&mut moved);
+                result.push(arg);
+            }
+            break;
+        }
+        let takes_value = arg == OsStr::new("--storage");
+        moved.push(arg);
+        if takes_value {
+            moved.extend(args.next());
+        }
+    }
+    result.append(&mut moved);
+    result.extend(args);
+    result
+}
 
-            let header_size = block_size_u64;
+fn main() {
+    let opt = Opt::from_iter(reorder_storage_options(std::env::args_os()));
 
-            let total_size = data_region_size + journal_region_size + header_size;
-            let journal_ratio: f64 = 0.01f64.max(journal_region_size as f64 / total_size as f64);
+    match opt.command {
+        Command::Create { storage, capacity } => {
+            println!(
+                "passed data region size = {}",
+                format_bytes_with_size(capacity)
+            );
+            let (total_size, journal_ratio) = storage_layout(capacity);
 
-            let nvm = track_try_unwrap!(FileNvm::create(opt.storage_path, total_size));
+            let nvm = track_try_unwrap!(FileNvmBuilder::new()
+                .exclusive_lock(!storage.no_lock)
+                .create(storage.storage_path, total_size));
             let storage = track_try_unwrap!(StorageBuilder::new()
                 .journal_region_ratio(journal_ratio)
                 .create(nvm));
@@ -273,10 +1465,13 @@ fn main() {
             println!("---------------");
             let actual_data_region_size = storage.header().data_region_size;
             let actual_journal_region_size = storage.header().journal_region_size;
-            println!("actual data region size = {}", actual_data_region_size);
+            println!(
+                "actual data region size = {}",
+                format_bytes_with_size(actual_data_region_size)
+            );
             println!(
                 "actual journal region size = {}",
-                actual_journal_region_size
+                format_bytes_with_size(actual_journal_region_size)
             );
             println!(
                 "actual journal region size ratio = {}",
@@ -284,110 +1479,630 @@ fn main() {
                     / (actual_journal_region_size + actual_data_region_size) as f64
             );
         }
-        Command::Open => {
-            let nvm = track_try_unwrap!(FileNvm::open(&opt.storage_path));
-            let storage = track_try_unwrap!(StorageBuilder::new().open(nvm));
-
-            let mut handle = StorageHandle::new(storage);
-            let mut rl = Editor::<()>::new();
-            loop {
-                let readline = rl.readline(">> ");
-                match readline {
-                    Ok(line) => {
-                        rl.add_history_entry(&line);
-                        handle_input(&mut handle, &line);
-                    }
-                    Err(ReadlineError::Interrupted) => {
-                        println!("CTRL-C");
-                        break;
-                    }
-                    Err(ReadlineError::Eof) => {
-                        println!("CTRL-D");
-                        break;
-                    }
-                    Err(err) => {
-                        println!("Error: {:?}", err);
-                        break;
+        Command::Open {
+            storage_path,
+            no_lock,
+            output,
+            access,
+            memory,
+            capacity,
+            save_to,
+            fault_plan,
+            history,
+            no_history,
+        } => {
+            let history_path = if no_history {
+                None
+            } else {
+                history.or_else(|| {
+                    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".kanils_history"))
+                })
+            };
+            if memory {
+                let (mut handle, image) = match (storage_path, capacity) {
+                    (Some(path), _) => track_try_unwrap!(load_into_memory(path, !no_lock)),
+                    (None, Some(capacity)) => track_try_unwrap!(create_in_memory(capacity)),
+                    (None, None) => unreachable!("`--storage` is required unless `--capacity`"),
+                };
+                handle.set_id_format(output.id_format);
+                let mut session = Session::new(handle);
+                run_repl(&mut session, history_path.as_deref());
+                if let Some(path) = save_to {
+                    track_try_unwrap!(session.handle_mut().journal_sync());
+                    track_try_unwrap!(image.save_to_file(&path, !no_lock));
+                    println!("saved the in-memory storage to {:?}", path);
+                }
+            } else {
+                let storage = StorageOpt {
+                    storage_path: storage_path.expect("`--capacity` requires `--memory`"),
+                    no_lock,
+                };
+                with_handle!(storage, output, access, fault_plan, |handle| run_repl(
+                    &mut Session::new(handle),
+                    history_path.as_deref()
+                ));
+            }
+        }
+        Command::Batch {
+            storage,
+            output,
+            access,
+            script,
+            stop_on_error,
+            fault_plan,
+        } => {
+            let reader: Box<dyn BufRead> = if script.to_str() == Some("-") {
+                Box::new(BufReader::new(io::stdin()))
+            } else {
+                Box::new(BufReader::new(track_try_unwrap!(
+                    File::open(&script).map_err(cannyls::Error::from)
+                )))
+            };
+            let summary = with_handle!(
+                storage,
+                output,
+                access,
+                fault_plan,
+                |handle| track_try_unwrap!(run_batch(
+                    &mut Session::new(handle),
+                    reader,
+                    stop_on_error
+                ))
+            );
+            if !summary.is_success() {
+                std::process::exit(1);
+            }
+        }
+        Command::Get {
+            storage,
+            device,
+            output,
+            access,
+            key,
+        } => {
+            with_device_or!(
+                lump_kinds,
+                storage,
+                output,
+                access.read_only,
+                device,
+                |client| client.get(key),
+                with_handle!(storage, output, access, |handle| track_try_unwrap!(
+                    handle.get(key)
+                ))
+            );
+        }
+        Command::GetBytes {
+            storage,
+            device,
+            output,
+            access,
+            key,
+        } => {
+            with_device_or!(
+                lump_kinds,
+                storage,
+                output,
+                access.read_only,
+                device,
+                |client| client.print_as_bytes(key),
+                with_handle!(storage, output, access, |handle| track_try_unwrap!(
+                    handle.print_as_bytes(key)
+                ))
+            );
+        }
+        Command::Put {
+            storage,
+            device,
+            output,
+            key,
+            value,
+        } => {
+            with_device_or!(
+                storage,
+                output,
+                false,
+                device,
+                |client| client.put(key, &value),
+                {
+                    let mut handle = open_handle(&storage, &output);
+                    track_try_unwrap!(handle.put(key, &value));
+                }
+            );
+        }
+        Command::Embed {
+            storage,
+            output,
+            key,
+            value,
+            value_file,
+        } => {
+            let value = match (value, value_file) {
+                (Some(value), _) => value.into_bytes(),
+                (None, Some(path)) => {
+                    let mut bytes = Vec::new();
+                    if path.to_str() == Some("-") {
+                        track_try_unwrap!(io::stdin()
+                            .read_to_end(&mut bytes)
+                            .map_err(cannyls::Error::from));
+                    } else {
+                        track_try_unwrap!(File::open(&path)
+                            .and_then(|mut file| file.read_to_end(&mut bytes))
+                            .map_err(cannyls::Error::from));
                     }
+                    bytes
                 }
+                (None, None) => unreachable!("`--value` or `--value-file` is required"),
+            };
+            if value.len() > LumpData::MAX_EMBEDDED_SIZE {
+                let message = format!(
+                    "the value is too large to embed ({} bytes > {} bytes); \
+                     use `Put` to store it in the data region",
+                    value.len(),
+                    LumpData::MAX_EMBEDDED_SIZE
+                );
+                clap::Error::with_description(&message, clap::ErrorKind::InvalidValue).exit();
             }
+            let mut handle = open_handle(&storage, &output);
+            track_try_unwrap!(handle.embed_binary(key, &value));
+        }
+        Command::Convert {
+            storage,
+            output,
+            key,
+            to,
+        } => {
+            let mut handle = open_handle(&storage, &output);
+            track_try_unwrap!(handle.convert(key, to));
+        }
+        Command::Journal {
+            storage,
+            output,
+            access,
+        } => {
+            with_handle!(storage, output, access, |handle| track_try_unwrap!(
+                handle.print_journal_info()
+            ));
+        }
+        Command::Watch {
+            storage_path,
+            lock,
+            output,
+            interval,
+            lines,
+            polls,
+            ..
+        } => {
+            let options = WatchOptions {
+                interval,
+                lines,
+                polls,
+                id_format: output.id_format,
+            };
+            track_try_unwrap!(run_watch(&storage_path, lock, &options));
+        }
+        Command::JournalGC { storage, confirm } => {
+            let mut handle =
+                track_try_unwrap!(StorageHandle::open(&storage.storage_path, !storage.no_lock));
+            if confirm.prompts() {
+                let snapshot = track_try_unwrap!(handle.journal_info());
+                confirm.confirm(&format!(
+                    "run journal GC on {:?} ({} entries; released records can no longer be inspected by `Journal`)?",
+                    storage.storage_path,
+                    snapshot.entries.len()
+                ));
+            }
+            track_try_unwrap!(handle.journal_gc());
+        }
+        Command::Import {
+            storage,
+            output,
+            from,
+        } => {
+            let mut handle = open_handle(&storage, &output);
+            let summary = track_try_unwrap!(import_from_file(&mut handle, &from));
+            println!(
+                "imported {} lumps ({} bytes, {} overwritten) from {:?}",
+                summary.lumps,
+                format_bytes_with_size(summary.bytes),
+                summary.overwritten,
+                from
+            );
         }
-        Command::Get => {
-            let mut handle = StorageHandle::create(&opt.storage_path);
-            let lumpid_str: String = opt.lumpid.unwrap();
-            handle.get(string_to_u128(&lumpid_str));
-        }
-        Command::GetBytes => {
-            let mut handle = StorageHandle::create(&opt.storage_path);
-            let lumpid_str: String = opt.lumpid.unwrap();
-            handle.print_as_bytes(string_to_u128(&lumpid_str));
-        }
-        Command::Put => {
-            let mut handle = StorageHandle::create(&opt.storage_path);
-            let lumpid_str: String = opt.lumpid.unwrap();
-            handle.put(string_to_u128(&lumpid_str), &opt.data.unwrap());
-        }
-        Command::Embed => {
-            let mut handle = StorageHandle::create(&opt.storage_path);
-            let lumpid_str: String = opt.lumpid.unwrap();
-            handle.embed(string_to_u128(&lumpid_str), &opt.data.unwrap());
-        }
-        Command::Journal => {
-            let mut handle = StorageHandle::create(&opt.storage_path);
-            handle.print_journal_info();
-        }
-        Command::JournalGC => {
-            let mut handle = StorageHandle::create(&opt.storage_path);
-            handle.journal_gc();
-        }
-        Command::List => {
-            let mut handle = StorageHandle::create(&opt.storage_path);
-            handle.print_list_of_lumpids();
-        }
-        Command::Delete => {
-            let mut handle = StorageHandle::create(&opt.storage_path);
-            let lumpid_str: String = opt.lumpid.unwrap();
-            handle.delete(string_to_u128(&lumpid_str));
-        }
-        Command::RangeDelete => {
-            let mut handle = StorageHandle::create(&opt.storage_path);
-            let lumpid_start_str: String = opt.lumpid_start.unwrap();
-            let lumpid_end_str: String = opt.lumpid_end.unwrap();
-            handle.delete_range(
-                string_to_u128(&lumpid_start_str),
-                string_to_u128(&lumpid_end_str),
+        Command::List {
+            storage,
+            device,
+            output,
+            access,
+        } => {
+            with_device_or!(
+                lump_kinds,
+                storage,
+                output,
+                access.read_only,
+                device,
+                |client| client.print_list_of_lumpids(),
+                with_handle!(storage, output, access, |handle| track_try_unwrap!(
+                    handle.print_list_of_lumpids()
+                ))
             );
         }
-        Command::Dump => {
-            let mut handle = StorageHandle::create(&opt.storage_path);
-            handle.print_all_key_value_pairs();
+        Command::Delete {
+            storage,
+            device,
+            confirm,
+            backup,
+            key,
+        } => {
+            let output = OutputOpt {
+                id_format: IdFormat::default(),
+            };
+            let mut handle = open_handle(&storage, &output);
+            let lump_ids = if handle.contains_key(key) {
+                vec![LumpId::new(key)]
+            } else {
+                Vec::new()
+            };
+            prepare_deletion(&mut handle, &storage, &confirm, &backup, &lump_ids);
+            with_opened_device_or!(handle, device, |client| client.delete(key), {
+                track_try_unwrap!(handle.delete(key));
+            });
         }
-        Command::Header => {
-            let mut handle = StorageHandle::create(&opt.storage_path);
-            handle.print_header_info();
+        Command::RangeDelete {
+            storage,
+            device,
+            output,
+            confirm,
+            backup,
+            start,
+            end,
+            dry_run,
+        } => {
+            if dry_run {
+                // 削除はしないので、`--device`の指定によらず直接読み込み専用で開く
+                let mut handle = open_read_only_handle(&storage, &output);
+                handle.print_delete_range_plan(start, end);
+                return;
+            }
+            let mut handle = open_handle(&storage, &output);
+            let lump_ids = handle.keys_in_range(start, end);
+            prepare_deletion(&mut handle, &storage, &confirm, &backup, &lump_ids);
+            with_opened_device_or!(handle, device, |client| client.delete_range(start, end), {
+                track_try_unwrap!(handle.delete_range(start, end));
+            });
         }
-        Command::ChangeMajorVersionTo => {
-            let new_version = opt.version.unwrap();
-            StorageHandle::change_major_version_to(&opt.storage_path, new_version);
+        Command::GetRange {
+            storage,
+            output,
+            access,
+            start,
+            end,
+            export,
+        } => with_handle!(storage, output, access, |handle| match export {
+            Some(ref path) => {
+                let lump_ids = handle.keys_in_range(start, end);
+                let (lumps, bytes) =
+                    track_try_unwrap!(export_to_file(&mut handle, &lump_ids, path));
+                println!(
+                    "exported {} lumps ({} bytes) to {:?}",
+                    lumps,
+                    format_bytes_with_size(bytes),
+                    path
+                );
+            }
+            None => track_try_unwrap!(handle.print_range(start, end)),
+        }),
+        Command::CountRange {
+            storage,
+            output,
+            access,
+            start,
+            end,
+            exact,
+        } => {
+            with_handle!(storage, output, access, |handle| track_try_unwrap!(
+                handle.print_range_count(start, end, exact)
+            ));
         }
-        Command::ChangeMinorVersionTo => {
-            let new_version = opt.version.unwrap();
-            StorageHandle::change_minor_version_to(&opt.storage_path, new_version);
+        Command::Dump {
+            storage,
+            output,
+            access,
+        } => {
+            with_handle!(storage, output, access, |handle| track_try_unwrap!(
+                handle.print_all_key_value_pairs()
+            ));
         }
-        Command::WBench => {
-            let count = opt.count.unwrap();
-            let size = size_to_bytes(&opt.size.unwrap()).unwrap();
-            bench::seq_write(opt.storage_path, count as u64, size as u64);
+        Command::Metrics {
+            storage,
+            access,
+            prometheus,
+        } => {
+            // メトリクスはストレージを開いている間だけ収集できる
+            let print = || {
+                if prometheus {
+                    print!("{}", metrics::to_prometheus_text());
+                } else {
+                    metrics::print_metrics();
+                }
+            };
+            if access.read_only {
+                let _handle = track_try_unwrap!(StorageHandle::open_read_only(
+                    &storage.storage_path,
+                    !storage.no_lock
+                ));
+                print();
+            } else {
+                let _handle =
+                    track_try_unwrap!(StorageHandle::open(&storage.storage_path, !storage.no_lock));
+                print();
+            }
+        }
+        Command::Scrub { storage, into } => {
+            let summary = track_try_unwrap!(scrub(&storage.storage_path, &into, !storage.no_lock));
+            println!("{}", summary);
+            println!("wrote the scrubbed storage to {:?}", into);
         }
-        Command::WRBench => {
-            let count = opt.count.unwrap();
-            let size = size_to_bytes(&opt.size.unwrap()).unwrap();
-            bench::marching(opt.storage_path, count as u64, size as u64);
+        Command::Header {
+            storage,
+            access: _,
+            raw: true,
+        } => {
+            let _lock = if storage.no_lock {
+                None
+            } else {
+                Some(track_try_unwrap!(StorageLock::acquire(
+                    &storage.storage_path,
+                    LockMode::Shared
+                )))
+            };
+            let bytes = track_try_unwrap!(read_raw_header(&storage.storage_path));
+            for line in header::format_raw(&bytes) {
+                println!("{}", line);
+            }
         }
-        Command::RandomGetBench => {
-            let count = opt.count.unwrap();
-            let size = size_to_bytes(&opt.size.unwrap()).unwrap();
-            bench::random_get(opt.storage_path, count as u64, size as u64);
+        Command::Header {
+            storage,
+            access,
+            raw: false,
+        } => {
+            if access.read_only {
+                let mut handle = track_try_unwrap!(StorageHandle::open_read_only(
+                    &storage.storage_path,
+                    !storage.no_lock
+                ));
+                handle.print_header_info();
+            } else {
+                let mut handle =
+                    track_try_unwrap!(StorageHandle::open(&storage.storage_path, !storage.no_lock));
+                handle.print_header_info();
+            }
+        }
+        Command::HeaderSet {
+            storage,
+            confirm,
+            uuid,
+        } => {
+            let _lock = lock_storage(&storage);
+            let mut header = track_try_unwrap!(LusfHeader::read_from_file(&storage.storage_path));
+            confirm.confirm(&format!(
+                "change the uuid of {:?} from {} to {}?",
+                storage.storage_path, header.instance_uuid, uuid
+            ));
+            let old_uuid = header.instance_uuid;
+            header.instance_uuid = uuid;
+            track_try_unwrap!(header.write_to_file(&storage.storage_path));
+            println!("uuid: {} => {}", old_uuid, uuid);
+        }
+        Command::ChangeMajorVersionTo { version } => {
+            let _lock = lock_storage(&version.storage);
+            confirm_version_change(&version, "major", |(major, _)| major);
+            StorageHandle::change_major_version_to(&version.storage.storage_path, version.version);
+        }
+        Command::ChangeMinorVersionTo { version } => {
+            let _lock = lock_storage(&version.storage);
+            confirm_version_change(&version, "minor", |(_, minor)| minor);
+            StorageHandle::change_minor_version_to(&version.storage.storage_path, version.version);
+        }
+        Command::Serve {
+            storage,
+            output,
+            access,
+            listen,
+        } => {
+            with_handle!(storage, output, access, |handle| {
+                let listener =
+                    track_try_unwrap!(TcpListener::bind(listen).map_err(cannyls::Error::from));
+                let address =
+                    track_try_unwrap!(listener.local_addr().map_err(cannyls::Error::from));
+                println!(
+                    "serving {:?}{} on http://{}",
+                    storage.storage_path,
+                    if handle.is_read_only() {
+                        " (read-only)"
+                    } else {
+                        ""
+                    },
+                    address
+                );
+                track_try_unwrap!(server::serve(handle, listener));
+            });
+        }
+        Command::Bench {
+            workload,
+            storages,
+            fault_plan,
+            run,
+        } => {
+            let storages = run.storages(!workload.is_read_only(), storages.unwrap_or(1));
+            let result = bench::workload(
+                storages,
+                workload,
+                run.device_options(),
+                run.per_thread,
+                fault_plan.as_deref(),
+            );
+            run.write_results(&result);
+        }
+        Command::WBench { bench } => {
+            let result = bench::seq_write(
+                bench.run.storage(true),
+                bench.count,
+                bench.size,
+                bench.run.device_options(),
+            );
+            bench.run.write_results(&result);
+        }
+        Command::WRBench { bench } => {
+            let result = bench::marching(
+                bench.run.storage(true),
+                bench.count,
+                bench.size,
+                bench.run.device_options(),
+            );
+            bench.run.write_results(&result);
+        }
+        Command::RandomGetBench { bench } => {
+            let result = bench::random_get(
+                bench.run.storage(true),
+                bench.count,
+                bench.size,
+                bench.run.device_options(),
+            );
+            bench.run.write_results(&result);
+        }
+        Command::Torture {
+            storage_path,
+            capacity,
+            workload,
+            rounds,
+            ops_per_round,
+            crash_modes,
+            kill_delay,
+            keep_files,
+            child_seed,
+        } => {
+            if let Some(seed) = child_seed {
+                track_try_unwrap!(torture::run_child(&storage_path, &workload, seed));
+                return;
+            }
+            let options = TortureOptions {
+                path: storage_path,
+                capacity,
+                spec: workload,
+                rounds,
+                ops_per_round,
+                modes: crash_modes,
+                kill_delay,
+                child_args: std::env::args_os().skip(1).collect(),
+                keep_files,
+            };
+            let summary = track_try_unwrap!(torture::run(&options));
+            if summary.is_success() {
+                println!(
+                    "no inconsistencies found in {} rounds ({} ops)",
+                    summary.rounds, summary.ops
+                );
+            } else {
+                println!(
+                    "found {} inconsistencies in round {}:",
+                    summary.violations.len(),
+                    summary.rounds
+                );
+                for violation in &summary.violations {
+                    println!("  {}", violation);
+                }
+                if let Some(ref path) = summary.failed_image {
+                    println!("kept the crashed storage at {:?}", path);
+                }
+                std::process::exit(1);
+            }
+        }
+        Command::BenchCompare {
+            baseline,
+            current,
+            threshold,
+            checks,
+        } => {
+            let baseline_result = track_try_unwrap!(SavedResult::load(&baseline));
+            let current_result = track_try_unwrap!(SavedResult::load(&current));
+            for (label, path, result) in &[
+                ("baseline", &baseline, &baseline_result),
+                ("current", &current, &current_result),
+            ] {
+                println!(
+                    "{:<8} = {:?} ({}, kanils {}, cannyls {})",
+                    label,
+                    path,
+                    result.benchmark,
+                    result.kanils_version.as_deref().unwrap_or("unknown"),
+                    result.cannyls_version.as_deref().unwrap_or("unknown")
+                );
+            }
+            if baseline_result.benchmark != current_result.benchmark {
+                println!("warning: the results are from different benchmarks");
+            }
+            let comparison = Comparison::new(&baseline_result, &current_result, &checks, threshold);
+            comparison.print();
+            let regressions = comparison.regressions();
+            if regressions.is_empty() {
+                println!("no regressions exceeding {}%", threshold);
+            } else {
+                println!(
+                    "{} regression(s) exceeding {}%:",
+                    regressions.len(),
+                    threshold
+                );
+                for diff in regressions {
+                    println!(
+                        "  [{}] {} {}: {:+.2}%",
+                        diff.phase,
+                        diff.op,
+                        diff.metric.name(),
+                        diff.delta_percent().unwrap_or(0.0)
+                    );
+                }
+                std::process::exit(1);
+            }
+        }
+        Command::Completions { shell } => {
+            Opt::clap().gen_completions_to("kanils", shell, &mut std::io::stdout());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Opt {
+        let args = reorder_storage_options(args.iter().map(OsString::from));
+        Opt::from_iter_safe(args).unwrap()
+    }
+
+    #[test]
+    fn storage_options_before_command_are_accepted() {
+        match parse(&["kanils", "--storage", "x", "Header"]).command {
+            Command::Header { storage, .. } => {
+                assert_eq!(storage.storage_path, PathBuf::from("x"));
+            }
+            command => panic!("unexpected command: {:?}", command),
+        }
+        match parse(&["kanils", "--no-lock", "--storage=x", "--read-only", "List"]).command {
+            Command::List {
+                storage, access, ..
+            } => {
+                assert_eq!(storage.storage_path, PathBuf::from("x"));
+                assert!(storage.no_lock);
+                assert!(access.read_only);
+            }
+            command => panic!("unexpected command: {:?}", command),
+        }
+        match parse(&["kanils", "Get", "--storage", "x", "--key", "1"]).command {
+            Command::Get { storage, .. } => {
+                assert_eq!(storage.storage_path, PathBuf::from("x"));
+            }
+            command => panic!("unexpected command: {:?}", command),
         }
     }
 }
@@ -0,0 +1,123 @@
+//! メモリ上に置いたストレージ（`--memory`）を扱う。
+//!
+//! ディスクに触れずに実験したり、lusfファイルをメモリ上に読み込んで試しに変更を加え、
+//! 必要であれば結果をファイルに書き出したりするために用いる。
+use cannyls::nvm::{NonVolatileMemory, SharedMemoryNvm};
+use cannyls::storage::{StorageBuilder, StorageHeader};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::handle::{storage_layout, StorageHandle};
+use crate::lock::{LockMode, StorageLock};
+
+/// メモリ上のストレージのイメージ（lusfファイルと同じ形式のバイト列）への参照。
+///
+/// ストレージと同じメモリを共有しているので、ストレージを開いたままでも内容を取り出せる。
+#[derive(Debug, Clone)]
+pub struct MemoryImage {
+    nvm: SharedMemoryNvm,
+}
+impl MemoryImage {
+    /// イメージ全体をバイト列として返す。
+    ///
+    /// ジャーナルのバッファに残っている書き込みは含まれないので、
+    /// 事前に`StorageHandle::journal_sync`を呼び出しておくこと。
+    pub fn to_bytes(&self) -> Result<Vec<u8>, cannyls::Error> {
+        let mut nvm = self.nvm.clone();
+        let mut bytes = vec![0; nvm.capacity() as usize];
+        track_io!(nvm.seek(SeekFrom::Start(0)))?;
+        track_io!(nvm.read_exact(&mut bytes))?;
+        Ok(bytes)
+    }
+
+    /// イメージを`path`にlusfファイルとして書き出す。
+    ///
+    /// `lock`が`true`の場合は、書き出しの間`path`の排他ロックを取得する。
+    pub fn save_to_file<P: AsRef<Path>>(&self, path: P, lock: bool) -> Result<(), cannyls::Error> {
+        let bytes = track!(self.to_bytes())?;
+        let _lock = if lock && path.as_ref().exists() {
+            Some(track!(StorageLock::acquire(&path, LockMode::Exclusive))?)
+        } else {
+            None
+        };
+        track_io!(fs::write(&path, bytes))?;
+        Ok(())
+    }
+}
+
+/// データ領域の容量が`capacity`バイトのストレージをメモリ上に新たに作成する。
+pub fn create_in_memory(
+    capacity: u64,
+) -> Result<(StorageHandle<SharedMemoryNvm>, MemoryImage), cannyls::Error> {
+    let (total_size, journal_ratio) = storage_layout(capacity);
+    let nvm = SharedMemoryNvm::new(vec![0; total_size as usize]);
+    let image = MemoryImage { nvm: nvm.clone() };
+    let storage = track!(StorageBuilder::new()
+        .journal_region_ratio(journal_ratio)
+        .create(nvm))?;
+    Ok((StorageHandle::new(storage), image))
+}
+
+/// lusfファイルの内容をメモリ上に読み込み、そのストレージを開く。
+///
+/// 以降の変更はメモリ上にのみ反映され、ファイルは変更されない。
+/// `lock`が`true`の場合は、読み込みの間ファイルの共有ロックを取得する。
+pub fn load_into_memory<P: AsRef<Path>>(
+    path: P,
+    lock: bool,
+) -> Result<(StorageHandle<SharedMemoryNvm>, MemoryImage), cannyls::Error> {
+    let mut bytes = {
+        let _lock = if lock {
+            Some(track!(StorageLock::acquire(&path, LockMode::Shared))?)
+        } else {
+            None
+        };
+        track_io!(fs::read(&path))?
+    };
+    let header = track!(StorageHeader::read_from(&bytes[..]))?;
+    // lusfファイルは末尾の未書き込みの部分を持たないことがあるので、ヘッダに記載のサイズまでゼロで埋める
+    bytes.resize(header.storage_size() as usize, 0);
+
+    let nvm = SharedMemoryNvm::new(bytes);
+    let image = MemoryImage { nvm: nvm.clone() };
+    let storage = track!(StorageBuilder::new().open(nvm))?;
+    Ok((StorageHandle::new(storage), image))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::Builder;
+    use trackable::result::TestResult;
+
+    use super::*;
+
+    #[test]
+    fn load_and_save_works() -> TestResult {
+        let dir = track_io!(Builder::new().prefix("cannyls_test").tempdir())?;
+        let path = dir.path().join("test.lusf");
+
+        let (mut handle, image) = track!(create_in_memory(1024 * 1024))?;
+        track!(handle.put_str(0, "foo"))?;
+        track!(handle.embed_str(1, "bar"))?;
+        track!(handle.journal_sync())?;
+        track!(image.save_to_file(&path, true))?;
+        drop(handle);
+
+        let (mut handle, image) = track!(load_into_memory(&path, true))?;
+        assert_eq!(handle.get_as_string(0)?, Some(Some("foo".to_owned())));
+        assert_eq!(handle.get_as_string(1)?, Some(Some("bar".to_owned())));
+        track!(handle.delete_key(0))?;
+        track!(handle.journal_sync())?;
+
+        // 書き出すまではファイルは変更されない
+        let (mut loaded, _) = track!(load_into_memory(&path, true))?;
+        assert!(loaded.get_as_string(0)?.is_some());
+
+        track!(image.save_to_file(&path, true))?;
+        let mut handle = StorageHandle::create(&path);
+        assert!(handle.get_as_string(0)?.is_none());
+        assert_eq!(handle.get_as_string(1)?, Some(Some("bar".to_owned())));
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+//! cannylsが記録するメトリクス（Prometheus形式）の出力。
+//!
+//! cannylsのストレージやデバイスは、操作の回数やジャーナル・データ領域の使用量などを
+//! `prometrics`のデフォルトのレジストリに登録する。
+//! ここではそれらを収集し、人間向けの一覧や、Prometheusのテキスト形式（exposition format）で出力する。
+//! メトリクスの名前はcannylsが登録したものをそのまま用いるので、frugalosのダッシュボードと同じ名前になる。
+//!
+//! 同じプロセス内の（同じ名前とラベルを持つ）メトリクスは合算される。
+//! 破棄されたストレージのメトリクスは収集されないので、収集する時点までストレージ
+//! （または`StorageMetrics`の複製）を保持しておく必要がある。
+use prometrics::metric::{MetricFamilies, MetricKind};
+use std::fs;
+use std::path::Path;
+
+/// プロセス内で登録されている全てのメトリクスを収集する。
+pub fn gather() -> MetricFamilies {
+    prometrics::default_gatherer()
+        .lock()
+        .expect("never fails")
+        .gather()
+}
+
+/// 収集したメトリクスをPrometheusのテキスト形式で返す。
+pub fn to_prometheus_text() -> String {
+    gather().to_text()
+}
+
+/// 収集したメトリクスをPrometheusのテキスト形式でファイルに書き出す。
+pub fn write_prometheus_file<P: AsRef<Path>>(path: P) -> Result<(), cannyls::Error> {
+    track_io!(fs::write(path, to_prometheus_text()))
+}
+
+/// 収集したメトリクスを、カウンタとゲージとに分けて出力する。
+pub fn print_metrics() {
+    print!("{}", format_metrics(&gather()));
+}
+
+// `print_metrics`の出力を作る（各行は`名前{ラベル} 値`）
+fn format_metrics(families: &MetricFamilies) -> String {
+    let families = families.as_ref();
+    if families.is_empty() {
+        return "there are no metrics\n".to_owned();
+    }
+    let mut output = String::new();
+    for &(kind, title) in &[
+        (MetricKind::Counter, "counters"),
+        (MetricKind::Gauge, "gauges"),
+    ] {
+        let lines = families
+            .iter()
+            .filter(|family| family.kind() == kind)
+            .map(|family| family.metrics().to_string())
+            .collect::<String>();
+        if !lines.is_empty() {
+            output.push_str(&format!("<{}>\n{}</{}>\n", title, lines, title));
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use cannyls::lump::LumpId;
+    use cannyls::nvm::MemoryNvm;
+    use cannyls::storage::StorageBuilder;
+    use prometrics::metrics::MetricBuilder;
+    use prometrics::Gatherer;
+    use trackable::result::TestResult;
+
+    use super::*;
+
+    #[test]
+    fn format_metrics_works() -> TestResult {
+        // デフォルトのレジストリは他のテストと共有されるので、専用のレジストリを用いる
+        let mut gatherer = Gatherer::new();
+        let nvm = MemoryNvm::new(vec![0; 1024 * 1024]);
+        let mut storage = track!(StorageBuilder::new()
+            .metrics(MetricBuilder::with_registry(gatherer.registry()))
+            .create(nvm))?;
+        let data = track!(storage.allocate_lump_data_with_bytes(b"foo"))?;
+        track!(storage.put(&LumpId::new(1), &data))?;
+
+        let families = gatherer.gather();
+        let text = families.to_text();
+        assert!(text.contains("# TYPE cannyls_storage_put_lumps_total counter"));
+        assert!(text.contains("cannyls_storage_put_lumps_total{phase=\"running\"} 1"));
+
+        let output = format_metrics(&families);
+        let counters =
+            &output[output.find("<counters>").unwrap()..output.find("</counters>").unwrap()];
+        let gauges = &output[output.find("<gauges>").unwrap()..output.find("</gauges>").unwrap()];
+        assert!(counters.contains("cannyls_storage_put_lumps_total{phase=\"running\"} 1\n"));
+        assert!(gauges.contains("cannyls_data_region_capacity_bytes "));
+        assert!(!output.contains("# TYPE"));
+
+        drop(families);
+        drop(storage);
+        assert_eq!(format_metrics(&gatherer.gather()), "there are no metrics\n");
+        Ok(())
+    }
+}
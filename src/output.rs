@@ -0,0 +1,110 @@
+//! lumpを操作するコマンドの実行結果の出力。
+//!
+//! `StorageHandle`（ストレージを直接操作する）と`DeviceClient`（デバイス経由で操作する）は、
+//! 同じコマンドに対して同じ形式で出力するので、その形式をここにまとめる。
+use cannyls::lump::LumpId;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::handle::LumpKind;
+use crate::lumpid::IdFormat;
+
+/// `put`や`embed`の結果を出力する（`created`が偽ならば既存のlumpを上書きした）。
+pub fn print_stored<T: fmt::Display>(
+    id_format: IdFormat,
+    operation: &str,
+    key: u128,
+    value: T,
+    created: bool,
+) {
+    let overwrite = if created { "" } else { "[overwrite] " };
+    println!(
+        "{}{} key={}, {}",
+        overwrite,
+        operation,
+        id_format.format(key),
+        value
+    );
+}
+
+/// `get`の結果を出力する。
+///
+/// 値がUTF-8の文字列ならば文字列として、そうでなければバイト列として出力する（`hex`が真ならば常に16進数表記のバイト列）。
+/// 格納場所（`kind`）が分かっていればそれも出力する。
+pub fn print_lump(
+    id_format: IdFormat,
+    key: u128,
+    lump: Option<(&[u8], Option<LumpKind>)>,
+    hex: bool,
+) {
+    let (bytes, kind) = match lump {
+        Some(lump) => lump,
+        None => {
+            println!("no entry for the key {}", id_format.format(key));
+            return;
+        }
+    };
+    let kind = kind.map_or_else(String::new, |kind| format!(", {}", kind));
+    if hex {
+        println!(
+            "get({}-bytes data{}) [hex format] =>\n{:02x?}",
+            bytes.len(),
+            kind,
+            bytes
+        );
+    } else {
+        match std::str::from_utf8(bytes) {
+            Ok(string) => println!("get(as string{}) => {:?}", kind, string),
+            Err(_) => println!("get({}-bytes data{}) =>\n{:?}", bytes.len(), kind, bytes),
+        }
+    }
+}
+
+/// `delete`の結果を出力する。
+pub fn print_deleted(deleted: bool) {
+    println!("delete result => {:?}", deleted);
+}
+
+/// `delete_range`の結果（削除したlump）を出力する。
+pub fn print_deleted_range(id_format: IdFormat, deleted: &[LumpId]) {
+    println!(
+        "delete_range result => [{}]",
+        format_ids(id_format, deleted)
+    );
+}
+
+/// lump idの一覧を出力する。
+///
+/// `kinds`を渡した場合は、各lumpの格納場所も出力する（`kinds`に含まれないlumpは`unknown`）。
+pub fn print_lump_ids(
+    id_format: IdFormat,
+    ids: &[LumpId],
+    kinds: Option<&BTreeMap<LumpId, LumpKind>>,
+) {
+    if ids.is_empty() {
+        println!("there are no lumps");
+        return;
+    }
+    println!("<lumpid list>");
+    for lump_id in ids {
+        let id = id_format.format(lump_id.as_u128());
+        match kinds {
+            Some(kinds) => println!("{} [{}]", id, format_kind(kinds.get(lump_id).cloned())),
+            None => println!("{}", id),
+        }
+    }
+    println!("</lumpid list>");
+}
+
+/// lumpの格納場所を出力する文字列にする（分からない場合は`unknown`）。
+pub fn format_kind(kind: Option<LumpKind>) -> String {
+    kind.map_or_else(|| "unknown".to_owned(), |kind| kind.to_string())
+}
+
+/// lump idの列を`, `で区切った文字列にする。
+pub fn format_ids(id_format: IdFormat, ids: &[LumpId]) -> String {
+    ids.iter()
+        .map(|lump_id| id_format.format(lump_id.as_u128()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,242 @@
+//! lusfファイルを一切書き換えずに開くための、読み込み専用のNVM。
+//!
+//! 稼働中のfrugalosプロセスが利用しているファイルなど、書き換えてはならないlusfファイルを
+//! 調査するために用いる。
+use cannyls::block::BlockSize;
+use cannyls::nvm::NonVolatileMemory;
+use cannyls::storage::StorageHeader;
+use cannyls::ErrorKind;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// lusfファイルを読み込み専用で扱う`NonVolatileMemory`の実装。
+///
+/// ファイルは読み込み専用で開かれる。
+/// ストレージのオープン時などにcannylsが行う書き込み（例えばminor versionの更新）は
+/// ブロック単位でメモリ上にのみ保持され、以降の読み込みには反映されるがファイルには書き込まれない。
+/// `sync`は何もしない。
+#[derive(Debug)]
+pub struct ReadOnlyNvm {
+    file: Arc<File>,
+    view_start: u64,
+    capacity: u64,
+    position: u64,
+
+    // ブロックの先頭位置（このNVM内での位置） => 書き込まれたブロックの内容
+    overlay: BTreeMap<u64, Vec<u8>>,
+}
+impl ReadOnlyNvm {
+    /// lusfファイルを読み込み専用で開く。
+    ///
+    /// `FileNvm::open`と同様に、容量はファイルのヘッダに記録された値を用いる。
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, cannyls::Error> {
+        let header = track!(StorageHeader::read_from_file(&path))?;
+        let file = track_io!(File::open(&path))?;
+        Ok(ReadOnlyNvm {
+            file: Arc::new(file),
+            view_start: 0,
+            capacity: header.storage_size(),
+            position: 0,
+            overlay: BTreeMap::new(),
+        })
+    }
+
+    fn block_len(&self) -> u64 {
+        u64::from(self.block_size().as_u16())
+    }
+
+    fn check_aligned(&self, n: u64) -> Result<(), cannyls::Error> {
+        if !self.block_size().is_aligned(n) {
+            track_panic!(
+                ErrorKind::InvalidInput,
+                "{} is not aligned to the block size {}",
+                n,
+                self.block_len()
+            );
+        }
+        Ok(())
+    }
+
+    fn seek_impl(&mut self, position: u64) -> Result<(), cannyls::Error> {
+        track!(self.check_aligned(position))?;
+        self.position = position;
+        Ok(())
+    }
+
+    fn read_impl(&mut self, buf: &mut [u8]) -> Result<usize, cannyls::Error> {
+        track!(self.check_aligned(buf.len() as u64))?;
+        let len = cmp::min((self.capacity - self.position) as usize, buf.len());
+        let buf = &mut buf[..len];
+
+        let file_position = self.view_start + self.position;
+        let mut filled = 0;
+        while filled < len {
+            let read_size = track_io!(self
+                .file
+                .read_at(&mut buf[filled..], file_position + filled as u64))?;
+            if read_size == 0 {
+                break;
+            }
+            filled += read_size;
+        }
+        // ファイルの末尾より後ろ（まだ書き込まれていない部分）はゼロとして扱う
+        for b in &mut buf[filled..] {
+            *b = 0;
+        }
+
+        let end = self.position + len as u64;
+        for (&block_position, block) in self.overlay.range(self.position..end) {
+            let offset = (block_position - self.position) as usize;
+            let n = cmp::min(block.len(), len - offset);
+            buf[offset..offset + n].copy_from_slice(&block[..n]);
+        }
+        self.position = end;
+        Ok(len)
+    }
+
+    fn write_impl(&mut self, buf: &[u8]) -> Result<usize, cannyls::Error> {
+        track!(self.check_aligned(buf.len() as u64))?;
+        let len = cmp::min((self.capacity - self.position) as usize, buf.len());
+        let block_len = self.block_len();
+        for (i, block) in buf[..len].chunks(block_len as usize).enumerate() {
+            self.overlay
+                .insert(self.position + i as u64 * block_len, block.to_vec());
+        }
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+impl NonVolatileMemory for ReadOnlyNvm {
+    fn sync(&mut self) -> Result<(), cannyls::Error> {
+        Ok(())
+    }
+    fn position(&self) -> u64 {
+        self.position
+    }
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+    fn block_size(&self) -> BlockSize {
+        BlockSize::min()
+    }
+    fn split(mut self, position: u64) -> Result<(Self, Self), cannyls::Error> {
+        track!(self.check_aligned(position))?;
+        if position > self.capacity {
+            track_panic!(
+                ErrorKind::InvalidInput,
+                "split position {} exceeds the capacity {}",
+                position,
+                self.capacity
+            );
+        }
+        let right_overlay = self
+            .overlay
+            .split_off(&position)
+            .into_iter()
+            .map(|(block_position, block)| (block_position - position, block))
+            .collect();
+        let left = ReadOnlyNvm {
+            file: Arc::clone(&self.file),
+            view_start: self.view_start,
+            capacity: position,
+            position: 0,
+            overlay: self.overlay,
+        };
+        let right = ReadOnlyNvm {
+            file: self.file,
+            view_start: self.view_start + position,
+            capacity: self.capacity - position,
+            position: 0,
+            overlay: right_overlay,
+        };
+        Ok((left, right))
+    }
+}
+impl Seek for ReadOnlyNvm {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = self.convert_to_offset(pos)?;
+        track!(self.seek_impl(position))?;
+        Ok(position)
+    }
+}
+impl Read for ReadOnlyNvm {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read_size = track!(self.read_impl(buf))?;
+        Ok(read_size)
+    }
+}
+impl Write for ReadOnlyNvm {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        track!(self.write_impl(buf))?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cannyls::nvm::FileNvm;
+    use cannyls::storage::Storage;
+    use std::fs;
+    use tempfile::Builder;
+    use trackable::result::TestResult;
+
+    use super::*;
+    use crate::handle::StorageHandle;
+
+    #[test]
+    fn writes_are_kept_in_memory() -> TestResult {
+        let dir = track_io!(Builder::new().prefix("cannyls_test").tempdir())?;
+        let path = dir.path().join("test.lusf");
+        track!(FileNvm::create(&path, 4_000_000).and_then(Storage::create))?;
+        let original = track_io!(fs::read(&path))?;
+
+        let nvm = track!(ReadOnlyNvm::open(&path))?;
+        let (_, mut right) = track!(nvm.split(1024))?;
+        track_io!(right.seek(SeekFrom::Start(512)))?;
+        track_io!(right.write_all(&[1; 512]))?;
+
+        let mut buf = vec![0; 1024];
+        track_io!(right.seek(SeekFrom::Start(0)))?;
+        track_io!(right.read_exact(&mut buf))?;
+        assert_eq!(&buf[..512], &original[1024..1536]);
+        assert_eq!(&buf[512..], &[1; 512][..]);
+        assert_eq!(track_io!(fs::read(&path))?, original);
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_handle_never_modifies_the_file() -> TestResult {
+        let dir = track_io!(Builder::new().prefix("cannyls_test").tempdir())?;
+        let path = dir.path().join("test.lusf");
+        {
+            let nvm = track!(FileNvm::create(&path, 4_000_000))?;
+            let mut handle = StorageHandle::new(track!(Storage::create(nvm))?);
+            track!(handle.put_str(0, "foo"))?;
+            track!(handle.embed_str(1, "bar"))?;
+            track!(handle.journal_sync())?;
+        }
+        let original = track_io!(fs::read(&path))?;
+
+        let mut handle = track!(StorageHandle::open_read_only(&path, true))?;
+        assert!(handle.is_read_only());
+        assert_eq!(handle.get_as_string(0)?, Some(Some("foo".to_owned())));
+        assert_eq!(handle.get_as_string(1)?, Some(Some("bar".to_owned())));
+        assert_eq!(track!(handle.journal_info())?.entries.len(), 2);
+        assert!(handle.put_str(2, "baz").is_err());
+        assert!(handle.delete_key(0).is_err());
+        assert!(handle.journal_gc().is_err());
+        track!(handle.journal_sync())?;
+        drop(handle);
+
+        assert_eq!(track_io!(fs::read(&path))?, original);
+        Ok(())
+    }
+}
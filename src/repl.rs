@@ -0,0 +1,970 @@
+//! 対話モード（`Open`）やバッチモード（`Batch`）で用いるコマンド言語を扱う。
+//!
+//! 1行が1コマンドで、コマンド名と引数は空白で区切る。
+//! 空白を含む引数は`"..."`または`'...'`で囲む。`"..."`の中と引用符の外では
+//! `\n`, `\t`, `\r`, `\0`, `\\`, `\"`, `\'`, `\xNN`（ASCII）, `\u{NNNN}` のエスケープが使える。
+//!
+//! ただし`put`と`embed`の値は、引用符で始まらない場合はキーより後の入力をそのまま
+//! （空白やバックスラッシュも含めて）値とし、エスケープは解釈しない。
+use cannyls::nvm::{FileNvm, NonVolatileMemory};
+use cannyls::ErrorKind;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::{Context, Editor, Helper};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::rc::Rc;
+use trackable::error::ErrorKindExt;
+
+use crate::handle::{check_embeddable, StorageHandle};
+use crate::lumpid::{parse_lump_id, IdFormat};
+use crate::metrics;
+
+/// 対話モードのコマンドの説明。
+pub struct CommandSpec {
+    /// コマンド名
+    pub name: &'static str,
+
+    /// 引数の書式
+    pub args: &'static str,
+
+    /// コマンドの説明
+    pub description: &'static str,
+}
+
+/// 対話モードで使えるコマンドの一覧。
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "put",
+        args: "<key> <value>",
+        description: "key-value組を追加する（既にkeyが存在する場合は上書きする）",
+    },
+    CommandSpec {
+        name: "embed",
+        args: "<key> <value>",
+        description: "key-value組を「埋め込み」lumpとして追加する",
+    },
+    CommandSpec {
+        name: "get",
+        args: "<key>",
+        description: "keyに対応する値を文字列として出力する",
+    },
+    CommandSpec {
+        name: "get_bytes",
+        args: "<key>",
+        description: "keyに対応する値をバイト列（16進数）として出力する",
+    },
+    CommandSpec {
+        name: "delete",
+        args: "<key>",
+        description: "keyを削除する",
+    },
+    CommandSpec {
+        name: "delete_range",
+        args: "<start> <end>",
+        description: "区間[start, end)に含まれるkeyを全て削除する",
+    },
+    CommandSpec {
+        name: "get_range",
+        args: "<start> <end>",
+        description: "区間[start, end)に含まれるlumpを出力する",
+    },
+    CommandSpec {
+        name: "count_range",
+        args: "<start> <end> [exact]",
+        description: "区間[start, end)に含まれるlumpの数とバイト数を出力する（`exact`を指定すると各lumpを読んで正確なバイト数を求める）",
+    },
+    CommandSpec {
+        name: "list",
+        args: "",
+        description: "lump idの一覧を出力する",
+    },
+    CommandSpec {
+        name: "dump",
+        args: "",
+        description: "全lumpを出力する",
+    },
+    CommandSpec {
+        name: "header",
+        args: "",
+        description: "ヘッダ情報を出力する",
+    },
+    CommandSpec {
+        name: "journal",
+        args: "",
+        description: "ジャーナル領域の内容を出力する",
+    },
+    CommandSpec {
+        name: "metrics",
+        args: "[prometheus]",
+        description: "cannylsが記録するメトリクスを出力する（`prometheus`を指定するとPrometheusのテキスト形式で出力する）",
+    },
+    CommandSpec {
+        name: "journal_gc",
+        args: "",
+        description: "ジャーナル領域に対してfull GCを行う",
+    },
+    CommandSpec {
+        name: "sync",
+        args: "",
+        description: "ジャーナルをディスクに同期する",
+    },
+    CommandSpec {
+        name: "begin",
+        args: "",
+        description: "トランザクションを開始する（以降の変更はcommitまで保留される）",
+    },
+    CommandSpec {
+        name: "pending",
+        args: "",
+        description: "トランザクションで保留中の変更を出力する",
+    },
+    CommandSpec {
+        name: "commit",
+        args: "",
+        description: "保留中の変更をまとめて適用し、ジャーナルを同期する",
+    },
+    CommandSpec {
+        name: "abort",
+        args: "",
+        description: "保留中の変更を破棄してトランザクションを終了する",
+    },
+    CommandSpec {
+        name: "autosync",
+        args: "[on|off]",
+        description:
+            "変更の度にジャーナルを同期するかどうかを切り替える（引数なしで現在の設定を出力）",
+    },
+    CommandSpec {
+        name: "id_format",
+        args: "<dec|hex|both>",
+        description: "出力するlump idの形式を切り替える",
+    },
+    CommandSpec {
+        name: "help",
+        args: "[command]",
+        description: "コマンドの一覧、または指定したコマンドの説明を出力する",
+    },
+    CommandSpec {
+        name: "quit",
+        args: "",
+        description: "対話モードを終了する（`exit`でも良い。保留中の変更は破棄される）",
+    },
+];
+
+/// 読み込み専用モードでは使えないコマンドの一覧。
+const READ_WRITE_COMMANDS: &[&str] = &[
+    "put",
+    "embed",
+    "delete",
+    "delete_range",
+    "journal_gc",
+    "begin",
+    "commit",
+];
+
+fn find_command(name: &str) -> Option<&'static CommandSpec> {
+    let name = if name == "exit" { "quit" } else { name };
+    COMMANDS.iter().find(|c| c.name == name)
+}
+
+/// コマンド実行後にどうするか。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    /// 次のコマンドを受け付ける
+    Continue,
+
+    /// 終了する
+    Quit,
+}
+
+fn invalid_input(message: String) -> cannyls::Error {
+    cannyls::Error::from(ErrorKind::InvalidInput.cause(message))
+}
+
+fn read_hex_digits<I: Iterator<Item = char>>(chars: &mut I, n: usize) -> Option<u32> {
+    let digits = chars.take(n).collect::<String>();
+    if digits.len() != n {
+        return None;
+    }
+    u32::from_str_radix(&digits, 16).ok()
+}
+
+// `\`の直後の文字列を解釈し、エスケープされた文字を返す
+fn read_escape<I: Iterator<Item = char>>(chars: &mut I) -> Option<char> {
+    match chars.next()? {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        '0' => Some('\0'),
+        'x' => read_hex_digits(chars, 2)
+            .filter(|&c| c < 0x80)
+            .and_then(std::char::from_u32),
+        'u' => {
+            if chars.next()? != '{' {
+                return None;
+            }
+            let digits = chars.take_while(|&c| c != '}').collect::<String>();
+            u32::from_str_radix(&digits, 16)
+                .ok()
+                .and_then(std::char::from_u32)
+        }
+        c => Some(c),
+    }
+}
+
+// `input`の先頭の単語を、引用符とエスケープを解釈しながら読み込み、単語と残りの入力を返す
+fn next_token(input: &str) -> Result<Option<(String, &str)>, cannyls::Error> {
+    let invalid_escape = || track!(invalid_input("invalid escape sequence".to_owned()));
+    let input = input.trim_start();
+    if input.is_empty() {
+        return Ok(None);
+    }
+
+    let mut token = String::new();
+    let mut chars = input.char_indices();
+    let mut rest = "";
+    while let Some((i, c)) = chars.next() {
+        let mut chars = chars.by_ref().map(|(_, c)| c);
+        match c {
+            c if c.is_whitespace() => {
+                rest = &input[i..];
+                break;
+            }
+            '\\' => token.push(read_escape(&mut chars).ok_or_else(invalid_escape)?),
+            '\'' => loop {
+                match chars.next() {
+                    Some('\'') => break,
+                    Some(c) => token.push(c),
+                    None => track_panic!(ErrorKind::InvalidInput, "unterminated single quote"),
+                }
+            },
+            '"' => loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => token.push(read_escape(&mut chars).ok_or_else(invalid_escape)?),
+                    Some(c) => token.push(c),
+                    None => track_panic!(ErrorKind::InvalidInput, "unterminated double quote"),
+                }
+            },
+            c => token.push(c),
+        }
+    }
+    Ok(Some((token, rest)))
+}
+
+/// 1行分の入力を、引用符とエスケープを解釈しながら単語に分割する。
+pub fn tokenize(mut input: &str) -> Result<Vec<String>, cannyls::Error> {
+    let mut tokens = Vec::new();
+    while let Some((token, rest)) = track!(next_token(input))? {
+        tokens.push(token);
+        input = rest;
+    }
+    Ok(tokens)
+}
+
+fn parse_key(input: &str) -> Result<u128, cannyls::Error> {
+    parse_lump_id(input).map_err(|e| track!(invalid_input(e.to_string())))
+}
+
+// `put`や`embed`の引数（キーと値）を読み込む
+//
+// 値が引用符で始まる場合は、引用符とエスケープを解釈した一つの単語を値とする。
+// それ以外の場合は、キーより後の入力をそのまま（空白やバックスラッシュも含めて）値とする。
+fn parse_key_value(spec: &CommandSpec, input: &str) -> Result<(u128, String), cannyls::Error> {
+    let usage = || track!(invalid_input(format!("usage: {} {}", spec.name, spec.args)));
+    let (key, rest) = track!(next_token(input))?.ok_or_else(usage)?;
+    let key = track!(parse_key(&key))?;
+    let rest = rest.trim_start();
+    if rest.is_empty() {
+        return Err(usage());
+    }
+    if !rest.starts_with('"') && !rest.starts_with('\'') {
+        return Ok((key, rest.to_owned()));
+    }
+    let mut tokens = track!(tokenize(rest))?;
+    if tokens.len() != 1 {
+        return Err(usage());
+    }
+    Ok((key, tokens.remove(0)))
+}
+
+fn check_arity(
+    spec: &CommandSpec,
+    args: &[String],
+    min: usize,
+    max: usize,
+) -> Result<(), cannyls::Error> {
+    if args.len() < min || args.len() > max {
+        track_panic!(
+            ErrorKind::InvalidInput,
+            "usage: {} {}",
+            spec.name,
+            spec.args
+        );
+    }
+    Ok(())
+}
+
+/// `help`コマンドの出力を行う。
+pub fn print_help(command: Option<&str>) -> Result<(), cannyls::Error> {
+    if let Some(name) = command {
+        let spec = match find_command(name) {
+            Some(spec) => spec,
+            None => track_panic!(ErrorKind::InvalidInput, "unknown command `{}`", name),
+        };
+        println!("{} {}", spec.name, spec.args);
+        println!("    {}", spec.description);
+    } else {
+        println!("<commands>");
+        for spec in COMMANDS {
+            let usage = format!("{} {}", spec.name, spec.args);
+            println!("  {:<28} {}", usage.trim_end(), spec.description);
+        }
+        println!("</commands>");
+        println!("unquoted values are stored verbatim: put 1 hello  world");
+        println!("quoted values can contain escapes: put 1 \"hello world\\n\"");
+    }
+    Ok(())
+}
+
+/// トランザクション中に保留されている変更。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PendingChange {
+    Put { key: u128, value: String },
+    Embed { key: u128, value: String },
+    Delete { key: u128 },
+    DeleteRange { start: u128, end: u128 },
+}
+impl PendingChange {
+    fn describe(&self, id_format: IdFormat) -> String {
+        match *self {
+            PendingChange::Put { key, ref value } => {
+                format!("put key={}, value={}", id_format.format(key), value)
+            }
+            PendingChange::Embed { key, ref value } => {
+                format!("embed key={}, value={}", id_format.format(key), value)
+            }
+            PendingChange::Delete { key } => format!("delete key={}", id_format.format(key)),
+            PendingChange::DeleteRange { start, end } => format!(
+                "delete_range start={}, end={}",
+                id_format.format(start),
+                id_format.format(end)
+            ),
+        }
+    }
+
+    fn apply<N: NonVolatileMemory>(
+        &self,
+        handle: &mut StorageHandle<N>,
+    ) -> Result<(), cannyls::Error> {
+        match *self {
+            PendingChange::Put { key, ref value } => track!(handle.put(key, value)),
+            PendingChange::Embed { key, ref value } => track!(handle.embed(key, value)),
+            PendingChange::Delete { key } => track!(handle.delete(key)),
+            PendingChange::DeleteRange { start, end } => track!(handle.delete_range(start, end)),
+        }
+    }
+
+    // 適用した変更を`lump_ids`に反映する
+    fn update_lump_ids(&self, lump_ids: &mut BTreeSet<u128>) {
+        match *self {
+            PendingChange::Put { key, .. } | PendingChange::Embed { key, .. } => {
+                lump_ids.insert(key);
+            }
+            PendingChange::Delete { key } => {
+                lump_ids.remove(&key);
+            }
+            PendingChange::DeleteRange { start, end } if start < end => {
+                let deleted = lump_ids.range(start..end).cloned().collect::<Vec<_>>();
+                for key in deleted {
+                    lump_ids.remove(&key);
+                }
+            }
+            PendingChange::DeleteRange { .. } => {}
+        }
+    }
+}
+
+/// 補完候補とするlump idの集合（`Session`と`ReplHelper`で共有する）。
+pub type SharedLumpIds = Rc<RefCell<BTreeSet<u128>>>;
+
+/// 対話モードやバッチモードのセッション。
+///
+/// ストレージに加えて、トランザクションで保留中の変更と`autosync`の設定を保持する。
+///
+/// - `begin`を実行すると、以降の変更系コマンド（`put`, `embed`, `delete`, `delete_range`）は
+///   ストレージには反映されずに保留される
+/// - `commit`で保留中の変更を順に適用し、最後に`journal_sync`を行う
+/// - `abort`で保留中の変更を破棄する
+/// - `autosync on`の場合、トランザクション外の変更系コマンドの度に`journal_sync`を行う
+///
+/// cannylsには複数の操作をまとめてアトミックに適用する仕組みはないため、
+/// `commit`の途中で失敗した場合にはそれ以前の変更は適用されたままとなる。
+///
+/// ストレージが読み込み専用モードで開かれている場合には、変更系のコマンドと
+/// トランザクション関連のコマンドは受け付けない。
+pub struct Session<N: NonVolatileMemory = FileNvm> {
+    handle: StorageHandle<N>,
+    pending: Option<Vec<PendingChange>>,
+    autosync: bool,
+    lump_ids: Option<SharedLumpIds>,
+}
+impl<N: NonVolatileMemory> Session<N> {
+    pub fn new(handle: StorageHandle<N>) -> Self {
+        Session {
+            handle,
+            pending: None,
+            autosync: false,
+            lump_ids: None,
+        }
+    }
+
+    /// ストレージに存在するlump idの集合を返す。
+    ///
+    /// 初回の呼び出し時にだけストレージから一覧を取得し、以降はこのセッションで適用した変更
+    /// （`put`, `embed`, `delete`, `delete_range`）の度に更新する。
+    /// `handle_mut`経由で直接行った変更は反映されない。
+    pub fn lump_ids(&mut self) -> SharedLumpIds {
+        let handle = &self.handle;
+        self.lump_ids
+            .get_or_insert_with(|| {
+                let ids = handle.all_keys().iter().map(|id| id.as_u128()).collect();
+                Rc::new(RefCell::new(ids))
+            })
+            .clone()
+    }
+
+    // 変更をストレージに適用し、lump idの集合にも反映する
+    fn apply(&mut self, change: &PendingChange) -> Result<(), cannyls::Error> {
+        track!(change.apply(&mut self.handle))?;
+        if let Some(ref lump_ids) = self.lump_ids {
+            change.update_lump_ids(&mut lump_ids.borrow_mut());
+        }
+        Ok(())
+    }
+
+    pub fn handle(&self) -> &StorageHandle<N> {
+        &self.handle
+    }
+
+    pub fn handle_mut(&mut self) -> &mut StorageHandle<N> {
+        &mut self.handle
+    }
+
+    /// セッションを終了する。
+    ///
+    /// トランザクション中であれば保留中の変更を破棄し、破棄した変更の数を出力する。
+    /// `quit`コマンドに加えて、対話モードでのCtrl-CやCtrl-Dによる終了でも用いる。
+    pub fn quit(&mut self) -> Control {
+        if let Some(pending) = self.pending.take() {
+            println!("discarded {} pending change(s)", pending.len());
+        }
+        Control::Quit
+    }
+
+    /// トランザクション中であれば、保留中の変更数を返す。
+    pub fn pending_changes(&self) -> Option<usize> {
+        self.pending.as_ref().map(Vec::len)
+    }
+
+    fn change(&mut self, change: PendingChange) -> Result<(), cannyls::Error> {
+        if let PendingChange::Embed { ref value, .. } = change {
+            track!(check_embeddable(value.as_bytes()))?;
+        }
+        if let Some(ref mut pending) = self.pending {
+            println!("staged: {}", change.describe(self.handle.id_format()));
+            pending.push(change);
+            return Ok(());
+        }
+        track!(self.apply(&change))?;
+        if self.autosync {
+            track!(self.handle.journal_sync())?;
+        }
+        Ok(())
+    }
+
+    fn print_pending(&self) {
+        match self.pending {
+            None => println!("not in a transaction"),
+            Some(ref pending) if pending.is_empty() => println!("there are no pending changes"),
+            Some(ref pending) => {
+                println!("<pending changes>");
+                for (i, change) in pending.iter().enumerate() {
+                    println!("[{}] {}", i, change.describe(self.handle.id_format()));
+                }
+                println!("</pending changes>");
+            }
+        }
+    }
+
+    fn commit(&mut self) -> Result<(), cannyls::Error> {
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => track_panic!(
+                ErrorKind::InvalidInput,
+                "not in a transaction (use `begin` first)"
+            ),
+        };
+        for (i, change) in pending.iter().enumerate() {
+            if let Err(e) = track!(self.apply(change)) {
+                println!(
+                    "commit failed at change [{}]; {} earlier change(s) have been applied and {} discarded",
+                    i,
+                    i,
+                    pending.len() - i
+                );
+                let _ = self.handle.journal_sync();
+                return Err(e);
+            }
+        }
+        track!(self.handle.journal_sync())?;
+        println!(
+            "committed {} change(s) and synced the journal",
+            pending.len()
+        );
+        Ok(())
+    }
+
+    /// 1行分のコマンドを解釈して実行する。
+    ///
+    /// コマンドが不正な場合には`ErrorKind::InvalidInput`のエラーを返す。
+    pub fn handle_input(&mut self, input: &str) -> Result<Control, cannyls::Error> {
+        let (name, rest) = match track!(next_token(input))? {
+            Some(token) => token,
+            None => return Ok(Control::Continue),
+        };
+        let spec = match find_command(&name) {
+            Some(spec) => spec,
+            None => track_panic!(
+                ErrorKind::InvalidInput,
+                "`{}` is an invalid command (type `help` to list commands)",
+                name
+            ),
+        };
+        if self.handle.is_read_only() && READ_WRITE_COMMANDS.contains(&spec.name) {
+            track_panic!(
+                ErrorKind::InvalidInput,
+                "`{}` is not available because the storage is opened in read-only mode",
+                spec.name
+            );
+        }
+
+        // `put`と`embed`の値は残りの入力をそのまま用いるので、それ以外のコマンドの引数だけを単語に分割する
+        let args = &if spec.name == "put" || spec.name == "embed" {
+            Vec::new()
+        } else {
+            track!(tokenize(rest))?
+        };
+        let handle = &mut self.handle;
+        match spec.name {
+            "put" | "embed" => {
+                let (key, value) = track!(parse_key_value(spec, rest))?;
+                if spec.name == "put" {
+                    track!(self.change(PendingChange::Put { key, value }))?;
+                } else {
+                    track!(self.change(PendingChange::Embed { key, value }))?;
+                }
+            }
+            "get" | "get_bytes" => {
+                track!(check_arity(spec, args, 1, 1))?;
+                let key = track!(parse_key(&args[0]))?;
+                if spec.name == "get" {
+                    track!(handle.get(key))?;
+                } else {
+                    track!(handle.print_as_bytes(key))?;
+                }
+            }
+            "delete" => {
+                track!(check_arity(spec, args, 1, 1))?;
+                let key = track!(parse_key(&args[0]))?;
+                track!(self.change(PendingChange::Delete { key }))?;
+            }
+            "delete_range" => {
+                track!(check_arity(spec, args, 2, 2))?;
+                let start = track!(parse_key(&args[0]))?;
+                let end = track!(parse_key(&args[1]))?;
+                track!(self.change(PendingChange::DeleteRange { start, end }))?;
+            }
+            "get_range" => {
+                track!(check_arity(spec, args, 2, 2))?;
+                let start = track!(parse_key(&args[0]))?;
+                let end = track!(parse_key(&args[1]))?;
+                track!(handle.print_range(start, end))?;
+            }
+            "count_range" => {
+                track!(check_arity(spec, args, 2, 3))?;
+                let start = track!(parse_key(&args[0]))?;
+                let end = track!(parse_key(&args[1]))?;
+                let exact = match args.get(2).map(String::as_str) {
+                    None => false,
+                    Some("exact") => true,
+                    Some(other) => track_panic!(
+                        ErrorKind::InvalidInput,
+                        "invalid argument `{}` (expected `exact`)",
+                        other
+                    ),
+                };
+                track!(handle.print_range_count(start, end, exact))?;
+            }
+            "list" => {
+                track!(check_arity(spec, args, 0, 0))?;
+                track!(handle.print_list_of_lumpids())?;
+            }
+            "dump" => {
+                track!(check_arity(spec, args, 0, 0))?;
+                track!(handle.print_all_key_value_pairs())?;
+            }
+            "header" => {
+                track!(check_arity(spec, args, 0, 0))?;
+                handle.print_header_info();
+            }
+            "journal" => {
+                track!(check_arity(spec, args, 0, 0))?;
+                track!(handle.print_journal_info())?;
+            }
+            "metrics" => {
+                track!(check_arity(spec, args, 0, 1))?;
+                match args.first().map(String::as_str) {
+                    None => metrics::print_metrics(),
+                    Some("prometheus") => print!("{}", metrics::to_prometheus_text()),
+                    Some(other) => track_panic!(
+                        ErrorKind::InvalidInput,
+                        "invalid argument `{}` (expected `prometheus`)",
+                        other
+                    ),
+                }
+            }
+            "journal_gc" => {
+                track!(check_arity(spec, args, 0, 0))?;
+                track!(handle.journal_gc())?;
+            }
+            "sync" => {
+                track!(check_arity(spec, args, 0, 0))?;
+                track!(handle.journal_sync())?;
+                println!("journal synced");
+            }
+            "begin" => {
+                track!(check_arity(spec, args, 0, 0))?;
+                if self.pending.is_some() {
+                    track_panic!(
+                        ErrorKind::InvalidInput,
+                        "already in a transaction (use `commit` or `abort` first)"
+                    );
+                }
+                self.pending = Some(Vec::new());
+                println!("transaction started");
+            }
+            "pending" => {
+                track!(check_arity(spec, args, 0, 0))?;
+                self.print_pending();
+            }
+            "commit" => {
+                track!(check_arity(spec, args, 0, 0))?;
+                track!(self.commit())?;
+            }
+            "abort" => {
+                track!(check_arity(spec, args, 0, 0))?;
+                let pending = match self.pending.take() {
+                    Some(pending) => pending,
+                    None => track_panic!(ErrorKind::InvalidInput, "not in a transaction"),
+                };
+                println!("discarded {} pending change(s)", pending.len());
+            }
+            "autosync" => {
+                track!(check_arity(spec, args, 0, 1))?;
+                match args.first().map(String::as_str) {
+                    None => {}
+                    Some("on") => self.autosync = true,
+                    Some("off") => self.autosync = false,
+                    Some(other) => track_panic!(
+                        ErrorKind::InvalidInput,
+                        "invalid argument `{}` (expected `on` or `off`)",
+                        other
+                    ),
+                }
+                println!("autosync = {}", if self.autosync { "on" } else { "off" });
+            }
+            "id_format" => {
+                track!(check_arity(spec, args, 1, 1))?;
+                let id_format = track!(args[0].parse::<IdFormat>().map_err(invalid_input))?;
+                handle.set_id_format(id_format);
+            }
+            "help" => {
+                track!(check_arity(spec, args, 0, 1))?;
+                track!(print_help(args.first().map(String::as_str)))?;
+            }
+            "quit" => {
+                track!(check_arity(spec, args, 0, 0))?;
+                return Ok(self.quit());
+            }
+            _ => unreachable!("{}", spec.name),
+        }
+        Ok(Control::Continue)
+    }
+}
+
+/// 対話モードやバッチモードで表示するためにエラーを整形する。
+///
+/// 入力の誤りについては原因のみを、それ以外のエラーについては履歴も含めて表示する。
+#[allow(deprecated)]
+pub fn describe_error(error: &cannyls::Error) -> String {
+    if *error.kind() == ErrorKind::InvalidInput {
+        // trackableのエラーは原因を`source`ではなく`cause`でのみ返す
+        if let Some(cause) = std::error::Error::cause(&**error) {
+            return cause.to_string();
+        }
+    }
+    error.to_string()
+}
+
+/// 対話モードでの補完とヒントの表示を行う`rustyline`のヘルパー。
+///
+/// コマンド名と、ストレージに存在するlump idを補完する。
+/// lump idの集合は`Session::lump_ids`と共有し、プロンプトの度に一覧を取り直すことはしない。
+#[derive(Debug, Default)]
+pub struct ReplHelper {
+    lump_ids: SharedLumpIds,
+}
+impl ReplHelper {
+    pub fn new(lump_ids: SharedLumpIds) -> Self {
+        ReplHelper { lump_ids }
+    }
+
+    // `words`に続く単語`word`の補完候補を返す
+    fn complete_word(&self, words: &[&str], word: &str) -> Vec<String> {
+        let complete_from = |candidates: &mut dyn Iterator<Item = String>| {
+            candidates
+                .filter(|c| c.starts_with(word))
+                .collect::<Vec<_>>()
+        };
+        if words.is_empty() {
+            return complete_from(&mut COMMANDS.iter().map(|c| c.name.to_owned()));
+        }
+        let spec = match find_command(words[0]) {
+            Some(spec) => spec,
+            None => return Vec::new(),
+        };
+        match spec.args.split_whitespace().nth(words.len() - 1) {
+            Some("<key>") | Some("<start>") | Some("<end>") => {
+                let hex = word.starts_with("0x") || word.starts_with("0X");
+                complete_from(&mut self.lump_ids.borrow().iter().map(|id| {
+                    if hex {
+                        format!("{}{:x}", &word[..2], id)
+                    } else {
+                        id.to_string()
+                    }
+                }))
+            }
+            Some("[command]") => complete_from(&mut COMMANDS.iter().map(|c| c.name.to_owned())),
+            Some("[on|off]") => complete_from(&mut ["on", "off"].iter().map(|&v| v.to_owned())),
+            Some("[exact]") => complete_from(&mut ["exact"].iter().map(|&v| v.to_owned())),
+            Some("<dec|hex|both>") => {
+                complete_from(&mut IdFormat::VARIANTS.iter().map(|&v| v.to_owned()))
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let start = line.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let words = line[..start].split_whitespace().collect::<Vec<_>>();
+        Ok((start, self.complete_word(&words, &line[start..])))
+    }
+}
+impl Hinter for ReplHelper {
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<String> {
+        if pos < line.len() || !line.ends_with(' ') {
+            return None;
+        }
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let spec = find_command(words.first()?)?;
+        let remaining = spec
+            .args
+            .split_whitespace()
+            .skip(words.len() - 1)
+            .collect::<Vec<_>>();
+        if remaining.is_empty() {
+            None
+        } else {
+            Some(remaining.join(" "))
+        }
+    }
+}
+impl Highlighter for ReplHelper {}
+impl Helper for ReplHelper {}
+
+/// 対話モードを実行する。
+///
+/// `history_path`が指定された場合は、そのファイルから入力履歴を読み込み、終了時に書き戻す。
+pub fn run_repl<N: NonVolatileMemory>(session: &mut Session<N>, history_path: Option<&Path>) {
+    let mut rl = Editor::<ReplHelper>::new();
+    rl.set_helper(Some(ReplHelper::new(session.lump_ids())));
+    if let Some(path) = history_path {
+        // 初回起動時には履歴ファイルが存在しないので、読み込みの失敗は無視する
+        let _ = rl.load_history(path);
+    }
+    loop {
+        let prompt = match session.pending_changes() {
+            Some(n) => format!("tx({})>> ", n),
+            None if session.handle().is_read_only() => "(read-only)>> ".to_owned(),
+            None => ">> ".to_owned(),
+        };
+        match rl.readline(&prompt) {
+            Ok(line) => {
+                rl.add_history_entry(line.as_str());
+                match session.handle_input(&line) {
+                    Ok(Control::Continue) => {}
+                    Ok(Control::Quit) => break,
+                    Err(e) => println!("error: {}", describe_error(&e)),
+                }
+            }
+            Err(ReadlineError::Interrupted) => {
+                println!("CTRL-C");
+                session.quit();
+                break;
+            }
+            Err(ReadlineError::Eof) => {
+                println!("CTRL-D");
+                session.quit();
+                break;
+            }
+            Err(err) => {
+                println!("Error: {:?}", err);
+                break;
+            }
+        }
+    }
+    if let Some(path) = history_path {
+        if let Err(e) = rl.save_history(path) {
+            println!("failed to save the history to {:?}: {}", path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cannyls::nvm::MemoryNvm;
+    use cannyls::storage::Storage;
+    use trackable::result::TestResult;
+
+    use super::*;
+
+    #[test]
+    fn tokenize_works() -> Result<(), cannyls::Error> {
+        assert_eq!(tokenize("put 1 foo")?, ["put", "1", "foo"]);
+        assert_eq!(tokenize("  put   1  \"a b\"  ")?, ["put", "1", "a b"]);
+        assert_eq!(
+            tokenize(r#"put 1 'a "b" \n'"#)?,
+            ["put", "1", r#"a "b" \n"#]
+        );
+        assert_eq!(
+            tokenize(r#"put 1 "a\tb\n\x41\u{1F980}""#)?,
+            ["put", "1", "a\tb\nA🦀"]
+        );
+        assert_eq!(
+            tokenize(r"put 1 hello\ world")?,
+            ["put", "1", "hello world"]
+        );
+        assert_eq!(tokenize(r#"put 1 """#)?, ["put", "1", ""]);
+        assert!(tokenize("put 1 \"abc").is_err());
+        assert!(tokenize(r#"put 1 "\xff""#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn put_stores_unquoted_values_verbatim() -> TestResult {
+        let storage = track!(Storage::create(MemoryNvm::new(vec![0; 1024 * 1024])))?;
+        let mut session = Session::new(StorageHandle::new(storage));
+        let get = |session: &mut Session<MemoryNvm>, key| {
+            track!(session.handle_mut().get_as_string(key)).map(|v| v.unwrap().unwrap())
+        };
+
+        track!(session.handle_input(r"put 1 hello  world \n it's"))?;
+        assert_eq!(get(&mut session, 1)?, r"hello  world \n it's");
+        track!(session.handle_input("embed 0x2   a\tb "))?;
+        assert_eq!(get(&mut session, 2)?, "a\tb ");
+        track!(session.handle_input(r#"put 3 "a  b\n""#))?;
+        assert_eq!(get(&mut session, 3)?, "a  b\n");
+        track!(session.handle_input(r#"put 4 'x' "#))?;
+        assert_eq!(get(&mut session, 4)?, "x");
+
+        assert!(session.handle_input("put 5").is_err());
+        assert!(session.handle_input("put 5 \"a\" b").is_err());
+        assert!(session.handle_input("put 5 \"a").is_err());
+        assert!(!session.handle().contains_key(5));
+        Ok(())
+    }
+
+    #[test]
+    fn session_keeps_lump_ids_up_to_date() -> TestResult {
+        let storage = track!(Storage::create(MemoryNvm::new(vec![0; 1024 * 1024])))?;
+        let mut handle = StorageHandle::new(storage);
+        track!(handle.put(1, "foo"))?;
+        let mut session = Session::new(handle);
+        let lump_ids = session.lump_ids();
+        let ids = || lump_ids.borrow().iter().cloned().collect::<Vec<_>>();
+        assert_eq!(ids(), [1]);
+
+        track!(session.handle_input("put 5 bar"))?;
+        track!(session.handle_input("embed 3 baz"))?;
+        track!(session.handle_input("put 9 qux"))?;
+        assert_eq!(ids(), [1, 3, 5, 9]);
+        track!(session.handle_input("delete 1"))?;
+        track!(session.handle_input("delete_range 2 6"))?;
+        assert_eq!(ids(), [9]);
+
+        track!(session.handle_input("begin"))?;
+        track!(session.handle_input("put 7 foo"))?;
+        assert_eq!(ids(), [9]);
+        track!(session.handle_input("commit"))?;
+        assert_eq!(ids(), [7, 9]);
+        Ok(())
+    }
+
+    #[test]
+    fn quit_discards_pending_changes() -> TestResult {
+        let storage = track!(Storage::create(MemoryNvm::new(vec![0; 1024 * 1024])))?;
+        let mut session = Session::new(StorageHandle::new(storage));
+        track!(session.handle_input("begin"))?;
+        track!(session.handle_input("put 1 foo"))?;
+        assert_eq!(session.pending_changes(), Some(1));
+
+        assert_eq!(session.quit(), Control::Quit);
+        assert_eq!(session.pending_changes(), None);
+        assert!(!session.handle().contains_key(1));
+        Ok(())
+    }
+
+    #[test]
+    fn helper_completes_commands_and_lump_ids() {
+        let lump_ids = Rc::new(RefCell::new(vec![10, 42, 420].into_iter().collect()));
+        let helper = ReplHelper::new(lump_ids);
+        assert_eq!(
+            helper.complete_word(&[], "get"),
+            ["get", "get_bytes", "get_range"]
+        );
+        assert_eq!(helper.complete_word(&["get"], "4"), ["42", "420"]);
+        assert_eq!(
+            helper.complete_word(&["delete_range", "1"], "0x1"),
+            ["0x1a4"]
+        );
+        assert_eq!(
+            helper.complete_word(&["help"], "jo"),
+            ["journal", "journal_gc"]
+        );
+        assert!(helper.complete_word(&["put", "42"], "").is_empty());
+    }
+}
@@ -0,0 +1,526 @@
+//! ベンチマーク結果の集計と出力。
+//!
+//! 操作（put, get, journal_syncなど）ごとにレイテンシのヒストグラムとスループットを集計し、
+//! 表形式で標準出力に出力したり、CSVまたはJSONのファイルに書き出したりする。
+use cannyls::metrics::StorageMetrics;
+use cannyls::nvm::NonVolatileMemory;
+use cannyls::storage::Storage;
+use cannyls::ErrorKind;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::duration::format_duration;
+use crate::histogram::Histogram;
+
+/// リンクしているcannylsのバージョン（`build.rs`が`cargo metadata`から求める。求められなかった場合は`unknown`）
+pub const CANNYLS_VERSION: &str = env!("KANILS_CANNYLS_VERSION");
+
+/// 出力するパーセンタイル（表示名, 値）
+///
+/// JSONでは`LatencyRecord`のフィールドになるので、変更する場合はそちらも合わせる。
+const PERCENTILES: [(&str, f64); 4] =
+    [("p50", 50.0), ("p90", 90.0), ("p99", 99.0), ("p99.9", 99.9)];
+
+/// 一種類の操作の集計結果。
+#[derive(Debug, Clone)]
+pub struct OpStats {
+    /// 操作の名前（例: `put`）
+    pub op: String,
+
+    /// 読み書きしたデータの合計バイト数
+    pub bytes: u64,
+
+    /// 操作の開始から完了までのレイテンシ
+    pub latency: Histogram,
+
+    /// `Device`経由の場合の、キューで待っていた時間
+    pub queueing: Option<Histogram>,
+
+    /// `Device`経由の場合の、実行に要した時間
+    pub execution: Option<Histogram>,
+}
+impl OpStats {
+    fn new(op: &str) -> Self {
+        OpStats {
+            op: op.to_owned(),
+            bytes: 0,
+            latency: Histogram::new(),
+            queueing: None,
+            execution: None,
+        }
+    }
+
+    /// 操作の回数
+    pub fn count(&self) -> u64 {
+        self.latency.count()
+    }
+
+    /// 1秒あたりの操作回数
+    pub fn ops_per_sec(&self, elapsed: Duration) -> f64 {
+        per_sec(self.count() as f64, elapsed)
+    }
+
+    /// 1秒あたりの読み書きのメガバイト数（1MB = 1,000,000バイト）
+    pub fn mb_per_sec(&self, elapsed: Duration) -> f64 {
+        per_sec(self.bytes as f64 / 1_000_000.0, elapsed)
+    }
+
+    // (名前, ヒストグラム)の一覧（`Device`経由の場合はキュー待ちと実行時間を含む）
+    fn histograms(&self) -> Vec<(&'static str, &Histogram)> {
+        let mut histograms = vec![("total", &self.latency)];
+        if let Some(ref h) = self.queueing {
+            histograms.push(("queueing", h));
+        }
+        if let Some(ref h) = self.execution {
+            histograms.push(("execution", h));
+        }
+        histograms
+    }
+
+    fn merge(&mut self, other: &OpStats) {
+        self.bytes += other.bytes;
+        self.latency.merge(&other.latency);
+        merge_optional(&mut self.queueing, &other.queueing);
+        merge_optional(&mut self.execution, &other.execution);
+    }
+}
+
+fn merge_optional(dst: &mut Option<Histogram>, src: &Option<Histogram>) {
+    if let Some(ref src) = *src {
+        dst.get_or_insert_with(Histogram::new).merge(src);
+    }
+}
+
+fn per_sec(amount: f64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs == 0.0 {
+        0.0
+    } else {
+        amount / secs
+    }
+}
+
+/// ベンチマークの一つのフェーズ（例: データの投入、ランダムな読み込み）の集計結果。
+#[derive(Debug, Clone)]
+pub struct PhaseResult {
+    /// フェーズの名前
+    pub name: String,
+
+    /// フェーズ全体の経過時間（スループットの計算に用いる）
+    pub elapsed: Duration,
+
+    /// 操作ごとの集計結果（最初に記録された順）
+    pub ops: Vec<OpStats>,
+}
+impl PhaseResult {
+    /// 結果を表形式で標準出力に出力する。
+    pub fn print(&self) {
+        let total = self.ops.iter().map(OpStats::count).sum::<u64>();
+        println!(
+            "[{}] {} ops in {}",
+            self.name,
+            total,
+            format_duration(self.elapsed)
+        );
+        let mut header = format!(
+            "  {:<22} {:>9} {:>11} {:>9} {:>10}",
+            "op", "count", "ops/s", "MB/s", "avg"
+        );
+        for (name, _) in PERCENTILES.iter() {
+            header.push_str(&format!(" {:>10}", name));
+        }
+        header.push_str(&format!(" {:>10}", "max"));
+        println!("{}", header);
+        for stats in &self.ops {
+            for (kind, histogram) in stats.histograms() {
+                let mut line = if kind == "total" {
+                    format!(
+                        "  {:<22} {:>9} {:>11.1} {:>9.2}",
+                        stats.op,
+                        stats.count(),
+                        stats.ops_per_sec(self.elapsed),
+                        stats.mb_per_sec(self.elapsed)
+                    )
+                } else {
+                    format!(
+                        "  {:<22} {:>9} {:>11} {:>9}",
+                        format!("{} ({})", stats.op, kind),
+                        "",
+                        "",
+                        ""
+                    )
+                };
+                line.push_str(&format!(" {:>10}", format_duration(histogram.mean())));
+                for &(_, p) in PERCENTILES.iter() {
+                    line.push_str(&format!(
+                        " {:>10}",
+                        format_duration(histogram.percentile(p))
+                    ));
+                }
+                line.push_str(&format!(" {:>10}", format_duration(histogram.max())));
+                println!("{}", line);
+            }
+        }
+    }
+}
+
+/// フェーズの実行中に、操作ごとのレイテンシを記録する。
+#[derive(Debug)]
+pub struct PhaseRecorder {
+    name: String,
+    started: Instant,
+    ops: Vec<OpStats>,
+}
+impl PhaseRecorder {
+    /// フェーズを開始する（経過時間の計測を始める）。
+    pub fn start(name: &str) -> Self {
+        PhaseRecorder {
+            name: name.to_owned(),
+            started: Instant::now(),
+            ops: Vec::new(),
+        }
+    }
+
+    fn stats_mut(&mut self, op: &str) -> &mut OpStats {
+        if let Some(i) = self.ops.iter().position(|s| s.op == op) {
+            &mut self.ops[i]
+        } else {
+            self.ops.push(OpStats::new(op));
+            self.ops.last_mut().expect("never fails")
+        }
+    }
+
+    /// `bytes`バイトを読み書きした操作`op`のレイテンシを記録する。
+    pub fn record(&mut self, op: &str, bytes: u64, latency: Duration) {
+        let stats = self.stats_mut(op);
+        stats.bytes += bytes;
+        stats.latency.record(latency);
+    }
+
+    /// `Device`経由の操作のレイテンシを、キュー待ちの時間と実行時間に分けて記録する。
+    pub fn record_split(&mut self, op: &str, bytes: u64, queueing: Duration, execution: Duration) {
+        let stats = self.stats_mut(op);
+        stats.bytes += bytes;
+        stats.latency.record(queueing + execution);
+        stats
+            .queueing
+            .get_or_insert_with(Histogram::new)
+            .record(queueing);
+        stats
+            .execution
+            .get_or_insert_with(Histogram::new)
+            .record(execution);
+    }
+
+    /// `f`を実行し、そのレイテンシを操作`op`として記録する。
+    pub fn time<T>(&mut self, op: &str, bytes: u64, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.record(op, bytes, start.elapsed());
+        result
+    }
+
+    /// 別に記録された結果（例えば別スレッドで記録したもの）を取り込む。
+    pub fn merge(&mut self, other: &PhaseRecorder) {
+        for stats in &other.ops {
+            self.stats_mut(&stats.op).merge(stats);
+        }
+    }
+
+    /// フェーズを終了し、結果を出力して返す。
+    pub fn finish(self) -> PhaseResult {
+        let result = PhaseResult {
+            name: self.name,
+            elapsed: self.started.elapsed(),
+            ops: self.ops,
+        };
+        result.print();
+        result
+    }
+}
+
+/// ベンチマーク全体の結果。
+#[derive(Debug, Clone)]
+pub struct BenchResult {
+    /// ベンチマークの名前（例: `seq_write`）
+    pub benchmark: String,
+
+    /// 各フェーズの結果
+    pub phases: Vec<PhaseResult>,
+
+    /// ベンチマークに用いたストレージのメトリクス（結果のファイルには含めない）
+    ///
+    /// ストレージを閉じた後にもメトリクスを収集（`metrics::gather`）できるように、複製を保持しておく。
+    pub storage_metrics: Vec<StorageMetrics>,
+}
+impl BenchResult {
+    pub fn new(benchmark: &str) -> Self {
+        BenchResult {
+            benchmark: benchmark.to_owned(),
+            phases: Vec::new(),
+            storage_metrics: Vec::new(),
+        }
+    }
+
+    /// ベンチマークに用いるストレージのメトリクスを保持する。
+    pub fn retain_metrics<N: NonVolatileMemory>(&mut self, storage: &Storage<N>) {
+        self.storage_metrics.push(storage.metrics().clone());
+    }
+
+    /// 結果を、結果ファイル（JSON）に書き出す形式にする。
+    pub fn to_record(&self) -> BenchResultRecord {
+        let phases = self
+            .phases
+            .iter()
+            .map(|phase| PhaseRecord {
+                name: phase.name.clone(),
+                elapsed_secs: phase.elapsed.as_secs_f64(),
+                ops: phase
+                    .ops
+                    .iter()
+                    .map(|stats| OpRecord {
+                        op: stats.op.clone(),
+                        count: stats.count(),
+                        bytes: stats.bytes,
+                        ops_per_sec: stats.ops_per_sec(phase.elapsed),
+                        mb_per_sec: stats.mb_per_sec(phase.elapsed),
+                        latency_ns: LatencyRecord::new(&stats.latency),
+                        queueing_ns: stats.queueing.as_ref().map(LatencyRecord::new),
+                        execution_ns: stats.execution.as_ref().map(LatencyRecord::new),
+                    })
+                    .collect(),
+            })
+            .collect();
+        BenchResultRecord {
+            benchmark: self.benchmark.clone(),
+            kanils_version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            cannyls_version: Some(CANNYLS_VERSION.to_owned()),
+            phases,
+        }
+    }
+
+    /// 結果をJSONで表現する。
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.to_record()).expect("never fails")
+    }
+
+    /// 結果をCSVで表現する（1行が一つのフェーズの一つの操作の一種類のレイテンシに対応する）。
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from(
+            "benchmark,phase,op,latency,count,bytes,elapsed_secs,ops_per_sec,mb_per_sec,avg_ns",
+        );
+        for (name, _) in PERCENTILES.iter() {
+            csv.push_str(&format!(",{}_ns", name.replace('.', "")));
+        }
+        csv.push_str(",max_ns\n");
+        for phase in &self.phases {
+            for stats in &phase.ops {
+                for (kind, histogram) in stats.histograms() {
+                    csv.push_str(&format!(
+                        "{},{},{},{},{},{},{},{},{},{}",
+                        csv_field(&self.benchmark),
+                        csv_field(&phase.name),
+                        csv_field(&stats.op),
+                        kind,
+                        stats.count(),
+                        stats.bytes,
+                        phase.elapsed.as_secs_f64(),
+                        stats.ops_per_sec(phase.elapsed),
+                        stats.mb_per_sec(phase.elapsed),
+                        histogram.mean().as_nanos()
+                    ));
+                    for &(_, p) in PERCENTILES.iter() {
+                        csv.push_str(&format!(",{}", histogram.percentile(p).as_nanos()));
+                    }
+                    csv.push_str(&format!(",{}\n", histogram.max().as_nanos()));
+                }
+            }
+        }
+        csv
+    }
+
+    /// 結果を`path`に書き出す。
+    ///
+    /// 形式は拡張子で決まる（`ResultsFormat::from_path`を参照）。
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), cannyls::Error> {
+        let path = path.as_ref();
+        let content = match ResultsFormat::from_path(path) {
+            Some(ResultsFormat::Csv) => self.to_csv(),
+            Some(ResultsFormat::Json) => format!("{}\n", self.to_json()),
+            None => track_panic!(
+                ErrorKind::InvalidInput,
+                "unknown results file format {:?} (the extension must be `.csv` or `.json`)",
+                path
+            ),
+        };
+        track_io!(fs::write(path, content))?;
+        Ok(())
+    }
+}
+
+/// 結果ファイルの形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResultsFormat {
+    Csv,
+    Json,
+}
+impl ResultsFormat {
+    /// ファイルの拡張子（`.csv`または`.json`）から形式を判定する。
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("csv") => Some(ResultsFormat::Csv),
+            Some("json") => Some(ResultsFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// 結果ファイル（JSON）に書き出す、ベンチマーク全体の結果。
+///
+/// `BenchCompare`は結果ファイルをこの形式で読み込む。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BenchResultRecord {
+    pub benchmark: String,
+
+    /// 計測したkanilsのバージョン
+    #[serde(default)]
+    pub kanils_version: Option<String>,
+
+    /// 計測したcannylsのバージョン（古いkanilsで書き出した結果には含まれない）
+    #[serde(default)]
+    pub cannyls_version: Option<String>,
+
+    pub phases: Vec<PhaseRecord>,
+}
+
+/// 結果ファイルに書き出す、一つのフェーズの結果。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PhaseRecord {
+    pub name: String,
+    pub elapsed_secs: f64,
+    pub ops: Vec<OpRecord>,
+}
+
+/// 結果ファイルに書き出す、一種類の操作の結果。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OpRecord {
+    pub op: String,
+    pub count: u64,
+    pub bytes: u64,
+    pub ops_per_sec: f64,
+    pub mb_per_sec: f64,
+
+    /// 操作の開始から完了までのレイテンシ
+    pub latency_ns: LatencyRecord,
+
+    /// `Device`経由の場合の、キューで待っていた時間
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queueing_ns: Option<LatencyRecord>,
+
+    /// `Device`経由の場合の、実行に要した時間
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub execution_ns: Option<LatencyRecord>,
+}
+
+/// 結果ファイルに書き出す、レイテンシの分布（ナノ秒）。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LatencyRecord {
+    pub avg: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    #[serde(rename = "p99.9")]
+    pub p99_9: u64,
+    pub max: u64,
+}
+impl LatencyRecord {
+    fn new(histogram: &Histogram) -> Self {
+        let nanos = |duration: Duration| duration.as_nanos() as u64;
+        LatencyRecord {
+            avg: nanos(histogram.mean()),
+            p50: nanos(histogram.percentile(50.0)),
+            p90: nanos(histogram.percentile(90.0)),
+            p99: nanos(histogram.percentile(99.0)),
+            p99_9: nanos(histogram.percentile(99.9)),
+            max: nanos(histogram.max()),
+        }
+    }
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_result() -> BenchResult {
+        let mut recorder = PhaseRecorder::start("Putting Data");
+        for i in 1..=10 {
+            recorder.record("put", 4096, Duration::from_micros(i * 100));
+        }
+        recorder.record("journal_sync", 0, Duration::from_millis(1));
+        let mut phase = recorder.finish();
+        phase.elapsed = Duration::from_secs(2);
+
+        let mut result = BenchResult::new("WBench");
+        result.phases.push(phase);
+        result
+    }
+
+    #[test]
+    fn throughput_works() {
+        let result = sample_result();
+        let phase = &result.phases[0];
+        assert_eq!(phase.ops[0].op, "put");
+        assert_eq!(phase.ops[0].ops_per_sec(phase.elapsed), 5.0);
+        assert_eq!(phase.ops[0].mb_per_sec(phase.elapsed), 0.02048);
+        assert_eq!(phase.ops[1].count(), 1);
+    }
+
+    #[test]
+    fn csv_and_json_work() {
+        let result = sample_result();
+        let csv = result.to_csv();
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("benchmark,phase,op,latency,count"));
+        assert!(lines[1].starts_with("WBench,Putting Data,put,total,10,40960,2,5,0.02048,550000,"));
+        assert!(lines[1].ends_with(",1000000"));
+
+        let json = result.to_json();
+        assert!(json.starts_with(r#"{"benchmark":"WBench","kanils_version":"#));
+        assert!(json.contains(&format!(r#""cannyls_version":"{}""#, CANNYLS_VERSION)));
+        assert!(json.contains(r#""op":"put","count":10,"bytes":40960,"ops_per_sec":5.0,"#));
+        assert!(json.contains(r#""latency_ns":{"avg":550000,"#));
+        assert!(!json.contains("queueing_ns"));
+        let record: BenchResultRecord = serde_json::from_str(&json).unwrap();
+        assert_eq!(record, result.to_record());
+    }
+
+    #[test]
+    fn merge_works() {
+        let mut a = PhaseRecorder::start("x");
+        a.record_split("get", 1, Duration::from_micros(1), Duration::from_micros(2));
+        let mut b = PhaseRecorder::start("x");
+        b.record_split("get", 1, Duration::from_micros(3), Duration::from_micros(4));
+        b.record("put", 1, Duration::from_micros(5));
+        a.merge(&b);
+        let result = a.finish();
+        assert_eq!(result.ops.len(), 2);
+        assert_eq!(result.ops[0].count(), 2);
+        assert_eq!(result.ops[0].bytes, 2);
+        assert_eq!(
+            result.ops[0].queueing.as_ref().map(Histogram::count),
+            Some(2)
+        );
+        assert_eq!(result.ops[1].queueing.as_ref().map(Histogram::count), None);
+    }
+}
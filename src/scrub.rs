@@ -0,0 +1,383 @@
+//! lusfファイルから利用者のデータを取り除いた複製を作る（不具合の報告用）。
+//!
+//! 作成する複製は、元のファイルと以下が一致する:
+//!
+//! - ヘッダ領域（バージョン、ブロックサイズ、UUID、各領域のサイズ）
+//! - ジャーナル領域のヘッダと、有効な区間（headからtailまで）の各レコードの位置と内容
+//! - 各lumpのid、サイズ、埋め込みかどうか、データ領域内での配置
+//!
+//! lumpの値（データ領域のlumpと、ジャーナルに埋め込まれたlump）はlump idから決まる繰り返しパターンで置き換える。
+//! 埋め込みのレコードはチェックサムを計算し直す。
+//! 削除済みのlumpの値が残っている可能性がある領域（データ領域の空き部分や、ジャーナルの解放済みの部分）はゼロで埋める。
+use adler32::RollingAdler32;
+use byteorder::{BigEndian, ByteOrder};
+use cannyls::lump::LumpId;
+use cannyls::nvm::NonVolatileMemory;
+use cannyls::storage::{JournalEntry, JournalRecord, StorageHeader};
+use cannyls::ErrorKind;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use crate::handle::StorageHandle;
+use crate::size::format_bytes_with_size;
+
+// ジャーナルのレコードの形式（cannylsの`storage::journal::record`を参照）
+const CHECKSUM_SIZE: usize = 4;
+const EMBEDDED_DATA_OFFSET: usize = CHECKSUM_SIZE + 1 + 16 + 2;
+const TAG_EMBED: u8 = 4;
+
+// `GoToFront`と`EndOfRecords`のサイズ（チェックサムとタグのみ）
+const MARKER_RECORD_SIZE: u64 = 5;
+
+// データ領域のlumpの末尾に置かれる、パディングの長さ
+const LUMP_DATA_TRAILER_SIZE: usize = 2;
+
+/// lumpの配置。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// データ領域の`start`ブロック目から`blocks`ブロックに、`size`バイトの値が置かれている
+    Data {
+        start: u64,
+        blocks: u16,
+        size: usize,
+    },
+
+    /// ジャーナル領域の`position`（リングバッファ内の位置）のレコードに、`size`バイトの値が埋め込まれている
+    Embedded { position: u64, size: usize },
+}
+
+/// `Scrub`の結果の集計。
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ScrubSummary {
+    pub data_lumps: u64,
+    pub data_bytes: u64,
+    pub embedded_lumps: u64,
+    pub embedded_bytes: u64,
+    pub journal_entries: u64,
+}
+impl fmt::Display for ScrubSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "scrubbed {} lumps in the data region ({}) and {} embedded lumps ({}); \
+             kept {} journal entries",
+            self.data_lumps,
+            format_bytes_with_size(self.data_bytes),
+            self.embedded_lumps,
+            format_bytes_with_size(self.embedded_bytes),
+            self.journal_entries
+        )
+    }
+}
+
+/// `lump_id`の値を置き換える、`len`バイトのパターン（`"<lump id> "`の繰り返し）を返す。
+pub fn filler(lump_id: &LumpId, len: usize) -> Vec<u8> {
+    format!("{} ", lump_id.as_u128())
+        .into_bytes()
+        .into_iter()
+        .cycle()
+        .take(len)
+        .collect()
+}
+
+// 埋め込みのレコード（チェックサムを含む）を作る
+fn encode_embed_record(lump_id: &LumpId, data: &[u8]) -> Vec<u8> {
+    let mut record = vec![0; EMBEDDED_DATA_OFFSET];
+    record[CHECKSUM_SIZE] = TAG_EMBED;
+    BigEndian::write_u128(&mut record[CHECKSUM_SIZE + 1..], lump_id.as_u128());
+    BigEndian::write_u16(&mut record[EMBEDDED_DATA_OFFSET - 2..], data.len() as u16);
+    record.extend_from_slice(data);
+
+    let mut adler32 = RollingAdler32::new();
+    adler32.update_buffer(&record[CHECKSUM_SIZE..]);
+    BigEndian::write_u32(&mut record, adler32.hash());
+    record
+}
+
+// lusfファイル内の各領域の位置
+struct Layout {
+    // ジャーナルのリングバッファの開始位置
+    ring_start: u64,
+
+    // データ領域の開始位置
+    data_start: u64,
+    block_size: u64,
+}
+impl Layout {
+    fn new(header: &StorageHeader) -> Self {
+        let block_size = u64::from(header.block_size.as_u16());
+        let journal_start = header.region_size();
+        Layout {
+            ring_start: journal_start + block_size,
+            data_start: journal_start + header.journal_region_size,
+            block_size,
+        }
+    }
+}
+
+fn read_at(file: &File, offset: u64, len: usize) -> Result<Vec<u8>, cannyls::Error> {
+    let mut buf = vec![0; len];
+    track_io!(file.read_exact_at(&mut buf, offset))?;
+    Ok(buf)
+}
+
+/// ジャーナルを再生して、存在する各lumpの配置を求める。
+///
+/// データ領域のlumpのサイズは、lusfファイルの`file`から各lumpの末尾のブロックを読んで求める。
+pub fn lump_placements<N: NonVolatileMemory>(
+    handle: &mut StorageHandle<N>,
+    file: &File,
+) -> Result<BTreeMap<LumpId, Placement>, cannyls::Error> {
+    let layout = Layout::new(handle.header());
+    let snapshot = track!(handle.journal_info())?;
+    let mut placements = BTreeMap::new();
+    for entry in &snapshot.entries {
+        match entry.record {
+            JournalRecord::Put(lump_id, ref portion) => {
+                let end = layout.data_start
+                    + (portion.start.as_u64() + u64::from(portion.len)) * layout.block_size;
+                let trailer = track!(read_at(
+                    file,
+                    end - LUMP_DATA_TRAILER_SIZE as u64,
+                    LUMP_DATA_TRAILER_SIZE
+                ))?;
+                let padding = BigEndian::read_u16(&trailer) as usize;
+                let blocks_bytes = portion.len as usize * layout.block_size as usize;
+                let size = blocks_bytes.saturating_sub(LUMP_DATA_TRAILER_SIZE + padding);
+                let placement = Placement::Data {
+                    start: portion.start.as_u64(),
+                    blocks: portion.len,
+                    size,
+                };
+                placements.insert(lump_id, placement);
+            }
+            JournalRecord::Embed(lump_id, ref data) => {
+                let placement = Placement::Embedded {
+                    position: entry.start.as_u64(),
+                    size: data.len(),
+                };
+                placements.insert(lump_id, placement);
+            }
+            JournalRecord::Delete(ref lump_id) => {
+                placements.remove(lump_id);
+            }
+            JournalRecord::DeleteRange(ref range) => {
+                let deleted = placements
+                    .range(range.clone())
+                    .map(|(lump_id, _)| *lump_id)
+                    .collect::<Vec<_>>();
+                for lump_id in deleted {
+                    placements.remove(&lump_id);
+                }
+            }
+            JournalRecord::EndOfRecords | JournalRecord::GoToFront => {}
+        }
+    }
+
+    // ジャーナルのGCは存在するlumpのレコードを末尾に移すので、有効な区間に全てのlumpのレコードがあるはず
+    let keys = handle.all_keys();
+    track_assert!(
+        keys.len() == placements.len() && keys.iter().all(|k| placements.contains_key(k)),
+        ErrorKind::StorageCorrupted,
+        "the journal entries do not match the lumps in the storage ({} lumps, {} in the journal)",
+        keys.len(),
+        placements.len()
+    );
+    Ok(placements)
+}
+
+// ジャーナルのエントリを複製に書き込む（埋め込みの値は置き換える）
+fn write_journal_entry(
+    source: &File,
+    output: &File,
+    layout: &Layout,
+    entry: &JournalEntry,
+) -> Result<(), cannyls::Error> {
+    let offset = layout.ring_start + entry.start.as_u64();
+    let bytes = if let JournalRecord::Embed(ref lump_id, ref data) = entry.record {
+        encode_embed_record(lump_id, &filler(lump_id, data.len()))
+    } else {
+        let len = entry.end().as_u64() - entry.start.as_u64();
+        track!(read_at(source, offset, len as usize))?
+    };
+    track_io!(output.write_all_at(&bytes, offset))
+}
+
+/// `source`のlusfファイルから利用者のデータを取り除いた複製を`output`に作成する。
+///
+/// `output`が既に存在する場合はエラーになる。
+/// 作成後に複製を開き、lumpの一覧と配置が元のファイルと一致することを検証する
+/// （失敗した場合は作成途中の複製を削除する）。
+pub fn scrub<P: AsRef<Path>, Q: AsRef<Path>>(
+    source: P,
+    output: Q,
+    lock: bool,
+) -> Result<ScrubSummary, cannyls::Error> {
+    let source = source.as_ref();
+    let output = output.as_ref();
+    let mut handle = track!(StorageHandle::open_read_only(source, lock))?;
+    let source_file = track_io!(File::open(source))?;
+    let placements = track!(lump_placements(&mut handle, &source_file))?;
+
+    let output_file = track_io!(OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(output))?;
+    let result =
+        write_scrubbed(&mut handle, &source_file, &output_file, &placements).and_then(|summary| {
+            drop(handle);
+
+            // 複製を開き直して、lumpの配置が一致することを確かめる
+            let mut scrubbed = track!(StorageHandle::open_read_only(output, false))?;
+            let scrubbed_placements = track!(lump_placements(&mut scrubbed, &output_file))?;
+            track_assert!(
+                scrubbed_placements == placements,
+                ErrorKind::Other,
+                "the scrubbed storage {:?} does not have the same lumps as {:?}",
+                output,
+                source
+            );
+            Ok(summary)
+        });
+    if result.is_err() {
+        let _ = fs::remove_file(output);
+    }
+    result
+}
+
+fn write_scrubbed<N: NonVolatileMemory>(
+    handle: &mut StorageHandle<N>,
+    source: &File,
+    output: &File,
+    placements: &BTreeMap<LumpId, Placement>,
+) -> Result<ScrubSummary, cannyls::Error> {
+    let snapshot = track!(handle.journal_info())?;
+    let header = handle.header();
+    let layout = Layout::new(header);
+    let file_size = track_io!(source.metadata())?.len();
+    track_io!(output.set_len(file_size))?;
+
+    // ヘッダ領域と、ジャーナル領域のヘッダ（リングバッファの開始位置）はそのまま複製する
+    let head = track!(read_at(source, 0, layout.ring_start as usize))?;
+    track_io!(output.write_all_at(&head, 0))?;
+
+    // `GoToFront`と`EndOfRecords`はエントリに含まれないので、位置から求めてそのまま複製する
+    let ring_capacity = header.journal_region_size - layout.block_size;
+    let copy_marker = |position: u64| {
+        let len = MARKER_RECORD_SIZE.min(ring_capacity - position);
+        let marker = track!(read_at(source, layout.ring_start + position, len as usize))?;
+        track_io!(output.write_all_at(&marker, layout.ring_start + position))
+    };
+    let mut summary = ScrubSummary::default();
+    let mut position = snapshot.head;
+    for entry in &snapshot.entries {
+        if entry.start.as_u64() != position {
+            // リングバッファの先頭に戻った
+            track!(copy_marker(position))?;
+        }
+        track!(write_journal_entry(source, output, &layout, entry))?;
+        position = entry.end().as_u64();
+        summary.journal_entries += 1;
+    }
+    if snapshot.tail != position {
+        track!(copy_marker(position))?;
+    }
+    track!(copy_marker(snapshot.tail))?;
+
+    for (lump_id, placement) in placements {
+        match *placement {
+            Placement::Data {
+                start,
+                blocks,
+                size,
+            } => {
+                let mut bytes = filler(lump_id, size);
+                let blocks_bytes = blocks as usize * layout.block_size as usize;
+                bytes.resize(blocks_bytes, 0);
+                let padding = blocks_bytes - size - LUMP_DATA_TRAILER_SIZE;
+                BigEndian::write_u16(
+                    &mut bytes[blocks_bytes - LUMP_DATA_TRAILER_SIZE..],
+                    padding as u16,
+                );
+                let offset = layout.data_start + start * layout.block_size;
+                track_io!(output.write_all_at(&bytes, offset))?;
+                summary.data_lumps += 1;
+                summary.data_bytes += size as u64;
+            }
+            Placement::Embedded { size, .. } => {
+                summary.embedded_lumps += 1;
+                summary.embedded_bytes += size as u64;
+            }
+        }
+    }
+    track_io!(output.sync_all())?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use cannyls::nvm::FileNvm;
+    use cannyls::storage::Storage;
+    use tempfile::Builder;
+    use trackable::result::TestResult;
+
+    use super::*;
+
+    #[test]
+    fn filler_works() {
+        assert_eq!(filler(&LumpId::new(42), 7), b"42 42 4");
+        assert!(filler(&LumpId::new(1), 0).is_empty());
+    }
+
+    #[test]
+    fn scrub_works() -> TestResult {
+        let dir = track_io!(Builder::new().prefix("kanils_test").tempdir())?;
+        let source = dir.path().join("source.lusf");
+        let output = dir.path().join("output.lusf");
+
+        let nvm = track!(FileNvm::create(&source, 4_000_000))?;
+        let mut handle = StorageHandle::new(track!(Storage::create(nvm))?);
+        track!(handle.put_str(1, "secret"))?;
+        track!(handle.put_bytes(2, &vec![0xAA; 1500]))?;
+        track!(handle.embed_str(3, "embedded secret"))?;
+        track!(handle.put_str(4, "deleted secret"))?;
+        track!(handle.delete_key(4))?;
+        track!(handle.journal_sync())?;
+        drop(handle);
+
+        let summary = track!(scrub(&source, &output, true))?;
+        assert_eq!(summary.data_lumps, 2);
+        assert_eq!(summary.data_bytes, 6 + 1500);
+        assert_eq!(summary.embedded_lumps, 1);
+        assert_eq!(summary.embedded_bytes, 15);
+
+        let mut scrubbed = track!(StorageHandle::open_read_only(&output, true))?;
+        assert_eq!(
+            scrubbed.header().instance_uuid,
+            track!(StorageHeader::read_from_file(&source))?.instance_uuid
+        );
+        assert_eq!(track!(scrubbed.get_as_bytes(1))?, Some(b"1 1 1 ".to_vec()));
+        assert_eq!(
+            track!(scrubbed.get_as_bytes(2))?.map(|v| v.len()),
+            Some(1500)
+        );
+        assert_eq!(
+            track!(scrubbed.get_as_string(3))?,
+            Some(Some("3 3 3 3 3 3 3 3".to_owned()))
+        );
+        assert_eq!(track!(scrubbed.get_as_bytes(4))?, None);
+
+        let bytes = track_io!(std::fs::read(&output))?;
+        for secret in &[&b"secret"[..], &[0xAA; 16][..]] {
+            assert!(!bytes.windows(secret.len()).any(|w| w == *secret));
+        }
+
+        // 既に存在するファイルには書き込まない
+        assert!(scrub(&source, &output, true).is_err());
+        Ok(())
+    }
+}
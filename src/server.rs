@@ -0,0 +1,710 @@
+//! lusfストレージをHTTPで操作するための、最小限のHTTP/1.1サーバ。
+//!
+//! 一つの`StorageHandle`を`Mutex`で保護し、接続ごとのスレッドからの操作を直列化する。
+//! 各接続では一つのリクエストだけを処理し、応答後に接続を閉じる（keep-aliveには対応しない）。
+//!
+//! | メソッド | パス | 内容 |
+//! |---|---|---|
+//! | `GET` | `/lumps/<id>` | lumpの値（バイト列）を返す |
+//! | `PUT` | `/lumps/<id>` | リクエストボディを値として書き込む（`?embed=yes`で埋め込みlump） |
+//! | `DELETE` | `/lumps/<id>` | lumpを削除する |
+//! | `GET` | `/lumps?start=<id>&end=<id>` | 区間[start, end)のlump idを返す（省略時は全て） |
+//! | `DELETE` | `/lumps?start=<id>&end=<id>` | 区間[start, end)のlumpを全て削除する |
+//! | `GET` | `/header` | ヘッダ情報 |
+//! | `GET` | `/journal` | ジャーナル領域の内容 |
+//! | `GET` | `/stats` | lump数、データ領域とジャーナル領域の使用量、操作の回数 |
+//! | `POST` | `/journal/sync` | ジャーナルをディスクに同期する |
+//! | `POST` | `/shutdown` | ジャーナルを同期してサーバを停止する |
+//!
+//! 変更系のリクエストは`?sync=yes`を付けると、応答前にジャーナルを同期する。
+//! lump id以外の応答とエラーはJSONで返す。
+use cannyls::lump::{LumpData, LumpId};
+use cannyls::nvm::NonVolatileMemory;
+use cannyls::storage::{JournalEntry, JournalRecord};
+use cannyls::ErrorKind;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::handle::StorageHandle;
+use crate::lumpid::{parse_lump_id, IdFormat};
+use crate::spec::parse_bool;
+
+// リクエストの読み込みを待つ最大の時間
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+// リクエストラインとヘッダの合計の最大サイズ
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// HTTPリクエスト。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+impl Request {
+    /// クエリパラメータ`key`の値を返す（同じキーが複数ある場合は最初のもの）。
+    pub fn query(&self, key: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// HTTPレスポンス。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+impl Response {
+    fn json(status: u16, body: &Value) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            body: format!("{}\n", body).into_bytes(),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Response::json(status, &json!({ "error": message }))
+    }
+
+    fn bytes(body: Vec<u8>) -> Self {
+        Response {
+            status: 200,
+            content_type: "application/octet-stream",
+            body,
+        }
+    }
+
+    /// レスポンスを`writer`に書き出す。
+    pub fn write_to<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            reason_phrase(self.status),
+            self.content_type,
+            self.body.len()
+        )?;
+        writer.write_all(&self.body)?;
+        writer.flush()
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        _ => "Unknown",
+    }
+}
+
+// `%xx`と`+`を復号する
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(b) => {
+                        decoded.push(b);
+                        i += 3;
+                        continue;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// `reader`からリクエストを一つ読み込む。
+///
+/// 何も送られずに接続が閉じられた場合は`Ok(None)`を返す。
+/// 不正なリクエストの場合は、クライアントに返すべきエラーレスポンスを返す。
+pub fn read_request<R: BufRead>(mut reader: R) -> Result<Option<Request>, Response> {
+    let io_error =
+        |e: std::io::Error| Response::error(400, &format!("cannot read the request: {}", e));
+
+    let mut head_size = 0;
+    let mut request_line = String::new();
+    head_size += reader.read_line(&mut request_line).map_err(io_error)?;
+    if request_line.is_empty() {
+        return Ok(None);
+    }
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+            (method.to_owned(), target.to_owned())
+        }
+        _ => {
+            return Err(Response::error(
+                400,
+                &format!("malformed request line {:?}", request_line.trim_end()),
+            ))
+        }
+    };
+
+    let mut content_length = None;
+    loop {
+        let mut line = String::new();
+        let n = reader.read_line(&mut line).map_err(io_error)?;
+        head_size += n;
+        if head_size > MAX_HEAD_SIZE {
+            return Err(Response::error(400, "the request header is too large"));
+        }
+        let line = line.trim_end();
+        if n == 0 || line.is_empty() {
+            break;
+        }
+        let (name, value) = match line.find(':') {
+            Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+            None => {
+                return Err(Response::error(
+                    400,
+                    &format!("malformed header {:?}", line),
+                ))
+            }
+        };
+        if name.eq_ignore_ascii_case("content-length") {
+            let length = value.parse::<usize>().map_err(|_| {
+                Response::error(400, &format!("invalid Content-Length {:?}", value))
+            })?;
+            content_length = Some(length);
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            return Err(Response::error(
+                501,
+                "Transfer-Encoding is not supported (send Content-Length instead)",
+            ));
+        }
+    }
+
+    let body = match content_length {
+        Some(length) if length > LumpData::MAX_SIZE => {
+            return Err(Response::error(
+                413,
+                &format!(
+                    "the request body must be at most {} bytes",
+                    LumpData::MAX_SIZE
+                ),
+            ))
+        }
+        Some(length) => {
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).map_err(io_error)?;
+            body
+        }
+        // ボディを持たない`POST`（`/journal/sync`など）は許容する
+        None if method == "PUT" => {
+            return Err(Response::error(411, "Content-Length is required"));
+        }
+        None => Vec::new(),
+    };
+
+    let (path, query) = match target.find('?') {
+        Some(i) => (&target[..i], &target[i + 1..]),
+        None => (target.as_str(), ""),
+    };
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.find('=') {
+            Some(i) => (percent_decode(&pair[..i]), percent_decode(&pair[i + 1..])),
+            None => (percent_decode(pair), String::new()),
+        })
+        .collect();
+    Ok(Some(Request {
+        method,
+        path: percent_decode(path),
+        query,
+        body,
+    }))
+}
+
+/// `route`がリクエストを処理した後にサーバがすべきこと。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Next {
+    Continue,
+    Shutdown,
+}
+
+fn storage_error(e: &cannyls::Error) -> Response {
+    let status = match *e.kind() {
+        ErrorKind::InvalidInput => 400,
+        _ => 500,
+    };
+    Response::error(status, &e.to_string())
+}
+
+fn parse_id(s: &str) -> Result<u128, Response> {
+    parse_lump_id(s).map_err(|e| Response::error(400, &e.to_string()))
+}
+
+fn query_flag(request: &Request, key: &str) -> Result<bool, Response> {
+    match request.query(key) {
+        None => Ok(false),
+        // `?sync`のように値を省略した場合は真とする
+        Some("") => Ok(true),
+        Some(value) => parse_bool(value)
+            .map_err(|e| Response::error(400, &format!("invalid `{}`: {}", key, e))),
+    }
+}
+
+fn ids_to_json(id_format: IdFormat, ids: &[LumpId]) -> Value {
+    ids.iter()
+        .map(|id| Value::from(id_format.format(id.as_u128())))
+        .collect()
+}
+
+fn journal_entry_to_json(id_format: IdFormat, entry: &JournalEntry) -> Value {
+    let id = |lump_id: &LumpId| id_format.format(lump_id.as_u128());
+    let position = entry.start.as_u64();
+    match entry.record {
+        JournalRecord::EndOfRecords => json!({ "position": position, "record": "EndOfRecords" }),
+        JournalRecord::GoToFront => json!({ "position": position, "record": "GoToFront" }),
+        JournalRecord::Put(ref lump_id, ref portion) => json!({
+            "position": position,
+            "record": "Put",
+            "key": id(lump_id),
+            "start": portion.start.as_u64(),
+            "len": portion.len,
+        }),
+        JournalRecord::Embed(ref lump_id, ref data) => json!({
+            "position": position,
+            "record": "Embed",
+            "key": id(lump_id),
+            "len": data.len(),
+        }),
+        JournalRecord::Delete(ref lump_id) => json!({
+            "position": position,
+            "record": "Delete",
+            "key": id(lump_id),
+        }),
+        JournalRecord::DeleteRange(ref range) => json!({
+            "position": position,
+            "record": "DeleteRange",
+            "start": id(&range.start),
+            "end": id(&range.end),
+        }),
+    }
+}
+
+fn header_to_json<N: NonVolatileMemory>(handle: &StorageHandle<N>) -> Value {
+    let header = handle.header();
+    json!({
+        "major_version": header.major_version,
+        "minor_version": header.minor_version,
+        "block_size": header.block_size.as_u16(),
+        "uuid": header.instance_uuid.to_string(),
+        "journal_region_size": header.journal_region_size,
+        "data_region_size": header.data_region_size,
+        "header_size": header.region_size(),
+        "storage_size": header.storage_size(),
+    })
+}
+
+fn stats_to_json<N: NonVolatileMemory>(
+    handle: &mut StorageHandle<N>,
+) -> Result<Value, cannyls::Error> {
+    let (embedded, block) = handle.lump_kind_counts();
+    let metrics = handle.metrics();
+    let data = metrics.data_region();
+    let journal = metrics.journal_region();
+    Ok(json!({
+        "lumps": metrics.lumps(),
+        "lump_kinds": {
+            "embedded": embedded,
+            "block": block,
+            "max_embedded_size": LumpData::MAX_EMBEDDED_SIZE,
+        },
+        "read_only": handle.is_read_only(),
+        "data_region": {
+            "capacity_bytes": data.capacity_bytes(),
+            "usage_bytes": data.usage_bytes(),
+        },
+        "journal_region": {
+            "capacity_bytes": journal.queue().capacity_bytes(),
+            "usage_bytes": journal.queue().usage_bytes(),
+            "records": journal.queue().queue_len(),
+            "syncs": journal.syncs(),
+        },
+        "operations": {
+            "put": metrics.put_lumps(),
+            "delete": metrics.delete_lumps(),
+            "get_journal": metrics.get_journal_lumps(),
+            "get_data": metrics.get_data_lumps(),
+        },
+    }))
+}
+
+// 変更系のリクエストの後に、`sync`（`?sync=yes`）であればジャーナルを同期する
+//
+// `?sync=`の値は、ストレージを変更する前に`query_flag`で検証しておくこと。
+fn sync_if_requested<N: NonVolatileMemory>(
+    handle: &mut StorageHandle<N>,
+    sync: bool,
+) -> Result<(), Response> {
+    if sync {
+        track!(handle.journal_sync()).map_err(|e| storage_error(&e))?;
+    }
+    Ok(())
+}
+
+fn lump<N: NonVolatileMemory>(
+    handle: &mut StorageHandle<N>,
+    request: &Request,
+    key: &str,
+) -> Result<Response, Response> {
+    let id = parse_id(key)?;
+    let key = handle.id_format().format(id);
+    match request.method.as_str() {
+        "GET" => match track!(handle.get_as_bytes(id)).map_err(|e| storage_error(&e))? {
+            Some(bytes) => Ok(Response::bytes(bytes)),
+            None => Err(Response::error(
+                404,
+                &format!("no entry for the key {}", key),
+            )),
+        },
+        "PUT" => {
+            let embed = query_flag(request, "embed")?;
+            let sync = query_flag(request, "sync")?;
+            let created = if embed {
+                track!(handle.embed_bytes(id, &request.body))
+            } else {
+                track!(handle.put_bytes(id, &request.body))
+            }
+            .map_err(|e| storage_error(&e))?;
+            sync_if_requested(handle, sync)?;
+            let json = json!({ "key": key, "size": request.body.len(), "created": created });
+            Ok(Response::json(if created { 201 } else { 200 }, &json))
+        }
+        "DELETE" => {
+            let sync = query_flag(request, "sync")?;
+            if !track!(handle.delete_key(id)).map_err(|e| storage_error(&e))? {
+                return Err(Response::error(
+                    404,
+                    &format!("no entry for the key {}", key),
+                ));
+            }
+            sync_if_requested(handle, sync)?;
+            Ok(Response::json(200, &json!({ "key": key, "deleted": true })))
+        }
+        _ => Err(Response::error(405, "use GET, PUT or DELETE")),
+    }
+}
+
+fn lumps<N: NonVolatileMemory>(
+    handle: &mut StorageHandle<N>,
+    request: &Request,
+) -> Result<Response, Response> {
+    let start = request.query("start").map(parse_id).transpose()?;
+    let end = request.query("end").map(parse_id).transpose()?;
+    let id_format = handle.id_format();
+    match request.method.as_str() {
+        "GET" => {
+            let ids = match (start, end) {
+                (None, None) => handle.all_keys(),
+                (start, end) => handle.keys_in_range(start.unwrap_or(0), end.unwrap_or(u128::MAX)),
+            };
+            let json = json!({ "count": ids.len(), "lumps": ids_to_json(id_format, &ids) });
+            Ok(Response::json(200, &json))
+        }
+        "DELETE" => {
+            // 誤って全てのlumpを削除しないように、区間の指定を必須とする
+            let (start, end) = match (start, end) {
+                (Some(start), Some(end)) => (start, end),
+                _ => {
+                    return Err(Response::error(
+                        400,
+                        "both `start` and `end` are required to delete a range",
+                    ))
+                }
+            };
+            let sync = query_flag(request, "sync")?;
+            let deleted =
+                track!(handle.delete_range_keys(start, end)).map_err(|e| storage_error(&e))?;
+            sync_if_requested(handle, sync)?;
+            let json = json!({
+                "count": deleted.len(),
+                "deleted": ids_to_json(id_format, &deleted),
+            });
+            Ok(Response::json(200, &json))
+        }
+        _ => Err(Response::error(405, "use GET or DELETE")),
+    }
+}
+
+fn expect_method(request: &Request, method: &str) -> Result<(), Response> {
+    if request.method == method {
+        Ok(())
+    } else {
+        Err(Response::error(405, &format!("use {}", method)))
+    }
+}
+
+/// リクエストを処理し、レスポンスとサーバがすべきことを返す。
+pub fn route<N: NonVolatileMemory>(
+    handle: &mut StorageHandle<N>,
+    request: &Request,
+) -> (Response, Next) {
+    let path = request.path.trim_end_matches('/');
+    let result = match path {
+        "/lumps" => lumps(handle, request),
+        _ if path.starts_with("/lumps/") => lump(handle, request, &path["/lumps/".len()..]),
+        "/header" => {
+            expect_method(request, "GET").map(|()| Response::json(200, &header_to_json(handle)))
+        }
+        "/stats" => expect_method(request, "GET").and_then(|()| {
+            let json = track!(stats_to_json(handle)).map_err(|e| storage_error(&e))?;
+            Ok(Response::json(200, &json))
+        }),
+        "/journal" => expect_method(request, "GET").and_then(|()| {
+            let snapshot = track!(handle.journal_info()).map_err(|e| storage_error(&e))?;
+            let id_format = handle.id_format();
+            let entries = snapshot
+                .entries
+                .iter()
+                .map(|entry| journal_entry_to_json(id_format, entry))
+                .collect::<Vec<_>>();
+            let json = json!({
+                "unreleased_head": snapshot.unreleased_head,
+                "head": snapshot.head,
+                "tail": snapshot.tail,
+                "entries": entries,
+            });
+            Ok(Response::json(200, &json))
+        }),
+        "/journal/sync" => expect_method(request, "POST").and_then(|()| {
+            track!(handle.journal_sync()).map_err(|e| storage_error(&e))?;
+            Ok(Response::json(200, &json!({ "synced": true })))
+        }),
+        "/shutdown" => {
+            return match expect_method(request, "POST")
+                .and_then(|()| track!(handle.journal_sync()).map_err(|e| storage_error(&e)))
+            {
+                Ok(()) => (
+                    Response::json(200, &json!({ "shutdown": true })),
+                    Next::Shutdown,
+                ),
+                Err(response) => (response, Next::Continue),
+            };
+        }
+        _ => Err(Response::error(
+            404,
+            &format!("unknown path {:?}", request.path),
+        )),
+    };
+    match result {
+        Ok(response) | Err(response) => (response, Next::Continue),
+    }
+}
+
+// 一つの接続を処理する
+fn handle_connection<N: NonVolatileMemory>(
+    handle: &Mutex<StorageHandle<N>>,
+    stream: TcpStream,
+) -> std::io::Result<Next> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let peer = stream.peer_addr()?;
+    let request = match read_request(BufReader::new(&stream)) {
+        Ok(Some(request)) => request,
+        Ok(None) => return Ok(Next::Continue),
+        Err(response) => {
+            println!("{} (malformed request) -> {}", peer, response.status);
+            response.write_to(&stream)?;
+            return Ok(Next::Continue);
+        }
+    };
+    let (response, next) = {
+        let mut handle = handle.lock().unwrap_or_else(|e| e.into_inner());
+        route(&mut handle, &request)
+    };
+    println!(
+        "{} {} {} -> {}",
+        peer, request.method, request.path, response.status
+    );
+    response.write_to(&stream)?;
+    Ok(next)
+}
+
+/// `listener`で接続を受け付け、`handle`のストレージを操作するリクエストを処理する。
+///
+/// `POST /shutdown`を受け付けると（ジャーナルを同期した上で）終了する。
+/// それ以外の方法でプロセスを終了する場合は、同期されていない操作が失われうるので、
+/// 必要に応じて`POST /journal/sync`か`?sync=yes`を用いること。
+pub fn serve<N>(handle: StorageHandle<N>, listener: TcpListener) -> Result<(), cannyls::Error>
+where
+    N: NonVolatileMemory + Send + 'static,
+{
+    let address = track_io!(listener.local_addr())?;
+    let handle = Arc::new(Mutex::new(handle));
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for stream in listener.incoming() {
+        if shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("cannot accept a connection: {}", e);
+                continue;
+            }
+        };
+        let handle = Arc::clone(&handle);
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || match handle_connection(&handle, stream) {
+            Ok(Next::Continue) => {}
+            Ok(Next::Shutdown) => {
+                shutdown.store(true, Ordering::SeqCst);
+                // `accept`で待機しているメインスレッドを起こす
+                let _ = TcpStream::connect(wake_address(address));
+            }
+            Err(e) => eprintln!("connection error: {}", e),
+        });
+    }
+    println!("shut down the server");
+    Ok(())
+}
+
+// `0.0.0.0`などで待ち受けている場合にも接続できるアドレスを返す
+fn wake_address(mut address: SocketAddr) -> SocketAddr {
+    if address.ip().is_unspecified() {
+        let loopback = match address {
+            SocketAddr::V4(_) => std::net::Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+        };
+        address.set_ip(loopback);
+    }
+    address
+}
+
+#[cfg(test)]
+mod tests {
+    use cannyls::nvm::MemoryNvm;
+    use cannyls::storage::Storage;
+    use trackable::result::TestResult;
+
+    use super::*;
+
+    fn request(method: &str, target: &str, body: &[u8]) -> Request {
+        let mut raw = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n",
+            method,
+            target,
+            body.len()
+        )
+        .into_bytes();
+        raw.extend_from_slice(body);
+        read_request(&raw[..]).unwrap().unwrap()
+    }
+
+    fn body_json(response: &Response) -> Value {
+        serde_json::from_slice(&response.body).unwrap()
+    }
+
+    #[test]
+    fn read_request_works() {
+        let req = request("PUT", "/lumps/0x2a?embed=true&sync", b"foo");
+        assert_eq!(req.method, "PUT");
+        assert_eq!(req.path, "/lumps/0x2a");
+        assert_eq!(req.query("embed"), Some("true"));
+        assert_eq!(req.query("sync"), Some(""));
+        assert_eq!(req.body, b"foo");
+
+        let req = request("GET", "/lumps?start=1%3A2%3A3", b"");
+        assert_eq!(req.query("start"), Some("1:2:3"));
+
+        assert_eq!(read_request(&b""[..]), Ok(None));
+        assert_eq!(read_request(&b"hello\r\n\r\n"[..]).unwrap_err().status, 400);
+        let raw = b"PUT /lumps/1 HTTP/1.1\r\n\r\n";
+        assert_eq!(read_request(&raw[..]).unwrap_err().status, 411);
+    }
+
+    #[test]
+    fn route_works() -> TestResult {
+        let nvm = MemoryNvm::new(vec![0; 1024 * 1024]);
+        let mut handle = StorageHandle::new(track!(Storage::create(nvm))?);
+
+        let (response, _) = route(&mut handle, &request("PUT", "/lumps/1", b"foo"));
+        assert_eq!(response.status, 201);
+        let (response, _) = route(&mut handle, &request("PUT", "/lumps/1", b"bar"));
+        assert_eq!(response.status, 200);
+        let (response, _) = route(&mut handle, &request("PUT", "/lumps/2?embed=yes", b"baz"));
+        assert_eq!(response.status, 201);
+        let (response, _) = route(&mut handle, &request("PUT", "/lumps/3", b"qux"));
+        assert_eq!(response.status, 201);
+        // 不正なクエリは、ストレージを変更する前に拒否する
+        let (response, _) = route(&mut handle, &request("PUT", "/lumps/4?sync=maybe", b"x"));
+        assert_eq!(response.status, 400);
+        let (response, _) = route(&mut handle, &request("PUT", "/lumps/4?embed=maybe", b"x"));
+        assert_eq!(response.status, 400);
+        let (response, _) = route(&mut handle, &request("DELETE", "/lumps/3?sync=maybe", b""));
+        assert_eq!(response.status, 400);
+        assert!(handle.contains_key(3));
+        assert!(!handle.contains_key(4));
+
+        let (response, _) = route(&mut handle, &request("GET", "/lumps/1", b""));
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"bar");
+        let (response, _) = route(&mut handle, &request("GET", "/lumps/9", b""));
+        assert_eq!(response.status, 404);
+        let (response, _) = route(&mut handle, &request("GET", "/lumps/foo", b""));
+        assert_eq!(response.status, 400);
+
+        let (response, _) = route(&mut handle, &request("GET", "/lumps?start=2", b""));
+        let json = body_json(&response);
+        assert_eq!(json["count"], 2);
+        assert_eq!(json["lumps"], json!(["2", "3"]));
+
+        let (response, _) = route(&mut handle, &request("DELETE", "/lumps", b""));
+        assert_eq!(response.status, 400);
+        let (response, _) = route(&mut handle, &request("DELETE", "/lumps?start=0&end=3", b""));
+        assert_eq!(body_json(&response)["deleted"], json!(["1", "2"]));
+        let (response, _) = route(&mut handle, &request("DELETE", "/lumps/3?sync", b""));
+        assert_eq!(response.status, 200);
+        let (response, _) = route(&mut handle, &request("DELETE", "/lumps/3", b""));
+        assert_eq!(response.status, 404);
+
+        let (response, _) = route(&mut handle, &request("GET", "/stats", b""));
+        let json = body_json(&response);
+        assert_eq!(json["lumps"], 0);
+        let (response, _) = route(&mut handle, &request("GET", "/journal", b""));
+        let json = body_json(&response);
+        assert!(json["entries"].is_array());
+        let (response, _) = route(&mut handle, &request("GET", "/header", b""));
+        assert_eq!(body_json(&response)["block_size"], 512);
+
+        let (response, next) = route(&mut handle, &request("GET", "/journal/sync", b""));
+        assert_eq!((response.status, next), (405, Next::Continue));
+        let (response, next) = route(&mut handle, &request("POST", "/shutdown", b""));
+        assert_eq!((response.status, next), (200, Next::Shutdown));
+        let (response, _) = route(&mut handle, &request("GET", "/unknown", b""));
+        assert_eq!(response.status, 404);
+        Ok(())
+    }
+}
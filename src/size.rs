@@ -0,0 +1,179 @@
+//! サイズ指定（`--capacity`や`--size`など）の解析と、人間に読みやすいサイズ表記を扱う。
+//!
+//! 単位の意味は次の通り（大文字小文字は区別しない）:
+//!
+//! - `B` または単位なし: バイト
+//! - `K`/`KB`, `M`/`MB`, `G`/`GB`, `T`/`TB`: 10進（1000の冪）
+//! - `KiB`, `MiB`, `GiB`, `TiB`: 2進（1024の冪）
+//!
+//! 数値部には`_`による区切りと小数（例: `1.5GiB`）を使えるが、
+//! 結果はバイト単位の整数にならなければならない。
+use std::fmt;
+
+/// サイズ文字列の解析に失敗したことを表すエラー。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseSizeError {
+    input: String,
+    reason: String,
+}
+impl ParseSizeError {
+    fn new(input: &str, reason: &str) -> Self {
+        ParseSizeError {
+            input: input.to_owned(),
+            reason: reason.to_owned(),
+        }
+    }
+}
+impl fmt::Display for ParseSizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid size `{}`: {}", self.input, self.reason)
+    }
+}
+impl std::error::Error for ParseSizeError {}
+
+const KILO: u64 = 1000;
+const KIBI: u64 = 1024;
+
+fn unit_multiplier(unit: &str) -> Option<u64> {
+    let m = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => KILO,
+        "m" | "mb" => KILO.pow(2),
+        "g" | "gb" => KILO.pow(3),
+        "t" | "tb" => KILO.pow(4),
+        "kib" => KIBI,
+        "mib" => KIBI.pow(2),
+        "gib" => KIBI.pow(3),
+        "tib" => KIBI.pow(4),
+        _ => return None,
+    };
+    Some(m)
+}
+
+/// `input`をバイト数に変換する。
+///
+/// 例: `4096`, `10_000`, `3MiB`, `1.5 GB`, `2TiB`
+pub fn parse_size(input: &str) -> Result<u64, ParseSizeError> {
+    let trimmed = input.trim();
+    let split = trimmed
+        .find(|c: char| !(c.is_ascii_digit() || c == '_' || c == '.'))
+        .unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(split);
+    let unit = unit.trim_start();
+
+    let multiplier = unit_multiplier(unit).ok_or_else(|| {
+        ParseSizeError::new(
+            input,
+            "unknown unit (expected one of B, K, KB, KiB, M, MB, MiB, G, GB, GiB, T, TB, TiB)",
+        )
+    })?;
+
+    let number: String = number.chars().filter(|&c| c != '_').collect();
+    let mut parts = number.splitn(2, '.');
+    let integer = parts.next().unwrap_or("");
+    let fraction = parts.next().unwrap_or("");
+    if integer.is_empty() && fraction.is_empty() {
+        return Err(ParseSizeError::new(input, "missing number"));
+    }
+    if fraction.contains('.') {
+        return Err(ParseSizeError::new(input, "too many decimal points"));
+    }
+
+    let overflow = || ParseSizeError::new(input, "too large");
+    let integer: u64 = if integer.is_empty() {
+        0
+    } else {
+        integer.parse().map_err(|_| overflow())?
+    };
+    let mut bytes = integer.checked_mul(multiplier).ok_or_else(overflow)?;
+
+    if !fraction.is_empty() {
+        // 小数部は `fraction / 10^digits * multiplier` を整数演算で求める
+        let digits = fraction.len() as u32;
+        let denominator = 10u128
+            .checked_pow(digits)
+            .ok_or_else(|| ParseSizeError::new(input, "too many fractional digits"))?;
+        let numerator: u128 = fraction.parse().map_err(|_| overflow())?;
+        let scaled = numerator
+            .checked_mul(u128::from(multiplier))
+            .ok_or_else(overflow)?;
+        if !scaled.is_multiple_of(denominator) {
+            return Err(ParseSizeError::new(
+                input,
+                "does not denote a whole number of bytes",
+            ));
+        }
+        let extra = (scaled / denominator) as u64;
+        bytes = bytes.checked_add(extra).ok_or_else(overflow)?;
+    }
+    Ok(bytes)
+}
+
+/// バイト数を2進接頭辞付きの読みやすい表記（例: `1.50 MiB`）に変換する。
+///
+/// 1KiB未満の場合は`512 B`のようにバイト単位で表記する。
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut unit = 0;
+    let mut divisor = 1u64;
+    while unit + 1 < UNITS.len() && bytes / divisor >= KIBI {
+        divisor *= KIBI;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else if bytes.is_multiple_of(divisor) {
+        format!("{} {}", bytes / divisor, UNITS[unit])
+    } else {
+        format!("{:.2} {}", bytes as f64 / divisor as f64, UNITS[unit])
+    }
+}
+
+/// `bytes (human readable)` の形式でバイト数を表記する。
+pub fn format_bytes_with_size(bytes: u64) -> String {
+    if bytes < KIBI {
+        bytes.to_string()
+    } else {
+        format!("{} ({})", bytes, format_size(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size_works() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("512B"), Ok(512));
+        assert_eq!(parse_size("10_000"), Ok(10_000));
+        assert_eq!(parse_size("3KB"), Ok(3_000));
+        assert_eq!(parse_size("3k"), Ok(3_000));
+        assert_eq!(parse_size("3KiB"), Ok(3 * 1024));
+        assert_eq!(parse_size("3MiB"), Ok(3 * 1024 * 1024));
+        assert_eq!(parse_size("2 GB"), Ok(2_000_000_000));
+        assert_eq!(parse_size("1.5GiB"), Ok(1024 * 1024 * 1024 * 3 / 2));
+        assert_eq!(parse_size("0.5K"), Ok(500));
+        assert_eq!(parse_size("1TiB"), Ok(1 << 40));
+    }
+
+    #[test]
+    fn parse_size_rejects_invalid_inputs() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("KB").is_err());
+        assert!(parse_size("3XB").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("1.2.3").is_err());
+        assert!(parse_size("0.1B").is_err());
+        assert!(parse_size("100000000TiB").is_err());
+    }
+
+    #[test]
+    fn format_size_works() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(2048), "2 KiB");
+        assert_eq!(format_size(1024 * 1024 * 3 / 2), "1.50 MiB");
+        assert_eq!(format_size(1 << 40), "1 TiB");
+        assert_eq!(format_bytes_with_size(4096), "4096 (4 KiB)");
+    }
+}
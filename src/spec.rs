@@ -0,0 +1,164 @@
+//! `key=value`の組を並べた記述（障害計画やワークロードなど）の解析。
+//!
+//! 組はカンマ（または空白・改行）で区切り、`#`から行末まではコメントとして無視する。例:
+//!
+//! ```text
+//! put=70,get=30   # コメント
+//! seed=42
+//! ```
+//!
+//! 個々のキーと値の解釈は、`parse_pairs`に渡す関数が行う。
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// 記述の解析に失敗したことを表すエラー。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseSpecError {
+    what: &'static str,
+    input: String,
+    reason: String,
+}
+impl ParseSpecError {
+    /// `what`（`"workload"`など）の記述`input`が`reason`のために不正であることを表すエラーを作る。
+    pub fn new(what: &'static str, input: &str, reason: &str) -> Self {
+        ParseSpecError {
+            what,
+            input: input.to_owned(),
+            reason: reason.to_owned(),
+        }
+    }
+}
+impl fmt::Display for ParseSpecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid {} `{}`: {}", self.what, self.input, self.reason)
+    }
+}
+impl std::error::Error for ParseSpecError {}
+
+/// 一つの`key=value`の組の解釈に失敗したことを表すエラー。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PairError {
+    /// 知らないキー
+    UnknownKey,
+
+    /// 値が不正（理由）
+    InvalidValue(String),
+}
+impl PairError {
+    /// 値が不正であることを表すエラーを作る。
+    pub fn invalid<E: fmt::Display>(reason: E) -> Self {
+        PairError::InvalidValue(reason.to_string())
+    }
+}
+
+/// `input`の`key=value`の組を先頭から順に`f(key, value)`に渡す。
+///
+/// `=`を含まない組や、`f`が返したエラーは、`what`の記述の解析エラーにする。
+pub fn parse_pairs<F>(what: &'static str, input: &str, mut f: F) -> Result<(), ParseSpecError>
+where
+    F: FnMut(&str, &str) -> Result<(), PairError>,
+{
+    let items = input
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or(""))
+        .flat_map(|line| line.split(|c: char| c == ',' || c.is_whitespace()))
+        .filter(|item| !item.is_empty());
+    for item in items {
+        let (key, value) = item.split_once('=').ok_or_else(|| {
+            ParseSpecError::new(
+                what,
+                input,
+                &format!("`{}` is not a `key=value` pair", item),
+            )
+        })?;
+        f(key, value).map_err(|e| {
+            let reason = match e {
+                PairError::UnknownKey => format!("unknown key `{}`", key),
+                PairError::InvalidValue(reason) => format!("{}: {}", key, reason),
+            };
+            ParseSpecError::new(what, input, &reason)
+        })?;
+    }
+    Ok(())
+}
+
+/// `what`の記述をファイル`path`から読み込む。
+pub fn read_spec_file<P: AsRef<Path>>(
+    what: &'static str,
+    path: P,
+) -> Result<String, ParseSpecError> {
+    fs::read_to_string(&path).map_err(|e| {
+        ParseSpecError::new(
+            what,
+            &path.as_ref().display().to_string(),
+            &format!("cannot read the file: {}", e),
+        )
+    })
+}
+
+/// 0以上1以下の比率を解析する。
+pub fn parse_ratio(s: &str) -> Result<f64, String> {
+    let ratio: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if (0.0..=1.0).contains(&ratio) {
+        Ok(ratio)
+    } else {
+        Err(format!("ratio `{}` is out of the range [0, 1]", s))
+    }
+}
+
+/// `yes`/`true`または`no`/`false`を解析する。
+pub fn parse_bool(s: &str) -> Result<bool, String> {
+    match s {
+        "yes" | "true" => Ok(true),
+        "no" | "false" => Ok(false),
+        _ => Err("expected `yes` or `no`".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Result<Vec<(String, u32)>, ParseSpecError> {
+        let mut pairs = Vec::new();
+        parse_pairs("test spec", input, |key, value| {
+            if key == "unknown" {
+                return Err(PairError::UnknownKey);
+            }
+            pairs.push((key.to_owned(), value.parse().map_err(PairError::invalid)?));
+            Ok(())
+        })?;
+        Ok(pairs)
+    }
+
+    #[test]
+    fn parse_pairs_works() {
+        assert_eq!(
+            parse("a=1, b=2 # comment\n\nc=3").unwrap(),
+            vec![
+                ("a".to_owned(), 1),
+                ("b".to_owned(), 2),
+                ("c".to_owned(), 3)
+            ]
+        );
+        assert_eq!(parse("").unwrap(), vec![]);
+        assert_eq!(
+            parse("a=1,b").unwrap_err().to_string(),
+            "invalid test spec `a=1,b`: `b` is not a `key=value` pair"
+        );
+        assert_eq!(
+            parse("unknown=1").unwrap_err().to_string(),
+            "invalid test spec `unknown=1`: unknown key `unknown`"
+        );
+        assert_eq!(
+            parse("a=x").unwrap_err().to_string(),
+            "invalid test spec `a=x`: a: invalid digit found in string"
+        );
+
+        assert_eq!(parse_ratio("0.5"), Ok(0.5));
+        assert!(parse_ratio("1.5").is_err());
+        assert_eq!(parse_bool("yes"), Ok(true));
+        assert!(parse_bool("maybe").is_err());
+    }
+}
@@ -0,0 +1,928 @@
+//! クラッシュ整合性を検証するためのtortureテスト。
+//!
+//! ランダムなワークロードを実行しながら、期待されるlumpの状態をメモリ上で追跡する。
+//! 無作為な時点でクラッシュを模擬し、`StorageBuilder::open`で開き直したストレージについて、
+//! 同期（`journal_sync`）済みの操作が全て残っていることと、
+//! 書き込まれたことのない値（ファントム）が現れないことを検証する。
+//! 同期されていない操作は、反映されていてもいなくても良い。
+//!
+//! クラッシュの模擬方法（`CrashMode`）:
+//!
+//! - `discard`: 最後の同期以降の書き込みを全て破棄する
+//! - `prefix`: 最後の同期以降の書き込みを先頭から無作為な数だけ残す（次の書き込みはブロック単位で途中まで残す）
+//! - `reorder`: 最後の同期以降の書き込みを無作為に選んで残す（ディスクによる書き込み順序の入れ替えを模擬する）
+//! - `truncate`: 全ての書き込みを残した上で、ファイルを同期時点の長さ以上の無作為な長さに切り詰める
+//! - `kill`: 子プロセスでワークロードを実行し、無作為な時点で`SIGKILL`により強制終了させる
+//!
+//! `kill`以外では、書き込みを記録するNVMを介してストレージを操作する。
+//! 同期の度に同期済みの内容をファイルの複製に反映しておき、クラッシュ時にはその複製に
+//! 残すと決めた書き込みを適用したものを、クラッシュ後のファイルとする。
+//! 検証に成功したら、クラッシュ後のファイルを開き直した状態から次のラウンドを続ける。
+use cannyls::block::BlockSize;
+use cannyls::lump::LumpId;
+use cannyls::nvm::{FileNvm, NonVolatileMemory};
+use cannyls::storage::{Storage, StorageBuilder};
+use cannyls::ErrorKind;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::size::format_bytes_with_size;
+use crate::workload::{fill_random, Op, OpKind, Payload, WorkloadGenerator, WorkloadSpec};
+
+/// クラッシュの模擬方法。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrashMode {
+    /// 最後の同期以降の書き込みを全て破棄する
+    Discard,
+
+    /// 最後の同期以降の書き込みを先頭から無作為な数だけ残す
+    Prefix,
+
+    /// 最後の同期以降の書き込みを無作為に選んで残す
+    Reorder,
+
+    /// ファイルを同期時点の長さ以上の無作為な長さに切り詰める
+    Truncate,
+
+    /// 子プロセスを強制終了させる
+    Kill,
+}
+impl CrashMode {
+    /// コマンドライン引数として受け付ける値の一覧。
+    pub const VARIANTS: [&'static str; 5] = ["discard", "prefix", "reorder", "truncate", "kill"];
+
+    /// 名前（`VARIANTS`の要素）
+    pub fn name(self) -> &'static str {
+        match self {
+            CrashMode::Discard => "discard",
+            CrashMode::Prefix => "prefix",
+            CrashMode::Reorder => "reorder",
+            CrashMode::Truncate => "truncate",
+            CrashMode::Kill => "kill",
+        }
+    }
+}
+impl fmt::Display for CrashMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+impl FromStr for CrashMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "discard" => Ok(CrashMode::Discard),
+            "prefix" => Ok(CrashMode::Prefix),
+            "reorder" => Ok(CrashMode::Reorder),
+            "truncate" => Ok(CrashMode::Truncate),
+            "kill" => Ok(CrashMode::Kill),
+            _ => Err(format!(
+                "invalid crash mode `{}` (expected one of {})",
+                s,
+                CrashMode::VARIANTS.join(", ")
+            )),
+        }
+    }
+}
+
+/// tortureテストの設定。
+#[derive(Debug, Clone)]
+pub struct TortureOptions {
+    /// 作業用に新たに作成するlusfファイルのパス
+    ///
+    /// 同じディレクトリに、末尾に`.durable`と`.crash`を付けた名前の作業用のファイルも作成する。
+    pub path: PathBuf,
+
+    /// データ領域の容量（`None`の場合は、ワークロードの全てのキーに最大サイズの値を書き込める大きさ。
+    /// ただしキーの数は高々`rounds * ops_per_round`とする）
+    pub capacity: Option<u64>,
+
+    /// 実行するワークロード（`ops`と`duration`は用いず、値は常に`payload=random`で生成する）
+    pub spec: WorkloadSpec,
+
+    /// クラッシュさせる回数
+    pub rounds: u64,
+
+    /// 一回のクラッシュまでに実行する操作の最大数（`kill`以外）
+    pub ops_per_round: u64,
+
+    /// 用いるクラッシュの模擬方法（ラウンドごとに無作為に選ぶ）
+    pub modes: Vec<CrashMode>,
+
+    /// `kill`の場合に、子プロセスが操作を開始してから強制終了させるまでの最大の時間
+    pub kill_delay: Duration,
+
+    /// `kill`の場合に起動する子プロセス（このプログラム自身）の引数
+    ///
+    /// 子プロセスはこの引数に`--child-seed <seed>`を加えて起動し、`run_child`を実行しなければならない。
+    pub child_args: Vec<OsString>,
+
+    /// 成功した場合にも作業用のlusfファイルを削除しない
+    pub keep_files: bool,
+}
+
+/// tortureテストの結果。
+#[derive(Debug, Clone, Default)]
+pub struct TortureSummary {
+    /// 実行したラウンドの数
+    pub rounds: u64,
+
+    /// 実行した操作の数
+    pub ops: u64,
+
+    /// 検出した不整合
+    pub violations: Vec<String>,
+
+    /// 不整合が検出されたクラッシュ後のファイル
+    pub failed_image: Option<PathBuf>,
+}
+impl TortureSummary {
+    /// 不整合が検出されなかったかどうか
+    pub fn is_success(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+/// tortureテストを実行する。
+///
+/// 不整合を検出した場合はそのラウンドで中断し、クラッシュ後のファイルを残す。
+pub fn run(options: &TortureOptions) -> Result<TortureSummary, cannyls::Error> {
+    if options.modes.is_empty() {
+        track_panic!(ErrorKind::InvalidInput, "no crash modes are given");
+    }
+    let spec = &options.spec;
+    let seed = spec.seed.unwrap_or_else(rand::random);
+    println!("workload: {}", spec);
+    println!(
+        "seed = {} (pass `seed={}` in the workload to reproduce this run)",
+        seed, seed
+    );
+
+    let files = TortureFiles::new(&options.path);
+    let capacity = options
+        .capacity
+        .unwrap_or_else(|| default_capacity(options));
+    track!(FileNvm::create(&files.working, capacity).and_then(Storage::create))?;
+    println!(
+        "created {:?} (capacity = {})",
+        files.working,
+        format_bytes_with_size(capacity)
+    );
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut state = BTreeMap::new();
+    let mut summary = TortureSummary::default();
+    for round in 1..=options.rounds {
+        let mode = *options.modes.choose(&mut rng).expect("never fails");
+        let round_seed = rng.gen();
+        let outcome = if mode == CrashMode::Kill {
+            track!(kill_round(options, &files, round_seed, state))?
+        } else {
+            track!(simulated_round(options, &files, mode, round_seed, state))?
+        };
+        println!(
+            "[round {}/{}] {}: {} ops ({} synced), {}, verified {} lumps",
+            round,
+            options.rounds,
+            mode,
+            outcome.ops,
+            outcome.synced_ops,
+            outcome.detail,
+            outcome.state.len()
+        );
+        summary.rounds = round;
+        summary.ops += outcome.ops;
+        if !outcome.violations.is_empty() {
+            let failed = files.suffixed(".failed");
+            track_io!(fs::rename(&outcome.image, &failed))?;
+            summary.violations = outcome.violations;
+            summary.failed_image = Some(failed);
+            return Ok(summary);
+        }
+        if outcome.image != files.working {
+            track_io!(fs::rename(&outcome.image, &files.working))?;
+        }
+        state = outcome.state;
+    }
+
+    for path in &[&files.durable, &files.crash] {
+        if path.exists() {
+            track_io!(fs::remove_file(path))?;
+        }
+    }
+    if !options.keep_files {
+        track_io!(fs::remove_file(&files.working))?;
+    }
+    Ok(summary)
+}
+
+/// `kill`のラウンドで起動される子プロセスの処理を実行する。
+///
+/// `path`のストレージに対して`seed`から生成した操作を強制終了されるまで実行し続け、
+/// 各操作の開始前に`begin <i>`を、同期の完了後に`synced <n>`（最初のn個の操作が同期済み）を標準出力に出力する。
+pub fn run_child<P: AsRef<Path>>(
+    path: P,
+    spec: &WorkloadSpec,
+    seed: u64,
+) -> Result<(), cannyls::Error> {
+    let nvm = track!(FileNvm::open(path.as_ref()))?;
+    let mut storage = track!(StorageBuilder::new().open(nvm))?;
+    let mut generator = WorkloadGenerator::new(spec, seed);
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut buf = Vec::new();
+    let mut i = 0;
+    loop {
+        let op = generator.next_op();
+        track_io!(writeln!(out, "begin {}", i).and_then(|()| out.flush()))?;
+        track!(run_op(&mut storage, &op, &mut buf))?;
+        if op.sync {
+            track_io!(writeln!(out, "synced {}", i + 1).and_then(|()| out.flush()))?;
+        }
+        i += 1;
+    }
+}
+
+// ワークロードの全てのキー（ただし、高々`--rounds`と`--ops-per-round`の積の数）に最大サイズの値を書き込める容量
+fn default_capacity(options: &TortureOptions) -> u64 {
+    let keys = options
+        .spec
+        .key_space
+        .min(options.rounds.saturating_mul(options.ops_per_round));
+    let total = keys.saturating_mul(options.spec.value_size.max().max(1));
+    total.saturating_mul(2).max(16 * 1024 * 1024)
+}
+
+// tortureテストで用いるファイル
+struct TortureFiles {
+    // ワークロードを実行するファイル
+    working: PathBuf,
+
+    // 同期済みの内容を保持するファイル
+    durable: PathBuf,
+
+    // クラッシュ後のファイル
+    crash: PathBuf,
+}
+impl TortureFiles {
+    fn new(path: &Path) -> Self {
+        let mut files = TortureFiles {
+            working: path.to_path_buf(),
+            durable: PathBuf::new(),
+            crash: PathBuf::new(),
+        };
+        files.durable = files.suffixed(".durable");
+        files.crash = files.suffixed(".crash");
+        files
+    }
+
+    fn suffixed(&self, suffix: &str) -> PathBuf {
+        let mut path = self.working.clone().into_os_string();
+        path.push(suffix);
+        PathBuf::from(path)
+    }
+}
+
+// 一回のラウンドの結果
+struct RoundOutcome {
+    ops: u64,
+    synced_ops: u64,
+    detail: String,
+
+    // クラッシュ後のファイルと、それを開き直して観測されたlumpの状態
+    image: PathBuf,
+    state: BTreeMap<LumpId, Value>,
+    violations: Vec<String>,
+}
+
+// `kill`以外の方法でクラッシュを模擬するラウンド
+fn simulated_round(
+    options: &TortureOptions,
+    files: &TortureFiles,
+    mode: CrashMode,
+    seed: u64,
+    state: BTreeMap<LumpId, Value>,
+) -> Result<RoundOutcome, cannyls::Error> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let log = Arc::new(Mutex::new(WriteLog::default()));
+    let nvm = track!(FileNvm::open(&files.working))?;
+    let mut storage = track!(StorageBuilder::new().open(CrashNvm::new(nvm, Arc::clone(&log))))?;
+
+    // 開く際の書き込みも含めて、ここまでの内容を同期済みとする
+    track_io!(fs::copy(&files.working, &files.durable))?;
+    {
+        let mut log = log.lock().expect("never fails");
+        log.pending.clear();
+        log.durable = Some(track_io!(OpenOptions::new()
+            .write(true)
+            .open(&files.durable))?);
+    }
+
+    let mut generator = WorkloadGenerator::new(&options.spec, rng.gen());
+    let mut tracker = Tracker::new(state);
+    let mut violations = Vec::new();
+    let mut buf = Vec::new();
+    let ops = rng.gen_range(1, options.ops_per_round.max(1) + 1);
+    for i in 0..ops {
+        let op = generator.next_op();
+        if op.kind == OpKind::Get {
+            // クラッシュ前の読み込みも検証する
+            let id = lump_id(op.key);
+            let actual = track!(storage.get(&id))?;
+            let expected = tracker.current.get(&id).copied();
+            if !Value::matches(expected, actual.as_ref().map(|d| d.as_bytes())) {
+                violations.push(format!(
+                    "lump {}: get before the crash returned {}, but {} was expected",
+                    id,
+                    describe(actual.as_ref().map(|d| d.as_bytes())),
+                    expected.map_or("nothing".to_owned(), |v| format!("{} bytes", v.size))
+                ));
+            }
+        }
+        track!(run_op(&mut storage, &op, &mut buf))?;
+        tracker.apply(i, &op);
+        if op.sync {
+            tracker.make_durable(i + 1);
+        }
+    }
+
+    // 以降の書き込みと同期（ストレージの破棄に伴うものなど）はクラッシュ後に行われたものとし、
+    // 同期済みの内容には反映しない
+    let pending = {
+        let mut log = log.lock().expect("never fails");
+        log.durable = None;
+        mem::take(&mut log.pending)
+    };
+    drop(storage);
+    let detail = track!(write_crash_image(files, mode, &pending, &mut rng))?;
+    let (state, recovery_violations) = track!(recover_and_verify(&files.crash, &tracker))?;
+    violations.extend(recovery_violations);
+    Ok(RoundOutcome {
+        ops,
+        synced_ops: tracker.synced_ops,
+        detail,
+        image: files.crash.clone(),
+        state,
+        violations,
+    })
+}
+
+// 最後の同期以降の書き込みのうち、`mode`に従って選んだものを同期済みの内容に適用したファイルを作成する
+fn write_crash_image(
+    files: &TortureFiles,
+    mode: CrashMode,
+    pending: &[(u64, Vec<u8>)],
+    rng: &mut StdRng,
+) -> Result<String, cannyls::Error> {
+    track_io!(fs::copy(&files.durable, &files.crash))?;
+    let file = track_io!(OpenOptions::new().write(true).open(&files.crash))?;
+    let synced_len = track_io!(file.metadata())?.len();
+    let apply = |(offset, data): &(u64, Vec<u8>)| file.write_all_at(data, *offset);
+    let block = usize::from(BlockSize::min().as_u16());
+
+    let detail = match mode {
+        CrashMode::Discard => format!("discarded {} unsynced writes", pending.len()),
+        CrashMode::Prefix => {
+            let kept = rng.gen_range(0, pending.len() + 1);
+            for write in &pending[..kept] {
+                track_io!(apply(write))?;
+            }
+            let mut detail = format!(
+                "kept the first {} of {} unsynced writes",
+                kept,
+                pending.len()
+            );
+            // 次の書き込みは、ブロック単位で途中まで反映されたものとする
+            if let Some((offset, data)) = pending.get(kept) {
+                let torn = rng.gen_range(0, data.len() / block + 1) * block;
+                if torn > 0 {
+                    track_io!(apply(&(*offset, data[..torn].to_vec())))?;
+                    detail.push_str(&format!(" and {} bytes of the next one", torn));
+                }
+            }
+            detail
+        }
+        CrashMode::Reorder => {
+            let mut kept = 0;
+            for write in pending {
+                if rng.gen() {
+                    track_io!(apply(write))?;
+                    kept += 1;
+                }
+            }
+            format!(
+                "kept {} of {} unsynced writes chosen at random",
+                kept,
+                pending.len()
+            )
+        }
+        CrashMode::Truncate => {
+            for write in pending {
+                track_io!(apply(write))?;
+            }
+            let written_len = track_io!(file.metadata())?.len();
+            let blocks = (written_len - synced_len) / block as u64;
+            let len = (synced_len + rng.gen_range(0, blocks + 1) * block as u64).min(written_len);
+            track_io!(file.set_len(len))?;
+            format!(
+                "kept all {} unsynced writes and truncated the file to {} bytes (synced: {}, written: {})",
+                pending.len(),
+                len,
+                synced_len,
+                written_len
+            )
+        }
+        CrashMode::Kill => unreachable!("`kill` does not simulate crashes"),
+    };
+    Ok(detail)
+}
+
+// 子プロセスでワークロードを実行し、強制終了させるラウンド
+fn kill_round(
+    options: &TortureOptions,
+    files: &TortureFiles,
+    seed: u64,
+    state: BTreeMap<LumpId, Value>,
+) -> Result<RoundOutcome, cannyls::Error> {
+    let exe = track_io!(std::env::current_exe())?;
+    let mut child = track_io!(Command::new(exe)
+        .args(&options.child_args)
+        .arg("--child-seed")
+        .arg(seed.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn())?;
+
+    // 子プロセスの出力から、開始された操作と同期済みの操作の数を読み取る
+    let begun = Arc::new(AtomicU64::new(0));
+    let synced = Arc::new(AtomicU64::new(0));
+    let reader = {
+        let stdout = child.stdout.take().expect("never fails");
+        let (begun, synced) = (Arc::clone(&begun), Arc::clone(&synced));
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                let mut words = line.split_whitespace();
+                match (
+                    words.next(),
+                    words.next().and_then(|n| n.parse::<u64>().ok()),
+                ) {
+                    (Some("begin"), Some(i)) => begun.store(i + 1, Ordering::SeqCst),
+                    (Some("synced"), Some(n)) => synced.store(n, Ordering::SeqCst),
+                    _ => {}
+                }
+            }
+        })
+    };
+
+    // 最初の操作が開始されてから、無作為な時間の後に強制終了させる
+    while begun.load(Ordering::SeqCst) == 0 {
+        if let Some(status) = track_io!(child.try_wait())? {
+            track_panic!(
+                ErrorKind::Other,
+                "the child process exited before starting the workload ({})",
+                status
+            );
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    let max_delay = options.kill_delay.as_micros() as u64;
+    let delay = StdRng::seed_from_u64(seed).gen_range(0, max_delay + 1);
+    thread::sleep(Duration::from_micros(delay));
+    track_io!(child.kill())?;
+    let status = track_io!(child.wait())?;
+    let _ = reader.join();
+    if status.code().is_some() {
+        track_panic!(
+            ErrorKind::Other,
+            "the child process exited unexpectedly ({})",
+            status
+        );
+    }
+
+    // 子プロセスと同じ操作を生成して、期待される状態を求める
+    let ops = begun.load(Ordering::SeqCst);
+    let mut generator = WorkloadGenerator::new(&options.spec, seed);
+    let mut tracker = Tracker::new(state);
+    for i in 0..ops {
+        tracker.apply(i, &generator.next_op());
+    }
+    tracker.make_durable(synced.load(Ordering::SeqCst));
+
+    let (state, violations) = track!(recover_and_verify(&files.working, &tracker))?;
+    Ok(RoundOutcome {
+        ops,
+        synced_ops: tracker.synced_ops,
+        detail: format!("killed the child process after {}us", delay),
+        image: files.working.clone(),
+        state,
+        violations,
+    })
+}
+
+// クラッシュ後のファイルを開き直して、期待される状態と比較する
+fn recover_and_verify(
+    path: &Path,
+    tracker: &Tracker,
+) -> Result<(BTreeMap<LumpId, Value>, Vec<String>), cannyls::Error> {
+    let storage = FileNvm::open(path).and_then(|nvm| StorageBuilder::new().open(nvm));
+    match storage {
+        Ok(mut storage) => track!(tracker.verify(&mut storage)),
+        Err(e) => Ok((
+            BTreeMap::new(),
+            vec![format!("failed to reopen the storage: {}", e)],
+        )),
+    }
+}
+
+// 更新系の操作（と必要ならジャーナルの同期）を実行する（`get`は何もしない）
+fn run_op<N: NonVolatileMemory>(
+    storage: &mut Storage<N>,
+    op: &Op,
+    buf: &mut Vec<u8>,
+) -> Result<(), cannyls::Error> {
+    let id = lump_id(op.key);
+    match op.kind {
+        OpKind::Put => {
+            let data =
+                track!(storage.allocate_lump_data_with_bytes(op.payload(Payload::Random, buf)))?;
+            track!(storage.put(&id, &data))?;
+        }
+        OpKind::Delete => {
+            track!(storage.delete(&id))?;
+        }
+        OpKind::DeleteRange => {
+            track!(storage.delete_range(id..lump_id(op.end)))?;
+        }
+        OpKind::Get => {}
+    }
+    if op.sync {
+        track!(storage.journal_sync())?;
+    }
+    Ok(())
+}
+
+fn lump_id(key: u64) -> LumpId {
+    LumpId::new(u128::from(key))
+}
+
+fn describe(actual: Option<&[u8]>) -> String {
+    actual.map_or("nothing".to_owned(), |bytes| {
+        format!("{} bytes", bytes.len())
+    })
+}
+
+// putされた値（内容は`payload_seed`から決まる）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Value {
+    payload_seed: u64,
+    size: u64,
+}
+impl Value {
+    fn bytes(self) -> Vec<u8> {
+        let mut buf = vec![0; self.size as usize];
+        fill_random(self.payload_seed, &mut buf);
+        buf
+    }
+
+    // 期待される値（`None`は存在しないこと）と実際の内容が一致するかどうか
+    fn matches(expected: Option<Value>, actual: Option<&[u8]>) -> bool {
+        match (expected, actual) {
+            (None, None) => true,
+            (Some(v), Some(bytes)) => v.size == bytes.len() as u64 && v.bytes() == bytes,
+            _ => false,
+        }
+    }
+}
+
+// 操作に伴うlumpの状態の変化の追跡
+struct Tracker {
+    // 同期済みの操作を全て反映した状態
+    durable: BTreeMap<LumpId, Value>,
+
+    // 全ての操作を反映した状態
+    current: BTreeMap<LumpId, Value>,
+
+    // 同期されていない変更（操作の番号, lump id, 変更後の値）
+    inflight: Vec<(u64, LumpId, Option<Value>)>,
+
+    // 同期済みの操作の数
+    synced_ops: u64,
+}
+impl Tracker {
+    fn new(state: BTreeMap<LumpId, Value>) -> Self {
+        Tracker {
+            durable: state.clone(),
+            current: state,
+            inflight: Vec::new(),
+            synced_ops: 0,
+        }
+    }
+
+    // `index`番目の操作`op`を反映する
+    fn apply(&mut self, index: u64, op: &Op) {
+        let id = lump_id(op.key);
+        match op.kind {
+            OpKind::Put => {
+                let value = Value {
+                    payload_seed: op.payload_seed,
+                    size: op.size,
+                };
+                self.current.insert(id, value);
+                self.inflight.push((index, id, Some(value)));
+            }
+            OpKind::Delete => {
+                self.current.remove(&id);
+                self.inflight.push((index, id, None));
+            }
+            OpKind::DeleteRange => {
+                let ids = self
+                    .current
+                    .range(id..lump_id(op.end))
+                    .map(|(&id, _)| id)
+                    .collect::<Vec<_>>();
+                for id in ids {
+                    self.current.remove(&id);
+                    self.inflight.push((index, id, None));
+                }
+            }
+            OpKind::Get => {}
+        }
+    }
+
+    // 最初の`ops`個の操作が同期されたことを反映する
+    fn make_durable(&mut self, ops: u64) {
+        let (synced, inflight): (Vec<_>, Vec<_>) = mem::take(&mut self.inflight)
+            .into_iter()
+            .partition(|&(index, _, _)| index < ops);
+        for (_, id, value) in synced {
+            match value {
+                Some(value) => self.durable.insert(id, value),
+                None => self.durable.remove(&id),
+            };
+        }
+        self.inflight = inflight;
+        self.synced_ops = self.synced_ops.max(ops);
+    }
+
+    // 開き直したストレージの各lumpが、同期済みの値か同期されていない変更後の値のいずれかであることを検証する
+    //
+    // 観測された状態と、検出した不整合の一覧を返す。
+    fn verify<N: NonVolatileMemory>(
+        &self,
+        storage: &mut Storage<N>,
+    ) -> Result<(BTreeMap<LumpId, Value>, Vec<String>), cannyls::Error> {
+        let mut ids = storage.list().into_iter().collect::<BTreeSet<_>>();
+        ids.extend(self.durable.keys().copied());
+        ids.extend(self.inflight.iter().map(|&(_, id, _)| id));
+
+        let mut observed = BTreeMap::new();
+        let mut violations = Vec::new();
+        for id in ids {
+            let data = track!(storage.get(&id))?;
+            let actual = data.as_ref().map(|d| d.as_bytes());
+            let synced = self.durable.get(&id).copied();
+            let mut candidates = vec![synced];
+            candidates.extend(
+                self.inflight
+                    .iter()
+                    .filter(|&&(_, i, _)| i == id)
+                    .map(|&(_, _, value)| value),
+            );
+            if let Some(&value) = candidates.iter().find(|&&v| Value::matches(v, actual)) {
+                if let Some(value) = value {
+                    observed.insert(id, value);
+                }
+                continue;
+            }
+
+            let violation = match (synced, actual) {
+                (Some(value), None) => {
+                    format!(
+                        "lump {}: lost (the synced {} bytes are missing)",
+                        id, value.size
+                    )
+                }
+                (_, Some(bytes)) if candidates.iter().all(Option::is_none) => format!(
+                    "lump {}: phantom ({} bytes, but the lump should not exist)",
+                    id,
+                    bytes.len()
+                ),
+                (_, actual) => {
+                    let mut violation = format!(
+                        "lump {}: corrupted ({} matches none of the {} expected values)",
+                        id,
+                        describe(actual),
+                        candidates.len()
+                    );
+                    // cannylsは削除や上書きで不要になった領域を、ジャーナルの同期を待たずに再利用する
+                    if synced.is_some() && candidates.len() > 1 {
+                        violation.push_str(
+                            "; the region of the synced value may have been reused \
+                             after its unsynced delete or overwrite",
+                        );
+                    }
+                    violation
+                }
+            };
+            violations.push(violation);
+        }
+        Ok((observed, violations))
+    }
+}
+
+// 最後の同期以降の書き込みの記録
+#[derive(Debug, Default)]
+struct WriteLog {
+    // 同期済みの内容を保持するファイル（同期の度に書き込みを反映する）
+    durable: Option<fs::File>,
+
+    // 最後の同期以降の書き込み（ファイル内の位置, 内容）
+    pending: Vec<(u64, Vec<u8>)>,
+}
+impl WriteLog {
+    fn sync(&mut self) -> Result<(), cannyls::Error> {
+        if let Some(ref durable) = self.durable {
+            for (offset, data) in &self.pending {
+                track_io!(durable.write_all_at(data, *offset))?;
+            }
+        }
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+// 同期されていない書き込みを記録する`NonVolatileMemory`の実装
+//
+// `split`で分割されたもの同士は記録を共有する（`FileNvm`の`sync`はファイル全体を同期するため）。
+#[derive(Debug)]
+struct CrashNvm<N> {
+    inner: N,
+    view_start: u64,
+    log: Arc<Mutex<WriteLog>>,
+}
+impl<N: NonVolatileMemory> CrashNvm<N> {
+    fn new(inner: N, log: Arc<Mutex<WriteLog>>) -> Self {
+        CrashNvm {
+            inner,
+            view_start: 0,
+            log,
+        }
+    }
+}
+impl<N: NonVolatileMemory> NonVolatileMemory for CrashNvm<N> {
+    fn sync(&mut self) -> Result<(), cannyls::Error> {
+        track!(self.inner.sync())?;
+        track!(self.log.lock().expect("never fails").sync())
+    }
+    fn position(&self) -> u64 {
+        self.inner.position()
+    }
+    fn capacity(&self) -> u64 {
+        self.inner.capacity()
+    }
+    fn block_size(&self) -> BlockSize {
+        self.inner.block_size()
+    }
+    fn split(self, position: u64) -> Result<(Self, Self), cannyls::Error> {
+        let (left, right) = track!(self.inner.split(position))?;
+        let left = CrashNvm {
+            inner: left,
+            view_start: self.view_start,
+            log: Arc::clone(&self.log),
+        };
+        let right = CrashNvm {
+            inner: right,
+            view_start: self.view_start + position,
+            log: self.log,
+        };
+        Ok((left, right))
+    }
+}
+impl<N: NonVolatileMemory> Seek for CrashNvm<N> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+impl<N: NonVolatileMemory> Read for CrashNvm<N> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+impl<N: NonVolatileMemory> Write for CrashNvm<N> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let offset = self.view_start + self.inner.position();
+        let written = self.inner.write(buf)?;
+        self.log
+            .lock()
+            .expect("never fails")
+            .pending
+            .push((offset, buf[..written].to_vec()));
+        Ok(written)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::Builder;
+    use trackable::result::TestResult;
+
+    use super::*;
+
+    fn options(dir: &Path, spec: &str, modes: Vec<CrashMode>) -> TortureOptions {
+        TortureOptions {
+            path: dir.join("torture.lusf"),
+            capacity: None,
+            spec: spec.parse().unwrap(),
+            rounds: 6,
+            ops_per_round: 50,
+            modes,
+            kill_delay: Duration::from_millis(10),
+            child_args: Vec::new(),
+            keep_files: false,
+        }
+    }
+
+    #[test]
+    fn simulated_crashes_keep_synced_operations() -> TestResult {
+        // 同期されていない書き込みを全て破棄する場合は、削除や上書きを含んでいても不整合は起きない
+        let dir = track_io!(Builder::new().prefix("cannyls_test").tempdir())?;
+        let spec = "put=6,get=2,delete=2,delete_range=1,key_space=50,size=1..8KiB,sync=5,seed=1";
+        let summary = track!(run(&options(dir.path(), spec, vec![CrashMode::Discard])))?;
+        assert!(summary.is_success(), "{:?}", summary.violations);
+        assert_eq!(summary.rounds, 6);
+        assert!(summary.ops > 0);
+
+        // 作業用のファイルは全て削除される
+        assert_eq!(track_io!(fs::read_dir(dir.path()))?.count(), 0);
+
+        // 新たなキーへのputのみであれば、同期されていない書き込みの一部が残っても不整合は起きない
+        let spec = "put=3,get=1,key_space=1000000000000,size=1..8KiB,sync=5,seed=2";
+        let modes = vec![CrashMode::Prefix, CrashMode::Reorder, CrashMode::Truncate];
+        let summary = track!(run(&options(dir.path(), spec, modes)))?;
+        assert!(summary.is_success(), "{:?}", summary.violations);
+        Ok(())
+    }
+
+    #[test]
+    fn tracker_detects_violations() -> TestResult {
+        let dir = track_io!(Builder::new().prefix("cannyls_test").tempdir())?;
+        let path = dir.path().join("test.lusf");
+        let mut storage = track!(FileNvm::create(&path, 4_000_000).and_then(Storage::create))?;
+        let put = |key, payload_seed| Op {
+            kind: OpKind::Put,
+            key,
+            end: key + 1,
+            size: 100,
+            payload_seed,
+            sync: false,
+        };
+
+        // 0: 同期済みのput, 1: 同期されていないput, 2: 同期されていないput（上書き）
+        let mut tracker = Tracker::new(BTreeMap::new());
+        tracker.apply(0, &put(1, 10));
+        tracker.make_durable(1);
+        tracker.apply(1, &put(2, 20));
+        tracker.apply(2, &put(2, 21));
+
+        let mut buf = Vec::new();
+        track!(run_op(&mut storage, &put(1, 10), &mut buf))?;
+        track!(run_op(&mut storage, &put(2, 20), &mut buf))?;
+        let (observed, violations) = track!(tracker.verify(&mut storage))?;
+        assert!(violations.is_empty(), "{:?}", violations);
+        assert_eq!(observed.len(), 2);
+
+        // 同期済みの値の消失、書き込まれていない値、書き込まれていないキー
+        track!(storage.delete(&lump_id(1)))?;
+        track!(run_op(&mut storage, &put(2, 99), &mut buf))?;
+        track!(run_op(&mut storage, &put(3, 30), &mut buf))?;
+        let (_, violations) = track!(tracker.verify(&mut storage))?;
+        assert_eq!(violations.len(), 3, "{:?}", violations);
+        assert!(violations[0].contains("lost"));
+        assert!(violations[1].contains("corrupted"));
+        assert!(violations[2].contains("phantom"));
+        Ok(())
+    }
+}
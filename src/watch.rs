@@ -0,0 +1,282 @@
+//! ジャーナル領域を定期的に読み直し、`tail -f`のように変化を出力するwatchモード。
+//!
+//! cannylsのストレージは開いた時点でジャーナルを読み込むので、他のプロセスによる変更を知るには
+//! 読み直す必要がある。ここではポーリングの度に、ストレージを開かずにジャーナル領域だけを直接読み込み
+//! （`journal::read_journal`）、前回との差分（追記されたレコード、head・tailの移動、GCによる解放）を出力する。
+//!
+//! ファイルには一切書き込まず、デフォルトではロックも取得しないので、
+//! 他のプロセスが書き込み中のストレージも監視できる。
+use std::collections::HashSet;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::header::LusfHeader;
+use crate::journal::{read_journal, JournalView};
+use crate::lock::{LockMode, StorageLock};
+use crate::lumpid::IdFormat;
+
+/// watchモードのオプション。
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    /// ジャーナルを読み直す間隔
+    pub interval: Duration,
+
+    /// 開始時に出力する、既存のエントリの（末尾からの）件数
+    pub lines: usize,
+
+    /// 読み直す回数（`None`の場合は無制限）
+    pub polls: Option<u64>,
+
+    /// 出力するlump idの形式
+    pub id_format: IdFormat,
+}
+
+// 前回のスナップショットの状態
+#[derive(Debug)]
+struct JournalState {
+    uuid: String,
+    unreleased_head: u64,
+    head: u64,
+    tail: u64,
+
+    // 各エントリの文字列表現（位置を含む）
+    entries: Vec<String>,
+}
+
+/// ジャーナル領域のスナップショットの差分を求める。
+#[derive(Debug)]
+pub struct JournalWatcher {
+    lines: usize,
+    id_format: IdFormat,
+    previous: Option<JournalState>,
+}
+impl JournalWatcher {
+    /// 最初のスナップショットでは、末尾の`lines`件のエントリを出力する。
+    pub fn new(lines: usize, id_format: IdFormat) -> Self {
+        JournalWatcher {
+            lines,
+            id_format,
+            previous: None,
+        }
+    }
+
+    /// 読み込んだジャーナル領域の内容`view`の、前回からの変化を表す行を返す。
+    pub fn update(&mut self, view: &JournalView) -> Vec<String> {
+        let current = JournalState {
+            uuid: view.uuid.clone(),
+            unreleased_head: view.unreleased_head,
+            head: view.head,
+            tail: view.tail,
+            entries: view
+                .entries
+                .iter()
+                .map(|entry| entry.format(self.id_format))
+                .collect(),
+        };
+
+        let mut lines = Vec::new();
+        match self.previous {
+            Some(ref previous) if previous.uuid == current.uuid => {
+                diff(previous, &current, &mut lines);
+            }
+            ref previous => {
+                if previous.is_some() {
+                    lines.push(format!(
+                        "the storage was re-created (uuid = {})",
+                        current.uuid
+                    ));
+                }
+                lines.push(format!(
+                    "journal: unreleased head = {}, head = {}, tail = {}, {} entries",
+                    current.unreleased_head,
+                    current.head,
+                    current.tail,
+                    current.entries.len()
+                ));
+                let skip = current.entries.len().saturating_sub(self.lines);
+                if skip > 0 && self.lines > 0 {
+                    lines.push(format!("... ({} older entries)", skip));
+                }
+                lines.extend(current.entries[skip..].iter().map(|e| format!("+ {}", e)));
+            }
+        }
+        self.previous = Some(current);
+        lines
+    }
+}
+
+fn diff(previous: &JournalState, current: &JournalState, lines: &mut Vec<String>) {
+    let previous_entries = previous.entries.iter().collect::<HashSet<_>>();
+    let current_entries = current.entries.iter().collect::<HashSet<_>>();
+    let released = previous
+        .entries
+        .iter()
+        .filter(|e| !current_entries.contains(e))
+        .count();
+
+    if previous.head != current.head {
+        lines.push(format!(
+            "head {} -> {} (released {} entries by GC)",
+            previous.head, current.head, released
+        ));
+    } else if released > 0 {
+        lines.push(format!("released {} entries by GC", released));
+    }
+    if previous.unreleased_head != current.unreleased_head {
+        lines.push(format!(
+            "unreleased head {} -> {}",
+            previous.unreleased_head, current.unreleased_head
+        ));
+    }
+    if previous.tail != current.tail {
+        lines.push(format!(
+            "tail {} -> {}{}",
+            previous.tail,
+            current.tail,
+            if current.tail < previous.tail {
+                " (wrapped around)"
+            } else {
+                ""
+            }
+        ));
+    }
+    lines.extend(
+        current
+            .entries
+            .iter()
+            .filter(|e| !previous_entries.contains(e))
+            .map(|e| format!("+ {}", e)),
+    );
+}
+
+/// `path`のlusfファイルのジャーナルを一度読み込む。
+///
+/// `lock`が`true`の場合は、読み込む間だけ共有ロックを取得する（他のプロセスが排他ロックを保持していればエラーになる）。
+pub fn poll_journal<P: AsRef<Path>>(path: P, lock: bool) -> Result<JournalView, cannyls::Error> {
+    let _lock = if lock {
+        Some(track!(StorageLock::acquire(&path, LockMode::Shared))?)
+    } else {
+        None
+    };
+    track!(read_journal(&path))
+}
+
+/// `path`のlusfファイルのジャーナルを`options.interval`毎に読み直し、変化を出力し続ける。
+///
+/// ファイルは読み込み専用で開くので、一切書き込まない。
+/// 開始時にファイルがlusfファイルでなければエラーを返す。
+/// それ以降の読み込みの失敗（ロックの競合や、書き込み中のレコードを読んだ場合など）は、
+/// エラーを出力して次の回に再試行する。
+pub fn run_watch<P: AsRef<Path>>(
+    path: P,
+    lock: bool,
+    options: &WatchOptions,
+) -> Result<(), cannyls::Error> {
+    let path = path.as_ref();
+    // パスの誤りなどは再試行しても直らないので、最初に確認しておく
+    track!(LusfHeader::read_from_file(path))?;
+
+    let started = Instant::now();
+    let mut watcher = JournalWatcher::new(options.lines, options.id_format);
+    let mut failing = false;
+    let mut poll = 0;
+    loop {
+        let result = poll_journal(path, lock).map(|view| watcher.update(&view));
+        let elapsed = started.elapsed().as_secs_f64();
+        match result {
+            Ok(lines) => {
+                if failing {
+                    println!("[+{:.1}s] the journal became readable again", elapsed);
+                    failing = false;
+                }
+                for line in lines {
+                    println!("[+{:.1}s] {}", elapsed, line);
+                }
+            }
+            Err(e) => {
+                if !failing {
+                    println!(
+                        "[+{:.1}s] cannot read the journal (retrying): {}",
+                        elapsed,
+                        e.to_string().lines().next().unwrap_or_default()
+                    );
+                    failing = true;
+                }
+            }
+        }
+        poll += 1;
+        if options.polls.is_some_and(|polls| poll >= polls) {
+            return Ok(());
+        }
+        thread::sleep(options.interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cannyls::nvm::{FileNvm, MemoryNvm};
+    use cannyls::storage::Storage;
+    use tempfile::Builder;
+    use trackable::result::TestResult;
+
+    use super::*;
+    use crate::handle::StorageHandle;
+
+    #[test]
+    fn journal_watcher_works() -> TestResult {
+        let nvm = MemoryNvm::new(vec![0; 1024 * 1024]);
+        let mut handle = StorageHandle::new(track!(Storage::create(nvm))?);
+        track!(handle.put_str(1, "foo"))?;
+        track!(handle.put_str(2, "bar"))?;
+        track!(handle.put_str(3, "baz"))?;
+
+        let mut watcher = JournalWatcher::new(2, IdFormat::default());
+        let lines = watcher.update(&track!(handle.journal_view())?);
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("journal: unreleased head = 0, head = 0, tail = "));
+        assert_eq!(lines[1], "... (1 older entries)");
+        assert!(lines[2].starts_with("+ [") && lines[2].contains("Put(key=2,"));
+        assert!(lines[3].contains("Put(key=3,"));
+
+        assert!(watcher.update(&track!(handle.journal_view())?).is_empty());
+
+        track!(handle.delete_key(1))?;
+        let lines = watcher.update(&track!(handle.journal_view())?);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("tail "));
+        assert!(lines[1].starts_with("+ [") && lines[1].ends_with("Delete(key=1)"));
+
+        track!(handle.journal_gc())?;
+        let lines = watcher.update(&track!(handle.journal_view())?);
+        assert!(lines.iter().any(|line| line.starts_with("head 0 -> ")));
+        Ok(())
+    }
+
+    #[test]
+    fn poll_journal_reads_storage_held_by_another_handle() -> TestResult {
+        let dir = track_io!(Builder::new().prefix("kanils_test").tempdir())?;
+        let path = dir.path().join("test.lusf");
+        let nvm = track!(FileNvm::create(&path, 1024 * 1024))?;
+        drop(track!(Storage::create(nvm))?);
+
+        // 他のハンドルが排他ロックを保持したまま書き込んでいる間も、ロックせずに読める
+        let mut writer = track!(StorageHandle::open(&path, true))?;
+        track!(writer.put_str(1, "foo"))?;
+        track!(writer.journal_sync())?;
+        let mut watcher = JournalWatcher::new(10, IdFormat::default());
+        let lines = watcher.update(&track!(poll_journal(&path, false))?);
+        assert!(lines.iter().any(|line| line.contains("Put(key=1,")));
+
+        track!(writer.delete_key(1))?;
+        track!(writer.journal_sync())?;
+        let lines = watcher.update(&track!(poll_journal(&path, false))?);
+        assert!(lines.iter().any(|line| line.ends_with("Delete(key=1)")));
+
+        // `--lock`の場合はロックの競合がエラーになる（`run_watch`は再試行する）
+        assert!(poll_journal(&path, true).is_err());
+        drop(writer);
+        assert!(poll_journal(&path, true).is_ok());
+        Ok(())
+    }
+}
@@ -0,0 +1,717 @@
+//! `Bench --workload`で用いるワークロードの記述と、それに従った操作列の生成。
+//!
+//! ワークロードは`key=value`の組をカンマ（または空白・改行）で区切って記述する（`spec`モジュールを参照）。
+//! `#`から行末まではコメントとして無視する。例:
+//!
+//! ```text
+//! put=70,get=25,delete=5,keys=zipfian:0.99,key_space=100000,size=1KiB..64KiB,sync=100,duration=30s,seed=42
+//! ```
+//!
+//! 使えるキーは次の通り:
+//!
+//! - `put`, `get`, `delete`, `delete_range`: 各操作の比率（重み）。少なくとも一つは正の値が必要
+//! - `keys`: キーの分布。`sequential`, `uniform`（デフォルト）, `zipfian[:θ]`（デフォルトはθ=0.99）,
+//!   `hotspot[:キーの割合:アクセスの割合]`（デフォルトは`hotspot:0.2:0.8`）のいずれか
+//! - `key_space`: 対象とするキーの数（lump idは`0..key_space`。デフォルトは10000）
+//! - `size`: putする値のサイズ。`4KiB`（固定）, `1KiB..64KiB`（一様分布）,
+//!   `4KiB@80|1MiB@20`（重み付きの選択）のいずれか（デフォルトは4KiB）
+//! - `range_len`: `delete_range`で削除する区間の長さ（デフォルトは100）
+//! - `sync`: ジャーナルの同期方針。`always`（操作毎）, `never`, または`N`（N操作毎。デフォルトは`never`）
+//! - `ops`, `duration`: 実行する操作の数と時間（例: `30s`）。両方指定した場合は先に達した方で終了する
+//!   （どちらも指定しなければ`ops=10000`）
+//! - `seed`: 乱数のシード（省略時はランダムに決めて表示する）
+//! - `payload`: putする値の内容。`random`（デフォルト）か`zero`
+//! - `preload`: `yes`ならば、計測前に全てのキーにputしておく（デフォルトは`no`）
+//! - `existing_keys`: `yes`ならば、`0..key_space`の代わりに、開始時点でストレージに存在するlump idを
+//!   キーとする（`key_space`は無視され、存在するlumpの数になる。デフォルトは`no`）
+//!
+//! `zipfian`と`hotspot`では、lump idの小さいキーほど頻繁にアクセスされる。
+use rand::distributions::Uniform;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::duration::{format_duration, parse_duration};
+use crate::size::{format_size, parse_size};
+use crate::spec::{
+    parse_bool, parse_pairs, parse_ratio, read_spec_file, PairError, ParseSpecError,
+};
+
+/// ワークロード中の操作の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpKind {
+    Put,
+    Get,
+    Delete,
+    DeleteRange,
+}
+impl OpKind {
+    const ALL: [OpKind; 4] = [
+        OpKind::Put,
+        OpKind::Get,
+        OpKind::Delete,
+        OpKind::DeleteRange,
+    ];
+
+    /// 操作の名前（ワークロードの記述と計測結果で用いる）
+    pub fn name(self) -> &'static str {
+        match self {
+            OpKind::Put => "put",
+            OpKind::Get => "get",
+            OpKind::Delete => "delete",
+            OpKind::DeleteRange => "delete_range",
+        }
+    }
+}
+
+/// キーの分布
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyDistribution {
+    /// `0, 1, 2, ...`の順に選び、`key_space`に達したら0に戻る
+    Sequential,
+
+    /// 一様に選ぶ
+    Uniform,
+
+    /// パラメータθのZipf分布に従って選ぶ（θは0以上1未満）
+    Zipfian(f64),
+
+    /// キーの先頭`keys`の割合に、アクセスの`ops`の割合を集中させる
+    Hotspot { keys: f64, ops: f64 },
+}
+impl fmt::Display for KeyDistribution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KeyDistribution::Sequential => write!(f, "sequential"),
+            KeyDistribution::Uniform => write!(f, "uniform"),
+            KeyDistribution::Zipfian(theta) => write!(f, "zipfian:{}", theta),
+            KeyDistribution::Hotspot { keys, ops } => write!(f, "hotspot:{}:{}", keys, ops),
+        }
+    }
+}
+
+/// 値のサイズの分布
+#[derive(Debug, Clone, PartialEq)]
+pub enum SizeDistribution {
+    /// 常に同じサイズ
+    Fixed(u64),
+
+    /// 区間`[min, max]`の一様分布
+    Uniform { min: u64, max: u64 },
+
+    /// 重み付きでいずれかのサイズを選ぶ（サイズ, 重み）
+    Choice(Vec<(u64, u64)>),
+}
+impl SizeDistribution {
+    /// 取り得る最大のサイズ
+    pub fn max(&self) -> u64 {
+        match *self {
+            SizeDistribution::Fixed(size) => size,
+            SizeDistribution::Uniform { max, .. } => max,
+            SizeDistribution::Choice(ref choices) => {
+                choices.iter().map(|&(size, _)| size).max().unwrap_or(0)
+            }
+        }
+    }
+
+    /// 分布に従ってサイズを一つ選ぶ。
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> u64 {
+        match *self {
+            SizeDistribution::Fixed(size) => size,
+            SizeDistribution::Uniform { min, max } => rng.sample(Uniform::new_inclusive(min, max)),
+            SizeDistribution::Choice(ref choices) => {
+                let total: u64 = choices.iter().map(|&(_, weight)| weight).sum();
+                let mut point = rng.gen_range(0, total);
+                for &(size, weight) in choices {
+                    if point < weight {
+                        return size;
+                    }
+                    point -= weight;
+                }
+                unreachable!("the point must be less than the total weight")
+            }
+        }
+    }
+}
+impl fmt::Display for SizeDistribution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SizeDistribution::Fixed(size) => write!(f, "{}", format_size(size)),
+            SizeDistribution::Uniform { min, max } => {
+                write!(f, "{}..{}", format_size(min), format_size(max))
+            }
+            SizeDistribution::Choice(ref choices) => {
+                for (i, &(size, weight)) in choices.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "|")?;
+                    }
+                    write!(f, "{}@{}", format_size(size), weight)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// ジャーナルの同期方針
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// `N`操作毎に同期する（`Every(1)`は操作毎）
+    Every(u64),
+
+    /// 明示的には同期しない
+    Never,
+}
+impl fmt::Display for SyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SyncPolicy::Every(1) => write!(f, "always"),
+            SyncPolicy::Every(n) => write!(f, "every {} ops", n),
+            SyncPolicy::Never => write!(f, "never"),
+        }
+    }
+}
+
+/// putする値の内容
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Payload {
+    /// シードから生成した疑似乱数列
+    Random,
+
+    /// 全て0
+    Zero,
+}
+
+/// ワークロードの記述。
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkloadSpec {
+    /// 各操作の重み
+    pub mix: Vec<(OpKind, u64)>,
+    pub keys: KeyDistribution,
+    pub key_space: u64,
+    pub value_size: SizeDistribution,
+    pub range_len: u64,
+    pub sync: SyncPolicy,
+
+    /// 実行する操作の数の上限
+    pub ops: Option<u64>,
+
+    /// 実行する時間の上限
+    pub duration: Option<Duration>,
+
+    pub seed: Option<u64>,
+    pub payload: Payload,
+    pub preload: bool,
+
+    /// 既存のlump idをキーとして用いるかどうか
+    pub existing_keys: bool,
+}
+impl Default for WorkloadSpec {
+    fn default() -> Self {
+        WorkloadSpec {
+            mix: Vec::new(),
+            keys: KeyDistribution::Uniform,
+            key_space: 10_000,
+            value_size: SizeDistribution::Fixed(4096),
+            range_len: 100,
+            sync: SyncPolicy::Never,
+            ops: None,
+            duration: None,
+            seed: None,
+            payload: Payload::Random,
+            preload: false,
+            existing_keys: false,
+        }
+    }
+}
+impl WorkloadSpec {
+    /// ストレージを変更しない（getのみからなる）ワークロードかどうか
+    pub fn is_read_only(&self) -> bool {
+        self.mix.iter().all(|&(op, _)| op == OpKind::Get)
+            && !self.preload
+            && self.sync == SyncPolicy::Never
+    }
+
+    /// `i`番目（0始まり）の操作を実行すべきかどうか
+    pub fn continues(&self, i: u64, elapsed: Duration) -> bool {
+        self.ops.is_none_or(|ops| i < ops) && self.duration.is_none_or(|d| elapsed < d)
+    }
+}
+impl fmt::Display for WorkloadSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mix = self
+            .mix
+            .iter()
+            .map(|&(op, weight)| format!("{}={}", op.name(), weight))
+            .collect::<Vec<_>>();
+        write!(
+            f,
+            "{}, keys = {} over {} keys, size = {}, sync = {}",
+            mix.join(","),
+            self.keys,
+            self.key_space,
+            self.value_size,
+            self.sync
+        )?;
+        if let Some(ops) = self.ops {
+            write!(f, ", ops = {}", ops)?;
+        }
+        if let Some(duration) = self.duration {
+            write!(f, ", duration = {}", format_duration(duration))?;
+        }
+        Ok(())
+    }
+}
+impl FromStr for WorkloadSpec {
+    type Err = ParseSpecError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let mut spec = WorkloadSpec::default();
+        parse_pairs("workload", input, |key, value| {
+            if let Some(op) = OpKind::ALL.iter().find(|op| op.name() == key) {
+                let weight = value.parse().map_err(PairError::invalid)?;
+                spec.mix.retain(|&(o, _)| o != *op);
+                spec.mix.push((*op, weight));
+                return Ok(());
+            }
+            match key {
+                "keys" => spec.keys = parse_key_distribution(value).map_err(PairError::invalid)?,
+                "key_space" => spec.key_space = parse_count(value).map_err(PairError::invalid)?,
+                "size" => {
+                    spec.value_size = parse_size_distribution(value).map_err(PairError::invalid)?
+                }
+                "range_len" => spec.range_len = parse_count(value).map_err(PairError::invalid)?,
+                "sync" => {
+                    spec.sync = match value {
+                        "always" => SyncPolicy::Every(1),
+                        "never" => SyncPolicy::Never,
+                        _ => SyncPolicy::Every(parse_count(value).map_err(PairError::invalid)?),
+                    }
+                }
+                "ops" => spec.ops = Some(parse_count(value).map_err(PairError::invalid)?),
+                "duration" => {
+                    spec.duration = Some(parse_duration(value).map_err(PairError::invalid)?)
+                }
+                "seed" => spec.seed = Some(value.parse().map_err(PairError::invalid)?),
+                "payload" => {
+                    spec.payload = match value {
+                        "random" => Payload::Random,
+                        "zero" => Payload::Zero,
+                        _ => return Err(PairError::invalid("expected `random` or `zero`")),
+                    }
+                }
+                "preload" => spec.preload = parse_bool(value).map_err(PairError::invalid)?,
+                "existing_keys" => {
+                    spec.existing_keys = parse_bool(value).map_err(PairError::invalid)?
+                }
+                _ => return Err(PairError::UnknownKey),
+            }
+            Ok(())
+        })?;
+
+        spec.mix.retain(|&(_, weight)| weight > 0);
+        if spec.mix.is_empty() {
+            return Err(ParseSpecError::new(
+                "workload",
+                input,
+                "no operations (specify a positive ratio for put, get, delete or delete_range)",
+            ));
+        }
+        if spec.ops.is_none() && spec.duration.is_none() {
+            spec.ops = Some(10_000);
+        }
+        Ok(spec)
+    }
+}
+
+/// `--workload`の値を解析する。
+///
+/// `@path`の形式の場合は、ファイル`path`からワークロードの記述を読み込む。
+pub fn parse_workload(s: &str) -> Result<WorkloadSpec, ParseSpecError> {
+    if let Some(path) = s.strip_prefix('@') {
+        read_spec_file("workload", path)?.parse()
+    } else {
+        s.parse()
+    }
+}
+
+// 正の整数を解析する（`_`による区切りを許す）
+fn parse_count(s: &str) -> Result<u64, String> {
+    let s: String = s.chars().filter(|&c| c != '_').collect();
+    match s.parse() {
+        Ok(0) => Err("must be positive".to_owned()),
+        Ok(n) => Ok(n),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_key_distribution(s: &str) -> Result<KeyDistribution, String> {
+    let mut parts = s.split(':');
+    let name = parts.next().unwrap_or("");
+    let params = parts.collect::<Vec<_>>();
+    let distribution = match (name, params.as_slice()) {
+        ("sequential", []) => KeyDistribution::Sequential,
+        ("uniform", []) => KeyDistribution::Uniform,
+        ("zipfian", []) => KeyDistribution::Zipfian(0.99),
+        ("zipfian", [theta]) => {
+            let theta = parse_ratio(theta)?;
+            if theta >= 1.0 {
+                return Err("the zipfian parameter must be less than 1".to_owned());
+            }
+            KeyDistribution::Zipfian(theta)
+        }
+        ("hotspot", []) => KeyDistribution::Hotspot {
+            keys: 0.2,
+            ops: 0.8,
+        },
+        ("hotspot", [keys, ops]) => KeyDistribution::Hotspot {
+            keys: parse_ratio(keys)?,
+            ops: parse_ratio(ops)?,
+        },
+        _ => {
+            return Err(format!(
+                "unknown distribution `{}` (expected sequential, uniform, \
+                 zipfian[:THETA] or hotspot[:KEYS:OPS])",
+                s
+            ))
+        }
+    };
+    Ok(distribution)
+}
+
+/// サイズの分布を解析する。
+///
+/// `4KiB`（固定）, `1KiB..64KiB`（一様分布）, `4KiB@80|1MiB@20`（重み付きの選択）のいずれかを受け付ける。
+pub fn parse_size_distribution(s: &str) -> Result<SizeDistribution, String> {
+    if s.contains('|') || s.contains('@') {
+        let choices = s
+            .split('|')
+            .map(|choice| {
+                let mut parts = choice.splitn(2, '@');
+                let size = parse_size(parts.next().unwrap_or("")).map_err(|e| e.to_string())?;
+                let weight = parts.next().map_or(Ok(1), parse_count)?;
+                Ok((size, weight))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(SizeDistribution::Choice(choices))
+    } else if let Some(split) = s.find("..") {
+        let min = parse_size(&s[..split]).map_err(|e| e.to_string())?;
+        let max = parse_size(&s[split + 2..]).map_err(|e| e.to_string())?;
+        if min > max {
+            return Err(format!("empty size range `{}`", s));
+        }
+        Ok(SizeDistribution::Uniform { min, max })
+    } else {
+        parse_size(s)
+            .map(SizeDistribution::Fixed)
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// ワークロードが生成する一つの操作。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Op {
+    pub kind: OpKind,
+
+    /// 対象のキー（`delete_range`の場合は区間の開始）
+    pub key: u64,
+
+    /// `delete_range`の区間の終了（この値を含まない）
+    pub end: u64,
+
+    /// putする値のサイズ
+    pub size: u64,
+
+    /// putする値を生成するためのシード
+    pub payload_seed: u64,
+
+    /// この操作の後にジャーナルを同期すべきかどうか
+    pub sync: bool,
+}
+impl Op {
+    /// putする値を`buf`に書き込み、その部分を返す。
+    pub fn payload<'a>(&self, payload: Payload, buf: &'a mut Vec<u8>) -> &'a [u8] {
+        buf.resize(self.size as usize, 0);
+        match payload {
+            Payload::Zero => buf.iter_mut().for_each(|b| *b = 0),
+            Payload::Random => fill_random(self.payload_seed, buf),
+        }
+        &buf[..]
+    }
+}
+
+/// `seed`から決まる疑似乱数列で`buf`を埋める（`Payload::Random`の値の生成に用いる）。
+///
+/// 値の生成は計測の妨げにならないように、軽量なsplitmix64で行う。
+pub fn fill_random(seed: u64, buf: &mut [u8]) {
+    let mut state = seed;
+    for chunk in buf.chunks_mut(8) {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+    }
+}
+
+// Gray et al. "Quickly Generating Billion-Record Synthetic Databases" の方法（YCSBと同じ）
+#[derive(Debug)]
+struct Zipfian {
+    n: u64,
+    theta: f64,
+    alpha: f64,
+    zetan: f64,
+    eta: f64,
+}
+impl Zipfian {
+    fn new(n: u64, theta: f64) -> Self {
+        let zeta = |n: u64| (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum::<f64>();
+        let zetan = zeta(n);
+        let zeta2 = zeta(2.min(n));
+        Zipfian {
+            n,
+            theta,
+            alpha: 1.0 / (1.0 - theta),
+            zetan,
+            eta: (1.0 - (2.0 / n as f64).powf(1.0 - theta)) / (1.0 - zeta2 / zetan),
+        }
+    }
+
+    fn sample<R: Rng>(&self, rng: &mut R) -> u64 {
+        let u: f64 = rng.gen();
+        let uz = u * self.zetan;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1.min(self.n - 1);
+        }
+        let rank = (self.n as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as u64;
+        rank.min(self.n - 1)
+    }
+}
+
+/// ワークロードに従って操作を生成する。
+///
+/// 同じシードからは常に同じ操作列が生成される。
+#[derive(Debug)]
+pub struct WorkloadGenerator {
+    spec: WorkloadSpec,
+    rng: StdRng,
+    zipfian: Option<Zipfian>,
+    next_sequential: u64,
+    ops_since_sync: u64,
+}
+impl WorkloadGenerator {
+    /// `seed`をシードとする生成器を作成する。
+    pub fn new(spec: &WorkloadSpec, seed: u64) -> Self {
+        let zipfian = match spec.keys {
+            KeyDistribution::Zipfian(theta) => Some(Zipfian::new(spec.key_space, theta)),
+            _ => None,
+        };
+        WorkloadGenerator {
+            spec: spec.clone(),
+            rng: StdRng::seed_from_u64(seed),
+            zipfian,
+            next_sequential: 0,
+            ops_since_sync: 0,
+        }
+    }
+
+    /// 次の操作を生成する。
+    pub fn next_op(&mut self) -> Op {
+        let total: u64 = self.spec.mix.iter().map(|&(_, weight)| weight).sum();
+        let mut point = self.rng.gen_range(0, total);
+        let mut kind = self.spec.mix[0].0;
+        for &(op, weight) in &self.spec.mix {
+            if point < weight {
+                kind = op;
+                break;
+            }
+            point -= weight;
+        }
+
+        let key = self.next_key();
+        let size = match kind {
+            OpKind::Put => self.spec.value_size.sample(&mut self.rng),
+            _ => 0,
+        };
+        self.ops_since_sync += 1;
+        let sync = match self.spec.sync {
+            SyncPolicy::Every(n) if self.ops_since_sync >= n => {
+                self.ops_since_sync = 0;
+                true
+            }
+            _ => false,
+        };
+        Op {
+            kind,
+            key,
+            end: key
+                .saturating_add(self.spec.range_len)
+                .min(self.spec.key_space),
+            size,
+            payload_seed: self.rng.gen(),
+            sync,
+        }
+    }
+
+    /// 事前投入（`preload`）のための、全キーへのputを生成する。
+    pub fn preload_ops(&mut self) -> Vec<Op> {
+        (0..self.spec.key_space)
+            .map(|key| Op {
+                kind: OpKind::Put,
+                key,
+                end: key + 1,
+                size: self.spec.value_size.sample(&mut self.rng),
+                payload_seed: self.rng.gen(),
+                sync: false,
+            })
+            .collect()
+    }
+
+    fn next_key(&mut self) -> u64 {
+        let n = self.spec.key_space;
+        match self.spec.keys {
+            KeyDistribution::Sequential => {
+                let key = self.next_sequential;
+                self.next_sequential = (key + 1) % n;
+                key
+            }
+            KeyDistribution::Uniform => self.rng.gen_range(0, n),
+            KeyDistribution::Zipfian(_) => {
+                let zipfian = self.zipfian.as_ref().expect("never fails");
+                zipfian.sample(&mut self.rng)
+            }
+            KeyDistribution::Hotspot { keys, ops } => {
+                let hot = ((n as f64 * keys) as u64).min(n);
+                let in_hot = self.rng.gen::<f64>() < ops;
+                if (in_hot && hot > 0) || hot == n {
+                    self.rng.gen_range(0, hot)
+                } else {
+                    self.rng.gen_range(hot, n)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_workload_works() {
+        let spec: WorkloadSpec = "put=70, get=25,delete=5 # comment\n\
+                                  keys=zipfian:0.9 key_space=1_000 size=1KiB..4KiB \
+                                  sync=100 duration=10s seed=42 payload=zero preload=yes"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            spec.mix,
+            vec![(OpKind::Put, 70), (OpKind::Get, 25), (OpKind::Delete, 5)]
+        );
+        assert_eq!(spec.keys, KeyDistribution::Zipfian(0.9));
+        assert_eq!(spec.key_space, 1000);
+        assert_eq!(
+            spec.value_size,
+            SizeDistribution::Uniform {
+                min: 1024,
+                max: 4096
+            }
+        );
+        assert_eq!(spec.sync, SyncPolicy::Every(100));
+        assert_eq!(spec.ops, None);
+        assert_eq!(spec.duration, Some(Duration::from_secs(10)));
+        assert_eq!(spec.seed, Some(42));
+        assert_eq!(spec.payload, Payload::Zero);
+        assert!(spec.preload);
+
+        let spec: WorkloadSpec = "get=1,size=4KiB@3|1MiB,keys=hotspot:0.1:0.9,sync=always"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            spec.value_size,
+            SizeDistribution::Choice(vec![(4096, 3), (1024 * 1024, 1)])
+        );
+        assert_eq!(
+            spec.keys,
+            KeyDistribution::Hotspot {
+                keys: 0.1,
+                ops: 0.9
+            }
+        );
+        assert_eq!(spec.sync, SyncPolicy::Every(1));
+        assert_eq!(spec.ops, Some(10_000));
+
+        assert!("".parse::<WorkloadSpec>().is_err());
+        assert!("put=0".parse::<WorkloadSpec>().is_err());
+        assert!("put=1,foo=2".parse::<WorkloadSpec>().is_err());
+        assert!("put=1,keys=zipfian:1".parse::<WorkloadSpec>().is_err());
+        assert!("put=1,size=4KiB..1KiB".parse::<WorkloadSpec>().is_err());
+        assert!("put=1,key_space=0".parse::<WorkloadSpec>().is_err());
+        assert!("put".parse::<WorkloadSpec>().is_err());
+    }
+
+    #[test]
+    fn generator_is_reproducible() {
+        let spec: WorkloadSpec = "put=1,get=1,delete_range=1,key_space=100,size=1..100,sync=3"
+            .parse()
+            .unwrap();
+        let ops = |seed| {
+            let mut generator = WorkloadGenerator::new(&spec, seed);
+            (0..1000).map(|_| generator.next_op()).collect::<Vec<_>>()
+        };
+        let ops1 = ops(1);
+        assert_eq!(ops1, ops(1));
+        assert_ne!(ops1, ops(2));
+
+        for (i, op) in ops1.iter().enumerate() {
+            assert!(op.key < 100);
+            assert_eq!(op.sync, i % 3 == 2);
+            match op.kind {
+                OpKind::Put => assert!(1 <= op.size && op.size <= 100),
+                OpKind::DeleteRange => assert!(op.key < op.end && op.end <= 100),
+                _ => assert_eq!(op.size, 0),
+            }
+        }
+        assert!(spec
+            .mix
+            .iter()
+            .all(|&(kind, _)| ops1.iter().any(|op| op.kind == kind)));
+
+        let mut a = Vec::new();
+        let mut b = Vec::new();
+        assert_eq!(
+            ops1[0].payload(Payload::Random, &mut a),
+            ops1[0].payload(Payload::Random, &mut b)
+        );
+    }
+
+    #[test]
+    fn key_distributions_work() {
+        let count_hot = |keys: &str| {
+            let spec: WorkloadSpec = format!("get=1,key_space=1000,keys={}", keys)
+                .parse()
+                .unwrap();
+            let mut generator = WorkloadGenerator::new(&spec, 0);
+            let keys = (0..10_000)
+                .map(|_| generator.next_op().key)
+                .collect::<Vec<_>>();
+            assert!(keys.iter().all(|&k| k < 1000));
+            keys.iter().filter(|&&k| k < 100).count()
+        };
+        // 先頭10%のキーへのアクセスの割合
+        assert!(count_hot("uniform") < 1500);
+        assert!(count_hot("zipfian") > 5000);
+        assert!(count_hot("hotspot:0.1:0.9") > 8500);
+
+        let spec: WorkloadSpec = "put=1,key_space=3,keys=sequential".parse().unwrap();
+        let mut generator = WorkloadGenerator::new(&spec, 0);
+        let keys = (0..5).map(|_| generator.next_op().key).collect::<Vec<_>>();
+        assert_eq!(keys, vec![0, 1, 2, 0, 1]);
+    }
+}